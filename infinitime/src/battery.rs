@@ -0,0 +1,151 @@
+//! Battery level timeline with discharge prediction and low level alerts
+
+use chrono::{DateTime, Duration, Utc};
+use std::collections::VecDeque;
+
+/// How far back the discharge rate is estimated from
+const WINDOW_HOURS: i64 = 24;
+/// Minimal timeline span and level drop to estimate discharge rate
+const MIN_SPAN_MINUTES: i64 = 30;
+const MIN_DROP: u8 = 2;
+/// Level raise that means the watch was charged, rather than measurement noise
+const CHARGE_STEP: u8 = 3;
+
+
+#[derive(Debug, Clone, Default)]
+pub struct BatteryMonitor {
+    /// Samples since the last charge, oldest first
+    timeline: VecDeque<(DateTime<Utc>, u8)>,
+    /// Alert thresholds in percent, highest first
+    thresholds: Vec<u8>,
+    /// Lowest threshold which was alerted since the last charge
+    alerted: Option<u8>,
+}
+
+impl BatteryMonitor {
+    pub fn new(thresholds: &[u8]) -> Self {
+        let mut monitor = Self::default();
+        monitor.set_thresholds(thresholds);
+        monitor
+    }
+
+    pub fn set_thresholds(&mut self, thresholds: &[u8]) {
+        self.thresholds = thresholds.to_vec();
+        self.thresholds.sort_unstable_by(|a, b| b.cmp(a));
+        self.thresholds.dedup();
+    }
+
+    /// Latest known battery level
+    pub fn level(&self) -> Option<u8> {
+        self.timeline.back().map(|(_, level)| *level)
+    }
+
+    pub fn timeline(&self) -> impl Iterator<Item = (DateTime<Utc>, u8)> + '_ {
+        self.timeline.iter().copied()
+    }
+
+    /// Add current battery level. Returns the threshold to alert about
+    /// if the level has just dropped to or below it.
+    pub fn update(&mut self, level: u8) -> Option<u8> {
+        self.update_at(Utc::now(), level)
+    }
+
+    pub fn update_at(&mut self, timestamp: DateTime<Utc>, level: u8) -> Option<u8> {
+        if let Some(previous) = self.level() {
+            if level >= previous.saturating_add(CHARGE_STEP) {
+                self.timeline.clear();
+            }
+        }
+        self.timeline.push_back((timestamp, level));
+        while let Some((oldest, _)) = self.timeline.front() {
+            if timestamp - *oldest > Duration::hours(WINDOW_HOURS) {
+                self.timeline.pop_front();
+            } else {
+                break;
+            }
+        }
+        self.check_thresholds(level)
+    }
+
+    /// Fill in the timeline from recorded history without raising alerts
+    pub fn seed(&mut self, samples: impl IntoIterator<Item = (DateTime<Utc>, u8)>) {
+        for (timestamp, level) in samples {
+            self.update_at(timestamp, level);
+        }
+        self.alerted = None;
+    }
+
+    /// Estimated time until the battery is empty, based on the discharge rate
+    /// since the last charge. `None` if there is not enough data yet.
+    pub fn time_to_empty(&self) -> Option<Duration> {
+        let (first, first_level) = *self.timeline.front()?;
+        let (last, last_level) = *self.timeline.back()?;
+        if last - first < Duration::minutes(MIN_SPAN_MINUTES) || first_level.saturating_sub(last_level) < MIN_DROP {
+            return None;
+        }
+
+        // Least squares fit of level over time
+        let n = self.timeline.len() as f64;
+        let points = self.timeline.iter()
+            .map(|(t, level)| ((*t - first).num_seconds() as f64, *level as f64));
+        let (sum_t, sum_l, sum_tt, sum_tl) = points.fold((0.0, 0.0, 0.0, 0.0), |acc, (t, l)| {
+            (acc.0 + t, acc.1 + l, acc.2 + t * t, acc.3 + t * l)
+        });
+        let denominator = n * sum_tt - sum_t * sum_t;
+        if denominator <= 0.0 {
+            return None;
+        }
+        let slope = (n * sum_tl - sum_t * sum_l) / denominator;
+        if slope >= 0.0 {
+            return None;
+        }
+        Some(Duration::seconds((last_level as f64 / -slope) as i64))
+    }
+
+    fn check_thresholds(&mut self, level: u8) -> Option<u8> {
+        if let Some(alerted) = self.alerted {
+            if level >= alerted.saturating_add(CHARGE_STEP) {
+                self.alerted = None;
+            }
+        }
+        let crossed = self.thresholds.iter().copied()
+            .rev()
+            .find(|threshold| level <= *threshold)?;
+        match self.alerted {
+            Some(alerted) if alerted <= crossed => None,
+            _ => {
+                self.alerted = Some(crossed);
+                Some(crossed)
+            }
+        }
+    }
+}
+
+/// Human readable estimate, e.g. "3 h 20 min"
+pub fn format_duration(duration: Duration) -> String {
+    let minutes = duration.num_minutes().max(0);
+    match (minutes / 60, minutes % 60) {
+        (0, minutes) => format!("{minutes} min"),
+        (hours, _) if hours >= 48 => format!("{} days", hours / 24),
+        (hours, minutes) => format!("{hours} h {minutes} min"),
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn time_to_empty_with_out_of_range_levels() {
+        // Levels come from the watch as is, they may exceed 100
+        let start = Utc::now();
+        let mut monitor = BatteryMonitor::new(&[]);
+        monitor.update_at(start, 255);
+        monitor.update_at(start + Duration::hours(1), 254);
+        assert_eq!(monitor.time_to_empty(), None);
+
+        monitor.update_at(start + Duration::hours(2), 250);
+        assert!(monitor.time_to_empty().is_some());
+    }
+}
@@ -1,10 +1,31 @@
+mod adapter;
+mod batch;
 mod device;
+mod manager;
 mod services;
-mod uuids;
+mod transport;
+mod update;
+pub mod uuids;
 
 pub use device::{
-    media_player::MediaPlayerEvent, notification::Notification,
+    capabilities::{Capabilities, Feature},
+    fs::{msg::Status as FsStatus, DirEntry, DEFAULT_FS_TIMEOUT}, fwupd::{DfuPackage, InitPacket, MAX_FIRMWARE_SIZE},
+    heart_rate::HeartRateMeasurement, media_player::MediaPlayerEvent, motion::MotionSample,
+    notification::Notification, resources::MAX_RESOURCE_SIZE,
     InfiniTime, ProgressEvent, ProgressRx, ProgressTx,
     progress_channel,
 };
+pub use adapter::{connect_device, find_device, init_adapter};
+pub use batch::{
+    batch_progress_channel, BatchFlash, BatchProgressRx, BatchProgressTx, FlashReport,
+};
+pub use manager::{DeviceManager, DEFAULT_RECONNECT_TIMEOUT};
 pub use services::start_gatt_services;
+pub use update::DeviceUpdate;
+pub use transport::{GattTransport, BluezTransport, FakeTransport};
+pub use tokio_util::sync::CancellationToken;
+
+#[cfg(feature = "github")]
+pub(crate) use device::ProgressTxWrapper;
+#[cfg(feature = "simulator")]
+pub(crate) use device::fs::msg as fs_msg;
@@ -0,0 +1,45 @@
+use super::InfiniTime;
+use crate::{Error, Result};
+use bluer::{Adapter, Address, Device};
+
+
+/// Open the default Bluetooth adapter and make sure it's powered on
+pub async fn init_adapter() -> Result<Adapter> {
+    let session = bluer::Session::new().await?;
+    let adapter = session.default_adapter().await?;
+    adapter.set_powered(true).await?;
+    Ok(adapter)
+}
+
+/// Find the device by address, or pick the default one: either already
+/// connected InfiniTime, or the only known InfiniTime device
+pub async fn find_device(adapter: &Adapter, address: Option<Address>) -> Result<Device> {
+    if let Some(address) = address {
+        return Ok(adapter.device(address)?);
+    }
+    let mut devices = InfiniTime::list_known_devices(adapter).await?;
+    for device in &devices {
+        if device.is_connected().await? {
+            return Ok(device.clone());
+        }
+    }
+    match devices.len() {
+        0 => Err(Error::NoKnownDevices),
+        1 => Ok(devices.remove(0)),
+        _ => Err(Error::MultipleKnownDevices),
+    }
+}
+
+/// Connect to the device found by [`find_device`] unless it's already
+/// connected, and check that it's an InfiniTime watch
+pub async fn connect_device(adapter: &Adapter, address: Option<Address>) -> Result<Device> {
+    let device = find_device(adapter, address).await?;
+    if !device.is_connected().await? {
+        log::info!("Connecting to {}...", device.address());
+        device.connect().await?;
+    }
+    if !InfiniTime::check_device(&device).await {
+        return Err(Error::NotInfiniTime(device.address()));
+    }
+    Ok(device)
+}
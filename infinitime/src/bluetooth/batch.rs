@@ -0,0 +1,119 @@
+use super::{manager::DEFAULT_RECONNECT_TIMEOUT, progress_channel, DeviceManager, DeviceUpdate, ProgressEvent};
+use crate::Result;
+use bluer::Address;
+use futures::StreamExt;
+use std::time::Duration;
+use tokio::sync::mpsc;
+
+
+/// The same firmware and/or resources flashed to several watches
+#[derive(Debug, Clone, Copy)]
+pub struct BatchFlash<'a> {
+    /// DFU package
+    pub firmware: Option<&'a [u8]>,
+    /// Resources archive, uploaded after the firmware
+    pub resources: Option<&'a [u8]>,
+    /// Maximal number of watches flashed at once
+    pub concurrency: usize,
+    /// How many times to start firmware upgrade over after a failure
+    pub attempts: u32,
+    /// How long to wait for the watch to come back after firmware upgrade
+    pub reconnect_timeout: Duration,
+}
+
+impl<'a> Default for BatchFlash<'a> {
+    fn default() -> Self {
+        Self {
+            firmware: None,
+            resources: None,
+            concurrency: 1,
+            attempts: 3,
+            reconnect_timeout: DEFAULT_RECONNECT_TIMEOUT,
+        }
+    }
+}
+
+/// Outcome of flashing one watch
+#[derive(Debug)]
+pub struct FlashReport {
+    pub address: Address,
+    /// Firmware version read from the watch after flashing
+    pub result: Result<String>,
+}
+
+pub type BatchProgressTx = mpsc::Sender<(Address, ProgressEvent)>;
+pub type BatchProgressRx = mpsc::Receiver<(Address, ProgressEvent)>;
+
+pub fn batch_progress_channel(capacity: usize) -> (BatchProgressTx, BatchProgressRx) {
+    mpsc::channel(capacity)
+}
+
+
+impl DeviceManager {
+    /// Flash watches by addresses, at most `batch.concurrency` at once.
+    /// Failure on one watch doesn't stop the others. Reports are returned
+    /// in the order the watches finished.
+    pub async fn flash_batch(
+        &self,
+        addresses: &[Address],
+        batch: BatchFlash<'_>,
+        progress_sender: Option<BatchProgressTx>,
+    ) -> Vec<FlashReport> {
+        futures::stream::iter(addresses.iter().copied())
+            .map(|address| {
+                let progress_sender = progress_sender.clone();
+                async move {
+                    let result = self.flash_one(address, batch, progress_sender).await;
+                    match &result {
+                        Ok(version) => log::info!("Flashed {}, firmware version: {}", address, version),
+                        Err(error) => log::error!("Failed to flash {}: {}", address, error),
+                    }
+                    FlashReport { address, result }
+                }
+            })
+            .buffer_unordered(batch.concurrency.max(1))
+            .collect()
+            .await
+    }
+
+    async fn flash_one(
+        &self,
+        address: Address,
+        batch: BatchFlash<'_>,
+        progress_sender: Option<BatchProgressTx>,
+    ) -> Result<String> {
+        let update = DeviceUpdate {
+            firmware: batch.firmware,
+            resources: batch.resources,
+            attempts: batch.attempts,
+            reconnect_timeout: batch.reconnect_timeout,
+        };
+        let (progress_tx, progress_task) = forward_progress(address, progress_sender);
+        let result = self.update_device(address, update, progress_tx, None).await;
+        if let Some(task) = progress_task {
+            _ = task.await;
+        }
+        result
+    }
+}
+
+/// Tag progress events of one watch with its address
+fn forward_progress(
+    address: Address,
+    progress_sender: Option<BatchProgressTx>,
+) -> (Option<super::ProgressTx>, Option<tokio::task::JoinHandle<()>>) {
+    match progress_sender {
+        Some(batch_tx) => {
+            let (progress_tx, mut progress_rx) = progress_channel(16);
+            let task = tokio::spawn(async move {
+                while let Some(event) = progress_rx.recv().await {
+                    if batch_tx.send((address, event)).await.is_err() {
+                        break;
+                    }
+                }
+            });
+            (Some(progress_tx), Some(task))
+        }
+        None => (None, None),
+    }
+}
@@ -1,62 +1,73 @@
-use super::uuids;
+use super::{uuids, transport::{BluezTransport, GattTransport}};
 use uuid::Uuid;
-use anyhow::{anyhow, Result};
-use bluer::{gatt::remote::Characteristic, Adapter, Device};
-use futures::{Stream, StreamExt};
-use std::{sync::{Arc, atomic::{AtomicBool, Ordering}}, collections::HashMap};
+use crate::{Error, Result};
+use bluer::{Adapter, Address, Device};
+use futures::{stream::{self, BoxStream}, Stream, StreamExt};
+use std::sync::{Arc, atomic::{AtomicBool, AtomicU64, Ordering}};
 use tokio::sync::mpsc;
 
+pub mod capabilities;
 pub mod fs;
 pub mod fwupd;
+pub mod heart_rate;
 pub mod notification;
 pub mod media_player;
+pub mod motion;
 pub mod resources;
 
 
 #[derive(Debug)]
 pub struct InfiniTime {
-    device: Arc<Device>,
-    characteristics: HashMap<Uuid, Characteristic>,
+    transport: Box<dyn GattTransport>,
+    device: Option<Arc<Device>>,
     is_upgrading_firmware: AtomicBool,
+    fs_timeout_ms: AtomicU64,
 }
 
 impl InfiniTime {
     pub async fn new(device: Arc<Device>) -> Result<Self> {
-        let characteristics = Self::read_characteristics_map(&device).await?;
-        log::debug!("Characteristics: {:#?}", characteristics.keys());
-        Ok(Self {
-            device,
-            characteristics,
+        let transport = BluezTransport::new(device.clone()).await?;
+        let mut infinitime = Self::with_transport(transport);
+        infinitime.device = Some(device);
+        Ok(infinitime)
+    }
+
+    /// Create InfiniTime on top of a custom GATT transport,
+    /// e.g. to run against a simulated watch
+    pub fn with_transport(transport: impl GattTransport + 'static) -> Self {
+        log::debug!("Characteristics: {:#?}", transport.characteristics());
+        Self {
+            transport: Box::new(transport),
+            device: None,
             is_upgrading_firmware: AtomicBool::new(false),
-        })
+            fs_timeout_ms: AtomicU64::new(fs::DEFAULT_FS_TIMEOUT.as_millis() as u64),
+        }
+    }
+
+    /// BlueZ device, if connected via BlueZ
+    pub fn device(&self) -> Option<&Device> {
+        self.device.as_deref()
     }
 
-    pub fn device(&self) -> &Device {
-        &self.device
+    pub fn address(&self) -> Address {
+        self.transport.address()
     }
 
     // -- Basic getters --
 
     pub async fn read_battery_level(&self) -> Result<u8> {
-        Ok(self.chr(&uuids::CHR_BATTERY_LEVEL)?.read().await?[0])
+        Ok(self.read_chr(uuids::CHR_BATTERY_LEVEL).await?[0])
     }
 
     pub async fn read_firmware_version(&self) -> Result<String> {
-        let bytes = self.chr(&uuids::CHR_FIRMWARE_REVISION)?.read().await?;
+        let bytes = self.read_chr(uuids::CHR_FIRMWARE_REVISION).await?;
         Ok(String::from_utf8(bytes)?)
     }
 
-    pub async fn read_heart_rate(&self) -> Result<u8> {
-        // TODO: Parse properly according to 3.106 Heart Rate Measurement
-        // from https://www.bluetooth.org/docman/handlers/DownloadDoc.ashx?doc_id=539729
-        Ok(self.chr(&uuids::CHR_HEART_RATE)?.read().await?[1])
-    }
-
     pub async fn read_step_count(&self) -> Result<u32> {
-        let data = self.chr(&uuids::CHR_STEP_COUNT)?
-            .read().await?
+        let data = self.read_chr(uuids::CHR_STEP_COUNT).await?
             .try_into()
-            .map_err(|_| anyhow!("Failed to convert Vec<u8> to [u8;4]"))?;
+            .map_err(|_| Error::Protocol(String::from("Failed to convert Vec<u8> to [u8;4]")))?;
         Ok(u32::from_le_bytes(data))
     }
 
@@ -65,27 +76,27 @@ impl InfiniTime {
     // -- Event streams --
 
     pub async fn get_battery_level_stream(&self) -> Result<impl Stream<Item = u8>> {
-        let stream = self.chr(&uuids::CHR_BATTERY_LEVEL)?.notify().await?;
+        let stream = self.notify_chr(uuids::CHR_BATTERY_LEVEL).await?;
         Ok(stream.filter_map(|v| async move { v.get(0).cloned() }))
     }
 
-    pub async fn get_heart_rate_stream(&self) -> Result<impl Stream<Item = u8>> {
-        let stream = self.chr(&uuids::CHR_HEART_RATE)?.notify().await?;
-        Ok(stream.filter_map(|v| async move { v.get(1).cloned() }))
-    }
-
     pub async fn get_step_count_stream(&self) -> Result<impl Stream<Item = u32>> {
-        let stream = self.chr(&uuids::CHR_STEP_COUNT)?.notify().await?;
+        let stream = self.notify_chr(uuids::CHR_STEP_COUNT).await?;
         Ok(stream.filter_map(|v| async move {
             v.try_into().ok().map(u32::from_le_bytes)
         }))
     }
 
+    /// Stream of BlueZ device property changes, which ends on disconnection.
+    /// For other transports it never yields anything.
     pub async fn get_property_stream(&self) -> Result<impl Stream<Item = bluer::DeviceProperty>> {
-        Ok(self.device.events().await?.map(|event| {
-            let bluer::DeviceEvent::PropertyChanged(property) = event;
-            property
-        }))
+        match &self.device {
+            Some(device) => Ok(device.events().await?.map(|event| {
+                let bluer::DeviceEvent::PropertyChanged(property) = event;
+                property
+            }).left_stream()),
+            None => Ok(stream::pending().right_stream()),
+        }
     }
 
     // -- Firmware upgrade --
@@ -112,20 +123,24 @@ impl InfiniTime {
         Ok(result)
     }
 
-    fn chr<'s>(&'s self, uuid: &Uuid) -> Result<&'s Characteristic> {
-        self.characteristics.get(uuid)
-            .ok_or(anyhow!("Characteristic not found by UUID: {}", uuid.to_string()))
+    fn check_chr(&self, uuid: &Uuid) -> Result<()> {
+        if self.transport.has_characteristic(uuid) {
+            Ok(())
+        } else {
+            Err(Error::CharacteristicNotFound(*uuid))
+        }
     }
 
-    async fn read_characteristics_map(device: &Device) -> Result<HashMap<Uuid, Characteristic>> {
-        let mut map = HashMap::new();
-        for service in device.services().await? {
-            for characteristic in service.characteristics().await? {
-                let uuid = characteristic.uuid().await?;
-                map.insert(uuid, characteristic);
-            }
-        }
-        Ok(map)
+    async fn read_chr(&self, uuid: Uuid) -> Result<Vec<u8>> {
+        self.transport.read(uuid).await
+    }
+
+    async fn write_chr(&self, uuid: Uuid, value: &[u8]) -> Result<()> {
+        self.transport.write(uuid, value).await
+    }
+
+    async fn notify_chr(&self, uuid: Uuid) -> Result<BoxStream<'static, Vec<u8>>> {
+        self.transport.notify(uuid).await
     }
 }
 
@@ -145,10 +160,10 @@ pub fn progress_channel(capacity: usize) -> (ProgressTx, ProgressRx) {
 
 // Private helper
 
-struct ProgressTxWrapper(Option<ProgressTx>);
+pub(crate) struct ProgressTxWrapper(pub(crate) Option<ProgressTx>);
 
 impl ProgressTxWrapper {
-    async fn report(&self, event: ProgressEvent) {
+    pub(crate) async fn report(&self, event: ProgressEvent) {
         if let Some(tx) = &self.0 {
             if let Err(err) = tx.send(event).await {
                 log::error!("Failed to send progress event: {}", err);
@@ -156,11 +171,11 @@ impl ProgressTxWrapper {
         }
     }
 
-    async fn report_msg<T: Into<String>>(&self, msg: T) {
+    pub(crate) async fn report_msg<T: Into<String>>(&self, msg: T) {
         self.report(ProgressEvent::Message(msg.into())).await;
     }
 
-    async fn report_num(&self, current: u32, total: u32) {
+    pub(crate) async fn report_num(&self, current: u32, total: u32) {
         self.report(ProgressEvent::Numbers { current, total }).await;
     }
 }
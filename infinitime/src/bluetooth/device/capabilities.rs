@@ -0,0 +1,111 @@
+use super::{uuids, InfiniTime};
+use crate::Result;
+use std::{collections::BTreeSet, fmt, str::FromStr};
+
+
+/// Watch feature that depends on the firmware version and build configuration
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Feature {
+    BatteryLevel,
+    HeartRate,
+    StepCount,
+    Motion,
+    FileSystem,
+    MediaPlayer,
+    Notifications,
+    FirmwareUpgrade,
+    Weather,
+    Navigation,
+}
+
+impl Feature {
+    pub const ALL: [Feature; 10] = [
+        Self::BatteryLevel, Self::HeartRate, Self::StepCount, Self::Motion,
+        Self::FileSystem, Self::MediaPlayer, Self::Notifications,
+        Self::FirmwareUpgrade, Self::Weather, Self::Navigation,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::BatteryLevel => "battery-level",
+            Self::HeartRate => "heart-rate",
+            Self::StepCount => "step-count",
+            Self::Motion => "motion",
+            Self::FileSystem => "file-system",
+            Self::MediaPlayer => "media-player",
+            Self::Notifications => "notifications",
+            Self::FirmwareUpgrade => "firmware-upgrade",
+            Self::Weather => "weather",
+            Self::Navigation => "navigation",
+        }
+    }
+
+    fn is_exposed_by(&self, infinitime: &InfiniTime) -> bool {
+        let has = |uuid| infinitime.transport.has_characteristic(&uuid);
+        match self {
+            Self::BatteryLevel => has(uuids::CHR_BATTERY_LEVEL),
+            Self::HeartRate => has(uuids::CHR_HEART_RATE),
+            Self::StepCount => has(uuids::CHR_STEP_COUNT),
+            Self::Motion => has(uuids::CHR_MOTION),
+            Self::FileSystem => has(uuids::CHR_FS_VERSION) && has(uuids::CHR_FS_TRANSFER),
+            Self::MediaPlayer => has(uuids::CHR_MP_EVENTS) && has(uuids::CHR_MP_STATUS),
+            Self::Notifications => has(uuids::CHR_NEW_ALERT),
+            Self::FirmwareUpgrade => has(uuids::CHR_FWUPD_CONTROL_POINT) && has(uuids::CHR_FWUPD_PACKET),
+            Self::Weather => has(uuids::CHR_WEATHER_DATA) || has(uuids::CHR_WEATHER_DATA_LEGACY),
+            Self::Navigation => has(uuids::CHR_NAV_FLAGS) && has(uuids::CHR_NAV_NARRATIVE),
+        }
+    }
+}
+
+impl fmt::Display for Feature {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+impl FromStr for Feature {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        Self::ALL.into_iter()
+            .find(|feature| feature.name() == s)
+            .ok_or_else(|| format!("Unknown feature: {s}"))
+    }
+}
+
+
+/// Features supported by the connected watch
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Capabilities {
+    pub firmware_version: String,
+    /// BLE file system protocol version, if the file system is supported
+    pub fs_version: Option<u16>,
+    features: BTreeSet<Feature>,
+}
+
+impl Capabilities {
+    pub fn supports(&self, feature: Feature) -> bool {
+        self.features.contains(&feature)
+    }
+
+    pub fn features(&self) -> impl Iterator<Item = Feature> + '_ {
+        self.features.iter().copied()
+    }
+}
+
+
+impl InfiniTime {
+    /// Detect supported features from the characteristics exposed by the watch
+    pub async fn capabilities(&self) -> Result<Capabilities> {
+        let features: BTreeSet<_> = Feature::ALL.into_iter()
+            .filter(|feature| feature.is_exposed_by(self))
+            .collect();
+        let fs_version = if features.contains(&Feature::FileSystem) {
+            Some(self.read_fs_version().await?)
+        } else {
+            None
+        };
+        let firmware_version = self.read_firmware_version().await?;
+        Ok(Capabilities { firmware_version, fs_version, features })
+    }
+}
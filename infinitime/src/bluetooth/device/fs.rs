@@ -1,12 +1,16 @@
 use super::{uuids, InfiniTime, ProgressTx, ProgressTxWrapper};
 use msg::{Response, Status};
 use chrono::Utc;
-use futures::{pin_mut, StreamExt};
-use anyhow::{anyhow, Result};
+use futures::{pin_mut, Stream, StreamExt};
+use crate::{Error, Result};
+use std::{sync::atomic::Ordering, time::Duration};
+use tokio_util::sync::CancellationToken;
 
-mod msg;
+pub(crate) mod msg;
 
 const CHUNK_SIZE: u32 = 200;
+/// How long to wait for the watch to respond to a single FS request
+pub const DEFAULT_FS_TIMEOUT: Duration = Duration::from_secs(5);
 
 #[derive(Debug)]
 pub struct DirEntry {
@@ -65,23 +69,26 @@ pub fn ancestors_union<'s>(paths: impl Iterator<Item=&'s str>) -> Vec<&'s str> {
 #[allow(unused)]
 impl InfiniTime {
     pub async fn read_fs_version(&self) -> Result<u16> {
-        let data = self.chr(&uuids::CHR_FS_VERSION)?.read().await?;
+        let data = self.read_chr(uuids::CHR_FS_VERSION).await?;
         Ok(u16::from_le_bytes(data.as_slice().try_into()?))
     }
 
+    /// Read file content starting from `position`. When `cancel` is triggered,
+    /// the transfer stops with `Error::Cancelled` without waiting for the
+    /// pending response.
     pub async fn read_file(
-        &self, path: &str, position: u32, progress_sender: Option<ProgressTx>
+        &self, path: &str, position: u32,
+        progress_sender: Option<ProgressTx>, cancel: Option<&CancellationToken>,
     ) -> Result<Vec<u8>> {
         log::info!("Reading file: {}", path);
-        let chr = self.chr(&uuids::CHR_FS_TRANSFER)?;
         let progress = ProgressTxWrapper(progress_sender);
-        let resp_stream = chr.notify().await?;
+        let resp_stream = self.notify_chr(uuids::CHR_FS_TRANSFER).await?;
         pin_mut!(resp_stream);
 
         // Init
         let req = msg::read_init_req(path, position, CHUNK_SIZE);
-        chr.write(&req).await?;
-        let resp = resp_stream.next().await.ok_or(anyhow!("No response"))?;
+        self.write_chr(uuids::CHR_FS_TRANSFER, &req).await?;
+        let resp = self.wait_fs_response(&mut resp_stream, msg::Command::ReadResp, cancel).await?;
         let parsed = msg::ReadResponse::deserialize_check(resp.as_slice())?;
 
         let total_size = parsed.total_size - position;
@@ -93,9 +100,10 @@ impl InfiniTime {
 
         // Read content
         while content.len() < total_size as usize {
+            check_cancelled(cancel)?;
             let req = msg::read_chunk_req(offset, CHUNK_SIZE);
-            chr.write(&req).await?;
-            let resp = resp_stream.next().await.ok_or(anyhow!("No response"))?;
+            self.write_chr(uuids::CHR_FS_TRANSFER, &req).await?;
+            let resp = self.wait_fs_response(&mut resp_stream, msg::Command::ReadResp, cancel).await?;
             let parsed = msg::ReadResponse::deserialize_check(resp.as_slice())?;
 
             content.extend_from_slice(parsed.data);
@@ -106,106 +114,171 @@ impl InfiniTime {
         Ok(content)
     }
 
+    /// Write `content` to the file starting from `position`. When `cancel`
+    /// is triggered, the transfer stops with `Error::Cancelled` without
+    /// waiting for the pending response. If a transfer from the beginning of
+    /// the file fails after the watch may have created it, the partially
+    /// written file is removed.
     pub async fn write_file(
-        &self, path: &str, content: &[u8], position: u32, progress_sender: Option<ProgressTx>
+        &self, path: &str, content: &[u8], position: u32,
+        progress_sender: Option<ProgressTx>, cancel: Option<&CancellationToken>,
     ) -> Result<()> {
         log::info!("Writing file: {}", path);
-        let chr = self.chr(&uuids::CHR_FS_TRANSFER)?;
         let progress = ProgressTxWrapper(progress_sender);
-        let resp_stream = chr.notify().await?;
+        check_cancelled(cancel)?;
+        let resp_stream = self.notify_chr(uuids::CHR_FS_TRANSFER).await?;
         pin_mut!(resp_stream);
 
         // Init
         let timestamp = Utc::now().timestamp_nanos_opt().unwrap_or(0) as u64;
         let req = msg::write_init_req(path, position, content.len() as u32, timestamp);
-        chr.write(&req).await?;
-        let resp = resp_stream.next().await.ok_or(anyhow!("No response"))?;
-        msg::WriteResponse::deserialize_check(resp.as_slice())?;
+        self.write_chr(uuids::CHR_FS_TRANSFER, &req).await?;
+        let result = match self.wait_fs_response(&mut resp_stream, msg::Command::WriteResp, cancel).await {
+            Ok(resp) => {
+                // Refused by the watch, so there's nothing to clean up
+                msg::WriteResponse::deserialize_check(resp.as_slice())?;
+                self.write_file_chunks(&mut resp_stream, content, position, &progress, cancel).await
+            }
+            Err(error) => Err(error),
+        };
+
+        if result.is_err() && position == 0 {
+            log::info!("Removing partially written file: {}", path);
+            // Cleanup must not be cancelled along with the transfer
+            if let Err(err) = self.delete_file(path, None).await {
+                log::warn!("Failed to remove partially written file '{}': {}", path, err);
+            }
+        }
+        result
+    }
 
-        // Write content
+    async fn write_file_chunks(
+        &self, resp_stream: &mut (impl Stream<Item = Vec<u8>> + Unpin), content: &[u8], position: u32,
+        progress: &ProgressTxWrapper, cancel: Option<&CancellationToken>,
+    ) -> Result<()> {
         let mut offset = position;
         for chunk in content.chunks(CHUNK_SIZE as usize) {
             log::trace!("Sending file chunk: {} - {}", offset, offset + chunk.len() as u32);
-            // Write chunk
+            check_cancelled(cancel)?;
             let req = msg::write_chunk_req(offset, chunk);
-            chr.write(&req).await?;
-            let resp = resp_stream.next().await.ok_or(anyhow!("No response"))?;
+            self.write_chr(uuids::CHR_FS_TRANSFER, &req).await?;
+            let resp = self.wait_fs_response(resp_stream, msg::Command::WriteResp, cancel).await?;
             msg::WriteResponse::deserialize_check(resp.as_slice())?;
+
             offset += chunk.len() as u32;
             progress.report_num(offset - position, content.len() as u32).await;
         }
-
         Ok(())
     }
 
-    pub async fn delete_file(&self, path: &str) -> Result<()> {
+    pub async fn delete_file(&self, path: &str, cancel: Option<&CancellationToken>) -> Result<()> {
         log::info!("Deleting file: {}", path);
-        let chr = self.chr(&uuids::CHR_FS_TRANSFER)?;
-        let resp_stream = chr.notify().await?;
+        let resp_stream = self.notify_chr(uuids::CHR_FS_TRANSFER).await?;
         pin_mut!(resp_stream);
 
         let req = msg::delete_req(path);
-        chr.write(&req).await?;
-        let resp = resp_stream.next().await.ok_or(anyhow!("No response"))?;
+        self.write_chr(uuids::CHR_FS_TRANSFER, &req).await?;
+        let resp = self.wait_fs_response(&mut resp_stream, msg::Command::DeleteResp, cancel).await?;
         msg::DeleteResponse::deserialize_check(resp.as_slice())?;
         Ok(())
     }
 
-    pub async fn make_dir(&self, path: &str) -> Result<()> {
+    pub async fn make_dir(&self, path: &str, cancel: Option<&CancellationToken>) -> Result<()> {
         log::info!("Making dir: {}", path);
-        let chr = self.chr(&uuids::CHR_FS_TRANSFER)?;
-        let resp_stream = chr.notify().await?;
+        let resp_stream = self.notify_chr(uuids::CHR_FS_TRANSFER).await?;
         pin_mut!(resp_stream);
 
         let timestamp = Utc::now().timestamp_nanos_opt().unwrap_or(0) as u64;
         let req = msg::make_dir_req(path, timestamp);
-        chr.write(&req).await?;
-        let resp = resp_stream.next().await.ok_or(anyhow!("No response"))?;
+        self.write_chr(uuids::CHR_FS_TRANSFER, &req).await?;
+        let resp = self.wait_fs_response(&mut resp_stream, msg::Command::MakeDirResp, cancel).await?;
         let parsed = msg::MakeDirResponse::deserialize(resp.as_slice())?;
         if parsed.status != Status::Ok && parsed.status != Status::Exists {
-            Err(anyhow!("LittleFS error: {:?}", parsed.status))
+            Err(Error::FileSystem(parsed.status))
         } else {
             Ok(())
         }
     }
 
-    pub async fn list_dir(&self, path: &str) -> Result<Vec<DirEntry>> {
+    pub async fn list_dir(&self, path: &str, cancel: Option<&CancellationToken>) -> Result<Vec<DirEntry>> {
         log::info!("Listing dir: {}", path);
-        let chr = self.chr(&uuids::CHR_FS_TRANSFER)?;
-        let resp_stream = chr.notify().await?;
+        let resp_stream = self.notify_chr(uuids::CHR_FS_TRANSFER).await?;
         pin_mut!(resp_stream);
 
         let req = msg::list_dir_req(path);
-        chr.write(&req).await?;
+        self.write_chr(uuids::CHR_FS_TRANSFER, &req).await?;
 
         let mut output = Vec::new();
-        while let Some(resp) = resp_stream.next().await {
+        loop {
+            let resp = self.wait_fs_response(&mut resp_stream, msg::Command::ListDirResp, cancel).await?;
             let parsed = msg::ListDirResponse::deserialize_check(resp.as_slice())?;
             output.push(DirEntry::from(&parsed));
-            if parsed.entry_idx >= parsed.entries_total - 1 {
+            if parsed.entry_idx + 1 >= parsed.entries_total {
                 break;
             }
         }
         Ok(output)
     }
 
-    pub async fn move_file(&self, old_path: &str, new_path: &str) -> Result<()> {
+    pub async fn move_file(&self, old_path: &str, new_path: &str, cancel: Option<&CancellationToken>) -> Result<()> {
         log::info!("Move file or directory: {} -> {}", old_path, new_path);
-        let chr = self.chr(&uuids::CHR_FS_TRANSFER)?;
-        let resp_stream = chr.notify().await?;
+        let resp_stream = self.notify_chr(uuids::CHR_FS_TRANSFER).await?;
         pin_mut!(resp_stream);
 
         let req = msg::move_req(old_path, new_path);
-        chr.write(&req).await?;
-        let resp = resp_stream.next().await.ok_or(anyhow!("No response"))?;
+        self.write_chr(uuids::CHR_FS_TRANSFER, &req).await?;
+        let resp = self.wait_fs_response(&mut resp_stream, msg::Command::MoveResp, cancel).await?;
         msg::MoveResp::deserialize_check(resp.as_slice())?;
         Ok(())
     }
 
-    pub async fn make_dirs(&self, path: &str) -> Result<()> {
+    pub async fn make_dirs(&self, path: &str, cancel: Option<&CancellationToken>) -> Result<()> {
         for p in ancestors(path).iter().rev() {
-            self.make_dir(p).await?;
+            self.make_dir(p, cancel).await?;
         }
         Ok(())
     }
+
+    /// Maximal time to wait for the watch to respond to a single FS request
+    pub fn fs_timeout(&self) -> Duration {
+        Duration::from_millis(self.fs_timeout_ms.load(Ordering::Relaxed))
+    }
+
+    pub fn set_fs_timeout(&self, timeout: Duration) {
+        self.fs_timeout_ms.store(timeout.as_millis() as u64, Ordering::Relaxed);
+    }
+
+    /// Wait for the response to the request, giving up on timeout or when
+    /// `cancel` is triggered. Late responses to abandoned requests of another
+    /// kind (e.g. after a timeout) are skipped.
+    async fn wait_fs_response(
+        &self, stream: &mut (impl Stream<Item = Vec<u8>> + Unpin),
+        expected: msg::Command, cancel: Option<&CancellationToken>,
+    ) -> Result<Vec<u8>> {
+        let response = async {
+            while let Some(response) = stream.next().await {
+                if response.first() == Some(&(expected as u8)) {
+                    return Ok(response);
+                }
+                log::debug!("Skipping stale FS response: {:02x?}", response);
+            }
+            Err(Error::NoResponse)
+        };
+        let response = tokio::time::timeout(self.fs_timeout(), response);
+        let response = match cancel {
+            Some(token) => tokio::select! {
+                response = response => response,
+                _ = token.cancelled() => return Err(Error::Cancelled),
+            },
+            None => response.await,
+        };
+        response.map_err(|_| Error::Timeout)?
+    }
+}
+
+pub(crate) fn check_cancelled(cancel: Option<&CancellationToken>) -> Result<()> {
+    match cancel {
+        Some(token) if token.is_cancelled() => Err(Error::Cancelled),
+        _ => Ok(()),
+    }
 }
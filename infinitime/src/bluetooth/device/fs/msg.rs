@@ -1,5 +1,4 @@
-use crate::utils::value_enum;
-use anyhow::{anyhow, Result};
+use crate::{utils::value_enum, Error, Result};
 
 // -- Commands and statuses --
 
@@ -49,7 +48,7 @@ impl Status {
     pub fn into_result(self) -> Result<()> {
         match self {
             Status::Ok => Ok(()),
-            error => Err(anyhow!("LittleFS error: {:?}", error))
+            error => Err(Error::FileSystem(error))
         }
     }
 }
@@ -292,9 +291,9 @@ impl<'s> Response<'s> for MoveResp {
 
 fn response_data_check(data: &[u8], min_size: usize, exp_cmd: Command) -> Result<()> {
     if data.len() < min_size {
-        Err(anyhow!("Unexpected response length: {} < {}", data.len(), min_size))
+        Err(Error::Protocol(format!("Unexpected response length: {} < {}", data.len(), min_size)))
     } else if data[0] != exp_cmd as u8 {
-        Err(anyhow!("Unexpected command: {:02x} != {:?}", data[0], exp_cmd))
+        Err(Error::Protocol(format!("Unexpected command: {:02x} != {:?}", data[0], exp_cmd)))
     } else {
         Ok(())
     }
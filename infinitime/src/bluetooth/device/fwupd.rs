@@ -1,49 +1,31 @@
 use crate::utils;
 use super::{uuids, InfiniTime, ProgressTx, ProgressTxWrapper};
-use anyhow::{anyhow, ensure, Result};
-use futures::{pin_mut, StreamExt};
-use serde::Deserialize;
-use std::{
-    io::{Cursor, Read},
-    sync::atomic::Ordering,
-};
+use crate::{Error, Result};
+use futures::{pin_mut, Stream, StreamExt};
+use std::{sync::atomic::Ordering, time::Duration};
 
+mod package;
+pub use package::{DfuPackage, InitPacket};
 
-pub const MAX_FIRMWARE_SIZE: usize = 512 * 1024;
-
-
-#[derive(Deserialize, Debug)]
-struct Manifest {
-    manifest: ManifestInner,
-}
 
-#[derive(Deserialize, Debug)]
-struct ManifestInner {
-    application: Application,
-    // dfu_version: String,
-}
+pub const MAX_FIRMWARE_SIZE: usize = 512 * 1024;
 
-#[derive(Deserialize, Debug)]
-struct Application {
-    bin_file: String,
-    dat_file: String,
-    // init_packet_data: InitPacketData,
-}
+/// How long to wait for any control point notification
+const RESPONSE_TIMEOUT: Duration = Duration::from_secs(10);
+/// Validation makes the watch compute firmware CRC, which takes longer
+const VALIDATION_TIMEOUT: Duration = Duration::from_secs(30);
+const PACKET_SIZE: usize = 20;
 
-// #[derive(Deserialize, Debug)]
-// struct InitPacketData {
-//     application_version: u32,
-//     device_revision: u16,
-//     device_type: u16,
-//     firmware_crc16: u16,
-//     softdevice_req: Vec<u16>,
-// }
+/// Abort the transfer and reset the DFU state machine
+const OP_SYS_RESET: u8 = 0x06;
+const OP_PACKET_RECEIPT: u8 = 0x11;
+const RESP_CRC_ERROR: u8 = 0x05;
 
 
 impl InfiniTime {
     pub async fn firmware_upgrade(&self, dfu_content: &[u8], progress_sender: Option<ProgressTx>) -> Result<()> {
-        let chr_ctrl = self.chr(&uuids::CHR_FWUPD_CONTROL_POINT)?;
-        let chr_packet = self.chr(&uuids::CHR_FWUPD_PACKET)?;
+        self.check_chr(&uuids::CHR_FWUPD_CONTROL_POINT)?;
+        self.check_chr(&uuids::CHR_FWUPD_PACKET)?;
 
         let progress = ProgressTxWrapper(progress_sender);
 
@@ -53,96 +35,155 @@ impl InfiniTime {
         let _guard = utils::ScopeGuard::new(|| self.is_upgrading_firmware.store(false, Ordering::SeqCst));
 
         progress.report_msg("Extracting firmware files...").await;
+        let package = DfuPackage::parse(dfu_content)?;
+        log::info!(
+            "Firmware package: version {}, {} bytes",
+            package.version().as_deref().unwrap_or("unknown"), package.firmware.len()
+        );
+
+        // The legacy DFU protocol has no way to resend a batch of packets at
+        // an offset: the watch appends every received packet to the image, so
+        // after a lost packet the whole image has to be sent again. That is left
+        // to the caller (see `DeviceManager::firmware_upgrade_with_retry`), after
+        // the watch is reset here and connected to again.
+        if let Err(error) = self.send_firmware(&package.init_packet_data, &package.firmware, &progress).await {
+            if error.is_transient() {
+                log::warn!("Firmware transfer failed: {}. Resetting DFU", error);
+                if let Err(reset_error) = self.write_chr(uuids::CHR_FWUPD_CONTROL_POINT, &[OP_SYS_RESET]).await {
+                    log::warn!("Failed to reset DFU: {}", reset_error);
+                }
+            }
+            return Err(error);
+        }
 
-        // Parse manifest from the archive
-        let mut zip = zip::ZipArchive::new(Cursor::new(dfu_content))?;
-        let mut json = String::new();
-        zip.by_name("manifest.json")?.read_to_string(&mut json)?;
-        let manifest = serde_json::from_str::<Manifest>(&json)
-            .map_err(|_| anyhow!("Invalid manifest.json"))?.manifest;
-
-
-        // Read DFU data
-        let mut init_packet = Vec::new();
-        zip.by_name(&manifest.application.dat_file)?.read_to_end(&mut init_packet)?;
+        progress.report_msg("Done!").await;
 
-        let mut firmware_buffer = Vec::new();
-        {
-            // file is not Send, so it needs to go out of scope before the next await
-            let mut file = zip.by_name(&manifest.application.bin_file)?;
-            ensure!(file.size() < MAX_FIRMWARE_SIZE as u64, "Firmware cannot be that large");
-            file.read_to_end(&mut firmware_buffer)?;
-        }
+        Ok(())
+    }
 
+    async fn send_firmware(&self, init_packet: &[u8], firmware_buffer: &[u8], progress: &ProgressTxWrapper) -> Result<()> {
         // Obtain characteristics
-        let control_point_stream = chr_ctrl.notify().await?;
+        let control_point_stream = self.notify_chr(uuids::CHR_FWUPD_CONTROL_POINT).await?;
         pin_mut!(control_point_stream);
 
         // Step 1
         progress.report_msg("Initiating firmware upgrade...").await;
-        chr_ctrl.write(&[0x01, 0x04]).await?;
+        self.write_chr(uuids::CHR_FWUPD_CONTROL_POINT, &[0x01, 0x04]).await?;
 
         // Step 2
         let mut size_packet = vec![0; 8];
         let firmware_size = firmware_buffer.len() as u32;
         size_packet.extend_from_slice(&firmware_size.to_le_bytes());
-        chr_packet.write(&size_packet).await?;
+        self.write_chr(uuids::CHR_FWUPD_PACKET, &size_packet).await?;
 
-        let receipt = control_point_stream.next().await
-            .ok_or(anyhow!("Control point notification stream ended"))?;
-        ensure!(receipt == &[0x10, 0x01, 0x01]);
+        let receipt = wait_response(&mut control_point_stream, RESPONSE_TIMEOUT).await?;
+        check_response(&receipt, &[0x10, 0x01, 0x01])?;
 
         // Step 3
         progress.report_msg("Sending DFU init packet...").await;
-        chr_ctrl.write(&[0x02, 0x00]).await?;
+        self.write_chr(uuids::CHR_FWUPD_CONTROL_POINT, &[0x02, 0x00]).await?;
 
         // Step 4
-        chr_packet.write(&init_packet).await?;
-        chr_ctrl.write(&[0x02, 0x01]).await?;
+        self.write_chr(uuids::CHR_FWUPD_PACKET, init_packet).await?;
+        self.write_chr(uuids::CHR_FWUPD_CONTROL_POINT, &[0x02, 0x01]).await?;
 
-        let receipt = control_point_stream.next().await
-            .ok_or(anyhow!("Control point notification stream ended"))?;
-        ensure!(receipt == &[0x10, 0x02, 0x01]);
+        let receipt = wait_response(&mut control_point_stream, RESPONSE_TIMEOUT).await?;
+        check_response(&receipt, &[0x10, 0x02, 0x01])?;
 
         // Step 5
         progress.report_msg("Configuring receipt interval...").await;
-        let receipt_interval = 100;
-        chr_ctrl.write(&[0x08, receipt_interval]).await?;
+        let receipt_interval: u8 = 100;
+        self.write_chr(uuids::CHR_FWUPD_CONTROL_POINT, &[0x08, receipt_interval]).await?;
 
         // Step 6
-        chr_ctrl.write(&[0x03]).await?;
+        self.write_chr(uuids::CHR_FWUPD_CONTROL_POINT, &[0x03]).await?;
 
         // Step 7
         progress.report_msg("Sending firmware...").await;
-        let mut bytes_sent = 0;
-        for (idx, packet) in firmware_buffer.chunks(20).enumerate() {
-            chr_packet.write(&packet).await?;
-            bytes_sent += packet.len() as u32;
-            if (idx + 1) % receipt_interval as usize == 0 {
-                let receipt = control_point_stream.next().await
-                    .ok_or(anyhow!("Control point notification stream ended"))?;
-                let bytes_received = u32::from_le_bytes(receipt[1..5].try_into()?);
-                ensure!(bytes_sent == bytes_received);
-                progress.report_num(bytes_sent, firmware_size).await;
+        let mut offset = 0;
+        let mut packets_since_receipt = 0;
+        while offset < firmware_buffer.len() {
+            let end = (offset + PACKET_SIZE).min(firmware_buffer.len());
+            self.write_chr(uuids::CHR_FWUPD_PACKET, &firmware_buffer[offset..end]).await?;
+            offset = end;
+            packets_since_receipt += 1;
+            // After the last packet the watch responds with firmware receipt instead
+            if packets_since_receipt < receipt_interval || offset == firmware_buffer.len() {
+                continue;
+            }
+            packets_since_receipt = 0;
+            let receipt = wait_notification(&mut control_point_stream, RESPONSE_TIMEOUT).await?;
+            let bytes_received = parse_packet_receipt(&receipt)?;
+            if bytes_received as usize != offset {
+                return Err(Error::PacketLost(format!(
+                    "{} bytes sent, {} bytes received",
+                    offset, bytes_received
+                )));
             }
+            progress.report_num(bytes_received, firmware_size).await;
         }
 
         // Step 8
         progress.report_msg("Waiting for firmware receipt...").await;
-        let receipt = control_point_stream.next().await
-            .ok_or(anyhow!("Control point notification stream ended"))?;
-        ensure!(receipt == &[0x10, 0x03, 0x01]);
-        chr_ctrl.write(&[0x04]).await?;
+        let receipt = wait_response(&mut control_point_stream, RESPONSE_TIMEOUT).await?;
+        check_response(&receipt, &[0x10, 0x03, 0x01])?;
+        self.write_chr(uuids::CHR_FWUPD_CONTROL_POINT, &[0x04]).await?;
 
         // Step 9
         progress.report_msg("Waiting for firmware validation...").await;
-        let receipt = control_point_stream.next().await
-            .ok_or(anyhow!("Control point notification stream ended"))?;
-        ensure!(receipt == &[0x10, 0x04, 0x01]);
-        chr_ctrl.write(&[0x05]).await?;
+        let receipt = wait_response(&mut control_point_stream, VALIDATION_TIMEOUT).await?;
+        if receipt == [0x10, 0x04, RESP_CRC_ERROR] {
+            return Err(Error::PacketLost("firmware CRC mismatch".into()));
+        }
+        check_response(&receipt, &[0x10, 0x04, 0x01])?;
+        self.write_chr(uuids::CHR_FWUPD_CONTROL_POINT, &[0x05]).await?;
 
-        progress.report_msg("Done!").await;
+        Ok(())
+    }
+}
+
+/// Wait for the next control point notification
+async fn wait_notification(
+    stream: &mut (impl Stream<Item = Vec<u8>> + Unpin),
+    timeout: Duration,
+) -> Result<Vec<u8>> {
+    tokio::time::timeout(timeout, stream.next()).await
+        .map_err(|_| Error::Timeout)?
+        .ok_or(Error::NoResponse)
+}
 
+/// Wait for the next request response, skipping packet receipts
+/// that may still be on the way
+async fn wait_response(
+    stream: &mut (impl Stream<Item = Vec<u8>> + Unpin),
+    timeout: Duration,
+) -> Result<Vec<u8>> {
+    let response = async {
+        while let Some(notification) = stream.next().await {
+            if notification.first() == Some(&OP_PACKET_RECEIPT) {
+                log::debug!("Skipping late packet receipt: {:02x?}", notification);
+            } else {
+                return Ok(notification);
+            }
+        }
+        Err(Error::NoResponse)
+    };
+    tokio::time::timeout(timeout, response).await
+        .map_err(|_| Error::Timeout)?
+}
+
+/// Number of firmware bytes received by the watch
+fn parse_packet_receipt(receipt: &[u8]) -> Result<u32> {
+    match receipt {
+        [OP_PACKET_RECEIPT, bytes @ ..] if bytes.len() >= 4 => Ok(u32::from_le_bytes(bytes[..4].try_into()?)),
+        _ => Err(Error::Dfu(format!("Invalid packet receipt: {:02x?}", receipt))),
+    }
+}
+
+fn check_response(response: &[u8], expected: &[u8]) -> Result<()> {
+    if response == expected {
         Ok(())
+    } else {
+        Err(Error::Dfu(format!("Unexpected response: {:02x?}, expected: {:02x?}", response, expected)))
     }
-}
\ No newline at end of file
+}
@@ -0,0 +1,332 @@
+use super::MAX_FIRMWARE_SIZE;
+use crate::{utils, Error, Result};
+use serde::Deserialize;
+use std::io::{Cursor, Read};
+
+
+/// Device type InfiniTime DFU packages are built for
+const DEVICE_TYPE_PINETIME: u16 = 0x0052;
+/// Device type value which matches any device
+const ANY: u16 = 0xffff;
+
+const MCUBOOT_IMAGE_MAGIC: u32 = 0x96f3b83d;
+const MCUBOOT_HEADER_SIZE: usize = 32;
+
+
+#[derive(Deserialize, Debug)]
+struct Manifest {
+    manifest: ManifestInner,
+}
+
+#[derive(Deserialize, Debug)]
+struct ManifestInner {
+    application: Option<Application>,
+    dfu_version: Option<f32>,
+}
+
+#[derive(Deserialize, Debug)]
+struct Application {
+    bin_file: String,
+    dat_file: String,
+    init_packet_data: Option<InitPacketData>,
+}
+
+#[derive(Deserialize, Debug)]
+struct InitPacketData {
+    application_version: Option<u32>,
+    device_revision: Option<u16>,
+    device_type: Option<u16>,
+    firmware_crc16: Option<u16>,
+    softdevice_req: Option<Vec<u16>>,
+}
+
+
+/// Legacy Nordic DFU init packet, sent to the bootloader before the firmware
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct InitPacket {
+    pub device_type: u16,
+    pub device_revision: u16,
+    pub application_version: u32,
+    /// Accepted SoftDevice firmware IDs
+    pub softdevice_req: Vec<u16>,
+    /// CRC-16/CCITT-FALSE of the firmware image
+    pub firmware_crc16: u16,
+}
+
+impl InitPacket {
+    pub fn parse(data: &[u8]) -> Result<Self> {
+        let invalid = || Error::Archive(format!("Invalid init packet: {:02x?}", data));
+        let u16_at = |offset: usize| -> Result<u16> {
+            let bytes = data.get(offset..offset + 2).ok_or_else(invalid)?;
+            Ok(u16::from_le_bytes(bytes.try_into()?))
+        };
+
+        let device_type = u16_at(0)?;
+        let device_revision = u16_at(2)?;
+        let application_version = u32::from_le_bytes(data.get(4..8).ok_or_else(invalid)?.try_into()?);
+        let softdevice_count = u16_at(8)? as usize;
+        let softdevice_req = (0..softdevice_count)
+            .map(|i| u16_at(10 + 2 * i))
+            .collect::<Result<Vec<_>>>()?;
+        let crc_offset = 10 + 2 * softdevice_count;
+        if data.len() != crc_offset + 2 {
+            return Err(invalid());
+        }
+        let firmware_crc16 = u16_at(crc_offset)?;
+
+        Ok(Self { device_type, device_revision, application_version, softdevice_req, firmware_crc16 })
+    }
+
+    /// Whether the package is meant for PineTime, or for any device
+    pub fn matches_device(&self) -> bool {
+        self.device_type == DEVICE_TYPE_PINETIME || self.device_type == ANY
+    }
+}
+
+
+/// Validated firmware upgrade package (DFU zip archive)
+#[derive(Debug, Clone)]
+pub struct DfuPackage {
+    pub init_packet: InitPacket,
+    /// Raw init packet, as sent to the watch
+    pub init_packet_data: Vec<u8>,
+    pub firmware: Vec<u8>,
+    pub dfu_version: Option<f32>,
+    /// Version from the MCUBoot image header. Legacy firmware
+    /// (prior to InfiniTime 1.0) is not an MCUBoot image and has none.
+    pub image_version: Option<String>,
+}
+
+impl DfuPackage {
+    /// Parse DFU archive and check that it is consistent and meant for
+    /// PineTime, so that broken packages are refused before the transfer
+    pub fn parse(dfu_content: &[u8]) -> Result<Self> {
+        let mut zip = zip::ZipArchive::new(Cursor::new(dfu_content))?;
+        let mut json = String::new();
+        zip.by_name("manifest.json")?.read_to_string(&mut json)?;
+        let manifest = serde_json::from_str::<Manifest>(&json)
+            .map_err(|e| Error::Archive(format!("Invalid manifest.json: {}", e)))?.manifest;
+        let application = manifest.application
+            .ok_or_else(|| Error::Archive(String::from("No application in manifest.json")))?;
+
+        let mut init_packet_data = Vec::new();
+        zip.by_name(&application.dat_file)?.read_to_end(&mut init_packet_data)?;
+        let init_packet = InitPacket::parse(&init_packet_data)?;
+
+        let mut firmware = Vec::new();
+        {
+            let file = zip.by_name(&application.bin_file)?;
+            if file.size() >= MAX_FIRMWARE_SIZE as u64 {
+                return Err(Error::Archive(String::from("Firmware cannot be that large")));
+            }
+            file.take(MAX_FIRMWARE_SIZE as u64).read_to_end(&mut firmware)?;
+        }
+        if firmware.is_empty() {
+            return Err(Error::Archive(String::from("Firmware image is empty")));
+        }
+
+        if let Some(expected) = &application.init_packet_data {
+            check_manifest(expected, &init_packet)?;
+        }
+        if !init_packet.matches_device() {
+            return Err(Error::Archive(format!(
+                "Package is built for another device type: {:#06x}", init_packet.device_type
+            )));
+        }
+        let crc = utils::crc16(&firmware);
+        if crc != init_packet.firmware_crc16 {
+            return Err(Error::Archive(format!(
+                "Firmware CRC mismatch: {:#06x}, expected: {:#06x}", crc, init_packet.firmware_crc16
+            )));
+        }
+
+        Ok(Self {
+            image_version: mcuboot_image_version(&firmware),
+            init_packet,
+            init_packet_data,
+            firmware,
+            dfu_version: manifest.dfu_version,
+        })
+    }
+
+    /// Application version: from the image header if available,
+    /// otherwise from the init packet unless it's a placeholder
+    pub fn version(&self) -> Option<String> {
+        self.image_version.clone().or(match self.init_packet.application_version {
+            u32::MAX => None,
+            version => Some(version.to_string()),
+        })
+    }
+
+    /// Whether the firmware is an MCUBoot image, i.e. InfiniTime 1.0 or newer
+    pub fn is_mcuboot_image(&self) -> bool {
+        self.image_version.is_some()
+    }
+}
+
+/// Manifest duplicates init packet fields, they must agree
+fn check_manifest(manifest: &InitPacketData, init_packet: &InitPacket) -> Result<()> {
+    let mismatch = |field: &str| Err(Error::Archive(format!("Init packet {} doesn't match manifest.json", field)));
+    if manifest.device_type.is_some_and(|v| v != init_packet.device_type) {
+        return mismatch("device type");
+    }
+    if manifest.device_revision.is_some_and(|v| v != init_packet.device_revision) {
+        return mismatch("device revision");
+    }
+    if manifest.application_version.is_some_and(|v| v != init_packet.application_version) {
+        return mismatch("application version");
+    }
+    if manifest.firmware_crc16.is_some_and(|v| v != init_packet.firmware_crc16) {
+        return mismatch("firmware CRC");
+    }
+    if manifest.softdevice_req.as_ref().is_some_and(|v| *v != init_packet.softdevice_req) {
+        return mismatch("SoftDevice requirements");
+    }
+    Ok(())
+}
+
+/// Version from the MCUBoot image header, e.g. "1.14.0"
+fn mcuboot_image_version(firmware: &[u8]) -> Option<String> {
+    let header = firmware.get(..MCUBOOT_HEADER_SIZE)?;
+    if u32::from_le_bytes(header[0..4].try_into().ok()?) != MCUBOOT_IMAGE_MAGIC {
+        return None;
+    }
+    let major = header[20];
+    let minor = header[21];
+    let revision = u16::from_le_bytes(header[22..24].try_into().ok()?);
+    Some(format!("{}.{}.{}", major, minor, revision))
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+
+    fn init_packet(softdevices: &[u16], crc: u16) -> Vec<u8> {
+        let mut data = Vec::new();
+        data.extend_from_slice(&DEVICE_TYPE_PINETIME.to_le_bytes());
+        data.extend_from_slice(&0xffffu16.to_le_bytes());
+        data.extend_from_slice(&0xffffffffu32.to_le_bytes());
+        data.extend_from_slice(&(softdevices.len() as u16).to_le_bytes());
+        for sd in softdevices {
+            data.extend_from_slice(&sd.to_le_bytes());
+        }
+        data.extend_from_slice(&crc.to_le_bytes());
+        data
+    }
+
+    fn package(init_packet: &[u8], firmware: &[u8]) -> Vec<u8> {
+        let manifest = r#"{"manifest": {"application": {"bin_file": "app.bin", "dat_file": "app.dat"}, "dfu_version": 0.5}}"#;
+        let mut zip = zip::ZipWriter::new(Cursor::new(Vec::new()));
+        let options = zip::write::FileOptions::default();
+        for (name, content) in [("manifest.json", manifest.as_bytes()), ("app.dat", init_packet), ("app.bin", firmware)] {
+            zip.start_file(name, options).unwrap();
+            zip.write_all(content).unwrap();
+        }
+        zip.finish().unwrap().into_inner()
+    }
+
+    /// MCUBoot image of the given version with some payload after the header
+    fn mcuboot_image(major: u8, minor: u8, revision: u16) -> Vec<u8> {
+        let mut image = vec![0; MCUBOOT_HEADER_SIZE + 64];
+        image[0..4].copy_from_slice(&MCUBOOT_IMAGE_MAGIC.to_le_bytes());
+        image[8..10].copy_from_slice(&(MCUBOOT_HEADER_SIZE as u16).to_le_bytes());
+        image[20] = major;
+        image[21] = minor;
+        image[22..24].copy_from_slice(&revision.to_le_bytes());
+        image[24..28].copy_from_slice(&0x12345678u32.to_le_bytes()); // build number
+        image
+    }
+
+    #[test]
+    fn parse_init_packet() {
+        let data = init_packet(&[0x0081, 0xfffe], 0xbeef);
+        let packet = InitPacket::parse(&data).unwrap();
+        assert_eq!(packet, InitPacket {
+            device_type: DEVICE_TYPE_PINETIME,
+            device_revision: 0xffff,
+            application_version: 0xffffffff,
+            softdevice_req: vec![0x0081, 0xfffe],
+            firmware_crc16: 0xbeef,
+        });
+        assert!(packet.matches_device());
+    }
+
+    #[test]
+    fn parse_init_packet_without_softdevices() {
+        let packet = InitPacket::parse(&init_packet(&[], 0x1234)).unwrap();
+        assert!(packet.softdevice_req.is_empty());
+        assert_eq!(packet.firmware_crc16, 0x1234);
+    }
+
+    #[test]
+    fn parse_truncated_init_packet() {
+        let data = init_packet(&[0xfffe], 0xbeef);
+        for len in 0..data.len() {
+            assert!(matches!(InitPacket::parse(&data[..len]), Err(Error::Archive(_))), "length {}", len);
+        }
+    }
+
+    #[test]
+    fn parse_init_packet_with_wrong_length() {
+        let mut data = init_packet(&[0xfffe], 0xbeef);
+        data.push(0);
+        assert!(matches!(InitPacket::parse(&data), Err(Error::Archive(_))));
+        // Softdevice count larger than the list
+        let mut data = init_packet(&[0xfffe], 0xbeef);
+        data[8] = 5;
+        assert!(matches!(InitPacket::parse(&data), Err(Error::Archive(_))));
+    }
+
+    #[test]
+    fn other_device_type() {
+        let mut data = init_packet(&[], 0);
+        data[0..2].copy_from_slice(&0x0053u16.to_le_bytes());
+        assert!(!InitPacket::parse(&data).unwrap().matches_device());
+        data[0..2].copy_from_slice(&ANY.to_le_bytes());
+        assert!(InitPacket::parse(&data).unwrap().matches_device());
+    }
+
+    #[test]
+    fn parse_package() {
+        let firmware = mcuboot_image(1, 14, 2);
+        let content = package(&init_packet(&[0xfffe], utils::crc16(&firmware)), &firmware);
+        let package = DfuPackage::parse(&content).unwrap();
+        assert_eq!(package.firmware, firmware);
+        assert_eq!(package.dfu_version, Some(0.5));
+        assert_eq!(package.image_version.as_deref(), Some("1.14.2"));
+        assert_eq!(package.version().as_deref(), Some("1.14.2"));
+        assert!(package.is_mcuboot_image());
+    }
+
+    #[test]
+    fn crc_mismatch() {
+        let firmware = mcuboot_image(1, 14, 0);
+        let crc = utils::crc16(&firmware) ^ 0x0001;
+        let result = DfuPackage::parse(&package(&init_packet(&[0xfffe], crc), &firmware));
+        assert!(matches!(result, Err(Error::Archive(message)) if message.contains("CRC mismatch")));
+    }
+
+    #[test]
+    fn mcuboot_header_offsets() {
+        assert_eq!(mcuboot_image_version(&mcuboot_image(1, 14, 0)).as_deref(), Some("1.14.0"));
+        // Revision is 16-bit little endian
+        assert_eq!(mcuboot_image_version(&mcuboot_image(2, 0, 0x0102)).as_deref(), Some("2.0.258"));
+        // Header must be complete and start with the magic
+        assert_eq!(mcuboot_image_version(&mcuboot_image(1, 14, 0)[..MCUBOOT_HEADER_SIZE - 1]), None);
+        let mut legacy = mcuboot_image(1, 14, 0);
+        legacy[0] ^= 0xff;
+        assert_eq!(mcuboot_image_version(&legacy), None);
+    }
+
+    #[test]
+    fn legacy_image_version_from_init_packet() {
+        let firmware = vec![0xaa; 100];
+        let mut data = init_packet(&[0xfffe], utils::crc16(&firmware));
+        data[4..8].copy_from_slice(&7u32.to_le_bytes());
+        let package = DfuPackage::parse(&package(&data, &firmware)).unwrap();
+        assert!(!package.is_mcuboot_image());
+        assert_eq!(package.version().as_deref(), Some("7"));
+    }
+}
@@ -0,0 +1,210 @@
+use super::{uuids, InfiniTime};
+use crate::{Error, Result};
+use futures::{Stream, StreamExt};
+
+
+mod flags {
+    pub const VALUE_FORMAT_U16: u8 = 0x01;
+    pub const SENSOR_CONTACT_STATUS: u8 = 0x02;
+    pub const SENSOR_CONTACT_SUPPORTED: u8 = 0x04;
+    pub const ENERGY_EXPENDED: u8 = 0x08;
+    pub const RR_INTERVAL: u8 = 0x10;
+}
+
+/// Heart Rate Measurement characteristic value, see section 3.106 in
+/// https://www.bluetooth.com/specifications/specs/gatt-specification-supplement/
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct HeartRateMeasurement {
+    /// Heart rate in beats per minute
+    pub bpm: u16,
+    /// Whether the sensor is in contact with the skin,
+    /// `None` if the sensor doesn't report it
+    pub sensor_contact: Option<bool>,
+    /// Accumulated energy expended in kilojoules
+    pub energy_expended: Option<u16>,
+    /// RR-intervals in units of 1/1024 second, oldest first
+    pub rr_intervals: Vec<u16>,
+}
+
+impl HeartRateMeasurement {
+    pub fn deserialize(data: &[u8]) -> Result<Self> {
+        let (&flags, mut data) = data.split_first()
+            .ok_or(Error::Protocol(String::from("Empty heart rate measurement")))?;
+
+        let bpm = if flags & flags::VALUE_FORMAT_U16 != 0 {
+            take_u16(&mut data)?
+        } else {
+            let (&bpm, rest) = data.split_first()
+                .ok_or(Error::Protocol(String::from("Heart rate measurement is too short")))?;
+            data = rest;
+            bpm as u16
+        };
+
+        let sensor_contact = (flags & flags::SENSOR_CONTACT_SUPPORTED != 0)
+            .then_some(flags & flags::SENSOR_CONTACT_STATUS != 0);
+
+        let energy_expended = if flags & flags::ENERGY_EXPENDED != 0 {
+            Some(take_u16(&mut data)?)
+        } else {
+            None
+        };
+
+        let rr_intervals = if flags & flags::RR_INTERVAL != 0 {
+            // Incomplete trailing byte is ignored rather than losing the whole measurement
+            if data.len() % 2 != 0 {
+                log::debug!("Ignoring incomplete RR-interval in heart rate measurement");
+            }
+            data.chunks_exact(2)
+                .map(|rr| u16::from_le_bytes([rr[0], rr[1]]))
+                .collect()
+        } else {
+            Vec::new()
+        };
+
+        Ok(Self { bpm, sensor_contact, energy_expended, rr_intervals })
+    }
+
+    #[cfg(feature = "simulator")]
+    pub(crate) fn serialize(&self) -> Vec<u8> {
+        let mut flags = 0;
+        let mut data = Vec::new();
+        match u8::try_from(self.bpm) {
+            Ok(bpm) => data.push(bpm),
+            Err(_) => {
+                flags |= flags::VALUE_FORMAT_U16;
+                data.extend_from_slice(&self.bpm.to_le_bytes());
+            }
+        }
+        if let Some(contact) = self.sensor_contact {
+            flags |= flags::SENSOR_CONTACT_SUPPORTED;
+            if contact {
+                flags |= flags::SENSOR_CONTACT_STATUS;
+            }
+        }
+        if let Some(energy) = self.energy_expended {
+            flags |= flags::ENERGY_EXPENDED;
+            data.extend_from_slice(&energy.to_le_bytes());
+        }
+        if !self.rr_intervals.is_empty() {
+            flags |= flags::RR_INTERVAL;
+            for rr in &self.rr_intervals {
+                data.extend_from_slice(&rr.to_le_bytes());
+            }
+        }
+        [[flags].as_slice(), &data].concat()
+    }
+
+    /// RR-intervals in milliseconds
+    pub fn rr_intervals_ms(&self) -> impl Iterator<Item = f64> + '_ {
+        self.rr_intervals.iter().map(|&rr| rr as f64 * 1000.0 / 1024.0)
+    }
+}
+
+fn take_u16(data: &mut &[u8]) -> Result<u16> {
+    if data.len() < 2 {
+        return Err(Error::Protocol(String::from("Heart rate measurement is too short")));
+    }
+    let (value, rest) = data.split_at(2);
+    *data = rest;
+    Ok(u16::from_le_bytes(value.try_into()?))
+}
+
+
+impl InfiniTime {
+    pub async fn read_heart_rate_measurement(&self) -> Result<HeartRateMeasurement> {
+        HeartRateMeasurement::deserialize(&self.read_chr(uuids::CHR_HEART_RATE).await?)
+    }
+
+    pub async fn get_heart_rate_measurement_stream(&self) -> Result<impl Stream<Item = HeartRateMeasurement>> {
+        let stream = self.notify_chr(uuids::CHR_HEART_RATE).await?;
+        Ok(stream.filter_map(|v| async move {
+            HeartRateMeasurement::deserialize(&v)
+                .map_err(|e| log::warn!("Invalid heart rate measurement: {e}"))
+                .ok()
+        }))
+    }
+
+    pub async fn read_heart_rate(&self) -> Result<u16> {
+        Ok(self.read_heart_rate_measurement().await?.bpm)
+    }
+
+    pub async fn get_heart_rate_stream(&self) -> Result<impl Stream<Item = u16>> {
+        let stream = self.get_heart_rate_measurement_stream().await?;
+        Ok(stream.map(|m| m.bpm))
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn u8_bpm() {
+        let measurement = HeartRateMeasurement::deserialize(&[0x00, 72]).unwrap();
+        assert_eq!(measurement, HeartRateMeasurement { bpm: 72, ..Default::default() });
+    }
+
+    #[test]
+    fn u16_bpm() {
+        let measurement = HeartRateMeasurement::deserialize(&[0x01, 0x2c, 0x01]).unwrap();
+        assert_eq!(measurement.bpm, 300);
+        assert!(HeartRateMeasurement::deserialize(&[0x01, 0x2c]).is_err());
+        assert!(HeartRateMeasurement::deserialize(&[]).is_err());
+    }
+
+    #[test]
+    fn sensor_contact() {
+        let measurement = HeartRateMeasurement::deserialize(&[0x06, 60]).unwrap();
+        assert_eq!(measurement.sensor_contact, Some(true));
+        let measurement = HeartRateMeasurement::deserialize(&[0x04, 60]).unwrap();
+        assert_eq!(measurement.sensor_contact, Some(false));
+        // Status bit without support bit means nothing
+        let measurement = HeartRateMeasurement::deserialize(&[0x02, 60]).unwrap();
+        assert_eq!(measurement.sensor_contact, None);
+    }
+
+    #[test]
+    fn energy_expended() {
+        let measurement = HeartRateMeasurement::deserialize(&[0x09, 0x50, 0x00, 0x10, 0x02]).unwrap();
+        assert_eq!(measurement.bpm, 80);
+        assert_eq!(measurement.energy_expended, Some(0x0210));
+        assert!(HeartRateMeasurement::deserialize(&[0x08, 80, 0x10]).is_err());
+    }
+
+    #[test]
+    fn rr_intervals() {
+        let measurement = HeartRateMeasurement::deserialize(&[0x10, 60, 0x00, 0x04, 0x10, 0x04]).unwrap();
+        assert_eq!(measurement.rr_intervals, [1024, 1040]);
+        assert_eq!(measurement.rr_intervals_ms().collect::<Vec<_>>(), [1000.0, 1015.625]);
+    }
+
+    #[test]
+    fn rr_intervals_after_energy_expended() {
+        let data = [0x18, 60, 0x10, 0x02, 0x00, 0x04];
+        let measurement = HeartRateMeasurement::deserialize(&data).unwrap();
+        assert_eq!(measurement.energy_expended, Some(0x0210));
+        assert_eq!(measurement.rr_intervals, [1024]);
+    }
+
+    #[test]
+    fn incomplete_rr_interval() {
+        let measurement = HeartRateMeasurement::deserialize(&[0x10, 60, 0x00, 0x04, 0x10]).unwrap();
+        assert_eq!(measurement.bpm, 60);
+        assert_eq!(measurement.rr_intervals, [1024]);
+        let measurement = HeartRateMeasurement::deserialize(&[0x10, 60, 0x10]).unwrap();
+        assert!(measurement.rr_intervals.is_empty());
+    }
+
+    #[cfg(feature = "simulator")]
+    #[test]
+    fn round_trip() {
+        let measurement = HeartRateMeasurement {
+            bpm: 260,
+            sensor_contact: Some(true),
+            energy_expended: Some(42),
+            rr_intervals: vec![230, 250],
+        };
+        assert_eq!(HeartRateMeasurement::deserialize(&measurement.serialize()).unwrap(), measurement);
+    }
+}
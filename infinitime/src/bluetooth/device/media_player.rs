@@ -1,8 +1,8 @@
 use super::{uuids, InfiniTime};
-use anyhow::Result;
+use crate::Result;
 use futures::{Stream, StreamExt};
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub enum MediaPlayerEvent {
     AppOpenned,
     Play,
@@ -26,49 +26,62 @@ impl MediaPlayerEvent {
             _ => None,
         }
     }
+
+    #[cfg(feature = "simulator")]
+    pub(crate) fn to_raw(self) -> u8 {
+        match self {
+            MediaPlayerEvent::AppOpenned => 0xe0,
+            MediaPlayerEvent::Play => 0x00,
+            MediaPlayerEvent::Pause => 0x01,
+            MediaPlayerEvent::Next => 0x03,
+            MediaPlayerEvent::Previous => 0x04,
+            MediaPlayerEvent::VolumeUp => 0x05,
+            MediaPlayerEvent::VolumeDown => 0x06,
+        }
+    }
 }
 
 
 impl InfiniTime {
     pub async fn get_media_player_events_stream(&self) -> Result<impl Stream<Item = MediaPlayerEvent>> {
-        let stream = self.chr(&uuids::CHR_MP_EVENTS)?.notify().await?;
+        let stream = self.notify_chr(uuids::CHR_MP_EVENTS).await?;
         Ok(stream.filter_map(|v| async move { MediaPlayerEvent::from_raw(v[0]) }))
     }
 
     pub async fn write_mp_artist(&self, artist: &str) -> Result<()> {
-        Ok(self.chr(&uuids::CHR_MP_ARTIST)?.write(artist.as_ref()).await?)
+        self.write_chr(uuids::CHR_MP_ARTIST, artist.as_ref()).await
     }
 
     pub async fn write_mp_album(&self, album: &str) -> Result<()> {
-        Ok(self.chr(&uuids::CHR_MP_ALBUM)?.write(album.as_ref()).await?)
+        self.write_chr(uuids::CHR_MP_ALBUM, album.as_ref()).await
     }
 
     pub async fn write_mp_track(&self, track: &str) -> Result<()> {
-        Ok(self.chr(&uuids::CHR_MP_TRACK)?.write(track.as_ref()).await?)
+        self.write_chr(uuids::CHR_MP_TRACK, track.as_ref()).await
     }
 
     pub async fn write_mp_playback_status(&self, playing: bool) -> Result<()> {
-        Ok(self.chr(&uuids::CHR_MP_STATUS)?.write(&[u8::from(playing)]).await?)
+        self.write_chr(uuids::CHR_MP_STATUS, &[u8::from(playing)]).await
     }
 
     pub async fn write_mp_position(&self, position: u32) -> Result<()> {
-        Ok(self.chr(&uuids::CHR_MP_POSITION)?.write(&position.to_be_bytes()).await?)
+        self.write_chr(uuids::CHR_MP_POSITION, &position.to_be_bytes()).await
     }
 
     pub async fn write_mp_duration(&self, duration: u32) -> Result<()> {
-        Ok(self.chr(&uuids::CHR_MP_DURATION)?.write(&duration.to_be_bytes()).await?)
+        self.write_chr(uuids::CHR_MP_DURATION, &duration.to_be_bytes()).await
     }
 
     pub async fn write_mp_playback_speed(&self, speed: f32) -> Result<()> {
         let percentage = (speed * 100.0) as u32;
-        Ok(self.chr(&uuids::CHR_MP_SPEED)?.write(&percentage.to_be_bytes()).await?)
+        self.write_chr(uuids::CHR_MP_SPEED, &percentage.to_be_bytes()).await
     }
 
     pub async fn write_mp_repeat(&self, repeat: bool) -> Result<()> {
-        Ok(self.chr(&uuids::CHR_MP_REPEAT)?.write(&[u8::from(repeat)]).await?)
+        self.write_chr(uuids::CHR_MP_REPEAT, &[u8::from(repeat)]).await
     }
 
     pub async fn write_mp_shuffle(&self, shuffle: bool) -> Result<()> {
-        Ok(self.chr(&uuids::CHR_MP_SHUFFLE)?.write(&[u8::from(shuffle)]).await?)
+        self.write_chr(uuids::CHR_MP_SHUFFLE, &[u8::from(shuffle)]).await
     }
 }
\ No newline at end of file
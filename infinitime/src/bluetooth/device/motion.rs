@@ -0,0 +1,46 @@
+use super::{uuids, InfiniTime};
+use crate::{Error, Result};
+use futures::{Stream, StreamExt};
+
+
+/// Raw accelerometer sample as reported by the watch motion service
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct MotionSample {
+    pub x: i16,
+    pub y: i16,
+    pub z: i16,
+}
+
+impl MotionSample {
+    pub fn deserialize(data: &[u8]) -> Result<Self> {
+        let data: &[u8; 6] = data.get(..6)
+            .ok_or(Error::Protocol(String::from("Motion sample is too short")))?
+            .try_into()?;
+        Ok(Self {
+            x: i16::from_le_bytes([data[0], data[1]]),
+            y: i16::from_le_bytes([data[2], data[3]]),
+            z: i16::from_le_bytes([data[4], data[5]]),
+        })
+    }
+
+    #[cfg(feature = "simulator")]
+    pub(crate) fn serialize(&self) -> Vec<u8> {
+        [self.x.to_le_bytes(), self.y.to_le_bytes(), self.z.to_le_bytes()].concat()
+    }
+}
+
+
+impl InfiniTime {
+    pub async fn read_motion(&self) -> Result<MotionSample> {
+        MotionSample::deserialize(&self.read_chr(uuids::CHR_MOTION).await?)
+    }
+
+    pub async fn get_motion_stream(&self) -> Result<impl Stream<Item = MotionSample>> {
+        let stream = self.notify_chr(uuids::CHR_MOTION).await?;
+        Ok(stream.filter_map(|v| async move {
+            MotionSample::deserialize(&v)
+                .map_err(|e| log::warn!("Invalid motion sample: {e}"))
+                .ok()
+        }))
+    }
+}
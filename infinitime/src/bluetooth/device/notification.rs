@@ -1,5 +1,5 @@
 use super::{uuids, InfiniTime};
-use anyhow::Result;
+use crate::Result;
 
 
 pub enum Notification<'s> {
@@ -32,7 +32,6 @@ impl InfiniTime {
                 [header, title.as_bytes()].join(&0)
             }
         };
-        let characteristic = self.chr(&uuids::CHR_NEW_ALERT)?;
-        Ok(characteristic.write(&message).await?)
+        self.write_chr(uuids::CHR_NEW_ALERT, &message).await
     }
 }
\ No newline at end of file
@@ -2,8 +2,9 @@ use super::{fs, InfiniTime, ProgressTx, ProgressTxWrapper};
 // use std::sync::mpsc;
 use std::io::{Cursor, Read};
 // use futures::{pin_mut, StreamExt};
-use anyhow::{anyhow, ensure, Result};
+use crate::{Error, Result};
 use serde::Deserialize;
+use tokio_util::sync::CancellationToken;
 use version_compare::Version;
 
 pub const MAX_RESOURCE_SIZE: usize = 4 * 1024 * 1024;
@@ -26,24 +27,47 @@ struct ObsoleteFile {
     since: String,
 }
 
+type Archive<'a> = zip::ZipArchive<Cursor<&'a [u8]>>;
+
+fn parse_manifest(zip: &mut Archive) -> Result<Resources> {
+    let mut json = String::new();
+    zip.by_name("resources.json")?.read_to_string(&mut json)?;
+    serde_json::from_str(&json)
+        .map_err(|e| Error::Archive(format!("Invalid resources.json: {}", e)))
+}
+
+/// Uncompressed size of the files listed in the resources archive manifest,
+/// which is how much `upload_resources` writes
+pub(crate) fn resources_size(resources_archive: &[u8]) -> Result<u32> {
+    let mut zip = zip::ZipArchive::new(Cursor::new(resources_archive))?;
+    let manifest = parse_manifest(&mut zip)?;
+    let mut size = 0u64;
+    for res in &manifest.resources {
+        size = size.saturating_add(zip.by_name(&res.filename)?.size());
+    }
+    u32::try_from(size).map_err(|_| Error::Archive(String::from("Resources are too large")))
+}
+
 
 impl InfiniTime {
-    pub async fn upload_resources(&self, resources_archive: &[u8], progress_sender: Option<ProgressTx>) -> Result<()>
-    {
+    /// Upload resource files from the archive. When `cancel` is triggered,
+    /// the upload stops between chunks and the file being written is removed.
+    pub async fn upload_resources(
+        &self, resources_archive: &[u8],
+        progress_sender: Option<ProgressTx>, cancel: Option<&CancellationToken>,
+    ) -> Result<()> {
         let progress = ProgressTxWrapper(progress_sender);
 
         // Parse manifest from the archive
         let mut zip = zip::ZipArchive::new(Cursor::new(resources_archive))?;
-        let mut json = String::new();
-        zip.by_name("resources.json")?.read_to_string(&mut json)?;
-        let manifest: Resources = serde_json::from_str(&json)
-            .map_err(|_| anyhow!("Invalid resources.json"))?;
+        let manifest = parse_manifest(&mut zip)?;
 
         // Make dirs
         let files = manifest.resources.iter().map(|r| r.path.as_str());
         for dir in fs::ancestors_union(files) {
+            fs::check_cancelled(cancel)?;
             progress.report_msg(format!("Creating directory: {}", dir)).await;
-            self.make_dir(dir).await?;
+            self.make_dir(dir, cancel).await?;
         }
 
         // Write new files
@@ -52,23 +76,27 @@ impl InfiniTime {
             {
                 // file is not Send, so it has to go out of scope befor the next await
                 let mut file = zip.by_name(&res.filename)?;
-                ensure!(file.size() < MAX_RESOURCE_SIZE as u64, "File too large: {}", res.filename);
+                if file.size() >= MAX_RESOURCE_SIZE as u64 {
+                    return Err(Error::Archive(format!("File too large: {}", res.filename)));
+                }
                 file.read_to_end(&mut content)?;
             }
             progress.report_msg(format!("Writing resource file: {}", &res.path)).await;
-            self.write_file(&res.path, &content, 0, progress.0.clone()).await?;
+            self.write_file(&res.path, &content, 0, progress.0.clone(), cancel).await?;
         }
 
         // Remove obsolete files
         let fw_version = self.read_firmware_version().await?;
         let current_version = Version::from(&fw_version)
-            .ok_or(anyhow!("Failed to parse current firmware version"))?;
+            .ok_or(Error::Protocol(format!("Failed to parse current firmware version: {}", fw_version)))?;
         for obsolete in manifest.obsolete_files {
             if let Some(obsolete_version) = Version::from(&obsolete.since) {
                 if current_version >= obsolete_version {
                     progress.report_msg(format!("Removing obsolete file: {}", &obsolete.path)).await;
-                    if let Err(err) = self.delete_file(&obsolete.path).await {
-                        log::warn!("Failed to delete file '{}': {}", &obsolete.path, err);
+                    match self.delete_file(&obsolete.path, cancel).await {
+                        Err(Error::Cancelled) => return Err(Error::Cancelled),
+                        Err(err) => log::warn!("Failed to delete file '{}': {}", &obsolete.path, err),
+                        Ok(()) => {}
                     }
                 }
             }
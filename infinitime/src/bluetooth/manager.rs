@@ -0,0 +1,181 @@
+use super::{device::ProgressTxWrapper, services, InfiniTime, ProgressTx};
+use crate::{Error, Result};
+use bluer::{gatt::local::ApplicationHandle, Adapter, Address, Device};
+use std::{collections::BTreeMap, fmt, sync::{Arc, Mutex}, time::Duration};
+
+/// Delay between reconnection attempts, e.g. while the watch reboots
+const RECONNECT_INTERVAL: Duration = Duration::from_secs(3);
+/// Enough for the watch to reboot after firmware upgrade
+pub const DEFAULT_RECONNECT_TIMEOUT: Duration = Duration::from_secs(120);
+
+
+/// Registry of InfiniTime watches connected via one Bluetooth adapter.
+///
+/// Every watch gets its own [`InfiniTime`] instance, while the adapter and
+/// the GATT services it serves to the watches (e.g. current time) are shared.
+pub struct DeviceManager {
+    adapter: Arc<Adapter>,
+    devices: Mutex<BTreeMap<Address, Arc<InfiniTime>>>,
+    gatt_server: Mutex<Option<ApplicationHandle>>,
+}
+
+impl DeviceManager {
+    pub fn new(adapter: Arc<Adapter>) -> Self {
+        Self {
+            adapter,
+            devices: Mutex::new(BTreeMap::new()),
+            gatt_server: Mutex::new(None),
+        }
+    }
+
+    pub fn adapter(&self) -> &Arc<Adapter> {
+        &self.adapter
+    }
+
+    /// Serve GATT services for all watches. Does nothing if already serving.
+    pub async fn start_gatt_services(&self) -> Result<()> {
+        if self.gatt_server.lock().unwrap().is_none() {
+            let handle = services::start_gatt_services(&self.adapter).await?;
+            self.gatt_server.lock().unwrap().get_or_insert(handle);
+        }
+        Ok(())
+    }
+
+    /// Unregister GATT services, e.g. when another process serves them
+    pub fn stop_gatt_services(&self) {
+        self.gatt_server.lock().unwrap().take();
+    }
+
+    pub async fn list_known_devices(&self) -> Result<Vec<Device>> {
+        InfiniTime::list_known_devices(&self.adapter).await
+    }
+
+    /// Connect to the watch by address, or return it if it's already managed
+    pub async fn connect(&self, address: Address) -> Result<Arc<InfiniTime>> {
+        if let Some(infinitime) = self.get(address) {
+            return Ok(infinitime);
+        }
+        let device = self.adapter.device(address)?;
+        if !device.is_connected().await? {
+            log::debug!("Connecting to {}...", address);
+            device.connect().await?;
+        }
+        self.attach(Arc::new(device)).await
+    }
+
+    /// Start managing already connected watch
+    pub async fn attach(&self, device: Arc<Device>) -> Result<Arc<InfiniTime>> {
+        let address = device.address();
+        if let Some(infinitime) = self.get(address) {
+            return Ok(infinitime);
+        }
+        if !InfiniTime::check_device(&device).await {
+            return Err(Error::NotInfiniTime(address));
+        }
+        let infinitime = Arc::new(InfiniTime::new(device).await?);
+        // Another task might have attached it in the meantime, keep the first one
+        let infinitime = self.devices.lock().unwrap()
+            .entry(address)
+            .or_insert(infinitime)
+            .clone();
+        Ok(infinitime)
+    }
+
+    /// Stop managing the watch, e.g. after its connection is lost
+    pub fn detach(&self, address: Address) -> Option<Arc<InfiniTime>> {
+        self.devices.lock().unwrap().remove(&address)
+    }
+
+    pub async fn disconnect(&self, address: Address) -> Result<()> {
+        self.detach(address);
+        self.adapter.device(address)?.disconnect().await?;
+        Ok(())
+    }
+
+    /// Forget the watch and keep trying to connect to it again until
+    /// it's back or the timeout expires
+    pub async fn reconnect(&self, address: Address, timeout: Duration) -> Result<Arc<InfiniTime>> {
+        self.detach(address);
+        let attempts = async {
+            // Give the watch time to actually disconnect, e.g. to reboot
+            tokio::time::sleep(RECONNECT_INTERVAL).await;
+            loop {
+                match self.connect(address).await {
+                    Ok(infinitime) => break infinitime,
+                    Err(error) => {
+                        log::debug!("Waiting for {} to come back: {}", address, error);
+                        tokio::time::sleep(RECONNECT_INTERVAL).await;
+                    }
+                }
+            }
+        };
+        tokio::time::timeout(timeout, attempts).await
+            .map_err(|_| Error::Timeout)
+    }
+
+    /// Upgrade firmware, starting over after reconnection if the transfer fails
+    /// in a way that may be recovered from (timeout, lost connection, lost packets).
+    /// The watch bootloader doesn't keep partially received image across
+    /// connections, so every attempt sends the whole firmware.
+    pub async fn firmware_upgrade_with_retry(
+        &self,
+        address: Address,
+        dfu_content: &[u8],
+        progress_sender: Option<ProgressTx>,
+        attempts: u32,
+        reconnect_timeout: Duration,
+    ) -> Result<()> {
+        let progress = ProgressTxWrapper(progress_sender.clone());
+        let mut infinitime = self.connect(address).await?;
+        let mut attempt = 1;
+        loop {
+            match infinitime.firmware_upgrade(dfu_content, progress_sender.clone()).await {
+                Ok(()) => break Ok(()),
+                Err(error) if error.is_transient() && attempt < attempts => {
+                    log::warn!("Firmware upgrade attempt {} of {} failed: {}", attempt, attempts, error);
+                    progress.report_msg(format!("Failed: {}. Reconnecting...", error)).await;
+                    drop(infinitime);
+                    // Start over on a new connection, so that the watch
+                    // doesn't carry on with the abandoned DFU session
+                    if let Err(error) = self.disconnect(address).await {
+                        log::debug!("Failed to disconnect from {}: {}", address, error);
+                    }
+                    infinitime = self.reconnect(address, reconnect_timeout).await?;
+                    attempt += 1;
+                    progress.report_msg(format!("Retrying (attempt {} of {})...", attempt, attempts)).await;
+                }
+                Err(error) => break Err(error),
+            }
+        }
+    }
+
+    pub fn get(&self, address: Address) -> Option<Arc<InfiniTime>> {
+        self.devices.lock().unwrap().get(&address).cloned()
+    }
+
+    /// Managed watches, ordered by address
+    pub fn devices(&self) -> Vec<Arc<InfiniTime>> {
+        self.devices.lock().unwrap().values().cloned().collect()
+    }
+
+    pub fn addresses(&self) -> Vec<Address> {
+        self.devices.lock().unwrap().keys().copied().collect()
+    }
+
+    pub fn len(&self) -> usize {
+        self.devices.lock().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+impl fmt::Debug for DeviceManager {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("DeviceManager")
+            .field("adapter", &self.adapter.name())
+            .field("devices", &self.addresses())
+            .finish()
+    }
+}
@@ -0,0 +1,99 @@
+use uuid::Uuid;
+use crate::{Error, Result};
+use bluer::{gatt::remote::Characteristic, Address, Device};
+use futures::{future::BoxFuture, stream::BoxStream, FutureExt, StreamExt};
+use std::{collections::HashMap, sync::Arc};
+
+mod fake;
+
+pub use fake::FakeTransport;
+
+
+/// Access to the watch GATT characteristics by their UUIDs.
+///
+/// `InfiniTime` talks to the watch only through this trait, so it can run
+/// on top of BlueZ ([`BluezTransport`]) or an in-process fake ([`FakeTransport`]).
+pub trait GattTransport: std::fmt::Debug + Send + Sync {
+    /// Bluetooth address of the watch
+    fn address(&self) -> Address;
+
+    /// UUIDs of all characteristics exposed by the watch
+    fn characteristics(&self) -> Vec<Uuid>;
+
+    fn has_characteristic(&self, uuid: &Uuid) -> bool {
+        self.characteristics().contains(uuid)
+    }
+
+    fn read(&self, uuid: Uuid) -> BoxFuture<'_, Result<Vec<u8>>>;
+
+    fn write<'s>(&'s self, uuid: Uuid, value: &'s [u8]) -> BoxFuture<'s, Result<()>>;
+
+    fn notify(&self, uuid: Uuid) -> BoxFuture<'_, Result<BoxStream<'static, Vec<u8>>>>;
+}
+
+
+/// GATT transport backed by BlueZ remote characteristics
+#[derive(Debug)]
+pub struct BluezTransport {
+    device: Arc<Device>,
+    characteristics: HashMap<Uuid, Characteristic>,
+}
+
+impl BluezTransport {
+    pub async fn new(device: Arc<Device>) -> Result<Self> {
+        let characteristics = Self::read_characteristics_map(&device).await?;
+        Ok(Self { device, characteristics })
+    }
+
+    pub fn device(&self) -> &Arc<Device> {
+        &self.device
+    }
+
+    fn chr<'s>(&'s self, uuid: &Uuid) -> Result<&'s Characteristic> {
+        self.characteristics.get(uuid)
+            .ok_or(Error::CharacteristicNotFound(*uuid))
+    }
+
+    async fn read_characteristics_map(device: &Device) -> Result<HashMap<Uuid, Characteristic>> {
+        let mut map = HashMap::new();
+        for service in device.services().await? {
+            for characteristic in service.characteristics().await? {
+                let uuid = characteristic.uuid().await?;
+                map.insert(uuid, characteristic);
+            }
+        }
+        Ok(map)
+    }
+}
+
+impl GattTransport for BluezTransport {
+    fn address(&self) -> Address {
+        self.device.address()
+    }
+
+    fn characteristics(&self) -> Vec<Uuid> {
+        self.characteristics.keys().cloned().collect()
+    }
+
+    fn has_characteristic(&self, uuid: &Uuid) -> bool {
+        self.characteristics.contains_key(uuid)
+    }
+
+    fn read(&self, uuid: Uuid) -> BoxFuture<'_, Result<Vec<u8>>> {
+        async move {
+            Ok(self.chr(&uuid)?.read().await?)
+        }.boxed()
+    }
+
+    fn write<'s>(&'s self, uuid: Uuid, value: &'s [u8]) -> BoxFuture<'s, Result<()>> {
+        async move {
+            Ok(self.chr(&uuid)?.write(value).await?)
+        }.boxed()
+    }
+
+    fn notify(&self, uuid: Uuid) -> BoxFuture<'_, Result<BoxStream<'static, Vec<u8>>>> {
+        async move {
+            Ok(self.chr(&uuid)?.notify().await?.boxed())
+        }.boxed()
+    }
+}
@@ -0,0 +1,166 @@
+use super::GattTransport;
+use uuid::Uuid;
+use crate::{Error, Result};
+use bluer::Address;
+use futures::{channel::mpsc, future::{self, BoxFuture}, stream::BoxStream, FutureExt, StreamExt};
+use std::{collections::HashMap, fmt, sync::{Arc, Mutex}};
+
+
+type WriteHandler = Box<dyn FnMut(&FakeTransport, &[u8]) -> Result<()> + Send>;
+
+/// In-process GATT transport, which doesn't need BlueZ or a real watch.
+///
+/// Characteristic values are kept in memory and can be changed at any time.
+/// Every write is recorded, and can be intercepted by a handler registered
+/// with [`FakeTransport::on_write`], which can respond with notifications.
+/// Cloned instances share the same state.
+#[derive(Clone)]
+pub struct FakeTransport {
+    address: Address,
+    state: Arc<Mutex<State>>,
+}
+
+#[derive(Default)]
+struct State {
+    values: HashMap<Uuid, Vec<u8>>,
+    writes: Vec<(Uuid, Vec<u8>)>,
+    subscribers: HashMap<Uuid, Vec<mpsc::UnboundedSender<Vec<u8>>>>,
+    handlers: HashMap<Uuid, WriteHandler>,
+}
+
+impl FakeTransport {
+    pub fn new(address: Address) -> Self {
+        Self { address, state: Default::default() }
+    }
+
+    /// Expose characteristic with the initial value
+    pub fn add_characteristic(&self, uuid: Uuid, value: impl Into<Vec<u8>>) {
+        self.state().values.insert(uuid, value.into());
+    }
+
+    /// Set characteristic value without notifying subscribers
+    pub fn set_value(&self, uuid: Uuid, value: impl Into<Vec<u8>>) {
+        self.add_characteristic(uuid, value);
+    }
+
+    pub fn value(&self, uuid: &Uuid) -> Option<Vec<u8>> {
+        self.state().values.get(uuid).cloned()
+    }
+
+    /// Set characteristic value and send it to all subscribers
+    pub fn notify_value(&self, uuid: Uuid, value: impl Into<Vec<u8>>) {
+        let value = value.into();
+        self.send_notification(&uuid, &value);
+        self.set_value(uuid, value);
+    }
+
+    /// Send notification to all subscribers without changing the stored value
+    pub fn send_notification(&self, uuid: &Uuid, value: &[u8]) {
+        if let Some(subscribers) = self.state().subscribers.get_mut(uuid) {
+            subscribers.retain(|tx| tx.unbounded_send(value.to_vec()).is_ok());
+        }
+    }
+
+    /// Intercept writes to the characteristic. The handler replaces
+    /// the default behavior of storing the written value.
+    pub fn on_write<F>(&self, uuid: Uuid, handler: F)
+    where
+        F: FnMut(&FakeTransport, &[u8]) -> Result<()> + Send + 'static
+    {
+        let mut state = self.state();
+        state.values.entry(uuid).or_default();
+        state.handlers.insert(uuid, Box::new(handler));
+    }
+
+    /// All writes received so far, in order
+    pub fn writes(&self) -> Vec<(Uuid, Vec<u8>)> {
+        self.state().writes.clone()
+    }
+
+    /// Values written to the characteristic so far, in order
+    pub fn writes_to(&self, uuid: &Uuid) -> Vec<Vec<u8>> {
+        self.state().writes.iter()
+            .filter(|(u, _)| u == uuid)
+            .map(|(_, v)| v.clone())
+            .collect()
+    }
+
+    pub fn clear_writes(&self) {
+        self.state().writes.clear();
+    }
+
+    fn state(&self) -> std::sync::MutexGuard<'_, State> {
+        // Poisoning can only happen if a write handler panics,
+        // the state itself stays consistent in that case
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn check(&self, uuid: &Uuid) -> Result<()> {
+        if self.state().values.contains_key(uuid) {
+            Ok(())
+        } else {
+            Err(Error::CharacteristicNotFound(*uuid))
+        }
+    }
+
+    fn handle_write(&self, uuid: Uuid, value: &[u8]) -> Result<()> {
+        self.check(&uuid)?;
+        // The handler is taken out of the state while running,
+        // so it can call back into the transport without deadlocking
+        let handler = {
+            let mut state = self.state();
+            state.writes.push((uuid, value.to_vec()));
+            state.handlers.remove(&uuid)
+        };
+        match handler {
+            Some(mut handler) => {
+                let result = handler(self, value);
+                self.state().handlers.entry(uuid).or_insert(handler);
+                result
+            }
+            None => {
+                self.set_value(uuid, value);
+                Ok(())
+            }
+        }
+    }
+}
+
+impl fmt::Debug for FakeTransport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let state = self.state();
+        f.debug_struct("FakeTransport")
+            .field("address", &self.address)
+            .field("characteristics", &state.values.keys())
+            .finish()
+    }
+}
+
+impl GattTransport for FakeTransport {
+    fn address(&self) -> Address {
+        self.address
+    }
+
+    fn characteristics(&self) -> Vec<Uuid> {
+        self.state().values.keys().cloned().collect()
+    }
+
+    fn read(&self, uuid: Uuid) -> BoxFuture<'_, Result<Vec<u8>>> {
+        let result = self.value(&uuid)
+            .ok_or(Error::CharacteristicNotFound(uuid));
+        future::ready(result).boxed()
+    }
+
+    fn write<'s>(&'s self, uuid: Uuid, value: &'s [u8]) -> BoxFuture<'s, Result<()>> {
+        future::ready(self.handle_write(uuid, value)).boxed()
+    }
+
+    fn notify(&self, uuid: Uuid) -> BoxFuture<'_, Result<BoxStream<'static, Vec<u8>>>> {
+        let result = self.check(&uuid).map(|()| {
+            let (tx, rx) = mpsc::unbounded();
+            self.state().subscribers.entry(uuid).or_default().push(tx);
+            rx.boxed()
+        });
+        future::ready(result).boxed()
+    }
+}
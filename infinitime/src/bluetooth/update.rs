@@ -0,0 +1,194 @@
+use super::{
+    device::{fs::check_cancelled, resources::resources_size, ProgressTxWrapper},
+    manager::DEFAULT_RECONNECT_TIMEOUT,
+    progress_channel, DeviceManager, DfuPackage, ProgressEvent, ProgressTx,
+};
+use crate::{compatibility, Error, Result};
+use bluer::Address;
+use std::{future::Future, time::Duration};
+use tokio_util::sync::CancellationToken;
+
+
+/// Firmware and/or matching resources flashed to one watch in a single operation
+#[derive(Debug, Clone, Copy)]
+pub struct DeviceUpdate<'a> {
+    /// DFU package
+    pub firmware: Option<&'a [u8]>,
+    /// Resources archive, uploaded after the watch is back with the new firmware
+    pub resources: Option<&'a [u8]>,
+    /// How many times to start firmware upgrade over after a failure
+    pub attempts: u32,
+    /// How long to wait for the watch to come back after firmware upgrade
+    pub reconnect_timeout: Duration,
+}
+
+impl<'a> Default for DeviceUpdate<'a> {
+    fn default() -> Self {
+        Self {
+            firmware: None,
+            resources: None,
+            attempts: 3,
+            reconnect_timeout: DEFAULT_RECONNECT_TIMEOUT,
+        }
+    }
+}
+
+impl DeviceManager {
+    /// Flash firmware, wait for the watch to reboot into it and check that it
+    /// reports the expected version, then upload resources. Progress of all
+    /// steps is reported on one scale. Returns the final firmware version.
+    pub async fn update_device(
+        &self,
+        address: Address,
+        update: DeviceUpdate<'_>,
+        progress_sender: Option<ProgressTx>,
+        cancel: Option<&CancellationToken>,
+    ) -> Result<String> {
+        let progress = ProgressTxWrapper(progress_sender);
+        let package = update.firmware.map(DfuPackage::parse).transpose()?;
+        let firmware_size = match &package {
+            Some(package) => u32::try_from(package.firmware.len())
+                .map_err(|_| Error::Archive(String::from("Firmware is too large")))?,
+            None => 0,
+        };
+        let resources_size = update.resources.map(resources_size).transpose()?.unwrap_or(0);
+        let total = firmware_size.checked_add(resources_size)
+            .ok_or_else(|| Error::Archive(String::from("Update is too large")))?;
+        let mut scale = ProgressScale { total, done: 0 };
+        let steps = update.firmware.is_some() as u32 + update.resources.is_some() as u32;
+        let mut step = 0;
+
+        let mut infinitime = self.connect(address).await?;
+
+        if let (Some(firmware), Some(package)) = (update.firmware, &package) {
+            step += 1;
+            check_cancelled(cancel)?;
+            progress.report_msg(format!("Step {} of {}: flashing firmware", step, steps)).await;
+            drop(infinitime);
+            let upgrade = scale.run(&progress, false, |progress_tx| {
+                self.firmware_upgrade_with_retry(
+                    address, firmware, progress_tx, update.attempts, update.reconnect_timeout
+                )
+            });
+            // The bootloader discards partially received image, nothing to clean up
+            match cancel {
+                Some(token) => tokio::select! {
+                    result = upgrade => result?,
+                    _ = token.cancelled() => return Err(Error::Cancelled),
+                },
+                None => upgrade.await?,
+            }
+            scale.done = firmware_size;
+
+            progress.report_msg("Waiting for the watch to reboot...").await;
+            infinitime = self.reconnect(address, update.reconnect_timeout).await?;
+            let installed = infinitime.read_firmware_version().await?;
+            if let Some(expected) = &package.image_version {
+                if !compatibility::versions_match(&installed, expected) {
+                    return Err(Error::Dfu(format!(
+                        "Watch runs firmware {} after upgrade, expected {}", installed, expected
+                    )));
+                }
+            }
+            log::info!("Watch {} is back with firmware {}", address, installed);
+        }
+
+        if let Some(resources) = update.resources {
+            step += 1;
+            check_cancelled(cancel)?;
+            progress.report_msg(format!("Step {} of {}: uploading resources", step, steps)).await;
+            let infinitime = &infinitime;
+            scale.run(&progress, true, |progress_tx| {
+                infinitime.upload_resources(resources, progress_tx, cancel)
+            }).await?;
+        }
+
+        infinitime.read_firmware_version().await
+    }
+}
+
+/// Maps progress numbers of the current step onto the whole operation
+struct ProgressScale {
+    total: u32,
+    /// Bytes transferred by the previous steps
+    done: u32,
+}
+
+impl ProgressScale {
+    /// Run the step, forwarding its progress. With `per_file` numbers
+    /// start from zero for every file, which is announced by a message.
+    async fn run<F, T>(
+        &mut self,
+        progress: &ProgressTxWrapper,
+        per_file: bool,
+        step: impl FnOnce(Option<ProgressTx>) -> F,
+    ) -> T
+    where
+        F: Future<Output = T>,
+    {
+        if progress.0.is_none() {
+            return step(None).await;
+        }
+
+        let (progress_tx, mut progress_rx) = progress_channel(16);
+        let base = self.done;
+        let total = self.total;
+        let forwarder = async {
+            let mut files_done = 0;
+            let mut current = 0;
+            while let Some(event) = progress_rx.recv().await {
+                match event {
+                    ProgressEvent::Message(_) => {
+                        if per_file {
+                            files_done += current;
+                            current = 0;
+                        }
+                        progress.report(event).await;
+                    }
+                    ProgressEvent::Numbers { current: step_current, .. } => {
+                        current = step_current;
+                        progress.report_num((base + files_done + current).min(total), total).await;
+                    }
+                }
+            }
+            files_done + current
+        };
+        let (result, step_done) = tokio::join!(step(Some(progress_tx)), forwarder);
+        self.done = base + step_done;
+        result
+    }
+}
+
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{Cursor, Write};
+
+    #[test]
+    fn resources_size_counts_listed_files() {
+        let manifest = r#"{
+            "resources": [
+                {"filename": "teko.bin", "path": "/fonts/teko.bin"},
+                {"filename": "lv_font_dots_40.bin", "path": "/fonts/lv_font_dots_40.bin"}
+            ],
+            "obsolete_files": []
+        }"#;
+        let mut zip = zip::ZipWriter::new(Cursor::new(Vec::new()));
+        let options = zip::write::FileOptions::default();
+        zip.start_file("resources.json", options).unwrap();
+        zip.write_all(manifest.as_bytes()).unwrap();
+        zip.start_file("teko.bin", options).unwrap();
+        zip.write_all(&[1; 300]).unwrap();
+        zip.start_file("lv_font_dots_40.bin", options).unwrap();
+        zip.write_all(&[2; 20]).unwrap();
+        // Not listed in the manifest, so not uploaded
+        zip.start_file("README.md", options).unwrap();
+        zip.write_all(&[3; 1000]).unwrap();
+        let archive = zip.finish().unwrap().into_inner();
+
+        assert_eq!(resources_size(&archive).unwrap(), 320);
+        assert!(resources_size(b"not a zip").is_err());
+    }
+}
@@ -29,4 +29,13 @@ pub const CHR_MP_REPEAT: Uuid = uuid!("0000000b-78fc-48fe-8e23-433b3a1942d0");
 pub const CHR_MP_SHUFFLE: Uuid = uuid!("0000000c-78fc-48fe-8e23-433b3a1942d0");
 
 pub const CHR_STEP_COUNT: Uuid = uuid!("00030001-78fc-48fe-8e23-433b3a1942d0");
-pub const CHR_MOTION: Uuid = uuid!("00030002-78fc-48fe-8e23-433b3a1942d0");
\ No newline at end of file
+pub const CHR_MOTION: Uuid = uuid!("00030002-78fc-48fe-8e23-433b3a1942d0");
+
+pub const CHR_NAV_FLAGS: Uuid = uuid!("00010001-78fc-48fe-8e23-433b3a1942d0");
+pub const CHR_NAV_NARRATIVE: Uuid = uuid!("00010002-78fc-48fe-8e23-433b3a1942d0");
+pub const CHR_NAV_MAN_DISTANCE: Uuid = uuid!("00010003-78fc-48fe-8e23-433b3a1942d0");
+pub const CHR_NAV_PROGRESS: Uuid = uuid!("00010004-78fc-48fe-8e23-433b3a1942d0");
+
+// Weather service was reworked in InfiniTime 1.14, older firmware exposes the legacy one
+pub const CHR_WEATHER_DATA_LEGACY: Uuid = uuid!("00040001-78fc-48fe-8e23-433b3a1942d0");
+pub const CHR_WEATHER_DATA: Uuid = uuid!("00050001-78fc-48fe-8e23-433b3a1942d0");
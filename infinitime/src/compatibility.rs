@@ -0,0 +1,357 @@
+//! Compatibility rules for firmware and resources updates
+
+use crate::bt::DfuPackage;
+use std::fmt;
+use version_compare::{Manifest, Version};
+
+/// First InfiniTime release booted by MCUBoot
+const MCUBOOT_VERSION: &str = "1.0.0";
+/// First InfiniTime release which loads resources from the file system
+const RESOURCES_VERSION: &str = "1.11.0";
+
+
+/// Problem with the planned update. Critical ones most likely leave
+/// the watch broken or unable to boot, the rest only need confirmation.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Warning {
+    /// Target firmware is older than the installed one
+    Downgrade { current: String, target: String },
+    /// Resources are built for another firmware version
+    ResourceMismatch { firmware: String, resources: String },
+    /// Firmware doesn't load resources from the file system
+    ResourcesUnsupported { firmware: String },
+    /// Target firmware predates MCUBoot and can't be booted by it
+    LegacyFirmware { target: Option<String> },
+    /// Watch runs legacy bootloader, which can't boot MCUBoot images.
+    /// It has to be replaced first (e.g. with InfiniTime reloader).
+    BootloaderUpgradeRequired { current: String },
+    /// Target is an unreleased CI build, which is not tested as much
+    UnstableBuild { build: String },
+}
+
+impl Warning {
+    pub fn is_critical(&self) -> bool {
+        matches!(
+            self,
+            Self::ResourcesUnsupported { .. } | Self::LegacyFirmware { .. } | Self::BootloaderUpgradeRequired { .. }
+        )
+    }
+
+    pub fn title(&self) -> &'static str {
+        match self {
+            Self::Downgrade { .. } => "Downgrading firmware",
+            Self::ResourceMismatch { .. } => "Resources version mismatch",
+            Self::ResourcesUnsupported { .. } => "Resources not supported",
+            Self::LegacyFirmware { .. } => "Legacy firmware",
+            Self::BootloaderUpgradeRequired { .. } => "Bootloader upgrade required",
+            Self::UnstableBuild { .. } => "Unstable build",
+        }
+    }
+}
+
+impl fmt::Display for Warning {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Downgrade { current, target } => {
+                write!(f, "Firmware {target} is older than the installed {current}")
+            }
+            Self::ResourceMismatch { firmware, resources } => {
+                write!(f, "Resources {resources} don't match firmware {firmware}")
+            }
+            Self::ResourcesUnsupported { firmware } => {
+                write!(f, "Firmware {firmware} doesn't support resources, {RESOURCES_VERSION} or newer is required")
+            }
+            Self::LegacyFirmware { target } => match target {
+                Some(target) => write!(f, "Firmware {target} predates MCUBoot and is not supported"),
+                None => write!(f, "Firmware image predates MCUBoot and is not supported"),
+            },
+            Self::BootloaderUpgradeRequired { current } => {
+                write!(f, "Installed firmware {current} uses legacy bootloader, it has to be upgraded to MCUBoot first")
+            }
+            Self::UnstableBuild { build } => {
+                write!(f, "{build} is not released and may be unstable or leave the watch unusable")
+            }
+        }
+    }
+}
+
+
+#[derive(Debug, Clone, Default)]
+struct FirmwareTarget {
+    version: Option<String>,
+    /// Known only when the DFU package itself is inspected
+    is_mcuboot: Option<bool>,
+}
+
+/// Firmware and/or resources which are about to be flashed
+#[derive(Debug, Clone, Default)]
+pub struct UpdatePlan {
+    current: Option<String>,
+    firmware: Option<FirmwareTarget>,
+    resources: Option<Option<String>>,
+    unstable_build: Option<String>,
+}
+
+impl UpdatePlan {
+    /// Plan against the firmware version currently running on the watch
+    pub fn new(current_firmware: Option<&str>) -> Self {
+        Self { current: current_firmware.map(String::from), ..Default::default() }
+    }
+
+    /// Flash firmware of the given version, e.g. from a release tag
+    pub fn firmware(mut self, version: Option<&str>) -> Self {
+        self.firmware = Some(FirmwareTarget { version: version.map(String::from), is_mcuboot: None });
+        self
+    }
+
+    /// Flash the given DFU package
+    pub fn firmware_package(mut self, package: &DfuPackage) -> Self {
+        self.firmware = Some(FirmwareTarget {
+            version: package.version(),
+            is_mcuboot: Some(package.is_mcuboot_image()),
+        });
+        self
+    }
+
+    /// Upload resources built for the given firmware version
+    pub fn resources(mut self, version: Option<&str>) -> Self {
+        self.resources = Some(version.map(String::from));
+        self
+    }
+
+    /// Target comes from an unreleased build, described by the label
+    pub fn unstable_build(mut self, label: &str) -> Self {
+        self.unstable_build = Some(label.to_string());
+        self
+    }
+
+    pub fn check(&self) -> Vec<Warning> {
+        let mut warnings = Vec::new();
+        if let Some(build) = &self.unstable_build {
+            warnings.push(Warning::UnstableBuild { build: build.clone() });
+        }
+        let current = self.current.as_deref().and_then(parse);
+        let current_is_legacy = current.as_ref().map(|v| *v < legacy_limit());
+
+        if let Some(firmware) = &self.firmware {
+            let target = firmware.version.as_deref().and_then(parse);
+            let target_is_legacy = match firmware.is_mcuboot {
+                Some(is_mcuboot) => Some(!is_mcuboot),
+                None => target.as_ref().map(|v| *v < legacy_limit()),
+            };
+
+            if target_is_legacy == Some(true) {
+                warnings.push(Warning::LegacyFirmware { target: firmware.version.clone() });
+            } else if current_is_legacy == Some(true) {
+                warnings.push(Warning::BootloaderUpgradeRequired { current: self.current.clone().unwrap_or_default() });
+            }
+            if let (Some(current), Some(target)) = (&current, &target) {
+                if target < current {
+                    warnings.push(Warning::Downgrade {
+                        current: self.current.clone().unwrap_or_default(),
+                        target: firmware.version.clone().unwrap_or_default(),
+                    });
+                }
+            }
+        }
+
+        if let Some(resources) = &self.resources {
+            // Resources are uploaded after the firmware is flashed
+            let firmware_version = match &self.firmware {
+                Some(firmware) => firmware.version.as_deref(),
+                None => self.current.as_deref(),
+            };
+            if let Some(firmware) = firmware_version.and_then(parse) {
+                let firmware_version = firmware_version.unwrap_or_default().to_string();
+                if firmware < resources_limit() {
+                    warnings.push(Warning::ResourcesUnsupported { firmware: firmware_version });
+                } else if let Some(resources_version) = resources.as_deref() {
+                    if parse(resources_version).is_some_and(|v| v != firmware) {
+                        warnings.push(Warning::ResourceMismatch {
+                            firmware: firmware_version,
+                            resources: resources_version.to_string(),
+                        });
+                    }
+                }
+            }
+        }
+
+        warnings
+    }
+}
+
+/// Version embedded in a release asset name,
+/// e.g. "1.14.0" from "infinitime-resources-1.14.0.zip"
+pub fn version_from_file_name(name: &str) -> Option<String> {
+    let stem = name.rsplit('/').next()?.trim_end_matches(".zip");
+    let (_, version) = stem.rsplit_once('-')?;
+    match version.chars().next() {
+        Some(c) if c.is_ascii_digit() && parse(version).is_some() => Some(version.to_string()),
+        _ => None,
+    }
+}
+
+/// Whether both strings denote the same version, e.g. "1.14" and "1.14.0"
+pub fn versions_match(a: &str, b: &str) -> bool {
+    match (parse(a), parse(b)) {
+        (Some(a), Some(b)) => a == b,
+        _ => a == b,
+    }
+}
+
+const MANIFEST: Manifest = Manifest {
+    max_depth: None,
+    ignore_text: true,
+};
+
+fn parse(version: &str) -> Option<Version<'_>> {
+    Version::from_manifest(version, &MANIFEST)
+}
+
+fn legacy_limit() -> Version<'static> {
+    Version::from_manifest(MCUBOOT_VERSION, &MANIFEST).unwrap()
+}
+
+fn resources_limit() -> Version<'static> {
+    Version::from_manifest(RESOURCES_VERSION, &MANIFEST).unwrap()
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use Warning::*;
+
+    /// Plan for flashing a DFU package, as `UpdatePlan::firmware_package` would build it
+    fn package(plan: UpdatePlan, version: Option<&str>, is_mcuboot: bool) -> UpdatePlan {
+        UpdatePlan {
+            firmware: Some(FirmwareTarget { version: version.map(String::from), is_mcuboot: Some(is_mcuboot) }),
+            ..plan
+        }
+    }
+
+    fn s(value: &str) -> String {
+        value.to_string()
+    }
+
+    #[test]
+    fn check() {
+        let cases: Vec<(&str, UpdatePlan, Vec<Warning>)> = vec![
+            ("upgrade", UpdatePlan::new(Some("1.13.0")).firmware(Some("1.14.0")), vec![]),
+            ("same version", UpdatePlan::new(Some("1.14.0")).firmware(Some("1.14")), vec![]),
+            ("unknown current", UpdatePlan::new(None).firmware(Some("1.14.0")), vec![]),
+            ("unknown target", UpdatePlan::new(Some("1.14.0")).firmware(None), vec![]),
+            (
+                "downgrade",
+                UpdatePlan::new(Some("1.14.0")).firmware(Some("1.13.0")),
+                vec![Downgrade { current: s("1.14.0"), target: s("1.13.0") }],
+            ),
+            (
+                "legacy target",
+                UpdatePlan::new(Some("1.0.0")).firmware(Some("0.15.0")),
+                vec![
+                    LegacyFirmware { target: Some(s("0.15.0")) },
+                    Downgrade { current: s("1.0.0"), target: s("0.15.0") },
+                ],
+            ),
+            (
+                "legacy package with MCUBoot version",
+                package(UpdatePlan::new(Some("1.13.0")), Some("1.14.0"), false),
+                vec![LegacyFirmware { target: Some(s("1.14.0")) }],
+            ),
+            (
+                "MCUBoot package with legacy version",
+                package(UpdatePlan::new(Some("1.0.0")), Some("0.15.0"), true),
+                vec![Downgrade { current: s("1.0.0"), target: s("0.15.0") }],
+            ),
+            (
+                "legacy package without version",
+                package(UpdatePlan::new(Some("1.13.0")), None, false),
+                vec![LegacyFirmware { target: None }],
+            ),
+            (
+                "legacy bootloader",
+                UpdatePlan::new(Some("0.15.0")).firmware(Some("1.14.0")),
+                vec![BootloaderUpgradeRequired { current: s("0.15.0") }],
+            ),
+            (
+                "legacy bootloader with MCUBoot package",
+                package(UpdatePlan::new(Some("0.15.0")), Some("1.14.0"), true),
+                vec![BootloaderUpgradeRequired { current: s("0.15.0") }],
+            ),
+            ("resources", UpdatePlan::new(Some("1.14.0")).resources(Some("1.14")), vec![]),
+            ("resources of unknown version", UpdatePlan::new(Some("1.14.0")).resources(None), vec![]),
+            (
+                "resources on old firmware",
+                UpdatePlan::new(Some("1.10.0")).resources(Some("1.11.0")),
+                vec![ResourcesUnsupported { firmware: s("1.10.0") }],
+            ),
+            (
+                "resources mismatch",
+                UpdatePlan::new(Some("1.13.0")).resources(Some("1.14.0")),
+                vec![ResourceMismatch { firmware: s("1.13.0"), resources: s("1.14.0") }],
+            ),
+            (
+                "resources for flashed firmware",
+                UpdatePlan::new(Some("1.13.0")).firmware(Some("1.14.0")).resources(Some("1.14.0")),
+                vec![],
+            ),
+            (
+                "resources for old flashed firmware",
+                UpdatePlan::new(Some("1.10.0")).firmware(Some("1.10.0")).resources(Some("1.14.0")),
+                vec![ResourcesUnsupported { firmware: s("1.10.0") }],
+            ),
+            (
+                "unstable build",
+                UpdatePlan::new(Some("1.14.0")).firmware(None).unstable_build("main@abcdef"),
+                vec![UnstableBuild { build: s("main@abcdef") }],
+            ),
+        ];
+
+        for (name, plan, expected) in cases {
+            assert_eq!(plan.check(), expected, "{name}");
+        }
+    }
+
+    #[test]
+    fn critical_warnings() {
+        assert!(LegacyFirmware { target: None }.is_critical());
+        assert!(BootloaderUpgradeRequired { current: s("0.15.0") }.is_critical());
+        assert!(ResourcesUnsupported { firmware: s("1.10.0") }.is_critical());
+        assert!(!Downgrade { current: s("1.14.0"), target: s("1.13.0") }.is_critical());
+        assert!(!UnstableBuild { build: s("main") }.is_critical());
+    }
+
+    #[test]
+    fn match_versions() {
+        let cases = [
+            ("1.14", "1.14.0", true),
+            ("1.14.0", "1.14.0", true),
+            ("1.14.0", "1.14.1", false),
+            ("1.14", "1.13.0", false),
+            ("main", "main", true),
+            ("main", "1.14.0", false),
+        ];
+        for (a, b, expected) in cases {
+            assert_eq!(versions_match(a, b), expected, "{a} vs {b}");
+            assert_eq!(versions_match(b, a), expected, "{b} vs {a}");
+        }
+    }
+
+    #[test]
+    fn file_name_versions() {
+        let cases = [
+            ("infinitime-resources-1.14.0.zip", Some("1.14.0")),
+            ("pinetime-mcuboot-app-dfu-1.14.0.zip", Some("1.14.0")),
+            ("/tmp/downloads/infinitime-resources-1.13.0.zip", Some("1.13.0")),
+            ("infinitime-resources-1.14", Some("1.14")),
+            ("infinitime-resources.zip", None),
+            ("pinetime-mcuboot-app-dfu-main.zip", None),
+            ("resources.zip", None),
+            ("", None),
+        ];
+        for (name, expected) in cases {
+            assert_eq!(version_from_file_name(name).as_deref(), expected, "{name}");
+        }
+    }
+}
@@ -0,0 +1,176 @@
+use crate::bt::FsStatus;
+use uuid::Uuid;
+
+pub type Result<T, E = Error> = std::result::Result<T, E>;
+
+
+/// Error type for everything in this crate
+#[derive(Debug, thiserror::Error)]
+#[non_exhaustive]
+pub enum Error {
+    /// Characteristic is not exposed by the watch,
+    /// usually because its firmware is too old
+    #[error("Characteristic not found by UUID: {0}")]
+    CharacteristicNotFound(Uuid),
+
+    /// Error reported by BlueZ
+    #[error("Bluetooth error: {0}")]
+    Bluetooth(#[from] bluer::Error),
+
+    /// Device doesn't advertise itself as an InfiniTime watch
+    #[error("Device {0} is not an InfiniTime watch")]
+    NotInfiniTime(bluer::Address),
+
+    /// No watch address is given and the adapter knows no InfiniTime devices
+    #[error("No known InfiniTime devices, pair the watch or scan for it first")]
+    NoKnownDevices,
+
+    /// No watch address is given and several InfiniTime devices are known
+    #[error("Multiple InfiniTime devices are known, specify one by address")]
+    MultipleKnownDevices,
+
+    /// Notification stream ended before the watch responded
+    #[error("No response from the watch")]
+    NoResponse,
+
+    /// The watch didn't respond or come back in time
+    #[error("Timed out waiting for the watch")]
+    Timeout,
+
+    /// Operation was cancelled by the caller
+    #[error("Operation cancelled")]
+    Cancelled,
+
+    /// Malformed or unexpected data received from the watch or another peer
+    #[error("Protocol error: {0}")]
+    Protocol(String),
+
+    /// Error status reported by LittleFS on the watch
+    #[error("LittleFS error: {0:?}")]
+    FileSystem(FsStatus),
+
+    /// Firmware upgrade protocol violation
+    #[error("DFU error: {0}")]
+    Dfu(String),
+
+    /// The watch received less firmware than was sent or the image
+    /// failed CRC validation, usually because packets were dropped over the air
+    #[error("Firmware packets lost: {0}")]
+    PacketLost(String),
+
+    /// Invalid DFU or resources archive
+    #[error("Invalid archive: {0}")]
+    Archive(String),
+
+    #[error("Archive error: {0}")]
+    Zip(#[from] zip::result::ZipError),
+
+    #[error("I/O error: {0}")]
+    Io(#[from] std::io::Error),
+
+    #[cfg(feature = "freedesktop")]
+    #[error("D-Bus error: {0}")]
+    DBus(#[from] zbus::Error),
+
+    #[cfg(feature = "freedesktop")]
+    #[error("D-Bus error: {0}")]
+    DBusFdo(#[from] zbus::fdo::Error),
+
+    #[cfg(feature = "github")]
+    #[error("HTTP error: {0}")]
+    Http(#[from] reqwest::Error),
+
+    /// Server responded with unsuccessful HTTP status
+    #[cfg(feature = "github")]
+    #[error("Request failed: {0}")]
+    HttpStatus(reqwest::StatusCode),
+
+    /// Downloaded asset is larger than the watch accepts,
+    /// or its length doesn't match the advertised one
+    #[cfg(feature = "github")]
+    #[error("Unexpected download size: {0}")]
+    DownloadSize(String),
+
+    /// GitHub API requests quota is used up until the reset time,
+    /// the limit is higher for requests with an access token
+    #[cfg(feature = "github")]
+    #[error("API rate limit of {} requests per hour exceeded", .0.limit)]
+    RateLimited(crate::gh::RateLimit),
+
+    /// Firmware releases source is misconfigured
+    #[cfg(feature = "github")]
+    #[error("Invalid release source: {0}")]
+    InvalidSource(String),
+
+    #[cfg(feature = "history")]
+    #[error("Database error: {0}")]
+    Database(#[from] rusqlite::Error),
+}
+
+impl Error {
+    /// Whether the operation may succeed if simply started over,
+    /// possibly after reconnecting to the watch
+    pub fn is_transient(&self) -> bool {
+        match self {
+            Self::Timeout | Self::NoResponse | Self::PacketLost(_) => true,
+            Self::Bluetooth(error) => is_disconnect(error),
+            _ => false,
+        }
+    }
+}
+
+/// Connection to the watch is lost or couldn't be established.
+/// BlueZ reports failed GATT requests on a dropped link as generic
+/// failures, which are only told apart by the message.
+fn is_disconnect(error: &bluer::Error) -> bool {
+    match error.kind {
+        bluer::ErrorKind::NotConnected | bluer::ErrorKind::ConnectionAttemptFailed => true,
+        bluer::ErrorKind::Failed => error.message.to_lowercase().contains("not connected"),
+        _ => false,
+    }
+}
+
+impl From<std::array::TryFromSliceError> for Error {
+    fn from(error: std::array::TryFromSliceError) -> Self {
+        Self::Protocol(error.to_string())
+    }
+}
+
+impl From<std::str::Utf8Error> for Error {
+    fn from(error: std::str::Utf8Error) -> Self {
+        Self::Protocol(error.to_string())
+    }
+}
+
+impl From<std::string::FromUtf8Error> for Error {
+    fn from(error: std::string::FromUtf8Error) -> Self {
+        Self::Protocol(error.to_string())
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn transient_errors() {
+        assert!(Error::Timeout.is_transient());
+        assert!(Error::NoResponse.is_transient());
+        assert!(Error::PacketLost(String::new()).is_transient());
+        assert!(!Error::Dfu(String::new()).is_transient());
+        assert!(!Error::Archive(String::new()).is_transient());
+        assert!(!Error::Cancelled.is_transient());
+    }
+
+    #[test]
+    fn transient_bluetooth_errors() {
+        let bluetooth = |kind, message: &str| Error::Bluetooth(bluer::Error { kind, message: message.to_string() });
+        assert!(bluetooth(bluer::ErrorKind::NotConnected, "").is_transient());
+        assert!(bluetooth(bluer::ErrorKind::ConnectionAttemptFailed, "").is_transient());
+        assert!(bluetooth(bluer::ErrorKind::Failed, "Not connected").is_transient());
+        assert!(!bluetooth(bluer::ErrorKind::Failed, "Operation failed").is_transient());
+        assert!(!bluetooth(bluer::ErrorKind::NotAuthorized, "").is_transient());
+        assert!(!bluetooth(bluer::ErrorKind::NotFound, "").is_transient());
+    }
+}
@@ -0,0 +1,82 @@
+use crate::Result;
+use futures::{stream, Stream, StreamExt};
+use zbus::{dbus_proxy, fdo::DBusProxy, names::BusName, Connection};
+
+/// Well-known session bus name owned by `watchmated`
+pub const BUS_NAME: &str = "io.gitlab.azymohliad.WatchMate1";
+/// Object path of the [`WatchMateProxy`] interface
+pub const OBJECT_PATH: &str = "/io/gitlab/azymohliad/WatchMate1";
+
+
+/// Check whether `watchmated` is running on the session bus.
+/// If so, front-ends should leave background sessions to it.
+pub async fn is_running(connection: &Connection) -> Result<bool> {
+    let proxy = DBusProxy::new(connection).await?;
+    let name = BusName::try_from(BUS_NAME).map_err(zbus::Error::from)?;
+    Ok(proxy.name_has_owner(name).await?)
+}
+
+/// Whether `watchmated` is running, followed by every change of that as it
+/// starts and stops, so front-ends can hand background sessions over to it
+/// and take them back.
+pub async fn watch_running(connection: &Connection) -> Result<impl Stream<Item = bool>> {
+    let proxy = DBusProxy::new(connection).await?;
+    // Subscribe before checking, so that no change is missed in between
+    let changes = proxy.receive_name_owner_changed_with_args(&[(0, BUS_NAME)]).await?
+        .filter_map(|msg| async move {
+            msg.args().ok().map(|args| args.new_owner.is_some())
+        });
+    let running = is_running(connection).await?;
+    Ok(stream::once(async move { running }).chain(changes))
+}
+
+
+/// Watch API published by `watchmated` on the session bus.
+///
+/// Properties are only meaningful while `Connected` is true. Paths
+/// passed to methods refer to the file system of the daemon.
+#[dbus_proxy(
+    interface = "io.gitlab.azymohliad.WatchMate1",
+    default_service = "io.gitlab.azymohliad.WatchMate1",
+    default_path = "/io/gitlab/azymohliad/WatchMate1"
+)]
+trait WatchMate {
+    /// Show notification on the watch
+    fn send_notification(&self, title: &str, body: &str) -> zbus::Result<()>;
+
+    /// Show incoming call on the watch
+    fn send_call(&self, caller: &str) -> zbus::Result<()>;
+
+    /// Upload local file to the watch file system, creating parent directories
+    fn upload_file(&self, source: &str, destination: &str) -> zbus::Result<()>;
+
+    /// Validate local DFU archive and list compatibility warnings
+    /// against the installed firmware
+    fn check_firmware(&self, path: &str) -> zbus::Result<Vec<String>>;
+
+    /// Flash firmware from local DFU archive. Fails without touching
+    /// the watch if the archive is broken or the update is unsafe.
+    fn flash_firmware(&self, path: &str) -> zbus::Result<()>;
+
+    /// Emitted while uploading files or flashing firmware
+    #[dbus_proxy(signal)]
+    fn progress(&self, message: &str, current: u32, total: u32) -> zbus::Result<()>;
+
+    #[dbus_proxy(property)]
+    fn connected(&self) -> zbus::Result<bool>;
+
+    #[dbus_proxy(property)]
+    fn address(&self) -> zbus::Result<String>;
+
+    #[dbus_proxy(property)]
+    fn battery_level(&self) -> zbus::Result<u8>;
+
+    #[dbus_proxy(property)]
+    fn heart_rate(&self) -> zbus::Result<u16>;
+
+    #[dbus_proxy(property)]
+    fn step_count(&self) -> zbus::Result<u32>;
+
+    #[dbus_proxy(property)]
+    fn firmware_version(&self) -> zbus::Result<String>;
+}
@@ -1,7 +1,7 @@
 use super::super::bt;
 use std::str::FromStr;
-use futures::{pin_mut, stream, Stream, StreamExt};
-use anyhow::Result;
+use futures::{future, pin_mut, stream, Stream, StreamExt};
+use crate::{Error, Result};
 use zbus::{fdo::DBusProxy, Connection, names::OwnedBusName};
 use mpris2_zbus::{player::{Player, PlaybackStatus, LoopStatus}, metadata::Metadata};
 
@@ -160,13 +160,15 @@ pub async fn run_control_session(media_player: &MediaPlayer, infinitime: &bt::In
                 }
             }
             Some(property) = playback_status_stream.next() => {
-                let status = PlaybackStatus::from_str(&property.get().await?)?;
+                let status = PlaybackStatus::from_str(&property.get().await?)
+                    .map_err(|_| Error::Protocol(String::from("Invalid playback status")))?;
                 log::debug!("Playback status: {:?}", status);
                 let is_playing = status == PlaybackStatus::Playing;
                 infinitime.write_mp_playback_status(is_playing).await?;
             }
             Some(property) = loop_status_stream.next() => {
-                let status = LoopStatus::from_str(&property.get().await?)?;
+                let status = LoopStatus::from_str(&property.get().await?)
+                    .map_err(|_| Error::Protocol(String::from("Invalid loop status")))?;
                 log::debug!("Loop status: {:?}", status);
                 let repeat = status == LoopStatus::Track;
                 infinitime.write_mp_repeat(repeat).await?;
@@ -212,3 +214,44 @@ pub async fn run_control_session(media_player: &MediaPlayer, infinitime: &bt::In
     }
     Ok(())
 }
+
+/// Control the most recently started media player, switching to
+/// the previous one when it exits. Runs until the players list stream ends.
+pub async fn run_auto_control_session(connection: &Connection, infinitime: &bt::InfiniTime) -> Result<()> {
+    let players_stream = get_players_update_stream(connection).await?;
+    pin_mut!(players_stream);
+    let mut players: Vec<OwnedBusName> = Vec::new();
+
+    loop {
+        let current = players.last().cloned();
+        let session = async {
+            match current {
+                Some(name) => {
+                    let media_player = MediaPlayer::new(connection, name).await?;
+                    run_control_session(&media_player, infinitime).await
+                }
+                None => future::pending().await,
+            }
+        };
+
+        tokio::select! {
+            event = players_stream.next() => match event {
+                Some(PlayersListEvent::PlayerAdded(name)) => {
+                    players.push(name);
+                }
+                Some(PlayersListEvent::PlayerRemoved(name)) => {
+                    players.retain(|p| p != &name);
+                }
+                None => break,
+            },
+            result = session => {
+                if let Err(error) = result {
+                    log::warn!("Media player control session error: {error}");
+                }
+                // Don't retry the same player over and over
+                players.pop();
+            }
+        }
+    }
+    Ok(())
+}
@@ -1,7 +1,7 @@
 use std::collections::HashMap;
 use zbus::zvariant::{Type, Value};
 use serde::Deserialize;
-use anyhow::Result;
+use crate::Result;
 use futures::TryStreamExt;
 
 use crate::bt;
@@ -19,6 +19,43 @@ struct DesktopNotification<'s> {
     expire_timeout: i32,
 }
 
+#[zbus::dbus_proxy(
+    interface = "org.freedesktop.Notifications",
+    default_service = "org.freedesktop.Notifications",
+    default_path = "/org/freedesktop/Notifications"
+)]
+trait Notifications {
+    #[allow(clippy::too_many_arguments)]
+    fn notify(
+        &self,
+        app_name: &str,
+        replaces_id: u32,
+        app_icon: &str,
+        summary: &str,
+        body: &str,
+        actions: &[&str],
+        hints: HashMap<&str, Value<'_>>,
+        expire_timeout: i32,
+    ) -> zbus::Result<u32>;
+}
+
+/// Show critical urgency desktop notification. Returns its ID, which can be passed
+/// as `replaces_id` to update the notification instead of showing a new one.
+pub async fn show_alert(
+    connection: &zbus::Connection,
+    icon: &str,
+    summary: &str,
+    body: &str,
+    replaces_id: u32,
+) -> Result<u32> {
+    let proxy = NotificationsProxy::new(connection).await?;
+    let hints = HashMap::from([("urgency", Value::U8(2))]);
+    let id = proxy.notify(APP_NAME, replaces_id, icon, summary, body, &[], hints, -1).await?;
+    Ok(id)
+}
+
+const APP_NAME: &str = "WatchMate";
+
 pub async fn run_notification_session(infinitime: &bt::InfiniTime) -> Result<()> {
     // Monitor requires a separate connection
     let connection = zbus::Connection::session().await?;
@@ -44,6 +81,11 @@ pub async fn run_notification_session(infinitime: &bt::InfiniTime) -> Result<()>
                     continue;
                 }
 
+                // Don't echo our own alerts back to the watch
+                if notification.app_name == APP_NAME {
+                    continue;
+                }
+
                 if infinitime.is_upgrading_firmware() {
                     continue;
                 }
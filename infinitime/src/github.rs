@@ -1,10 +1,23 @@
-use std::{env, path::{Path, PathBuf}};
+use std::{env, path::{Path, PathBuf}, time::{Duration, SystemTime}};
 use tokio::{fs::File, io::AsyncWriteExt};
-use anyhow::{anyhow, Result};
-use serde::Deserialize;
-use reqwest::IntoUrl;
+use crate::{bt::{self, ProgressTx, ProgressTxWrapper}, Error, Result};
+use serde::{Deserialize, Serialize};
+use reqwest::{header::{HeaderMap, ETAG, IF_NONE_MATCH, RANGE, RETRY_AFTER}, IntoUrl, Response, StatusCode, Url};
 
-#[derive(Deserialize, Debug, PartialEq)]
+mod cache;
+mod ci;
+mod source;
+pub use cache::{CachedAsset, ReleaseCache, ReleaseList, DEFAULT_MAX_CACHE_SIZE};
+pub use ci::{CiArtifacts, CiBuild};
+pub use source::{AssetNames, ReleaseSource, OFFICIAL_REPO};
+
+/// Room for archive headers, manifest and init packet
+/// on top of the firmware image or resource files
+const ARCHIVE_OVERHEAD: usize = 64 * 1024;
+/// How many times an interrupted download is resumed
+const MAX_RESUME_ATTEMPTS: u32 = 3;
+
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
 pub struct ReleaseInfo {
     pub name: String,
     #[serde(rename = "tag_name")]
@@ -14,7 +27,7 @@ pub struct ReleaseInfo {
     pub assets: Vec<Asset>,
 }
 
-#[derive(Clone, Deserialize, Debug, PartialEq)]
+#[derive(Clone, Serialize, Deserialize, Debug, PartialEq)]
 pub struct Asset {
     pub name: String,
     pub url: String,
@@ -24,59 +37,246 @@ pub struct Asset {
     pub size: u32,
 }
 
-impl ReleaseInfo {
-    pub fn get_dfu_asset(&self) -> Option<&Asset> {
-        self.assets.iter().find(|a| {
-            a.name.starts_with("pinetime-mcuboot-app-dfu") &&
-            a.name.ends_with(".zip")
-        })
+/// What the asset contains, which limits its size
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AssetKind {
+    Firmware,
+    Resources,
+}
+
+impl AssetKind {
+    /// Largest download of this kind the watch could accept
+    pub fn max_size(&self) -> usize {
+        match self {
+            Self::Firmware => bt::MAX_FIRMWARE_SIZE + ARCHIVE_OVERHEAD,
+            Self::Resources => bt::MAX_RESOURCE_SIZE + ARCHIVE_OVERHEAD,
+        }
     }
+}
+
+/// GitHub API rate limit, which is reported once it's exceeded
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RateLimit {
+    /// Requests allowed per hour, 60 for anonymous requests
+    pub limit: u32,
+    /// When requests are allowed again, if known
+    pub reset: Option<SystemTime>,
+}
+
+impl RateLimit {
+    /// Rate limit from the headers of a response rejected because of it
+    fn exceeded(status: StatusCode, headers: &HeaderMap) -> Option<Self> {
+        if status != StatusCode::FORBIDDEN && status != StatusCode::TOO_MANY_REQUESTS {
+            return None;
+        }
+        let header = |name: &str| -> Option<u64> {
+            headers.get(name)?.to_str().ok()?.parse().ok()
+        };
+        // Secondary limits only tell how long to wait
+        let retry_after = header(RETRY_AFTER.as_str())
+            .map(|secs| SystemTime::now() + Duration::from_secs(secs));
+        if header("x-ratelimit-remaining") != Some(0) && retry_after.is_none() {
+            return None;
+        }
+        let reset = header("x-ratelimit-reset")
+            .map(|secs| SystemTime::UNIX_EPOCH + Duration::from_secs(secs))
+            .or(retry_after);
+        let limit = header("x-ratelimit-limit").unwrap_or(60) as u32;
+        Some(Self { limit, reset })
+    }
+}
 
-    pub fn get_resources_asset(&self) -> Option<&Asset> {
-        self.assets.iter().find(|a| {
-            a.name.starts_with("infinitime-resources") &&
-            a.name.ends_with(".zip")
+/// Response of a conditional request
+#[derive(Debug)]
+enum Fetched<T> {
+    /// Content has changed since the given ETag, or no ETag was given
+    Modified { content: T, etag: Option<String> },
+    NotModified,
+}
+
+impl<T> Fetched<T> {
+    fn try_map<U>(self, f: impl FnOnce(T) -> Result<U>) -> Result<Fetched<U>> {
+        Ok(match self {
+            Self::Modified { content, etag } => Fetched::Modified { content: f(content)?, etag },
+            Self::NotModified => Fetched::NotModified,
         })
     }
 }
 
+impl ReleaseInfo {
+    pub fn get_dfu_asset(&self, names: &AssetNames) -> Option<&Asset> {
+        self.assets.iter().find(|a| names.is_dfu(&a.name))
+    }
+
+    pub fn get_resources_asset(&self, names: &AssetNames) -> Option<&Asset> {
+        self.assets.iter().find(|a| names.is_resources(&a.name))
+    }
+}
+
+/// Official InfiniTime releases
 pub async fn list_releases() -> Result<Vec<ReleaseInfo>> {
+    ReleaseSource::default().list_releases().await
+}
+
+/// Whole content at the URL, without size limit
+pub async fn download_content(url: impl IntoUrl) -> Result<Vec<u8>>
+{
+    download_streamed(url.into_url()?, None, 0, usize::MAX, None).await
+}
+
+/// Content of the asset, which is rejected if it's larger than the watch accepts
+async fn download_asset(
+    url: Url,
+    token: Option<&str>,
+    asset: &Asset,
+    kind: AssetKind,
+    progress: Option<ProgressTx>,
+) -> Result<Vec<u8>> {
+    download_streamed(url, token, asset.size, kind.max_size(), progress).await
+}
+
+/// Download with the access token, if any, reporting progress when the size is known.
+/// Interrupted downloads are resumed with range requests. Content above `max_size`
+/// is rejected, before it's downloaded if the server tells its size up front.
+/// Expected `size` of 0 means it's unknown.
+async fn download_streamed(
+    url: Url,
+    token: Option<&str>,
+    size: u32,
+    max_size: usize,
+    progress: Option<ProgressTx>,
+) -> Result<Vec<u8>> {
+    let progress = ProgressTxWrapper(progress);
+    let mut total = (size != 0).then_some(size as u64);
+    if let Some(total) = total {
+        check_size(total, max_size)?;
+    }
+
     let client = reqwest::Client::new();
-    let response = client
-        .get("https://api.github.com/repos/InfiniTimeOrg/InfiniTime/releases")
-        .header("Accept", "application/vnd.github+json")
-        .header("User-Agent", "WatchMate")
-        .send().await?;
+    let mut content = Vec::new();
+    let mut attempts = 0;
+    loop {
+        let mut request = client
+            .get(url.clone())
+            .header("Accept", "application/octet-stream")
+            .header("User-Agent", "WatchMate");
+        if let Some(token) = token {
+            request = request.bearer_auth(token);
+        }
+        if !content.is_empty() {
+            request = request.header(RANGE, format!("bytes={}-", content.len()));
+        }
+        let mut response = request.send().await?;
 
-    let status = response.status();
-    if status.is_success() {
-        let releases = response.json().await?;
-        // dbg!(&releases);
-        Ok(releases)
+        let status = response.status();
+        if status == StatusCode::PARTIAL_CONTENT {
+            log::info!("Resuming download of {} from {} bytes", url, content.len());
+        } else if status.is_success() {
+            // Server ignored the range, so it's sent from the start
+            content.clear();
+        } else {
+            return Err(status_error(response).await);
+        }
+        if let Some(length) = response.content_length() {
+            let length = content.len() as u64 + length;
+            check_size(length, max_size)?;
+            if let Some(expected) = total.filter(|total| *total != length) {
+                return Err(Error::DownloadSize(format!(
+                    "server reports {} bytes, expected {}", length, expected
+                )));
+            }
+            total = Some(length);
+        }
+
+        let interrupted = loop {
+            match response.chunk().await {
+                Ok(Some(chunk)) => {
+                    content.extend_from_slice(&chunk);
+                    check_size(content.len() as u64, max_size)?;
+                    if let Some(total) = total {
+                        progress.report_num(content.len() as u32, total as u32).await;
+                    }
+                }
+                Ok(None) => break None,
+                Err(error) => break Some(error),
+            }
+        };
+        match interrupted {
+            None => break,
+            Some(error) if attempts < MAX_RESUME_ATTEMPTS && !content.is_empty() => {
+                log::warn!("Download of {} interrupted: {}", url, error);
+                attempts += 1;
+            }
+            Some(error) => return Err(error.into()),
+        }
+    }
+
+    match total {
+        Some(total) if content.len() as u64 != total => Err(Error::DownloadSize(format!(
+            "received {} bytes, expected {}", content.len(), total
+        ))),
+        _ => Ok(content),
+    }
+}
+
+fn check_size(size: u64, max_size: usize) -> Result<()> {
+    if size > max_size as u64 {
+        Err(Error::DownloadSize(format!("{} bytes exceeds the limit of {} bytes", size, max_size)))
     } else {
-        let text = response.text().await?;
-        log::error!("Request failed: {}\n{}", status, text);
-        Err(anyhow!("Request failed: {}", status))
+        Ok(())
     }
 }
 
-pub async fn download_content(url: impl IntoUrl) -> Result<Vec<u8>>
-{
+async fn get_json<T: serde::de::DeserializeOwned>(url: &str, accept: &str, token: Option<&str>) -> Result<T> {
+    match get_json_conditional(url, accept, token, None).await? {
+        Fetched::Modified { content, .. } => Ok(content),
+        Fetched::NotModified => Err(Error::HttpStatus(StatusCode::NOT_MODIFIED)),
+    }
+}
+
+/// Get JSON unless it's the same as the one with the given ETag.
+/// Such requests don't count against the GitHub API rate limit.
+async fn get_json_conditional<T: serde::de::DeserializeOwned>(
+    url: &str,
+    accept: &str,
+    token: Option<&str>,
+    etag: Option<&str>,
+) -> Result<Fetched<T>> {
     let client = reqwest::Client::new();
-    let response = client
+    let mut request = client
         .get(url)
-        .header("Accept", "application/octet-stream")
-        .header("User-Agent", "WatchMate")
-        .send().await?;
+        .header("Accept", accept)
+        .header("User-Agent", "WatchMate");
+    if let Some(token) = token {
+        request = request.bearer_auth(token);
+    }
+    if let Some(etag) = etag {
+        request = request.header(IF_NONE_MATCH, etag);
+    }
+    let response = request.send().await?;
 
     let status = response.status();
-    if status.is_success() {
-        let content = response.bytes().await?;
-        Ok(content.to_vec())
+    if status == StatusCode::NOT_MODIFIED {
+        Ok(Fetched::NotModified)
+    } else if status.is_success() {
+        let etag = response.headers().get(ETAG)
+            .and_then(|value| value.to_str().ok())
+            .map(String::from);
+        Ok(Fetched::Modified { content: response.json().await?, etag })
     } else {
-        let text = response.text().await?;
-        log::error!("Request failed: {}\n{}", status, text);
-        Err(anyhow!("Request failed: {}", status))
+        Err(status_error(response).await)
+    }
+}
+
+/// Error for the unsuccessful response, telling exceeded rate limit apart
+async fn status_error(response: Response) -> Error {
+    let status = response.status();
+    let rate_limit = RateLimit::exceeded(status, response.headers());
+    let text = response.text().await.unwrap_or_default();
+    log::error!("Request failed: {}\n{}", status, text);
+    match rate_limit {
+        Some(rate_limit) => Error::RateLimited(rate_limit),
+        None => Error::HttpStatus(status),
     }
 }
 
@@ -95,7 +295,11 @@ pub async fn _download_file(url: impl IntoUrl, filepath: impl AsRef<Path>) -> Re
 pub fn _get_download_dir() -> Result<PathBuf> {
     match env::var("XDG_DOWNLOAD_DIR") {
         Ok(value) => Ok(PathBuf::from(value)),
-        Err(_) => Ok(Path::new(&env::var("HOME")?).join("Downloads")),
+        Err(_) => {
+            let home = env::var("HOME")
+                .map_err(|e| std::io::Error::new(std::io::ErrorKind::NotFound, e))?;
+            Ok(Path::new(&home).join("Downloads"))
+        }
     }
 }
 
@@ -0,0 +1,337 @@
+use super::{Asset, AssetKind, AssetNames, Fetched, RateLimit, ReleaseInfo, ReleaseSource};
+use crate::{bt::ProgressTx, Error, Result};
+use sha2::{Digest, Sha256};
+use std::{
+    env,
+    path::{Path, PathBuf},
+    time::SystemTime,
+};
+use tokio::fs;
+
+/// Downloaded assets are evicted, oldest used first, above this total size
+pub const DEFAULT_MAX_CACHE_SIZE: u64 = 256 * 1024 * 1024;
+
+const RELEASES_FILE: &str = "releases.json";
+const ETAG_FILE: &str = "releases.etag";
+const ASSETS_DIR: &str = "assets";
+const CHECKSUM_EXTENSION: &str = "sha256";
+
+/// Releases list, possibly served from the cache
+#[derive(Debug)]
+pub struct ReleaseList {
+    pub releases: Vec<ReleaseInfo>,
+    /// When the list was fetched, if GitHub was unreachable
+    /// and the cached one is returned instead
+    pub cached_at: Option<SystemTime>,
+    /// Set if the cached list is returned because
+    /// the GitHub API rate limit is exceeded
+    pub rate_limit: Option<RateLimit>,
+}
+
+/// Asset file stored in the cache
+#[derive(Debug, Clone)]
+pub struct CachedAsset {
+    /// Tag of the release the asset belongs to
+    pub tag: String,
+    pub name: String,
+    pub path: PathBuf,
+    pub size: u64,
+    /// When the asset was last downloaded or used
+    pub used_at: SystemTime,
+}
+
+impl CachedAsset {
+    pub fn is_dfu(&self, names: &AssetNames) -> bool {
+        names.is_dfu(&self.name)
+    }
+
+    pub fn is_resources(&self, names: &AssetNames) -> bool {
+        names.is_resources(&self.name)
+    }
+}
+
+/// Local copy of release metadata and downloaded assets of one source, so
+/// that firmware can be flashed without reaching it. Assets are stored by
+/// release (`assets/<tag>/<name>`), as releases may have same-named assets.
+/// Every asset is stored next to its SHA-256 checksum (in `sha256sum`
+/// format), corrupted files are discarded and downloaded again.
+#[derive(Debug, Clone)]
+pub struct ReleaseCache {
+    dir: PathBuf,
+    source: ReleaseSource,
+    max_size: u64,
+    token: Option<String>,
+}
+
+impl ReleaseCache {
+    pub fn new(dir: impl Into<PathBuf>, source: ReleaseSource) -> Self {
+        Self { dir: dir.into(), source, max_size: DEFAULT_MAX_CACHE_SIZE, token: None }
+    }
+
+    /// Cache of the source in the user cache directory
+    /// (`$XDG_CACHE_HOME/watchmate/releases/<source id>`)
+    pub fn user_default(source: ReleaseSource) -> Result<Self> {
+        let dir = user_cache_dir()?.join("watchmate").join("releases").join(source.id());
+        Ok(Self::new(dir, source))
+    }
+
+    pub fn with_max_size(mut self, max_size: u64) -> Self {
+        self.max_size = max_size;
+        self
+    }
+
+    /// GitHub personal access token, which raises the API rate limit
+    pub fn with_token(mut self, token: Option<String>) -> Self {
+        self.token = token.filter(|t| !t.is_empty());
+        self
+    }
+
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    pub fn source(&self) -> &ReleaseSource {
+        &self.source
+    }
+
+    /// Fetch releases from the source and remember them. If the source
+    /// can't be reached, the last fetched list is returned instead.
+    /// The list is only fetched again if it has changed (by its ETag).
+    pub async fn list_releases(&self) -> Result<ReleaseList> {
+        let cached = self.load_releases().await.unwrap_or_else(|error| {
+            log::warn!("Failed to load cached releases: {}", error);
+            None
+        });
+        let etag = match cached {
+            Some(_) => fs::read_to_string(self.dir.join(ETAG_FILE)).await.ok(),
+            None => None,
+        };
+        match self.source.fetch_releases(etag.as_deref(), self.token.as_deref()).await {
+            Ok(Fetched::Modified { content: releases, etag }) => {
+                if let Err(error) = self.save_releases(&releases, etag.as_deref()).await {
+                    log::warn!("Failed to cache releases list: {}", error);
+                }
+                Ok(ReleaseList { releases, cached_at: None, rate_limit: None })
+            }
+            Ok(Fetched::NotModified) => {
+                log::info!("Releases haven't changed, using the cached list");
+                match cached {
+                    Some(list) => {
+                        // Rewritten to remember when the list was last up to date
+                        if let Err(error) = self.save_releases(&list.releases, etag.as_deref()).await {
+                            log::warn!("Failed to cache releases list: {}", error);
+                        }
+                        Ok(ReleaseList { cached_at: None, ..list })
+                    }
+                    None => Err(Error::HttpStatus(reqwest::StatusCode::NOT_MODIFIED)),
+                }
+            }
+            Err(error) => {
+                log::warn!("Failed to fetch releases, trying the cache: {}", error);
+                let rate_limit = match &error {
+                    Error::RateLimited(rate_limit) => Some(*rate_limit),
+                    _ => None,
+                };
+                match cached {
+                    Some(list) => Ok(ReleaseList { rate_limit, ..list }),
+                    None => Err(error),
+                }
+            }
+        }
+    }
+
+    /// Content of the asset of the release tagged `tag` from the cache,
+    /// or downloaded and stored in it. Assets of a local source are read directly.
+    pub async fn download(
+        &self,
+        tag: &str,
+        asset: &Asset,
+        kind: AssetKind,
+        progress: Option<ProgressTx>,
+    ) -> Result<Vec<u8>> {
+        if self.source.is_local() {
+            return self.source.download(asset, kind, self.token.as_deref(), progress).await;
+        }
+        if let Some(content) = self.get(tag, asset).await? {
+            log::info!("Using cached asset: {}/{}", tag, asset.name);
+            return Ok(content);
+        }
+        let content = self.source.download(asset, kind, self.token.as_deref(), progress).await?;
+        if let Err(error) = self.put(tag, &asset.name, &content).await {
+            log::warn!("Failed to cache asset {}/{}: {}", tag, asset.name, error);
+        }
+        Ok(content)
+    }
+
+    /// Cached content of the asset, if it's there and intact
+    pub async fn get(&self, tag: &str, asset: &Asset) -> Result<Option<Vec<u8>>> {
+        let path = self.asset_path(tag, &asset.name)?;
+        let content = match fs::read(&path).await {
+            Ok(content) => content,
+            Err(error) if error.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(error) => return Err(error.into()),
+        };
+        let expected = fs::read_to_string(checksum_path(&path)).await.unwrap_or_default();
+        let size_matches = asset.size == 0 || content.len() == asset.size as usize;
+        if !size_matches || expected.split_whitespace().next() != Some(checksum(&content).as_str()) {
+            log::warn!("Cached asset {}/{} is corrupted, removing it", tag, asset.name);
+            self.remove(tag, &asset.name).await?;
+            return Ok(None);
+        }
+        // Keep recently used assets from eviction. The checksum file is
+        // rewritten, as it's small and its time tells when the asset was used.
+        if let Err(error) = fs::write(checksum_path(&path), &expected).await {
+            log::debug!("Failed to update access time of {:?}: {}", path, error);
+        }
+        Ok(Some(content))
+    }
+
+    /// Store asset content, then evict old assets if the cache is too large
+    pub async fn put(&self, tag: &str, name: &str, content: &[u8]) -> Result<()> {
+        let path = self.asset_path(tag, name)?;
+        fs::create_dir_all(self.dir.join(ASSETS_DIR).join(tag)).await?;
+        fs::write(&path, content).await?;
+        fs::write(checksum_path(&path), format!("{}  {}\n", checksum(content), name)).await?;
+        self.evict(&path).await
+    }
+
+    pub async fn remove(&self, tag: &str, name: &str) -> Result<()> {
+        let path = self.asset_path(tag, name)?;
+        for path in [checksum_path(&path), path] {
+            match fs::remove_file(&path).await {
+                Err(error) if error.kind() != std::io::ErrorKind::NotFound => return Err(error.into()),
+                _ => {}
+            }
+        }
+        // Only succeeds once the release has no assets left
+        let _ = fs::remove_dir(self.dir.join(ASSETS_DIR).join(tag)).await;
+        Ok(())
+    }
+
+    /// Cached assets, most recently used first
+    pub async fn assets(&self) -> Result<Vec<CachedAsset>> {
+        let mut assets = Vec::new();
+        let mut releases = match fs::read_dir(self.dir.join(ASSETS_DIR)).await {
+            Ok(entries) => entries,
+            Err(error) if error.kind() == std::io::ErrorKind::NotFound => return Ok(assets),
+            Err(error) => return Err(error.into()),
+        };
+        while let Some(release) = releases.next_entry().await? {
+            let Some(tag) = release.file_name().to_str().map(str::to_string) else {
+                continue;
+            };
+            if !release.file_type().await?.is_dir() {
+                continue;
+            }
+            let mut entries = fs::read_dir(release.path()).await?;
+            while let Some(entry) = entries.next_entry().await? {
+                let path = entry.path();
+                if path.extension().is_some_and(|ext| ext == CHECKSUM_EXTENSION) {
+                    continue;
+                }
+                let metadata = entry.metadata().await?;
+                if let (true, Some(name)) = (metadata.is_file(), path.file_name().and_then(|n| n.to_str())) {
+                    let used_at = match fs::metadata(checksum_path(&path)).await {
+                        Ok(checksum) => checksum.modified()?,
+                        Err(_) => metadata.modified()?,
+                    };
+                    assets.push(CachedAsset {
+                        tag: tag.clone(),
+                        name: name.to_string(),
+                        size: metadata.len(),
+                        used_at,
+                        path,
+                    });
+                }
+            }
+        }
+        assets.sort_by_key(|a| std::cmp::Reverse(a.used_at));
+        Ok(assets)
+    }
+
+    /// Remove all cached assets and releases list
+    pub async fn clear(&self) -> Result<()> {
+        match fs::remove_dir_all(&self.dir).await {
+            Err(error) if error.kind() != std::io::ErrorKind::NotFound => Err(error.into()),
+            _ => Ok(()),
+        }
+    }
+
+    /// Remove least recently used assets until the cache fits `max_size`.
+    /// The asset stored at `keep` is never removed.
+    async fn evict(&self, keep: &Path) -> Result<()> {
+        let assets = self.assets().await?;
+        let mut total: u64 = assets.iter().map(|a| a.size).sum();
+        for asset in assets.iter().rev() {
+            if total <= self.max_size {
+                break;
+            }
+            if asset.path != keep {
+                log::info!("Evicting cached asset: {}/{}", asset.tag, asset.name);
+                self.remove(&asset.tag, &asset.name).await?;
+                total -= asset.size;
+            }
+        }
+        Ok(())
+    }
+
+    async fn save_releases(&self, releases: &[ReleaseInfo], etag: Option<&str>) -> Result<()> {
+        let json = serde_json::to_vec(releases)
+            .map_err(|e| Error::Protocol(format!("Failed to serialize releases: {}", e)))?;
+        fs::create_dir_all(&self.dir).await?;
+        fs::write(self.dir.join(RELEASES_FILE), json).await?;
+        match etag {
+            Some(etag) => fs::write(self.dir.join(ETAG_FILE), etag).await?,
+            None => match fs::remove_file(self.dir.join(ETAG_FILE)).await {
+                Err(error) if error.kind() != std::io::ErrorKind::NotFound => return Err(error.into()),
+                _ => {}
+            },
+        }
+        Ok(())
+    }
+
+    async fn load_releases(&self) -> Result<Option<ReleaseList>> {
+        let path = self.dir.join(RELEASES_FILE);
+        let json = match fs::read(&path).await {
+            Ok(json) => json,
+            Err(error) if error.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(error) => return Err(error.into()),
+        };
+        let releases = serde_json::from_slice(&json)
+            .map_err(|e| Error::Protocol(format!("Invalid cached releases: {}", e)))?;
+        let cached_at = fs::metadata(&path).await?.modified()?;
+        Ok(Some(ReleaseList { releases, cached_at: Some(cached_at), rate_limit: None }))
+    }
+
+    fn asset_path(&self, tag: &str, name: &str) -> Result<PathBuf> {
+        // Tags and asset names come from the network, don't let them escape the cache
+        for part in [tag, name] {
+            if part.is_empty() || part.contains(['/', '\\']) || part.starts_with('.') {
+                return Err(Error::Archive(format!("Invalid asset name: {}/{}", tag, name)));
+            }
+        }
+        Ok(self.dir.join(ASSETS_DIR).join(tag).join(name))
+    }
+}
+
+fn checksum(content: &[u8]) -> String {
+    Sha256::digest(content).iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+fn checksum_path(path: &Path) -> PathBuf {
+    let mut path = path.as_os_str().to_owned();
+    path.push(".");
+    path.push(CHECKSUM_EXTENSION);
+    PathBuf::from(path)
+}
+
+fn user_cache_dir() -> Result<PathBuf> {
+    match env::var("XDG_CACHE_HOME") {
+        Ok(value) if !value.is_empty() => Ok(PathBuf::from(value)),
+        _ => {
+            let home = env::var("HOME")
+                .map_err(|e| std::io::Error::new(std::io::ErrorKind::NotFound, e))?;
+            Ok(Path::new(&home).join(".cache"))
+        }
+    }
+}
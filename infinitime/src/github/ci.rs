@@ -0,0 +1,267 @@
+use super::{download_asset, get_json, source::parse_url, Asset, AssetKind, OFFICIAL_REPO};
+use crate::{bt::ProgressTx, Error, Result};
+use serde::Deserialize;
+use std::{
+    collections::HashMap,
+    io::{Cursor, Read},
+};
+
+const API_ACCEPT: &str = "application/vnd.github+json";
+
+
+/// Unreleased firmware built by CI for a pull request or a branch.
+/// It's not tested as much as releases, so it should be treated as unstable.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CiBuild {
+    /// Pull request number, none for branch builds
+    pub pull_request: Option<u32>,
+    /// Pull request title or branch name
+    pub title: String,
+    pub branch: String,
+    pub commit: String,
+    /// Pull request or workflow run page
+    pub url: String,
+    /// DFU package artifact
+    pub firmware: Option<Asset>,
+    /// Resources artifact
+    pub resources: Option<Asset>,
+}
+
+impl CiBuild {
+    /// Short description, e.g. "PR #1234: Title (0123abc)" or "main (0123abc)"
+    pub fn label(&self) -> String {
+        let commit = &self.commit[..self.commit.len().min(7)];
+        match self.pull_request {
+            Some(number) => format!("PR #{}: {} ({})", number, self.title, commit),
+            None => format!("{} ({})", self.title, commit),
+        }
+    }
+
+    /// Take firmware and resources from the artifacts of the build run
+    fn with_artifacts(mut self, artifacts: &[&Artifact]) -> Self {
+        for artifact in artifacts {
+            let name = artifact.name.to_lowercase();
+            let (slot, kind) = if name.contains("dfu") {
+                (&mut self.firmware, AssetKind::Firmware)
+            } else if name.contains("resources") {
+                (&mut self.resources, AssetKind::Resources)
+            } else {
+                continue;
+            };
+            if artifact.size_in_bytes > kind.max_size() as u64 {
+                log::warn!("Skipping artifact {}: {} bytes is too large", artifact.name, artifact.size_in_bytes);
+                continue;
+            }
+            if slot.is_none() {
+                *slot = Some(Asset {
+                    name: artifact.name.clone(),
+                    url: artifact.archive_download_url.clone(),
+                    direct_url: artifact.archive_download_url.clone(),
+                    content_type: String::from("application/zip"),
+                    // Artifact size is not necessarily the size of the archive
+                    // it's served in, so the length is left to the response
+                    size: 0,
+                });
+            }
+        }
+        self
+    }
+}
+
+
+/// CI artifacts of a GitHub repository. Listing them works anonymously,
+/// but GitHub only lets authenticated users download artifacts.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CiArtifacts {
+    repo: String,
+    branch: String,
+    token: Option<String>,
+}
+
+impl Default for CiArtifacts {
+    fn default() -> Self {
+        Self::new(OFFICIAL_REPO, None)
+    }
+}
+
+impl CiArtifacts {
+    /// Artifacts of the GitHub repository ("owner/name"),
+    /// accessed with the personal access token, if any
+    pub fn new(repo: &str, token: Option<String>) -> Self {
+        Self {
+            repo: repo.to_string(),
+            branch: String::from("main"),
+            token: token.filter(|t| !t.is_empty()),
+        }
+    }
+
+    /// Branch whose latest build is listed along with pull requests
+    pub fn with_branch(mut self, branch: &str) -> Self {
+        self.branch = branch.to_string();
+        self
+    }
+
+    pub fn has_token(&self) -> bool {
+        self.token.is_some()
+    }
+
+    /// Latest build of the branch, followed by builds of open pull requests,
+    /// newest first. Only builds with a firmware artifact are listed.
+    pub async fn list_builds(&self) -> Result<Vec<CiBuild>> {
+        let api = format!("https://api.github.com/repos/{}", self.repo);
+        let token = self.token.as_deref();
+        let pulls: Vec<PullRequest> = get_json(&format!("{}/pulls?state=open&per_page=50", api), API_ACCEPT, token).await?;
+        let artifacts: ArtifactList = get_json(&format!("{}/actions/artifacts?per_page=100", api), API_ACCEPT, token).await?;
+
+        // Artifacts are listed newest first, so the first run of a commit is its latest one
+        let mut runs: HashMap<&str, Vec<&Artifact>> = HashMap::new();
+        let mut branch_commit = None;
+        for artifact in artifacts.artifacts.iter().filter(|a| !a.expired) {
+            let Some(run) = &artifact.workflow_run else { continue };
+            if branch_commit.is_none() && run.head_branch.as_deref() == Some(self.branch.as_str()) {
+                branch_commit = Some(run.head_sha.as_str());
+            }
+            let run_artifacts = runs.entry(run.head_sha.as_str()).or_default();
+            let same_run = match run_artifacts.first().and_then(|a| a.workflow_run.as_ref()) {
+                Some(latest) => latest.id == run.id,
+                None => true,
+            };
+            if same_run {
+                run_artifacts.push(artifact);
+            }
+        }
+
+        let mut builds = Vec::new();
+        if let Some(commit) = branch_commit {
+            let run_artifacts = &runs[commit];
+            let run_id = run_artifacts[0].workflow_run.as_ref().map_or(0, |r| r.id);
+            builds.push(CiBuild {
+                pull_request: None,
+                title: self.branch.clone(),
+                branch: self.branch.clone(),
+                commit: commit.to_string(),
+                url: format!("https://github.com/{}/actions/runs/{}", self.repo, run_id),
+                firmware: None,
+                resources: None,
+            }.with_artifacts(run_artifacts));
+        }
+        for pull in pulls {
+            if let Some(run_artifacts) = runs.get(pull.head.sha.as_str()) {
+                builds.push(CiBuild {
+                    pull_request: Some(pull.number),
+                    title: pull.title,
+                    branch: pull.head.name,
+                    commit: pull.head.sha,
+                    url: pull.html_url,
+                    firmware: None,
+                    resources: None,
+                }.with_artifacts(run_artifacts));
+            }
+        }
+        builds.retain(|b| b.firmware.is_some());
+        Ok(builds)
+    }
+
+    /// Content of the artifact. GitHub wraps artifacts into a zip archive,
+    /// so a DFU package or resources archive inside it is unpacked.
+    pub async fn download(&self, asset: &Asset, kind: AssetKind, progress: Option<ProgressTx>) -> Result<Vec<u8>> {
+        let Some(token) = self.token.as_deref() else {
+            return Err(Error::InvalidSource(String::from("Downloading CI artifacts requires a GitHub token")));
+        };
+        let content = download_asset(parse_url(&asset.url)?, Some(token), asset, kind, progress).await?;
+        unpack_artifact(content)
+    }
+}
+
+
+#[derive(Deserialize, Debug)]
+struct PullRequest {
+    number: u32,
+    title: String,
+    html_url: String,
+    head: GitRef,
+}
+
+#[derive(Deserialize, Debug)]
+struct GitRef {
+    #[serde(rename = "ref")]
+    name: String,
+    sha: String,
+}
+
+#[derive(Deserialize, Debug)]
+struct ArtifactList {
+    artifacts: Vec<Artifact>,
+}
+
+#[derive(Deserialize, Debug)]
+struct Artifact {
+    name: String,
+    size_in_bytes: u64,
+    archive_download_url: String,
+    expired: bool,
+    workflow_run: Option<WorkflowRun>,
+}
+
+#[derive(Deserialize, Debug)]
+struct WorkflowRun {
+    id: u64,
+    head_branch: Option<String>,
+    head_sha: String,
+}
+
+
+/// Artifact archive is either the package itself (files uploaded as is),
+/// or contains the package zip (zip uploaded as a file)
+fn unpack_artifact(content: Vec<u8>) -> Result<Vec<u8>> {
+    let mut zip = zip::ZipArchive::new(Cursor::new(content))?;
+    if zip.file_names().any(|name| name == "manifest.json" || name == "resources.json") {
+        return Ok(zip.into_inner().into_inner());
+    }
+    let inner = zip.file_names()
+        .find(|name| name.ends_with(".zip"))
+        .map(String::from)
+        .ok_or_else(|| Error::Archive(String::from("No DFU package or resources in the artifact")))?;
+    let mut unpacked = Vec::new();
+    zip.by_name(&inner)?.read_to_end(&mut unpacked)?;
+    Ok(unpacked)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn artifact(name: &str, size_in_bytes: u64) -> Artifact {
+        Artifact {
+            name: name.to_string(),
+            size_in_bytes,
+            archive_download_url: format!("https://api.github.com/artifacts/{}/zip", name),
+            expired: false,
+            workflow_run: None,
+        }
+    }
+
+    #[test]
+    fn build_artifacts() {
+        let firmware = artifact("InfiniTime DFU 1.14.0", 400_000);
+        let resources = artifact("InfiniTime resources 1.14.0", 10 * 1024 * 1024);
+        let other = artifact("InfiniTime MCUBoot 1.14.0", 1000);
+        let build = CiBuild {
+            pull_request: None,
+            title: String::from("main"),
+            branch: String::from("main"),
+            commit: String::from("0123456789abcdef"),
+            url: String::new(),
+            firmware: None,
+            resources: None,
+        }.with_artifacts(&[&firmware, &resources, &other]);
+
+        let firmware = build.firmware.unwrap();
+        assert_eq!(firmware.name, "InfiniTime DFU 1.14.0");
+        // Length of the served archive is only known from the response
+        assert_eq!(firmware.size, 0);
+        // Too large for the watch
+        assert!(build.resources.is_none());
+    }
+}
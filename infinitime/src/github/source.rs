@@ -0,0 +1,479 @@
+use super::{check_size, download_asset, get_json_conditional, Asset, AssetKind, Fetched, ReleaseInfo};
+use crate::{bt::ProgressTx, Error, Result};
+use reqwest::{StatusCode, Url};
+use serde::Deserialize;
+use std::{cmp::Ordering, path::PathBuf};
+use tokio::fs;
+use version_compare::Version;
+
+/// Repository of the official InfiniTime releases
+pub const OFFICIAL_REPO: &str = "InfiniTimeOrg/InfiniTime";
+
+
+/// Where firmware releases are fetched from
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ReleaseSource {
+    /// Releases of a GitHub repository, as "owner/name"
+    GitHub(String),
+    /// Releases of a Gitea (or Forgejo) repository, by its web URL,
+    /// e.g. "https://codeberg.org/owner/name"
+    Gitea(String),
+    /// JSON index of releases at the URL, in the same format as GitHub
+    /// releases but only with the fields used here. Asset URLs may be
+    /// relative to the index:
+    /// `[{"name": "...", "tag_name": "1.14.0", "assets": [{"name": "...", "browser_download_url": "..."}]}]`
+    HttpIndex(String),
+    /// Local directory with a subdirectory of assets per release
+    LocalDir(PathBuf),
+}
+
+impl Default for ReleaseSource {
+    fn default() -> Self {
+        Self::GitHub(OFFICIAL_REPO.to_string())
+    }
+}
+
+impl ReleaseSource {
+    /// Source by its kind ("github", "gitea", "http-index" or "local-dir") and location
+    pub fn new(kind: &str, location: &str) -> Result<Self> {
+        let location = location.trim();
+        if location.is_empty() {
+            return Err(Error::InvalidSource(format!("No location for {} source", kind)));
+        }
+        match kind {
+            "github" => {
+                match location.split_once('/') {
+                    Some((owner, name)) if !owner.is_empty() && !name.is_empty() && !name.contains('/') => {
+                        Ok(Self::GitHub(location.to_string()))
+                    }
+                    _ => Err(Error::InvalidSource(format!("Expected GitHub repository as owner/name: {}", location))),
+                }
+            }
+            "gitea" => {
+                parse_remote_url(location)?;
+                Ok(Self::Gitea(location.trim_end_matches('/').to_string()))
+            }
+            "http-index" => {
+                parse_remote_url(location)?;
+                Ok(Self::HttpIndex(location.to_string()))
+            }
+            "local-dir" => Ok(Self::LocalDir(PathBuf::from(location))),
+            _ => Err(Error::InvalidSource(format!("Unknown source kind: {}", kind))),
+        }
+    }
+
+    /// Identifier which is safe to use as a file name,
+    /// distinct for different sources
+    pub fn id(&self) -> String {
+        let (kind, location) = match self {
+            Self::GitHub(repo) => ("github", repo.as_str()),
+            Self::Gitea(url) => ("gitea", url.as_str()),
+            Self::HttpIndex(url) => ("http-index", url.as_str()),
+            Self::LocalDir(path) => ("local-dir", path.to_str().unwrap_or_default()),
+        };
+        let location = location
+            .trim_start_matches("https://")
+            .trim_start_matches("http://")
+            .chars()
+            .map(|c| if c.is_ascii_alphanumeric() || c == '.' || c == '-' { c } else { '_' })
+            .collect::<String>();
+        format!("{}-{}", kind, location)
+    }
+
+    pub fn is_local(&self) -> bool {
+        matches!(self, Self::LocalDir(_))
+    }
+
+    /// Releases, newest first
+    pub async fn list_releases(&self) -> Result<Vec<ReleaseInfo>> {
+        match self.fetch_releases(None, None).await? {
+            Fetched::Modified { content, .. } => Ok(content),
+            Fetched::NotModified => Err(Error::HttpStatus(StatusCode::NOT_MODIFIED)),
+        }
+    }
+
+    /// Releases, unless they haven't changed since the ETag.
+    /// The token is only sent to GitHub.
+    pub(super) async fn fetch_releases(&self, etag: Option<&str>, token: Option<&str>) -> Result<Fetched<Vec<ReleaseInfo>>> {
+        match self {
+            Self::GitHub(repo) => {
+                let url = format!("https://api.github.com/repos/{}/releases", repo);
+                get_json_conditional(&url, "application/vnd.github+json", token, etag).await
+            }
+            Self::Gitea(repo_url) => {
+                // API lives at the root of the instance: https://host/api/v1/repos/owner/name
+                let mut parts = repo_url.rsplitn(3, '/');
+                let (name, owner, base) = match (parts.next(), parts.next(), parts.next()) {
+                    (Some(name), Some(owner), Some(base)) => (name, owner, base),
+                    _ => return Err(Error::InvalidSource(format!("Expected Gitea repository URL: {}", repo_url))),
+                };
+                let url = parse_remote_url(&format!("{}/api/v1/repos/{}/{}/releases", base, owner, name))?;
+                let releases: Fetched<Vec<IndexRelease>> = get_json_conditional(url.as_str(), "application/json", None, etag).await?;
+                releases.try_map(|releases| releases.into_iter().map(|r| r.resolve(&url)).collect())
+            }
+            Self::HttpIndex(index_url) => {
+                let url = parse_remote_url(index_url)?;
+                let releases: Fetched<Vec<IndexRelease>> = get_json_conditional(url.as_str(), "application/json", None, etag).await?;
+                releases.try_map(|releases| releases.into_iter().map(|r| r.resolve(&url)).collect())
+            }
+            Self::LocalDir(dir) => Ok(Fetched::Modified { content: list_local_releases(dir).await?, etag: None }),
+        }
+    }
+
+    /// Content of the release asset, downloaded with progress reported.
+    /// Only assets of a local source are read from the file system.
+    /// The token is only sent to GitHub.
+    pub async fn download(
+        &self,
+        asset: &Asset,
+        kind: AssetKind,
+        token: Option<&str>,
+        progress: Option<ProgressTx>,
+    ) -> Result<Vec<u8>> {
+        match self {
+            Self::LocalDir(dir) => {
+                let url = parse_url(&asset.url)?;
+                let path = match url.scheme() {
+                    "file" => url.to_file_path().ok(),
+                    _ => None,
+                };
+                let path = path.ok_or_else(|| Error::InvalidSource(format!("Invalid asset path: {}", asset.url)))?;
+                let path = fs::canonicalize(path).await?;
+                if !path.starts_with(fs::canonicalize(dir).await?) {
+                    return Err(Error::InvalidSource(format!("Asset is outside of {:?}: {:?}", dir, path)));
+                }
+                check_size(fs::metadata(&path).await?.len(), kind.max_size())?;
+                Ok(fs::read(path).await?)
+            }
+            Self::GitHub(_) => download_asset(parse_remote_url(&asset.url)?, token, asset, kind, progress).await,
+            Self::Gitea(_) | Self::HttpIndex(_) => {
+                download_asset(parse_remote_url(&asset.url)?, None, asset, kind, progress).await
+            }
+        }
+    }
+}
+
+
+/// Names of the release assets, which differ between forks
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AssetNames {
+    pub dfu_prefix: String,
+    pub resources_prefix: String,
+}
+
+impl Default for AssetNames {
+    fn default() -> Self {
+        Self {
+            dfu_prefix: String::from("pinetime-mcuboot-app-dfu"),
+            resources_prefix: String::from("infinitime-resources"),
+        }
+    }
+}
+
+impl AssetNames {
+    pub fn is_dfu(&self, name: &str) -> bool {
+        name.starts_with(&self.dfu_prefix) && name.ends_with(".zip")
+    }
+
+    pub fn is_resources(&self, name: &str) -> bool {
+        name.starts_with(&self.resources_prefix) && name.ends_with(".zip")
+    }
+}
+
+
+/// Release as listed by Gitea or an HTTP index
+#[derive(Deserialize, Debug)]
+struct IndexRelease {
+    name: Option<String>,
+    tag_name: String,
+    #[serde(default)]
+    html_url: String,
+    #[serde(default)]
+    assets: Vec<IndexAsset>,
+}
+
+#[derive(Deserialize, Debug)]
+struct IndexAsset {
+    name: String,
+    browser_download_url: String,
+    #[serde(default)]
+    size: u32,
+}
+
+impl IndexRelease {
+    fn resolve(self, base: &Url) -> Result<ReleaseInfo> {
+        let assets = self.assets.into_iter()
+            .map(|asset| {
+                let url = base.join(&asset.browser_download_url)
+                    .map_err(|e| Error::InvalidSource(format!("Invalid URL of {}: {}", asset.name, e)))?;
+                if !is_remote(&url) {
+                    return Err(Error::InvalidSource(format!("Unsupported URL of {}: {}", asset.name, url)));
+                }
+                let url = url.to_string();
+                Ok(Asset {
+                    name: asset.name,
+                    direct_url: url.clone(),
+                    url,
+                    content_type: String::from("application/zip"),
+                    size: asset.size,
+                })
+            })
+            .collect::<Result<_>>()?;
+        Ok(ReleaseInfo {
+            name: self.name.unwrap_or_else(|| self.tag_name.clone()),
+            tag: self.tag_name,
+            url: self.html_url,
+            assets,
+        })
+    }
+}
+
+
+/// Every subdirectory is a release named after it, with its zip files as assets
+async fn list_local_releases(dir: &std::path::Path) -> Result<Vec<ReleaseInfo>> {
+    let dir = fs::canonicalize(dir).await?;
+    let mut releases = Vec::new();
+    let mut entries = fs::read_dir(&dir).await?;
+    while let Some(entry) = entries.next_entry().await? {
+        let path = entry.path();
+        let Some(tag) = path.file_name().and_then(|n| n.to_str()).map(String::from) else {
+            continue;
+        };
+        if !entry.file_type().await?.is_dir() {
+            continue;
+        }
+
+        let mut assets = Vec::new();
+        let mut files = fs::read_dir(&path).await?;
+        while let Some(file) = files.next_entry().await? {
+            let file_path = file.path();
+            let Some(name) = file_path.file_name().and_then(|n| n.to_str()).map(String::from) else {
+                continue;
+            };
+            let metadata = file.metadata().await?;
+            if metadata.is_file() && name.ends_with(".zip") {
+                let url = file_url(&file_path)?;
+                assets.push(Asset {
+                    name,
+                    direct_url: url.clone(),
+                    url,
+                    content_type: String::from("application/zip"),
+                    size: metadata.len() as u32,
+                });
+            }
+        }
+        assets.sort_by(|a, b| a.name.cmp(&b.name));
+
+        let url = Url::from_directory_path(&path)
+            .map_err(|_| Error::InvalidSource(format!("Invalid release directory: {:?}", path)))?;
+        releases.push(ReleaseInfo { name: tag.clone(), tag, url: url.to_string(), assets });
+    }
+    releases.sort_by(|a, b| compare_tags(&b.tag, &a.tag));
+    Ok(releases)
+}
+
+fn file_url(path: &std::path::Path) -> Result<String> {
+    Url::from_file_path(path)
+        .map(|url| url.to_string())
+        .map_err(|_| Error::InvalidSource(format!("Invalid asset path: {:?}", path)))
+}
+
+/// Versions compare numerically and are newer than any other tag
+fn compare_tags(a: &str, b: &str) -> Ordering {
+    match (Version::from(a), Version::from(b)) {
+        (Some(a), Some(b)) => a.compare(b).ord().unwrap_or(Ordering::Equal),
+        (Some(_), None) => Ordering::Greater,
+        (None, Some(_)) => Ordering::Less,
+        (None, None) => a.cmp(b),
+    }
+}
+
+pub(super) fn parse_url(url: &str) -> Result<Url> {
+    Url::parse(url).map_err(|e| Error::InvalidSource(format!("Invalid URL {}: {}", url, e)))
+}
+
+/// URL which is fetched over HTTP(S), as opposed to e.g. a local file
+fn parse_remote_url(url: &str) -> Result<Url> {
+    let parsed = parse_url(url)?;
+    if is_remote(&parsed) {
+        Ok(parsed)
+    } else {
+        Err(Error::InvalidSource(format!("Expected HTTP(S) URL: {}", url)))
+    }
+}
+
+fn is_remote(url: &Url) -> bool {
+    matches!(url.scheme(), "http" | "https")
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Empty directory, unique to the test
+    async fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("watchmate-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir).await;
+        fs::create_dir_all(&dir).await.unwrap();
+        dir
+    }
+
+    fn asset(url: &str) -> Asset {
+        Asset {
+            name: String::from("infinitime-resources-1.14.0.zip"),
+            url: url.to_string(),
+            direct_url: url.to_string(),
+            content_type: String::from("application/zip"),
+            size: 0,
+        }
+    }
+
+    #[test]
+    fn new_source() {
+        let ok = [
+            ("github", " owner/name ", ReleaseSource::GitHub(String::from("owner/name"))),
+            ("gitea", "https://codeberg.org/owner/name/", ReleaseSource::Gitea(String::from("https://codeberg.org/owner/name"))),
+            ("http-index", "http://localhost:8080/index.json", ReleaseSource::HttpIndex(String::from("http://localhost:8080/index.json"))),
+            ("local-dir", "/srv/releases", ReleaseSource::LocalDir(PathBuf::from("/srv/releases"))),
+        ];
+        for (kind, location, expected) in ok {
+            assert_eq!(ReleaseSource::new(kind, location).unwrap(), expected, "{kind} {location}");
+        }
+
+        let invalid = [
+            ("github", ""),
+            ("github", "owner"),
+            ("github", "owner/"),
+            ("github", "/name"),
+            ("github", "owner/name/extra"),
+            ("gitea", "codeberg.org/owner/name"),
+            ("gitea", "file:///srv/owner/name"),
+            ("http-index", "file:///srv/index.json"),
+            ("http-index", "ftp://example.com/index.json"),
+            ("local-dir", "  "),
+            ("gitlab", "owner/name"),
+        ];
+        for (kind, location) in invalid {
+            assert!(
+                matches!(ReleaseSource::new(kind, location), Err(Error::InvalidSource(_))),
+                "{kind} {location}"
+            );
+        }
+    }
+
+    #[test]
+    fn source_id() {
+        assert_eq!(ReleaseSource::default().id(), "github-InfiniTimeOrg_InfiniTime");
+        let source = ReleaseSource::new("http-index", "https://example.com/fw/index.json").unwrap();
+        assert_eq!(source.id(), "http-index-example.com_fw_index.json");
+    }
+
+    #[test]
+    fn resolve_index_release() {
+        let index: Vec<IndexRelease> = serde_json::from_str(r#"[{
+            "tag_name": "1.14.0",
+            "assets": [
+                {"name": "relative.zip", "browser_download_url": "1.14.0/relative.zip", "size": 10},
+                {"name": "rooted.zip", "browser_download_url": "/files/rooted.zip"},
+                {"name": "absolute.zip", "browser_download_url": "https://cdn.example.com/absolute.zip"}
+            ]
+        }]"#).unwrap();
+        let base = Url::parse("https://example.com/fw/index.json").unwrap();
+        let release = index.into_iter().next().unwrap().resolve(&base).unwrap();
+        assert_eq!(release.name, "1.14.0");
+        assert_eq!(release.tag, "1.14.0");
+        let urls: Vec<_> = release.assets.iter().map(|a| a.url.as_str()).collect();
+        assert_eq!(urls, [
+            "https://example.com/fw/1.14.0/relative.zip",
+            "https://example.com/files/rooted.zip",
+            "https://cdn.example.com/absolute.zip",
+        ]);
+        assert_eq!(release.assets[0].size, 10);
+    }
+
+    #[test]
+    fn resolve_rejects_non_http_urls() {
+        let base = Url::parse("https://example.com/fw/index.json").unwrap();
+        for url in ["file:///etc/passwd", "ftp://example.com/fw.zip", "data:application/zip;base64,AAAA"] {
+            let release = IndexRelease {
+                name: None,
+                tag_name: String::from("1.14.0"),
+                html_url: String::new(),
+                assets: vec![IndexAsset { name: String::from("fw.zip"), browser_download_url: url.to_string(), size: 0 }],
+            };
+            assert!(matches!(release.resolve(&base), Err(Error::InvalidSource(_))), "{url}");
+        }
+    }
+
+    #[test]
+    fn tag_order() {
+        let mut tags = vec!["main", "1.9.0", "1.14", "1.13.0", "1.14.1", "develop", "1.14.0"];
+        tags.sort_by(|a, b| compare_tags(b, a));
+        assert_eq!(tags, ["1.14.1", "1.14", "1.14.0", "1.13.0", "1.9.0", "main", "develop"]);
+    }
+
+    #[tokio::test]
+    async fn local_releases() {
+        let dir = temp_dir("local-releases").await;
+        fs::create_dir(dir.join("1.13.0")).await.unwrap();
+        fs::create_dir(dir.join("1.14.0")).await.unwrap();
+        fs::create_dir(dir.join("nightly")).await.unwrap();
+        fs::write(dir.join("1.14.0/pinetime-mcuboot-app-dfu-1.14.0.zip"), b"dfu").await.unwrap();
+        fs::write(dir.join("1.14.0/infinitime-resources-1.14.0.zip"), b"resources").await.unwrap();
+        fs::write(dir.join("1.14.0/notes.txt"), b"ignored").await.unwrap();
+        fs::write(dir.join("README"), b"ignored").await.unwrap();
+
+        let source = ReleaseSource::LocalDir(dir.clone());
+        let releases = source.list_releases().await.unwrap();
+        let tags: Vec<_> = releases.iter().map(|r| r.tag.as_str()).collect();
+        assert_eq!(tags, ["1.14.0", "1.13.0", "nightly"]);
+        assert!(releases[1].assets.is_empty());
+
+        let assets = &releases[0].assets;
+        let names: Vec<_> = assets.iter().map(|a| a.name.as_str()).collect();
+        assert_eq!(names, ["infinitime-resources-1.14.0.zip", "pinetime-mcuboot-app-dfu-1.14.0.zip"]);
+        assert_eq!(assets[0].size, 9);
+        assert_eq!(releases[0].get_dfu_asset(&AssetNames::default()), Some(&assets[1]));
+
+        let content = source.download(&assets[0], AssetKind::Resources, None, None).await.unwrap();
+        assert_eq!(content, b"resources");
+
+        fs::remove_dir_all(dir).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn local_source_reads_only_its_directory() {
+        let dir = temp_dir("local-source").await;
+        let outside = temp_dir("local-source-outside").await;
+        fs::create_dir(dir.join("1.14.0")).await.unwrap();
+        fs::write(outside.join("fw.zip"), b"outside").await.unwrap();
+        let source = ReleaseSource::LocalDir(dir.clone());
+
+        let escaped = format!("{}/1.14.0/../../{}/fw.zip",
+            file_url(&dir).unwrap(), outside.file_name().unwrap().to_str().unwrap());
+        for url in [file_url(&outside.join("fw.zip")).unwrap(), escaped, String::from("https://example.com/fw.zip")] {
+            let result = source.download(&asset(&url), AssetKind::Firmware, None, None).await;
+            assert!(matches!(result, Err(Error::InvalidSource(_))), "{url}");
+        }
+
+        fs::remove_dir_all(dir).await.unwrap();
+        fs::remove_dir_all(outside).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn remote_source_never_reads_files() {
+        let dir = temp_dir("remote-source").await;
+        fs::write(dir.join("fw.zip"), b"local").await.unwrap();
+        let url = file_url(&dir.join("fw.zip")).unwrap();
+        for source in [
+            ReleaseSource::default(),
+            ReleaseSource::new("gitea", "https://codeberg.org/owner/name").unwrap(),
+            ReleaseSource::new("http-index", "https://example.com/index.json").unwrap(),
+        ] {
+            let result = source.download(&asset(&url), AssetKind::Firmware, None, None).await;
+            assert!(matches!(result, Err(Error::InvalidSource(_))), "{:?}", source);
+        }
+        fs::remove_dir_all(dir).await.unwrap();
+    }
+}
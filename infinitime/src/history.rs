@@ -0,0 +1,359 @@
+//! Persistent time series of the watch readings.
+//!
+//! Readings are stored in SQLite database together with their timestamps and
+//! the watch address, so several watches can share one database.
+
+use crate::{bt, Result};
+use bluer::Address;
+use chrono::{DateTime, Datelike, Days, Local, NaiveDate, TimeZone, Utc};
+use futures::{pin_mut, StreamExt};
+use rusqlite::{params, Connection, OptionalExtension};
+use std::{collections::BTreeMap, ops::Range, path::{Path, PathBuf}, sync::Mutex};
+
+pub mod export;
+
+const SCHEMA: &str = "
+    CREATE TABLE IF NOT EXISTS battery_level (
+        address TEXT NOT NULL,
+        timestamp INTEGER NOT NULL,
+        level INTEGER NOT NULL
+    );
+    CREATE INDEX IF NOT EXISTS battery_level_idx ON battery_level (address, timestamp);
+
+    CREATE TABLE IF NOT EXISTS heart_rate (
+        address TEXT NOT NULL,
+        timestamp INTEGER NOT NULL,
+        bpm INTEGER NOT NULL
+    );
+    CREATE INDEX IF NOT EXISTS heart_rate_idx ON heart_rate (address, timestamp);
+
+    CREATE TABLE IF NOT EXISTS step_count (
+        address TEXT NOT NULL,
+        timestamp INTEGER NOT NULL,
+        count INTEGER NOT NULL,
+        delta INTEGER NOT NULL
+    );
+    CREATE INDEX IF NOT EXISTS step_count_idx ON step_count (address, timestamp);
+
+    CREATE TABLE IF NOT EXISTS reboot (
+        address TEXT NOT NULL,
+        timestamp INTEGER NOT NULL
+    );
+    CREATE INDEX IF NOT EXISTS reboot_idx ON reboot (address, timestamp);
+";
+
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Reading {
+    BatteryLevel(u8),
+    HeartRate(u16),
+    StepCount(u32),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Sample<T> {
+    pub timestamp: DateTime<Utc>,
+    pub value: T,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DailySteps {
+    /// Local date
+    pub date: NaiveDate,
+    pub steps: u32,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct WeeklySteps {
+    /// Local date of the week's Monday
+    pub week_start: NaiveDate,
+    pub steps: u32,
+}
+
+
+pub struct History {
+    connection: Mutex<Connection>,
+}
+
+impl History {
+    pub fn open(path: &Path) -> Result<Self> {
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        Self::init(Connection::open(path)?)
+    }
+
+    /// Open database at the default location, shared by all WatchMate apps
+    pub fn open_default() -> Result<Self> {
+        Self::open(&Self::default_path())
+    }
+
+    pub fn open_in_memory() -> Result<Self> {
+        Self::init(Connection::open_in_memory()?)
+    }
+
+    /// `$XDG_DATA_HOME/watchmate/history.sqlite3`
+    pub fn default_path() -> PathBuf {
+        let data_home = std::env::var_os("XDG_DATA_HOME")
+            .map(PathBuf::from)
+            .filter(|path| path.is_absolute())
+            .unwrap_or_else(|| {
+                let home = std::env::var_os("HOME").unwrap_or_default();
+                PathBuf::from(home).join(".local/share")
+            });
+        data_home.join("watchmate").join("history.sqlite3")
+    }
+
+    fn init(connection: Connection) -> Result<Self> {
+        // Both the app and watchmated may write to the same database
+        connection.busy_timeout(std::time::Duration::from_secs(5))?;
+        connection.execute_batch(SCHEMA)?;
+        Ok(Self { connection: Mutex::new(connection) })
+    }
+
+    pub fn record(&self, address: Address, reading: Reading) -> Result<()> {
+        self.record_at(address, Utc::now(), reading)
+    }
+
+    pub fn record_at(&self, address: Address, timestamp: DateTime<Utc>, reading: Reading) -> Result<()> {
+        let connection = self.connection.lock().unwrap();
+        let address = address.to_string();
+        let timestamp = timestamp.timestamp_millis();
+        match reading {
+            Reading::BatteryLevel(level) => {
+                connection.execute(
+                    "INSERT INTO battery_level (address, timestamp, level) VALUES (?1, ?2, ?3)",
+                    params![address, timestamp, level],
+                )?;
+            }
+            Reading::HeartRate(bpm) => {
+                connection.execute(
+                    "INSERT INTO heart_rate (address, timestamp, bpm) VALUES (?1, ?2, ?3)",
+                    params![address, timestamp, bpm],
+                )?;
+            }
+            Reading::StepCount(count) => {
+                let previous: Option<(u32, i64)> = connection.query_row(
+                    "SELECT count, timestamp FROM step_count WHERE address = ?1 AND timestamp <= ?2
+                     ORDER BY timestamp DESC LIMIT 1",
+                    params![address, timestamp],
+                    |row| Ok((row.get(0)?, row.get(1)?)),
+                ).optional()?;
+                let delta = match previous {
+                    Some((previous, previous_timestamp)) => {
+                        let rebooted: bool = connection.query_row(
+                            "SELECT EXISTS (SELECT 1 FROM reboot WHERE address = ?1
+                             AND timestamp > ?2 AND timestamp <= ?3)",
+                            params![address, previous_timestamp, timestamp],
+                            |row| row.get(0),
+                        )?;
+                        // The watch resets its counter at midnight and on reboot,
+                        // in which case everything it reports is new steps
+                        let reset = count < previous || rebooted
+                            || local_date(previous_timestamp) != local_date(timestamp);
+                        if reset { count } else { count - previous }
+                    }
+                    // The counter starts at midnight, so it's all today's steps
+                    None => count,
+                };
+                connection.execute(
+                    "INSERT INTO step_count (address, timestamp, count, delta) VALUES (?1, ?2, ?3, ?4)",
+                    params![address, timestamp, count, delta],
+                )?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Remember that the watch rebooted, e.g. after firmware upgrade,
+    /// so its step counter is known to start over
+    pub fn record_reboot(&self, address: Address) -> Result<()> {
+        self.record_reboot_at(address, Utc::now())
+    }
+
+    pub fn record_reboot_at(&self, address: Address, timestamp: DateTime<Utc>) -> Result<()> {
+        let connection = self.connection.lock().unwrap();
+        connection.execute(
+            "INSERT INTO reboot (address, timestamp) VALUES (?1, ?2)",
+            params![address.to_string(), timestamp.timestamp_millis()],
+        )?;
+        Ok(())
+    }
+
+    /// Addresses of all watches with recorded history
+    pub fn devices(&self) -> Result<Vec<Address>> {
+        let connection = self.connection.lock().unwrap();
+        let mut statement = connection.prepare(
+            "SELECT address FROM battery_level
+             UNION SELECT address FROM heart_rate
+             UNION SELECT address FROM step_count",
+        )?;
+        let addresses = statement.query_map([], |row| row.get::<_, String>(0))?
+            .filter_map(|address| address.ok()?.parse().ok())
+            .collect();
+        Ok(addresses)
+    }
+
+    pub fn battery_levels(&self, address: Address, range: Range<DateTime<Utc>>) -> Result<Vec<Sample<u8>>> {
+        self.query("SELECT timestamp, level FROM battery_level", address, range)
+    }
+
+    pub fn heart_rates(&self, address: Address, range: Range<DateTime<Utc>>) -> Result<Vec<Sample<u16>>> {
+        self.query("SELECT timestamp, bpm FROM heart_rate", address, range)
+    }
+
+    /// Raw step counter values as reported by the watch
+    pub fn step_counts(&self, address: Address, range: Range<DateTime<Utc>>) -> Result<Vec<Sample<u32>>> {
+        self.query("SELECT timestamp, count FROM step_count", address, range)
+    }
+
+    /// Steps walked since the previous reading, accounting for the watch counter resets
+    pub fn steps(&self, address: Address, range: Range<DateTime<Utc>>) -> Result<Vec<Sample<u32>>> {
+        self.query("SELECT timestamp, delta FROM step_count", address, range)
+    }
+
+    /// Steps walked per local day, accounting for the watch counter resets
+    pub fn daily_steps(&self, address: Address, range: Range<DateTime<Utc>>) -> Result<Vec<DailySteps>> {
+        let mut days = BTreeMap::new();
+        for sample in self.steps(address, range)? {
+            let date = sample.timestamp.with_timezone(&Local).date_naive();
+            *days.entry(date).or_insert(0) += sample.value;
+        }
+        Ok(days.into_iter().map(|(date, steps)| DailySteps { date, steps }).collect())
+    }
+
+    /// Steps walked per week (starting on Monday), accounting for the watch counter resets
+    pub fn weekly_steps(&self, address: Address, range: Range<DateTime<Utc>>) -> Result<Vec<WeeklySteps>> {
+        let mut weeks = BTreeMap::new();
+        for day in self.daily_steps(address, range)? {
+            let days_from_monday = day.date.weekday().num_days_from_monday() as u64;
+            let week_start = day.date - Days::new(days_from_monday);
+            *weeks.entry(week_start).or_insert(0) += day.steps;
+        }
+        Ok(weeks.into_iter().map(|(week_start, steps)| WeeklySteps { week_start, steps }).collect())
+    }
+
+    fn query<T: rusqlite::types::FromSql>(
+        &self, select: &str, address: Address, range: Range<DateTime<Utc>>
+    ) -> Result<Vec<Sample<T>>> {
+        let connection = self.connection.lock().unwrap();
+        let sql = format!(
+            "{select} WHERE address = ?1 AND timestamp >= ?2 AND timestamp < ?3 ORDER BY timestamp"
+        );
+        let mut statement = connection.prepare(&sql)?;
+        let rows = statement.query_map(
+            params![address.to_string(), range.start.timestamp_millis(), range.end.timestamp_millis()],
+            |row| Ok((row.get::<_, i64>(0)?, row.get::<_, T>(1)?)),
+        )?;
+        let mut samples = Vec::new();
+        for row in rows {
+            let (timestamp, value) = row?;
+            if let Some(timestamp) = Utc.timestamp_millis_opt(timestamp).single() {
+                samples.push(Sample { timestamp, value });
+            }
+        }
+        Ok(samples)
+    }
+}
+
+/// Local date of the timestamp in milliseconds, as stored in the database
+fn local_date(timestamp: i64) -> Option<NaiveDate> {
+    Local.timestamp_millis_opt(timestamp).earliest().map(|t| t.date_naive())
+}
+
+
+/// Record readings from the watch until its data streams end
+pub async fn run_recorder(history: &History, infinitime: &bt::InfiniTime) -> Result<()> {
+    let address = infinitime.address();
+    let record = |reading| {
+        if let Err(error) = history.record(address, reading) {
+            log::error!("Failed to record {:?}: {}", reading, error);
+        }
+    };
+
+    if let Ok(level) = infinitime.read_battery_level().await {
+        record(Reading::BatteryLevel(level));
+    }
+    if let Ok(count) = infinitime.read_step_count().await {
+        record(Reading::StepCount(count));
+    }
+
+    let bl_stream = infinitime.get_battery_level_stream().await?;
+    let hr_stream = infinitime.get_heart_rate_stream().await?;
+    let sc_stream = infinitime.get_step_count_stream().await?;
+    pin_mut!(bl_stream, hr_stream, sc_stream);
+    loop {
+        tokio::select! {
+            Some(level) = bl_stream.next() => record(Reading::BatteryLevel(level)),
+            // Zero means the heart rate sensor is off
+            Some(bpm) = hr_stream.next() => if bpm > 0 {
+                record(Reading::HeartRate(bpm))
+            },
+            Some(count) = sc_stream.next() => record(Reading::StepCount(count)),
+            else => break Ok(()),
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ADDRESS: Address = Address::new([1, 2, 3, 4, 5, 6]);
+
+    fn at(day: u32, hour: u32) -> DateTime<Utc> {
+        Local.with_ymd_and_hms(2026, 3, day, hour, 0, 0).unwrap().with_timezone(&Utc)
+    }
+
+    /// Record step counts and return deltas stored for them
+    fn deltas(history: &History, counts: &[(DateTime<Utc>, u32)]) -> Vec<u32> {
+        for (timestamp, count) in counts {
+            history.record_at(ADDRESS, *timestamp, Reading::StepCount(*count)).unwrap();
+        }
+        history.steps(ADDRESS, at(1, 0)..at(28, 0)).unwrap()
+            .into_iter()
+            .map(|sample| sample.value)
+            .collect()
+    }
+
+    #[test]
+    fn first_sample() {
+        let history = History::open_in_memory().unwrap();
+        assert_eq!(deltas(&history, &[(at(10, 9), 1200)]), [1200]);
+    }
+
+    #[test]
+    fn same_day_increase() {
+        let history = History::open_in_memory().unwrap();
+        let counts = [(at(10, 9), 1200), (at(10, 12), 3000), (at(10, 18), 3000)];
+        assert_eq!(deltas(&history, &counts), [1200, 1800, 0]);
+    }
+
+    #[test]
+    fn counter_drop() {
+        let history = History::open_in_memory().unwrap();
+        let counts = [(at(10, 9), 1200), (at(10, 12), 300), (at(10, 13), 500)];
+        assert_eq!(deltas(&history, &counts), [1200, 300, 200]);
+    }
+
+    #[test]
+    fn day_rollover_with_higher_count() {
+        let history = History::open_in_memory().unwrap();
+        let counts = [(at(10, 22), 1200), (at(11, 20), 5000), (at(11, 21), 5100)];
+        assert_eq!(deltas(&history, &counts), [1200, 5000, 100]);
+        let daily = history.daily_steps(ADDRESS, at(1, 0)..at(28, 0)).unwrap();
+        assert_eq!(daily.iter().map(|day| day.steps).collect::<Vec<_>>(), [1200, 5100]);
+    }
+
+    #[test]
+    fn reboot_with_higher_count() {
+        let history = History::open_in_memory().unwrap();
+        history.record_at(ADDRESS, at(10, 9), Reading::StepCount(1200)).unwrap();
+        history.record_reboot_at(ADDRESS, at(10, 10)).unwrap();
+        // Another watch rebooting doesn't matter
+        history.record_reboot_at(Address::any(), at(10, 13)).unwrap();
+        let counts = [(at(10, 12), 1500), (at(10, 14), 1700)];
+        assert_eq!(deltas(&history, &counts), [1200, 1500, 200]);
+    }
+}
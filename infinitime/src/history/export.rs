@@ -0,0 +1,433 @@
+//! Export of the recorded history to formats understood by analysis and fitness tools.
+//!
+//! CSV contains everything. TCX and FIT carry heart rate timeline only, as
+//! they have no place for daily step counts. GPX is not supported, because
+//! its track points require coordinates, which the watch doesn't record.
+
+use super::{History, Sample};
+use crate::Result;
+use bluer::Address;
+use chrono::{DateTime, SecondsFormat, Utc};
+use std::{fmt::Write, ops::Range};
+
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    Csv,
+    Tcx,
+    Fit,
+}
+
+impl Format {
+    pub const ALL: [Format; 3] = [Self::Csv, Self::Tcx, Self::Fit];
+
+    pub fn extension(&self) -> &'static str {
+        match self {
+            Self::Csv => "csv",
+            Self::Tcx => "tcx",
+            Self::Fit => "fit",
+        }
+    }
+}
+
+pub fn export(history: &History, address: Address, range: Range<DateTime<Utc>>, format: Format) -> Result<Vec<u8>> {
+    match format {
+        Format::Csv => to_csv(history, address, range),
+        Format::Tcx => to_tcx(history, address, range),
+        Format::Fit => to_fit(history, address, range),
+    }
+}
+
+
+/// One reading per line: `timestamp,address,metric,value`, where metric is
+/// one of `battery_level`, `heart_rate`, `step_count` (raw watch counter)
+/// or `steps` (walked since the previous reading)
+pub fn to_csv(history: &History, address: Address, range: Range<DateTime<Utc>>) -> Result<Vec<u8>> {
+    let mut rows: Vec<(DateTime<Utc>, &str, u32)> = Vec::new();
+    rows.extend(history.battery_levels(address, range.clone())?
+        .into_iter().map(|s| (s.timestamp, "battery_level", s.value as u32)));
+    rows.extend(history.heart_rates(address, range.clone())?
+        .into_iter().map(|s| (s.timestamp, "heart_rate", s.value as u32)));
+    rows.extend(history.step_counts(address, range.clone())?
+        .into_iter().map(|s| (s.timestamp, "step_count", s.value)));
+    rows.extend(history.steps(address, range)?
+        .into_iter().map(|s| (s.timestamp, "steps", s.value)));
+    rows.sort_by_key(|row| row.0);
+
+    let mut csv = String::from("timestamp,address,metric,value\n");
+    for (timestamp, metric, value) in rows {
+        _ = writeln!(csv, "{},{},{},{}", format_time(timestamp), address, metric, value);
+    }
+    Ok(csv.into_bytes())
+}
+
+/// Garmin Training Center activity with heart rate track
+pub fn to_tcx(history: &History, address: Address, range: Range<DateTime<Utc>>) -> Result<Vec<u8>> {
+    let heart_rates = history.heart_rates(address, range.clone())?;
+    let summary = Summary::new(&heart_rates, range);
+    let start = format_time(summary.start);
+
+    let mut tcx = String::new();
+    tcx.push_str("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n");
+    tcx.push_str("<TrainingCenterDatabase xmlns=\"http://www.garmin.com/xmlschemas/TrainingCenterDatabase/v2\">\n");
+    tcx.push_str("  <Activities>\n");
+    tcx.push_str("    <Activity Sport=\"Other\">\n");
+    _ = writeln!(tcx, "      <Id>{}</Id>", start);
+    _ = writeln!(tcx, "      <Lap StartTime=\"{}\">", start);
+    _ = writeln!(tcx, "        <TotalTimeSeconds>{}</TotalTimeSeconds>", summary.duration_secs());
+    tcx.push_str("        <DistanceMeters>0</DistanceMeters>\n");
+    tcx.push_str("        <Calories>0</Calories>\n");
+    if let (Some(avg), Some(max)) = (summary.avg_heart_rate, summary.max_heart_rate) {
+        _ = writeln!(tcx, "        <AverageHeartRateBpm><Value>{}</Value></AverageHeartRateBpm>", avg);
+        _ = writeln!(tcx, "        <MaximumHeartRateBpm><Value>{}</Value></MaximumHeartRateBpm>", max);
+    }
+    tcx.push_str("        <Intensity>Active</Intensity>\n");
+    tcx.push_str("        <TriggerMethod>Manual</TriggerMethod>\n");
+    // Track must contain at least one point
+    if !heart_rates.is_empty() {
+        tcx.push_str("        <Track>\n");
+        for sample in &heart_rates {
+            _ = writeln!(
+                tcx,
+                "          <Trackpoint><Time>{}</Time><HeartRateBpm><Value>{}</Value></HeartRateBpm></Trackpoint>",
+                format_time(sample.timestamp), sample.value
+            );
+        }
+        tcx.push_str("        </Track>\n");
+    }
+    tcx.push_str("      </Lap>\n");
+    tcx.push_str("    </Activity>\n");
+    tcx.push_str("  </Activities>\n");
+    tcx.push_str("</TrainingCenterDatabase>\n");
+    Ok(tcx.into_bytes())
+}
+
+/// FIT activity file with heart rate records
+pub fn to_fit(history: &History, address: Address, range: Range<DateTime<Utc>>) -> Result<Vec<u8>> {
+    let heart_rates = history.heart_rates(address, range.clone())?;
+    let summary = Summary::new(&heart_rates, range);
+    let start = fit::timestamp(summary.start);
+    let end = fit::timestamp(summary.end);
+    let elapsed_ms = summary.duration_secs() * 1000;
+    let avg_hr = summary.avg_heart_rate.map_or(fit::INVALID_U8, fit::heart_rate);
+    let max_hr = summary.max_heart_rate.map_or(fit::INVALID_U8, fit::heart_rate);
+
+    let mut writer = fit::Writer::default();
+
+    writer.define(0, fit::MSG_FILE_ID, &[
+        (0, 1, fit::ENUM),      // type
+        (1, 2, fit::UINT16),    // manufacturer
+        (2, 2, fit::UINT16),    // product
+        (4, 4, fit::UINT32),    // time_created
+    ]);
+    writer.data(0, &[
+        &[fit::FILE_ACTIVITY],
+        &fit::MANUFACTURER_DEVELOPMENT.to_le_bytes(),
+        &0u16.to_le_bytes(),
+        &start.to_le_bytes(),
+    ]);
+
+    writer.define(1, fit::MSG_RECORD, &[
+        (253, 4, fit::UINT32),  // timestamp
+        (3, 1, fit::UINT8),     // heart_rate
+    ]);
+    for sample in &heart_rates {
+        writer.data(1, &[
+            &fit::timestamp(sample.timestamp).to_le_bytes(),
+            &[fit::heart_rate(sample.value)],
+        ]);
+    }
+
+    writer.define(2, fit::MSG_LAP, &[
+        (253, 4, fit::UINT32),  // timestamp
+        (2, 4, fit::UINT32),    // start_time
+        (7, 4, fit::UINT32),    // total_elapsed_time
+        (8, 4, fit::UINT32),    // total_timer_time
+        (15, 1, fit::UINT8),    // avg_heart_rate
+        (16, 1, fit::UINT8),    // max_heart_rate
+    ]);
+    writer.data(2, &[
+        &end.to_le_bytes(),
+        &start.to_le_bytes(),
+        &elapsed_ms.to_le_bytes(),
+        &elapsed_ms.to_le_bytes(),
+        &[avg_hr],
+        &[max_hr],
+    ]);
+
+    writer.define(3, fit::MSG_SESSION, &[
+        (253, 4, fit::UINT32),  // timestamp
+        (2, 4, fit::UINT32),    // start_time
+        (7, 4, fit::UINT32),    // total_elapsed_time
+        (8, 4, fit::UINT32),    // total_timer_time
+        (5, 1, fit::ENUM),      // sport
+        (16, 1, fit::UINT8),    // avg_heart_rate
+        (17, 1, fit::UINT8),    // max_heart_rate
+        (26, 2, fit::UINT16),   // num_laps
+    ]);
+    writer.data(3, &[
+        &end.to_le_bytes(),
+        &start.to_le_bytes(),
+        &elapsed_ms.to_le_bytes(),
+        &elapsed_ms.to_le_bytes(),
+        &[fit::SPORT_GENERIC],
+        &[avg_hr],
+        &[max_hr],
+        &1u16.to_le_bytes(),
+    ]);
+
+    writer.define(4, fit::MSG_ACTIVITY, &[
+        (253, 4, fit::UINT32),  // timestamp
+        (0, 4, fit::UINT32),    // total_timer_time
+        (1, 2, fit::UINT16),    // num_sessions
+        (2, 1, fit::ENUM),      // type
+        (3, 1, fit::ENUM),      // event
+        (4, 1, fit::ENUM),      // event_type
+    ]);
+    writer.data(4, &[
+        &end.to_le_bytes(),
+        &elapsed_ms.to_le_bytes(),
+        &1u16.to_le_bytes(),
+        &[fit::ACTIVITY_MANUAL],
+        &[fit::EVENT_ACTIVITY],
+        &[fit::EVENT_TYPE_STOP],
+    ]);
+
+    Ok(writer.finish())
+}
+
+
+struct Summary {
+    start: DateTime<Utc>,
+    end: DateTime<Utc>,
+    avg_heart_rate: Option<u16>,
+    max_heart_rate: Option<u16>,
+}
+
+impl Summary {
+    /// Activity spans recorded samples, or the whole range if there are none
+    fn new(heart_rates: &[Sample<u16>], range: Range<DateTime<Utc>>) -> Self {
+        let start = heart_rates.first().map_or(range.start, |s| s.timestamp);
+        let end = heart_rates.last().map_or(range.end, |s| s.timestamp);
+        let sum: u64 = heart_rates.iter().map(|s| s.value as u64).sum();
+        let avg_heart_rate = (!heart_rates.is_empty())
+            .then(|| (sum / heart_rates.len() as u64) as u16);
+        let max_heart_rate = heart_rates.iter().map(|s| s.value).max();
+        Self { start, end, avg_heart_rate, max_heart_rate }
+    }
+
+    fn duration_secs(&self) -> u32 {
+        (self.end - self.start).num_seconds().max(0) as u32
+    }
+}
+
+fn format_time(time: DateTime<Utc>) -> String {
+    time.to_rfc3339_opts(SecondsFormat::Secs, true)
+}
+
+
+/// Minimal FIT protocol encoder, see https://developer.garmin.com/fit/protocol/
+mod fit {
+    use chrono::{DateTime, Utc};
+
+    pub const ENUM: u8 = 0x00;
+    pub const UINT8: u8 = 0x02;
+    pub const UINT16: u8 = 0x84;
+    pub const UINT32: u8 = 0x86;
+
+    pub const MSG_FILE_ID: u16 = 0;
+    pub const MSG_SESSION: u16 = 18;
+    pub const MSG_LAP: u16 = 19;
+    pub const MSG_RECORD: u16 = 20;
+    pub const MSG_ACTIVITY: u16 = 34;
+
+    pub const FILE_ACTIVITY: u8 = 4;
+    pub const MANUFACTURER_DEVELOPMENT: u16 = 255;
+    pub const SPORT_GENERIC: u8 = 0;
+    pub const ACTIVITY_MANUAL: u8 = 0;
+    pub const EVENT_ACTIVITY: u8 = 26;
+    pub const EVENT_TYPE_STOP: u8 = 1;
+    pub const INVALID_U8: u8 = 0xff;
+
+    const PROTOCOL_VERSION: u8 = 0x10;
+    const PROFILE_VERSION: u16 = 2132;
+    const HEADER_SIZE: u8 = 14;
+    /// 1989-12-31T00:00:00Z as UNIX timestamp
+    const EPOCH: i64 = 631065600;
+
+    pub fn timestamp(time: DateTime<Utc>) -> u32 {
+        (time.timestamp() - EPOCH).max(0) as u32
+    }
+
+    pub fn heart_rate(bpm: u16) -> u8 {
+        bpm.min(254) as u8
+    }
+
+    #[derive(Default)]
+    pub struct Writer {
+        records: Vec<u8>,
+    }
+
+    impl Writer {
+        /// Define local message type as a list of (field number, size, base type)
+        pub fn define(&mut self, local: u8, global: u16, fields: &[(u8, u8, u8)]) {
+            self.records.extend_from_slice(&[0x40 | local, 0, 0]);
+            self.records.extend_from_slice(&global.to_le_bytes());
+            self.records.push(fields.len() as u8);
+            for &(number, size, base_type) in fields {
+                self.records.extend_from_slice(&[number, size, base_type]);
+            }
+        }
+
+        /// Write message of previously defined local type, field values must
+        /// be in the definition order
+        pub fn data(&mut self, local: u8, values: &[&[u8]]) {
+            self.records.push(local);
+            for value in values {
+                self.records.extend_from_slice(value);
+            }
+        }
+
+        pub fn finish(self) -> Vec<u8> {
+            let mut file = vec![HEADER_SIZE, PROTOCOL_VERSION];
+            file.extend_from_slice(&PROFILE_VERSION.to_le_bytes());
+            file.extend_from_slice(&(self.records.len() as u32).to_le_bytes());
+            file.extend_from_slice(b".FIT");
+            let header_crc = crc(&file);
+            file.extend_from_slice(&header_crc.to_le_bytes());
+            file.extend_from_slice(&self.records);
+            let file_crc = crc(&file);
+            file.extend_from_slice(&file_crc.to_le_bytes());
+            file
+        }
+    }
+
+    pub fn crc(data: &[u8]) -> u16 {
+        const TABLE: [u16; 16] = [
+            0x0000, 0xcc01, 0xd801, 0x1400, 0xf001, 0x3c00, 0x2800, 0xe401,
+            0xa001, 0x6c00, 0x7800, 0xb401, 0x5000, 0x9c01, 0x8801, 0x4400,
+        ];
+        let mut crc = 0u16;
+        for &byte in data {
+            for nibble in [byte & 0x0f, byte >> 4] {
+                let tmp = TABLE[(crc & 0x0f) as usize];
+                crc = (crc >> 4) & 0x0fff;
+                crc = crc ^ tmp ^ TABLE[nibble as usize];
+            }
+        }
+        crc
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::history::Reading;
+    use chrono::TimeZone;
+
+    const ADDRESS: Address = Address::new([0x01, 0x02, 0x03, 0x04, 0x05, 0xab]);
+
+    fn at(hour: u32, min: u32) -> DateTime<Utc> {
+        Utc.with_ymd_and_hms(2026, 3, 10, hour, min, 0).unwrap()
+    }
+
+    fn history() -> History {
+        let history = History::open_in_memory().unwrap();
+        let record = |timestamp, reading| history.record_at(ADDRESS, timestamp, reading).unwrap();
+        record(at(10, 0), Reading::BatteryLevel(80));
+        record(at(12, 0), Reading::StepCount(1000));
+        record(at(12, 10), Reading::HeartRate(70));
+        record(at(12, 20), Reading::HeartRate(90));
+        record(at(12, 30), Reading::StepCount(1500));
+        // Another watch
+        history.record_at(Address::any(), at(12, 0), Reading::HeartRate(60)).unwrap();
+        history
+    }
+
+    #[test]
+    fn csv() {
+        let csv = to_csv(&history(), ADDRESS, at(0, 0)..at(23, 0)).unwrap();
+        assert_eq!(String::from_utf8(csv).unwrap(), "\
+            timestamp,address,metric,value\n\
+            2026-03-10T10:00:00Z,01:02:03:04:05:AB,battery_level,80\n\
+            2026-03-10T12:00:00Z,01:02:03:04:05:AB,step_count,1000\n\
+            2026-03-10T12:00:00Z,01:02:03:04:05:AB,steps,1000\n\
+            2026-03-10T12:10:00Z,01:02:03:04:05:AB,heart_rate,70\n\
+            2026-03-10T12:20:00Z,01:02:03:04:05:AB,heart_rate,90\n\
+            2026-03-10T12:30:00Z,01:02:03:04:05:AB,step_count,1500\n\
+            2026-03-10T12:30:00Z,01:02:03:04:05:AB,steps,500\n\
+        ");
+    }
+
+    #[test]
+    fn csv_range() {
+        let csv = to_csv(&history(), ADDRESS, at(12, 10)..at(12, 30)).unwrap();
+        assert_eq!(String::from_utf8(csv).unwrap().lines().count(), 3);
+    }
+
+    #[test]
+    fn fit_crc() {
+        // CRC-16/ARC check value
+        assert_eq!(fit::crc(b"123456789"), 0xbb3d);
+    }
+
+    #[test]
+    fn fit_header_and_crc() {
+        let file = to_fit(&history(), ADDRESS, at(0, 0)..at(23, 0)).unwrap();
+
+        let header = &file[..14];
+        assert_eq!(header[0], 14);
+        assert_eq!(&header[8..12], b".FIT");
+        let data_size = u32::from_le_bytes(header[4..8].try_into().unwrap()) as usize;
+        assert_eq!(file.len(), 14 + data_size + 2);
+        // CRC over data followed by its CRC is zero
+        assert_eq!(fit::crc(header), 0);
+        assert_eq!(fit::crc(&file), 0);
+    }
+
+    #[test]
+    fn fit_records() {
+        let file = to_fit(&history(), ADDRESS, at(0, 0)..at(23, 0)).unwrap();
+        let records = &file[14..file.len() - 2];
+
+        // Walk the records, counting data messages per global message number
+        let mut definitions = std::collections::HashMap::new();
+        let mut messages = std::collections::BTreeMap::new();
+        let mut heart_rates = Vec::new();
+        let mut offset = 0;
+        while offset < records.len() {
+            let header = records[offset];
+            let local = header & 0x0f;
+            if header & 0x40 != 0 {
+                let global = u16::from_le_bytes([records[offset + 3], records[offset + 4]]);
+                let count = records[offset + 5] as usize;
+                let fields = &records[offset + 6..offset + 6 + 3 * count];
+                let size: usize = fields.chunks(3).map(|field| field[1] as usize).sum();
+                definitions.insert(local, (global, size));
+                offset += 6 + 3 * count;
+            } else {
+                let (global, size) = definitions[&local];
+                *messages.entry(global).or_insert(0) += 1;
+                if global == fit::MSG_RECORD {
+                    let timestamp = u32::from_le_bytes(records[offset + 1..offset + 5].try_into().unwrap());
+                    heart_rates.push((timestamp, records[offset + 5]));
+                }
+                offset += 1 + size;
+            }
+        }
+        assert_eq!(offset, records.len());
+
+        assert_eq!(messages, [
+            (fit::MSG_FILE_ID, 1),
+            (fit::MSG_SESSION, 1),
+            (fit::MSG_LAP, 1),
+            (fit::MSG_RECORD, 2),
+            (fit::MSG_ACTIVITY, 1),
+        ].into());
+        assert_eq!(heart_rates, [
+            (fit::timestamp(at(12, 10)), 70),
+            (fit::timestamp(at(12, 20)), 90),
+        ]);
+    }
+}
@@ -1,6 +1,12 @@
 pub mod bluetooth;
 pub use bluetooth as bt;
 
+pub mod battery;
+pub mod compatibility;
+
+mod error;
+pub use error::{Error, Result};
+
 #[cfg(feature = "freedesktop")]
 pub mod freedesktop;
 #[cfg(feature = "freedesktop")]
@@ -11,6 +17,14 @@ pub mod github;
 #[cfg(feature = "github")]
 pub use github as gh;
 
+#[cfg(feature = "history")]
+pub mod history;
+
+#[cfg(feature = "simulator")]
+pub mod simulator;
+#[cfg(feature = "simulator")]
+pub use simulator as sim;
+
 mod utils;
 
 
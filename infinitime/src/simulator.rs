@@ -0,0 +1,264 @@
+//! In-process InfiniTime simulator.
+//!
+//! Emulates the GATT services of a watch running InfiniTime on top of
+//! [`bt::FakeTransport`], so the rest of the crate can be used without
+//! BlueZ or a PineTime:
+//!
+//! ```ignore
+//! let sim = Simulator::new();
+//! sim.set_battery_level(42);
+//! let infinitime = sim.infinitime();
+//! assert_eq!(infinitime.read_battery_level().await?, 42);
+//! ```
+
+use crate::bt::{self, uuids, FakeTransport, MediaPlayerEvent};
+use bluer::Address;
+use std::{sync::{Arc, Mutex}, time::Duration};
+
+mod dfu;
+mod fs;
+
+pub use dfu::DfuFault;
+pub use fs::FsFault;
+
+
+pub const DEFAULT_ADDRESS: Address = Address::new([0xc0, 0xff, 0xee, 0x00, 0x00, 0x01]);
+pub const DEFAULT_FIRMWARE_VERSION: &str = "1.14.0";
+
+
+/// Notification received by the simulated watch
+#[derive(Debug, Clone, PartialEq)]
+pub struct ReceivedNotification {
+    pub category: u8,
+    pub title: String,
+    pub content: String,
+}
+
+/// Media player info written to the simulated watch
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct MediaPlayerState {
+    pub artist: String,
+    pub album: String,
+    pub track: String,
+    pub playing: bool,
+    pub position: u32,
+    pub duration: u32,
+    pub speed: u32,
+    pub repeat: bool,
+    pub shuffle: bool,
+}
+
+/// Simulated InfiniTime watch
+#[derive(Debug, Clone)]
+pub struct Simulator {
+    transport: FakeTransport,
+    fs: Arc<Mutex<fs::FileSystem>>,
+    dfu: Arc<Mutex<dfu::Bootloader>>,
+}
+
+impl Simulator {
+    pub fn new() -> Self {
+        Self::with_address(DEFAULT_ADDRESS)
+    }
+
+    pub fn with_address(address: Address) -> Self {
+        let transport = FakeTransport::new(address);
+        let fs = Arc::new(Mutex::new(fs::FileSystem::default()));
+        let dfu = Arc::new(Mutex::new(dfu::Bootloader::default()));
+
+        // Basic data
+        transport.add_characteristic(uuids::CHR_BATTERY_LEVEL, [100]);
+        transport.add_characteristic(uuids::CHR_HEART_RATE, [0x00, 0]);
+        transport.add_characteristic(uuids::CHR_STEP_COUNT, 0u32.to_le_bytes());
+        transport.add_characteristic(uuids::CHR_MOTION, [0; 6]);
+        transport.add_characteristic(uuids::CHR_FIRMWARE_REVISION, DEFAULT_FIRMWARE_VERSION);
+
+        // Notifications
+        transport.add_characteristic(uuids::CHR_NEW_ALERT, []);
+        transport.add_characteristic(uuids::CHR_NOTIFICATION_EVENT, []);
+
+        // Media player
+        transport.add_characteristic(uuids::CHR_MP_EVENTS, []);
+        for uuid in [
+            uuids::CHR_MP_STATUS, uuids::CHR_MP_ARTIST, uuids::CHR_MP_TRACK,
+            uuids::CHR_MP_ALBUM, uuids::CHR_MP_POSITION, uuids::CHR_MP_DURATION,
+            uuids::CHR_MP_SPEED, uuids::CHR_MP_REPEAT, uuids::CHR_MP_SHUFFLE,
+        ] {
+            transport.add_characteristic(uuid, []);
+        }
+
+        // File system
+        transport.add_characteristic(uuids::CHR_FS_VERSION, fs::VERSION.to_le_bytes());
+        let fs_ = fs.clone();
+        transport.on_write(uuids::CHR_FS_TRANSFER, move |transport, request| {
+            let responses = fs_.lock().unwrap().handle_request(request);
+            for response in responses {
+                transport.send_notification(&uuids::CHR_FS_TRANSFER, &response);
+            }
+            Ok(())
+        });
+
+        // Firmware upgrade
+        let dfu_ = dfu.clone();
+        transport.on_write(uuids::CHR_FWUPD_CONTROL_POINT, move |transport, request| {
+            if let Some(response) = dfu_.lock().unwrap().handle_control(request) {
+                transport.send_notification(&uuids::CHR_FWUPD_CONTROL_POINT, &response);
+            }
+            Ok(())
+        });
+        let dfu_ = dfu.clone();
+        transport.on_write(uuids::CHR_FWUPD_PACKET, move |transport, packet| {
+            if let Some(response) = dfu_.lock().unwrap().handle_packet(packet) {
+                transport.send_notification(&uuids::CHR_FWUPD_CONTROL_POINT, &response);
+            }
+            Ok(())
+        });
+
+        Self { transport, fs, dfu }
+    }
+
+    /// Transport for creating `InfiniTime` instances connected to this simulator
+    pub fn transport(&self) -> FakeTransport {
+        self.transport.clone()
+    }
+
+    pub fn infinitime(&self) -> bt::InfiniTime {
+        bt::InfiniTime::with_transport(self.transport())
+    }
+
+    // -- Basic data --
+
+    pub fn set_battery_level(&self, level: u8) {
+        self.transport.notify_value(uuids::CHR_BATTERY_LEVEL, [level]);
+    }
+
+    pub fn set_heart_rate(&self, rate: u16) {
+        self.set_heart_rate_measurement(&bt::HeartRateMeasurement {
+            bpm: rate,
+            ..Default::default()
+        });
+    }
+
+    pub fn set_heart_rate_measurement(&self, measurement: &bt::HeartRateMeasurement) {
+        self.transport.notify_value(uuids::CHR_HEART_RATE, measurement.serialize());
+    }
+
+    pub fn set_step_count(&self, count: u32) {
+        self.transport.notify_value(uuids::CHR_STEP_COUNT, count.to_le_bytes());
+    }
+
+    pub fn set_motion(&self, sample: bt::MotionSample) {
+        self.transport.notify_value(uuids::CHR_MOTION, sample.serialize());
+    }
+
+    pub fn set_firmware_version(&self, version: &str) {
+        self.transport.set_value(uuids::CHR_FIRMWARE_REVISION, version);
+    }
+
+    /// Change values periodically, like a watch being worn. Runs forever.
+    pub async fn animate(&self, interval: Duration) {
+        let mut battery = 100u8;
+        let mut steps = 0u32;
+        let mut tick = 0u32;
+        loop {
+            tokio::time::sleep(interval).await;
+            tick = tick.wrapping_add(1);
+            steps += tick % 7 + 3;
+            self.set_step_count(steps);
+            // Arm swinging, with gravity mostly along z
+            let swing = (tick % 8) as i16 * 64 - 256;
+            self.set_motion(bt::MotionSample { x: swing, y: -swing / 2, z: -1024 + swing.abs() });
+            let bpm = 60 + (tick * 13 % 40) as u16;
+            self.set_heart_rate_measurement(&bt::HeartRateMeasurement {
+                bpm,
+                sensor_contact: Some(true),
+                energy_expended: None,
+                rr_intervals: vec![60 * 1024 / bpm],
+            });
+            if tick % 10 == 0 {
+                battery = battery.saturating_sub(1);
+                self.set_battery_level(battery);
+            }
+        }
+    }
+
+    // -- Notifications --
+
+    pub fn received_notifications(&self) -> Vec<ReceivedNotification> {
+        self.transport.writes_to(&uuids::CHR_NEW_ALERT).iter()
+            .filter_map(|message| {
+                // [category, count, 0, title, 0, content]
+                let category = *message.first()?;
+                let mut parts = message.get(3..)?.splitn(2, |b| *b == 0);
+                let title = String::from_utf8_lossy(parts.next()?).into_owned();
+                let content = String::from_utf8_lossy(parts.next().unwrap_or_default()).into_owned();
+                Some(ReceivedNotification { category, title, content })
+            })
+            .collect()
+    }
+
+    // -- Media player --
+
+    /// Emulate user pressing media player control on the watch
+    pub fn press_media_button(&self, event: MediaPlayerEvent) {
+        self.transport.send_notification(&uuids::CHR_MP_EVENTS, &[event.to_raw()]);
+    }
+
+    pub fn media_player(&self) -> MediaPlayerState {
+        let string = |uuid| String::from_utf8_lossy(&self.transport.value(&uuid).unwrap_or_default()).into_owned();
+        let flag = |uuid| self.transport.value(&uuid).and_then(|v| v.first().cloned()).unwrap_or(0) != 0;
+        let number = |uuid| self.transport.value(&uuid)
+            .and_then(|v| v.try_into().ok())
+            .map(u32::from_be_bytes)
+            .unwrap_or(0);
+        MediaPlayerState {
+            artist: string(uuids::CHR_MP_ARTIST),
+            album: string(uuids::CHR_MP_ALBUM),
+            track: string(uuids::CHR_MP_TRACK),
+            playing: flag(uuids::CHR_MP_STATUS),
+            position: number(uuids::CHR_MP_POSITION),
+            duration: number(uuids::CHR_MP_DURATION),
+            speed: number(uuids::CHR_MP_SPEED),
+            repeat: flag(uuids::CHR_MP_REPEAT),
+            shuffle: flag(uuids::CHR_MP_SHUFFLE),
+        }
+    }
+
+    // -- File system --
+
+    pub fn read_file(&self, path: &str) -> Option<Vec<u8>> {
+        self.fs.lock().unwrap().read(path)
+    }
+
+    /// Put file on the simulated file system, creating parent directories
+    pub fn write_file(&self, path: &str, content: &[u8]) {
+        self.fs.lock().unwrap().put(path, content);
+    }
+
+    pub fn file_paths(&self) -> Vec<String> {
+        self.fs.lock().unwrap().file_paths()
+    }
+
+    /// Make the file system service misbehave, e.g. to reproduce flaky connection
+    pub fn set_fs_fault(&self, fault: Option<FsFault>) {
+        self.fs.lock().unwrap().set_fault(fault);
+    }
+
+    // -- Firmware upgrade --
+
+    /// Firmware image received via DFU, after it was validated and activated
+    pub fn installed_firmware(&self) -> Option<Vec<u8>> {
+        self.dfu.lock().unwrap().installed().map(<[u8]>::to_vec)
+    }
+
+    /// Make the bootloader misbehave, e.g. to reproduce flaky connection
+    pub fn set_dfu_fault(&self, fault: Option<DfuFault>) {
+        self.dfu.lock().unwrap().set_fault(fault);
+    }
+}
+
+impl Default for Simulator {
+    fn default() -> Self {
+        Self::new()
+    }
+}
@@ -0,0 +1,204 @@
+//! Legacy Nordic DFU bootloader emulation, following the same
+//! state machine as the InfiniTime DFU service.
+
+use crate::{bt::MAX_FIRMWARE_SIZE, utils};
+
+
+// Control point opcodes
+const OP_START_DFU: u8 = 0x01;
+const OP_INIT_DFU_PARAMS: u8 = 0x02;
+const OP_RECEIVE_FIRMWARE_IMAGE: u8 = 0x03;
+const OP_VALIDATE_FIRMWARE: u8 = 0x04;
+const OP_ACTIVATE_IMAGE_AND_RESET: u8 = 0x05;
+const OP_SYS_RESET: u8 = 0x06;
+const OP_PACKET_RECEIPT_NOTIF_REQ: u8 = 0x08;
+const OP_RESPONSE: u8 = 0x10;
+const OP_PACKET_RECEIPT_NOTIF: u8 = 0x11;
+
+// Response values
+const RESP_SUCCESS: u8 = 0x01;
+const RESP_INVALID_STATE: u8 = 0x02;
+const RESP_NOT_SUPPORTED: u8 = 0x03;
+const RESP_DATA_SIZE_EXCEEDS_LIMIT: u8 = 0x04;
+const RESP_CRC_ERROR: u8 = 0x05;
+
+const IMAGE_TYPE_APPLICATION: u8 = 0x04;
+
+
+/// Bootloader misbehavior to inject into a DFU session
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DfuFault {
+    /// Stop responding after receiving the given number of firmware bytes
+    StallAfter(u32),
+    /// Lose the firmware packet with the given index (counting from 0),
+    /// as if it was dropped over the air. Only the first transfer is affected
+    LosePacket(u32),
+}
+
+#[derive(Debug, Default, PartialEq)]
+enum State {
+    #[default]
+    Idle,
+    WaitingImageSize,
+    WaitingInitCommand,
+    ReceivingInitPacket,
+    WaitingReceiveCommand,
+    ReceivingFirmware,
+    WaitingValidation,
+    WaitingActivation,
+}
+
+#[derive(Debug, Default)]
+pub struct Bootloader {
+    state: State,
+    image_size: u32,
+    init_packet: Vec<u8>,
+    firmware: Vec<u8>,
+    receipt_interval: u32,
+    packets_received: u32,
+    packets_since_receipt: u32,
+    installed: Option<Vec<u8>>,
+    fault: Option<DfuFault>,
+}
+
+impl Bootloader {
+    pub fn installed(&self) -> Option<&[u8]> {
+        self.installed.as_deref()
+    }
+
+    pub fn set_fault(&mut self, fault: Option<DfuFault>) {
+        self.fault = fault;
+    }
+
+    /// Handle control point write, return control point notification if any
+    pub fn handle_control(&mut self, req: &[u8]) -> Option<Vec<u8>> {
+        let opcode = *req.first()?;
+        if self.is_stalled() {
+            return None;
+        }
+        match (opcode, &self.state) {
+            (OP_START_DFU, _) => {
+                // Restarting is allowed at any point, like on the watch
+                *self = Self { installed: self.installed.take(), fault: self.fault, ..Default::default() };
+                if req.get(1) == Some(&IMAGE_TYPE_APPLICATION) {
+                    self.state = State::WaitingImageSize;
+                    None
+                } else {
+                    Some(response(opcode, RESP_NOT_SUPPORTED))
+                }
+            }
+            (OP_SYS_RESET, _) => {
+                log::info!("Simulator: DFU reset in state {:?}", self.state);
+                *self = Self { installed: self.installed.take(), fault: self.fault, ..Default::default() };
+                None
+            }
+            (OP_INIT_DFU_PARAMS, State::WaitingInitCommand) if req.get(1) == Some(&0x00) => {
+                self.state = State::ReceivingInitPacket;
+                None
+            }
+            (OP_INIT_DFU_PARAMS, State::ReceivingInitPacket) if req.get(1) == Some(&0x01) => {
+                self.state = State::WaitingReceiveCommand;
+                Some(response(opcode, RESP_SUCCESS))
+            }
+            (OP_PACKET_RECEIPT_NOTIF_REQ, State::WaitingReceiveCommand) => {
+                self.receipt_interval = *req.get(1)? as u32;
+                None
+            }
+            (OP_RECEIVE_FIRMWARE_IMAGE, State::WaitingReceiveCommand) => {
+                self.state = State::ReceivingFirmware;
+                None
+            }
+            (OP_VALIDATE_FIRMWARE, State::WaitingValidation) => {
+                if self.check_crc() {
+                    self.state = State::WaitingActivation;
+                    Some(response(opcode, RESP_SUCCESS))
+                } else {
+                    self.state = State::Idle;
+                    Some(response(opcode, RESP_CRC_ERROR))
+                }
+            }
+            (OP_ACTIVATE_IMAGE_AND_RESET, State::WaitingActivation) => {
+                log::info!("Simulator: firmware activated ({} bytes)", self.firmware.len());
+                self.installed = Some(std::mem::take(&mut self.firmware));
+                self.state = State::Idle;
+                None
+            }
+            _ => {
+                log::warn!("Simulator: unexpected DFU command {:02x?} in state {:?}", req, self.state);
+                Some(response(opcode, RESP_INVALID_STATE))
+            }
+        }
+    }
+
+    /// Handle packet characteristic write, return control point notification if any
+    pub fn handle_packet(&mut self, packet: &[u8]) -> Option<Vec<u8>> {
+        if self.is_stalled() {
+            return None;
+        }
+        match self.state {
+            State::WaitingImageSize => {
+                // Softdevice, bootloader and application sizes
+                let app_size = u32::from_le_bytes(packet.get(8..12)?.try_into().ok()?);
+                if app_size as usize > MAX_FIRMWARE_SIZE {
+                    self.state = State::Idle;
+                    Some(response(OP_START_DFU, RESP_DATA_SIZE_EXCEEDS_LIMIT))
+                } else {
+                    self.image_size = app_size;
+                    self.state = State::WaitingInitCommand;
+                    Some(response(OP_START_DFU, RESP_SUCCESS))
+                }
+            }
+            State::ReceivingInitPacket => {
+                self.init_packet.extend_from_slice(packet);
+                None
+            }
+            State::ReceivingFirmware => {
+                let index = self.packets_received;
+                self.packets_received += 1;
+                if self.fault == Some(DfuFault::LosePacket(index)) {
+                    self.fault = None;
+                } else {
+                    self.firmware.extend_from_slice(packet);
+                }
+                self.packets_since_receipt += 1;
+
+                if self.firmware.len() as u32 >= self.image_size {
+                    self.state = State::WaitingValidation;
+                    Some(response(OP_RECEIVE_FIRMWARE_IMAGE, RESP_SUCCESS))
+                } else if self.receipt_interval > 0 && self.packets_since_receipt >= self.receipt_interval {
+                    self.packets_since_receipt = 0;
+                    let bytes = (self.firmware.len() as u32).to_le_bytes();
+                    Some([[OP_PACKET_RECEIPT_NOTIF].as_slice(), &bytes].concat())
+                } else {
+                    None
+                }
+            }
+            _ => {
+                log::warn!("Simulator: unexpected DFU packet in state {:?}", self.state);
+                None
+            }
+        }
+    }
+
+    fn is_stalled(&self) -> bool {
+        match self.fault {
+            Some(DfuFault::StallAfter(bytes)) => self.firmware.len() as u32 >= bytes,
+            _ => false,
+        }
+    }
+
+    /// Verify firmware CRC against the one at the end of the init packet
+    fn check_crc(&self) -> bool {
+        let len = self.init_packet.len();
+        if len < 2 {
+            // Nothing to verify against
+            return true;
+        }
+        let expected = u16::from_le_bytes([self.init_packet[len - 2], self.init_packet[len - 1]]);
+        utils::crc16(&self.firmware) == expected
+    }
+}
+
+fn response(opcode: u8, value: u8) -> Vec<u8> {
+    vec![OP_RESPONSE, opcode, value]
+}
@@ -0,0 +1,403 @@
+//! BLE FS service emulation on top of an in-memory file system,
+//! which reports errors the same way LittleFS does on the watch.
+
+use crate::bt::fs_msg::{Command, Status};
+use std::collections::BTreeMap;
+
+pub const VERSION: u16 = 1;
+
+/// Size of the LittleFS partition on PineTime external flash
+const CAPACITY: usize = 0x34c000;
+/// LittleFS default LFS_NAME_MAX
+const NAME_MAX: usize = 255;
+
+
+/// File system service misbehavior to inject
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FsFault {
+    /// Hold back the response to the write request with the given index
+    /// (counting from 0 since the fault is set, init requests included)
+    /// until the next request, as if it was late over the air
+    DelayWriteResponse(u32),
+}
+
+#[derive(Debug)]
+enum Node {
+    File { content: Vec<u8>, timestamp: u64 },
+    Dir { timestamp: u64 },
+}
+
+#[derive(Debug)]
+struct PendingWrite {
+    path: String,
+    end: u32,
+    timestamp: u64,
+}
+
+#[derive(Debug)]
+pub struct FileSystem {
+    nodes: BTreeMap<String, Node>,
+    pending_read: Option<String>,
+    pending_write: Option<PendingWrite>,
+    fault: Option<FsFault>,
+    write_responses: u32,
+    delayed_response: Option<Vec<u8>>,
+}
+
+impl Default for FileSystem {
+    fn default() -> Self {
+        let mut nodes = BTreeMap::new();
+        nodes.insert(String::from("/"), Node::Dir { timestamp: 0 });
+        Self {
+            nodes,
+            pending_read: None,
+            pending_write: None,
+            fault: None,
+            write_responses: 0,
+            delayed_response: None,
+        }
+    }
+}
+
+impl FileSystem {
+    pub fn read(&self, path: &str) -> Option<Vec<u8>> {
+        match self.nodes.get(&normalize(path)) {
+            Some(Node::File { content, .. }) => Some(content.clone()),
+            _ => None,
+        }
+    }
+
+    pub fn put(&mut self, path: &str, content: &[u8]) {
+        let path = normalize(path);
+        let mut dir = parent(&path);
+        while let Some(d) = dir {
+            self.nodes.entry(d.to_string()).or_insert(Node::Dir { timestamp: 0 });
+            dir = parent(d);
+        }
+        self.nodes.insert(path, Node::File { content: content.to_vec(), timestamp: 0 });
+    }
+
+    pub fn file_paths(&self) -> Vec<String> {
+        self.nodes.iter()
+            .filter(|(_, node)| matches!(node, Node::File { .. }))
+            .map(|(path, _)| path.clone())
+            .collect()
+    }
+
+    pub fn set_fault(&mut self, fault: Option<FsFault>) {
+        self.fault = fault;
+        self.write_responses = 0;
+    }
+
+    /// Handle raw request written to FS transfer characteristic,
+    /// return raw responses to be notified back
+    pub fn handle_request(&mut self, req: &[u8]) -> Vec<Vec<u8>> {
+        let command = req.first().and_then(|c| Command::try_from(*c).ok());
+        // Late response arrives before the one to the current request
+        let mut responses: Vec<_> = self.delayed_response.take().into_iter().collect();
+        let response = match command {
+            Some(Command::ReadInit) => self.read_init(req),
+            Some(Command::ReadChunk) => self.read_chunk(req),
+            Some(Command::WriteInit) => self.write_init(req),
+            Some(Command::WriteChunk) => self.write_chunk(req),
+            Some(Command::Delete) => self.delete(req),
+            Some(Command::MakeDir) => self.make_dir(req),
+            Some(Command::ListDir) => {
+                responses.extend(self.list_dir(req));
+                return responses;
+            }
+            Some(Command::Move) => self.move_node(req),
+            _ => {
+                log::warn!("Simulator: unexpected FS request: {:02x?}", req);
+                None
+            }
+        };
+        if matches!(command, Some(Command::WriteInit | Command::WriteChunk)) {
+            let index = self.write_responses;
+            self.write_responses += 1;
+            if self.fault == Some(FsFault::DelayWriteResponse(index)) {
+                self.fault = None;
+                self.delayed_response = response;
+                return responses;
+            }
+        }
+        responses.extend(response);
+        responses
+    }
+
+    fn used_space(&self) -> usize {
+        self.nodes.values()
+            .map(|node| match node {
+                Node::File { content, .. } => content.len(),
+                Node::Dir { .. } => 0,
+            })
+            .sum()
+    }
+
+    fn read_init(&mut self, req: &[u8]) -> Option<Vec<u8>> {
+        let path_len = u16_at(req, 2)? as usize;
+        let offset = u32_at(req, 4)?;
+        let chunk_size = u32_at(req, 8)?;
+        let path = normalize(str_at(req, 12, path_len)?);
+        self.pending_read = None;
+        let result = match self.nodes.get(&path) {
+            Some(Node::File { .. }) => {
+                self.pending_read = Some(path);
+                self.read_response(offset, chunk_size)
+            }
+            Some(Node::Dir { .. }) => read_resp(Status::IsDir, 0, 0, &[]),
+            None => read_resp(Status::NoDirectoryEntry, 0, 0, &[]),
+        };
+        Some(result)
+    }
+
+    fn read_chunk(&mut self, req: &[u8]) -> Option<Vec<u8>> {
+        let offset = u32_at(req, 4)?;
+        let chunk_size = u32_at(req, 8)?;
+        Some(self.read_response(offset, chunk_size))
+    }
+
+    fn read_response(&self, offset: u32, chunk_size: u32) -> Vec<u8> {
+        let content = self.pending_read.as_ref()
+            .and_then(|path| self.nodes.get(path));
+        match content {
+            Some(Node::File { content, .. }) => {
+                let start = (offset as usize).min(content.len());
+                let end = (start + chunk_size as usize).min(content.len());
+                read_resp(Status::Ok, offset, content.len() as u32, &content[start..end])
+            }
+            _ => read_resp(Status::InvalidParam, offset, 0, &[]),
+        }
+    }
+
+    fn write_init(&mut self, req: &[u8]) -> Option<Vec<u8>> {
+        let path_len = u16_at(req, 2)? as usize;
+        let position = u32_at(req, 4)?;
+        let timestamp = u64_at(req, 8)?;
+        let length = u32_at(req, 16)?;
+        let path = normalize(str_at(req, 20, path_len)?);
+        self.pending_write = None;
+
+        let status = self.check_new_path(&path);
+        if status != Status::Ok && status != Status::Exists {
+            return Some(write_resp(status, 0, timestamp, 0));
+        }
+        if let Some(Node::Dir { .. }) = self.nodes.get(&path) {
+            return Some(write_resp(Status::IsDir, 0, timestamp, 0));
+        }
+        let existing = match self.nodes.remove(&path) {
+            Some(Node::File { mut content, .. }) => {
+                content.truncate(position as usize);
+                content
+            }
+            _ => Vec::new(),
+        };
+        if self.used_space() + position as usize + length as usize > CAPACITY {
+            return Some(write_resp(Status::NoSpaceLeft, 0, timestamp, 0));
+        }
+        self.nodes.insert(path.clone(), Node::File { content: existing, timestamp });
+        self.pending_write = Some(PendingWrite { path, end: position + length, timestamp });
+        Some(write_resp(Status::Ok, position, timestamp, length))
+    }
+
+    fn write_chunk(&mut self, req: &[u8]) -> Option<Vec<u8>> {
+        let offset = u32_at(req, 4)?;
+        let size = u32_at(req, 8)? as usize;
+        let data = req.get(12..12 + size)?;
+        let Some(pending) = &mut self.pending_write else {
+            return Some(write_resp(Status::InvalidParam, offset, 0, 0));
+        };
+        let timestamp = pending.timestamp;
+        let Some(Node::File { content, .. }) = self.nodes.get_mut(&pending.path) else {
+            return Some(write_resp(Status::NoDirectoryEntry, offset, timestamp, 0));
+        };
+        let start = offset as usize;
+        if start > content.len() {
+            content.resize(start, 0);
+        }
+        let end = start + data.len();
+        if end > content.len() {
+            content.resize(end, 0);
+        }
+        content[start..end].copy_from_slice(data);
+        let remained = pending.end.saturating_sub(end as u32);
+        if remained == 0 {
+            self.pending_write = None;
+        }
+        Some(write_resp(Status::Ok, offset, timestamp, remained))
+    }
+
+    fn delete(&mut self, req: &[u8]) -> Option<Vec<u8>> {
+        let path_len = u16_at(req, 2)? as usize;
+        let path = normalize(str_at(req, 4, path_len)?);
+        let status = match self.nodes.get(&path) {
+            None => Status::NoDirectoryEntry,
+            Some(Node::Dir { .. }) if self.children(&path).next().is_some() => Status::NotEmpty,
+            Some(_) if path == "/" => Status::InvalidParam,
+            Some(_) => {
+                self.nodes.remove(&path);
+                Status::Ok
+            }
+        };
+        Some(vec![Command::DeleteResp as u8, status as u8])
+    }
+
+    fn make_dir(&mut self, req: &[u8]) -> Option<Vec<u8>> {
+        let path_len = u16_at(req, 2)? as usize;
+        let timestamp = u64_at(req, 8)?;
+        let path = normalize(str_at(req, 16, path_len)?);
+        let status = self.check_new_path(&path);
+        if status == Status::Ok {
+            self.nodes.insert(path, Node::Dir { timestamp });
+        }
+        let mut resp = vec![Command::MakeDirResp as u8, status as u8, 0, 0, 0, 0, 0, 0];
+        resp.extend_from_slice(&timestamp.to_le_bytes());
+        Some(resp)
+    }
+
+    fn list_dir(&self, req: &[u8]) -> Vec<Vec<u8>> {
+        let Some(path) = u16_at(req, 2).and_then(|len| str_at(req, 4, len as usize)) else {
+            return Vec::new();
+        };
+        let path = normalize(path);
+        match self.nodes.get(&path) {
+            Some(Node::Dir { timestamp }) => {
+                // LittleFS lists "." and ".." as well
+                let mut entries = vec![
+                    (".", 1, *timestamp, 0),
+                    ("..", 1, 0, 0),
+                ];
+                for (child, node) in self.children(&path) {
+                    let name = child.rsplit('/').next().unwrap_or(child);
+                    entries.push(match node {
+                        Node::Dir { timestamp } => (name, 1, *timestamp, 0),
+                        Node::File { content, timestamp } => (name, 0, *timestamp, content.len() as u32),
+                    });
+                }
+                let total = entries.len() as u32;
+                entries.into_iter().enumerate()
+                    .map(|(idx, (name, flags, timestamp, size))| {
+                        list_dir_resp(Status::Ok, idx as u32, total, flags, timestamp, size, name)
+                    })
+                    .collect()
+            }
+            Some(Node::File { .. }) => vec![list_dir_resp(Status::NotDir, 0, 1, 0, 0, 0, "")],
+            None => vec![list_dir_resp(Status::NoDirectoryEntry, 0, 1, 0, 0, 0, "")],
+        }
+    }
+
+    fn move_node(&mut self, req: &[u8]) -> Option<Vec<u8>> {
+        let old_len = u16_at(req, 2)? as usize;
+        let new_len = u16_at(req, 4)? as usize;
+        let old_path = normalize(str_at(req, 6, old_len)?);
+        let new_path = normalize(str_at(req, 7 + old_len, new_len)?);
+        let status = if !self.nodes.contains_key(&old_path) {
+            Status::NoDirectoryEntry
+        } else {
+            match self.check_new_path(&new_path) {
+                Status::Ok => {
+                    let prefix = format!("{}/", old_path);
+                    let moved = self.nodes.keys()
+                        .filter(|p| **p == old_path || p.starts_with(&prefix))
+                        .cloned()
+                        .collect::<Vec<_>>();
+                    for path in moved {
+                        let node = self.nodes.remove(&path).unwrap();
+                        let path = format!("{}{}", new_path, &path[old_path.len()..]);
+                        self.nodes.insert(path, node);
+                    }
+                    Status::Ok
+                }
+                status => status,
+            }
+        };
+        Some(vec![Command::MoveResp as u8, status as u8])
+    }
+
+    /// Check whether a new node can be created at the path
+    fn check_new_path(&self, path: &str) -> Status {
+        if path.rsplit('/').next().map_or(0, str::len) > NAME_MAX {
+            return Status::NameTooLong;
+        }
+        if self.nodes.contains_key(path) {
+            return Status::Exists;
+        }
+        match parent(path).and_then(|p| self.nodes.get(p)) {
+            Some(Node::Dir { .. }) => Status::Ok,
+            Some(Node::File { .. }) => Status::NotDir,
+            None => Status::NoDirectoryEntry,
+        }
+    }
+
+    fn children<'s>(&'s self, dir: &'s str) -> impl Iterator<Item = (&'s String, &'s Node)> {
+        self.nodes.iter()
+            .filter(move |(path, _)| path.as_str() != "/" && parent(path) == Some(dir))
+    }
+}
+
+
+// -- Helpers --
+
+fn normalize(path: &str) -> String {
+    let trimmed = path.trim_matches('/');
+    format!("/{}", trimmed)
+}
+
+fn parent(path: &str) -> Option<&str> {
+    match path.rsplit_once('/')? {
+        ("", "") => None,
+        ("", _) => Some("/"),
+        (parent, _) => Some(parent),
+    }
+}
+
+fn u16_at(data: &[u8], offset: usize) -> Option<u16> {
+    Some(u16::from_le_bytes(data.get(offset..offset + 2)?.try_into().ok()?))
+}
+
+fn u32_at(data: &[u8], offset: usize) -> Option<u32> {
+    Some(u32::from_le_bytes(data.get(offset..offset + 4)?.try_into().ok()?))
+}
+
+fn u64_at(data: &[u8], offset: usize) -> Option<u64> {
+    Some(u64::from_le_bytes(data.get(offset..offset + 8)?.try_into().ok()?))
+}
+
+fn str_at(data: &[u8], offset: usize, len: usize) -> Option<&str> {
+    std::str::from_utf8(data.get(offset..offset + len)?).ok()
+}
+
+fn read_resp(status: Status, offset: u32, total_size: u32, data: &[u8]) -> Vec<u8> {
+    [
+        [Command::ReadResp as u8, status as u8, 0x00, 0x00].as_slice(),
+        &offset.to_le_bytes(),
+        &total_size.to_le_bytes(),
+        &(data.len() as u32).to_le_bytes(),
+        data,
+    ].concat()
+}
+
+fn write_resp(status: Status, offset: u32, timestamp: u64, remained: u32) -> Vec<u8> {
+    [
+        [Command::WriteResp as u8, status as u8, 0x00, 0x00].as_slice(),
+        &offset.to_le_bytes(),
+        &timestamp.to_le_bytes(),
+        &remained.to_le_bytes(),
+    ].concat()
+}
+
+fn list_dir_resp(
+    status: Status, entry_idx: u32, entries_total: u32, flags: u32, timestamp: u64, size: u32, path: &str
+) -> Vec<u8> {
+    let path = path.as_bytes();
+    [
+        [Command::ListDirResp as u8, status as u8].as_slice(),
+        &(path.len() as u16).to_le_bytes(),
+        &entry_idx.to_le_bytes(),
+        &entries_total.to_le_bytes(),
+        &flags.to_le_bytes(),
+        &timestamp.to_le_bytes(),
+        &size.to_le_bytes(),
+        path,
+    ].concat()
+}
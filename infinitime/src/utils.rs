@@ -9,6 +9,20 @@ impl<F: Fn() -> ()> Drop for ScopeGuard<F> {
 }
 
 
+/// CRC-16/CCITT-FALSE, as used by Nordic DFU
+pub fn crc16(data: &[u8]) -> u16 {
+    let mut crc: u16 = 0xffff;
+    for byte in data {
+        crc = crc.swap_bytes();
+        crc ^= *byte as u16;
+        crc ^= (crc & 0xff) >> 4;
+        crc ^= crc << 12;
+        crc ^= (crc & 0xff) << 5;
+    }
+    crc
+}
+
+
 /// Declare enum that is convertible from a primitive
 /// type via automatic TryFrom implementation
 macro_rules! value_enum {
@@ -24,12 +38,12 @@ macro_rules! value_enum {
         }
 
         impl TryFrom<$type> for $name {
-            type Error = anyhow::Error;
+            type Error = crate::Error;
 
             fn try_from(v: $type) -> Result<Self, Self::Error> {
                 match v {
                     $(x if x == Self::$variant as $type => Ok(Self::$variant),)*
-                    _ => Err(anyhow::anyhow!("Invalid enum value: {}", v)),
+                    _ => Err(crate::Error::Protocol(format!("Invalid enum value: {}", v))),
                 }
             }
         }
@@ -0,0 +1,253 @@
+//! Release sources against a local HTTP stand-in for a release server
+
+use infinitime::{
+    gh::{AssetKind, ReleaseCache, ReleaseSource},
+    Error,
+};
+use std::{
+    collections::HashMap,
+    net::SocketAddr,
+    path::PathBuf,
+    sync::{Arc, Mutex},
+};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+};
+
+const ETAG: &str = "\"releases-v1\"";
+
+/// Request as seen by the server, header names are lowercase
+#[derive(Debug, Clone)]
+struct Request {
+    path: String,
+    headers: HashMap<String, String>,
+}
+
+/// Minimal HTTP/1.1 server which serves fixed files and records requests.
+/// Every response closes the connection.
+#[derive(Clone)]
+struct Server {
+    addr: SocketAddr,
+    files: Arc<Mutex<HashMap<String, Vec<u8>>>>,
+    requests: Arc<Mutex<Vec<Request>>>,
+}
+
+impl Server {
+    async fn start() -> Self {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let server = Self {
+            addr: listener.local_addr().unwrap(),
+            files: Default::default(),
+            requests: Default::default(),
+        };
+        let handler = server.clone();
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                tokio::spawn(handler.clone().serve(stream));
+            }
+        });
+        server
+    }
+
+    fn url(&self, path: &str) -> String {
+        format!("http://{}{}", self.addr, path)
+    }
+
+    fn put(&self, path: &str, content: impl Into<Vec<u8>>) {
+        self.files.lock().unwrap().insert(path.to_string(), content.into());
+    }
+
+    fn requests(&self, path: &str) -> Vec<Request> {
+        self.requests.lock().unwrap().iter().filter(|r| r.path == path).cloned().collect()
+    }
+
+    async fn serve(self, mut stream: TcpStream) {
+        let mut data = Vec::new();
+        let mut buf = [0; 1024];
+        while !data.windows(4).any(|w| w == b"\r\n\r\n") {
+            match stream.read(&mut buf).await {
+                Ok(0) | Err(_) => return,
+                Ok(n) => data.extend_from_slice(&buf[..n]),
+            }
+        }
+        let head = String::from_utf8_lossy(&data).to_string();
+        let mut lines = head.lines();
+        let path = lines.next().unwrap().split(' ').nth(1).unwrap().to_string();
+        let headers = lines
+            .filter_map(|line| line.split_once(": "))
+            .map(|(name, value)| (name.to_lowercase(), value.to_string()))
+            .collect::<HashMap<_, _>>();
+        let request = Request { path, headers };
+        self.requests.lock().unwrap().push(request.clone());
+
+        let file = self.files.lock().unwrap().get(&request.path).cloned();
+        let response = match file {
+            Some(_) if request.headers.get("if-none-match").map(String::as_str) == Some(ETAG) => {
+                format!("HTTP/1.1 304 Not Modified\r\nETag: {}\r\nConnection: close\r\n\r\n", ETAG).into_bytes()
+            }
+            Some(content) => {
+                let mut response = format!(
+                    "HTTP/1.1 200 OK\r\nContent-Length: {}\r\nETag: {}\r\nConnection: close\r\n\r\n",
+                    content.len(), ETAG
+                ).into_bytes();
+                response.extend_from_slice(&content);
+                response
+            }
+            None => b"HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n".to_vec(),
+        };
+        let _ = stream.write_all(&response).await;
+        let _ = stream.shutdown().await;
+    }
+}
+
+/// Index with one release, whose assets are relative to it
+fn index() -> String {
+    String::from(r#"[
+        {"name": "InfiniTime 1.14.0", "tag_name": "1.14.0", "assets": [
+            {"name": "pinetime-mcuboot-app-dfu-1.14.0.zip", "browser_download_url": "1.14.0/dfu.zip", "size": 3},
+            {"name": "infinitime-resources-1.14.0.zip", "browser_download_url": "/files/resources.zip"}
+        ]},
+        {"tag_name": "1.13.0"}
+    ]"#)
+}
+
+async fn index_server() -> (Server, ReleaseSource) {
+    let server = Server::start().await;
+    server.put("/fw/index.json", index());
+    server.put("/fw/1.14.0/dfu.zip", "dfu");
+    server.put("/files/resources.zip", "resources");
+    let source = ReleaseSource::new("http-index", &server.url("/fw/index.json")).unwrap();
+    (server, source)
+}
+
+/// Empty directory, unique to the test
+fn temp_dir(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("watchmate-{}-{}", name, std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+
+#[tokio::test]
+async fn http_index_releases() {
+    let (server, source) = index_server().await;
+
+    let releases = source.list_releases().await.unwrap();
+    assert_eq!(releases.len(), 2);
+    assert_eq!(releases[0].name, "InfiniTime 1.14.0");
+    assert_eq!(releases[1].name, "1.13.0");
+    let assets = &releases[0].assets;
+    assert_eq!(assets[0].url, server.url("/fw/1.14.0/dfu.zip"));
+    assert_eq!(assets[1].url, server.url("/files/resources.zip"));
+
+    let dfu = source.download(&assets[0], AssetKind::Firmware, None, None).await.unwrap();
+    assert_eq!(dfu, b"dfu");
+    let resources = source.download(&assets[1], AssetKind::Resources, None, None).await.unwrap();
+    assert_eq!(resources, b"resources");
+}
+
+#[tokio::test]
+async fn http_index_with_file_asset() {
+    let server = Server::start().await;
+    server.put("/index.json", r#"[{"tag_name": "1.14.0", "assets": [
+        {"name": "pinetime-mcuboot-app-dfu-1.14.0.zip", "browser_download_url": "file:///etc/passwd"}
+    ]}]"#);
+    let source = ReleaseSource::new("http-index", &server.url("/index.json")).unwrap();
+    assert!(matches!(source.list_releases().await, Err(Error::InvalidSource(_))));
+}
+
+#[tokio::test]
+async fn missing_asset() {
+    let (server, source) = index_server().await;
+    let releases = source.list_releases().await.unwrap();
+    server.files.lock().unwrap().remove("/fw/1.14.0/dfu.zip");
+    let result = source.download(&releases[0].assets[0], AssetKind::Firmware, None, None).await;
+    assert!(matches!(result, Err(Error::HttpStatus(status)) if status.as_u16() == 404));
+}
+
+#[tokio::test]
+async fn cached_releases() {
+    let (server, source) = index_server().await;
+    let dir = temp_dir("cached-releases");
+    let cache = ReleaseCache::new(&dir, source);
+
+    let list = cache.list_releases().await.unwrap();
+    assert_eq!(list.releases.len(), 2);
+    assert!(list.cached_at.is_none());
+
+    // Unchanged list is not sent again
+    let list = cache.list_releases().await.unwrap();
+    assert_eq!(list.releases.len(), 2);
+    let requests = server.requests("/fw/index.json");
+    assert_eq!(requests.len(), 2);
+    assert_eq!(requests[0].headers.get("if-none-match"), None);
+    assert_eq!(requests[1].headers.get("if-none-match").map(String::as_str), Some(ETAG));
+
+    // Cached list is used when the server fails
+    server.files.lock().unwrap().remove("/fw/index.json");
+    let list = cache.list_releases().await.unwrap();
+    assert_eq!(list.releases.len(), 2);
+    assert!(list.cached_at.is_some());
+
+    // Assets are downloaded once
+    let asset = &list.releases[0].assets[0];
+    for _ in 0..2 {
+        assert_eq!(cache.download("1.14.0", asset, AssetKind::Firmware, None).await.unwrap(), b"dfu");
+    }
+    assert_eq!(server.requests("/fw/1.14.0/dfu.zip").len(), 1);
+
+    std::fs::remove_dir_all(dir).unwrap();
+}
+
+#[tokio::test]
+async fn token_is_not_sent_to_other_sources() {
+    let (server, source) = index_server().await;
+    let dir = temp_dir("token-not-sent");
+    let cache = ReleaseCache::new(&dir, source).with_token(Some(String::from("secret")));
+
+    let list = cache.list_releases().await.unwrap();
+    cache.download("1.14.0", &list.releases[0].assets[0], AssetKind::Firmware, None).await.unwrap();
+    let requests = server.requests.lock().unwrap().clone();
+    assert_eq!(requests.len(), 2);
+    assert!(requests.iter().all(|r| !r.headers.contains_key("authorization")));
+
+    std::fs::remove_dir_all(dir).unwrap();
+}
+
+#[tokio::test]
+async fn cached_assets_by_release() {
+    let server = Server::start().await;
+    server.put("/index.json", r#"[
+        {"tag_name": "1.14.0", "assets": [{"name": "resources.zip", "browser_download_url": "/1.14.0.zip"}]},
+        {"tag_name": "1.13.0", "assets": [{"name": "resources.zip", "browser_download_url": "/1.13.0.zip"}]}
+    ]"#);
+    server.put("/1.14.0.zip", "new");
+    server.put("/1.13.0.zip", "old");
+    let source = ReleaseSource::new("http-index", &server.url("/index.json")).unwrap();
+    let dir = temp_dir("cached-by-release");
+    let cache = ReleaseCache::new(&dir, source);
+
+    // Same-named assets of different releases don't replace each other
+    let list = cache.list_releases().await.unwrap();
+    for _ in 0..2 {
+        for (release, content) in list.releases.iter().zip([b"new", b"old"]) {
+            let asset = &release.assets[0];
+            assert_eq!(cache.download(&release.tag, asset, AssetKind::Resources, None).await.unwrap(), content);
+        }
+    }
+    assert_eq!(server.requests("/1.14.0.zip").len(), 1);
+    assert_eq!(server.requests("/1.13.0.zip").len(), 1);
+
+    let mut tags = cache.assets().await.unwrap().into_iter().map(|a| a.tag).collect::<Vec<_>>();
+    tags.sort();
+    assert_eq!(tags, ["1.13.0", "1.14.0"]);
+    assert!(dir.join("assets/1.13.0/resources.zip").is_file());
+
+    cache.remove("1.13.0", "resources.zip").await.unwrap();
+    assert!(!dir.join("assets/1.13.0").exists());
+    assert!(matches!(cache.remove("..", "resources.zip").await, Err(Error::Archive(_))));
+
+    std::fs::remove_dir_all(dir).unwrap();
+}
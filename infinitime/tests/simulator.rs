@@ -0,0 +1,419 @@
+//! Integration tests driving `InfiniTime` over the in-process transport,
+//! with and without the simulated watch behind it.
+
+use futures::StreamExt;
+use infinitime::{
+    bluer::Address,
+    bt::{self, uuids, FakeTransport, MediaPlayerEvent},
+    sim::{DfuFault, FsFault, Simulator},
+    Error,
+};
+use std::{io::{Cursor, Write}, time::Duration};
+use zip::{write::FileOptions, ZipWriter};
+
+
+const FIRMWARE_SIZE: usize = 5000;
+
+
+// -- Helpers --
+
+fn zip(files: &[(&str, &[u8])]) -> Vec<u8> {
+    let mut zip = ZipWriter::new(Cursor::new(Vec::new()));
+    for (name, content) in files {
+        zip.start_file(*name, FileOptions::default()).unwrap();
+        zip.write_all(content).unwrap();
+    }
+    zip.finish().unwrap().into_inner()
+}
+
+/// CRC-16/CCITT-FALSE, as used by the legacy DFU init packet
+fn crc16(data: &[u8]) -> u16 {
+    let mut crc = 0xffffu16;
+    for byte in data {
+        crc = crc.swap_bytes();
+        crc ^= *byte as u16;
+        crc ^= (crc & 0xff) >> 4;
+        crc ^= crc << 12;
+        crc ^= (crc & 0xff) << 5;
+    }
+    crc
+}
+
+fn firmware() -> Vec<u8> {
+    (0..FIRMWARE_SIZE).map(|i| (i * 7 % 251) as u8).collect()
+}
+
+fn dfu_package(firmware: &[u8]) -> Vec<u8> {
+    dfu_package_with_crc(firmware, crc16(firmware))
+}
+
+fn dfu_package_with_crc(firmware: &[u8], crc: u16) -> Vec<u8> {
+    let init_packet = [
+        0x0052u16.to_le_bytes().as_slice(),  // device type
+        &0xffffu16.to_le_bytes(),            // device revision
+        &0xffffffffu32.to_le_bytes(),        // application version
+        &1u16.to_le_bytes(),                 // softdevice count
+        &0xfffeu16.to_le_bytes(),            // any softdevice
+        &crc.to_le_bytes(),
+    ].concat();
+    let manifest = r#"{"manifest": {
+        "application": {"bin_file": "pinetime-mcuboot-app-image.bin", "dat_file": "pinetime-mcuboot-app-image.dat"},
+        "dfu_version": 0.5
+    }}"#;
+    zip(&[
+        ("manifest.json", manifest.as_bytes()),
+        ("pinetime-mcuboot-app-image.dat", &init_packet),
+        ("pinetime-mcuboot-app-image.bin", firmware),
+    ])
+}
+
+fn resources_bundle() -> Vec<u8> {
+    let manifest = r#"{
+        "resources": [
+            {"filename": "teko.bin", "path": "/fonts/teko.bin"},
+            {"filename": "lv_font_dots_40.bin", "path": "/fonts/lv_font_dots_40.bin"},
+            {"filename": "7segments_40.bin", "path": "/fonts/7segments_40.bin"}
+        ],
+        "obsolete_files": [
+            {"path": "/fonts/old.bin", "since": "1.11.0"},
+            {"path": "/fonts/future.bin", "since": "99.0.0"}
+        ]
+    }"#;
+    zip(&[
+        ("resources.json", manifest.as_bytes()),
+        ("teko.bin", &[1; 300]),
+        ("lv_font_dots_40.bin", &[2; 10]),
+        ("7segments_40.bin", &[]),
+    ])
+}
+
+
+// -- Fake transport --
+
+#[tokio::test]
+async fn fake_transport_reads_and_notifies() {
+    let transport = FakeTransport::new(Address::new([1, 2, 3, 4, 5, 6]));
+    transport.add_characteristic(uuids::CHR_BATTERY_LEVEL, [80]);
+    transport.add_characteristic(uuids::CHR_FIRMWARE_REVISION, "1.13.0");
+    let infinitime = bt::InfiniTime::with_transport(transport.clone());
+
+    assert_eq!(infinitime.address(), Address::new([1, 2, 3, 4, 5, 6]));
+    assert!(infinitime.device().is_none());
+    assert_eq!(infinitime.read_battery_level().await.unwrap(), 80);
+    assert_eq!(infinitime.read_firmware_version().await.unwrap(), "1.13.0");
+
+    let stream = infinitime.get_battery_level_stream().await.unwrap();
+    futures::pin_mut!(stream);
+    transport.notify_value(uuids::CHR_BATTERY_LEVEL, [79]);
+    assert_eq!(stream.next().await, Some(79));
+    assert_eq!(infinitime.read_battery_level().await.unwrap(), 79);
+}
+
+#[tokio::test]
+async fn fake_transport_missing_characteristic() {
+    let transport = FakeTransport::new(Address::any());
+    let infinitime = bt::InfiniTime::with_transport(transport);
+
+    let result = infinitime.read_step_count().await;
+    assert!(matches!(result, Err(Error::CharacteristicNotFound(uuid)) if uuid == uuids::CHR_STEP_COUNT));
+}
+
+
+// -- File system --
+
+#[tokio::test]
+async fn write_read_list_delete_file() {
+    let sim = Simulator::new();
+    let infinitime = sim.infinitime();
+
+    // Spans several chunks
+    let content: Vec<u8> = (0..1000).map(|i| i as u8).collect();
+    infinitime.make_dirs("/data/notes/todo.txt", None).await.unwrap();
+    infinitime.write_file("/data/notes/todo.txt", &content, 0, None, None).await.unwrap();
+    assert_eq!(sim.read_file("/data/notes/todo.txt"), Some(content.clone()));
+
+    let read = infinitime.read_file("/data/notes/todo.txt", 0, None, None).await.unwrap();
+    assert_eq!(read, content);
+    let tail = infinitime.read_file("/data/notes/todo.txt", 900, None, None).await.unwrap();
+    assert_eq!(tail, &content[900..]);
+
+    let entries = infinitime.list_dir("/data/notes", None).await.unwrap();
+    let names: Vec<_> = entries.iter().map(|e| e.path.as_str()).collect();
+    assert_eq!(names, [".", "..", "todo.txt"]);
+    assert_eq!(entries[2].size, 1000);
+    assert!(!entries[2].is_dir);
+    assert!(entries[0].is_dir);
+
+    infinitime.move_file("/data/notes/todo.txt", "/data/done.txt", None).await.unwrap();
+    assert_eq!(sim.file_paths(), ["/data/done.txt"]);
+
+    infinitime.delete_file("/data/done.txt", None).await.unwrap();
+    assert!(sim.file_paths().is_empty());
+}
+
+#[tokio::test]
+async fn read_missing_file() {
+    let sim = Simulator::new();
+    let infinitime = sim.infinitime();
+
+    let result = infinitime.read_file("/missing.txt", 0, None, None).await;
+    assert!(matches!(result, Err(Error::FileSystem(bt::FsStatus::NoDirectoryEntry))));
+    let result = infinitime.delete_file("/missing.txt", None).await;
+    assert!(matches!(result, Err(Error::FileSystem(_))));
+}
+
+#[tokio::test]
+async fn cancelled_write_removes_partial_file() {
+    let sim = Simulator::new();
+    let infinitime = sim.infinitime();
+    let token = bt::CancellationToken::new();
+    // Progress channel without slack, so the transfer waits for the receiver
+    let (progress_tx, mut progress_rx) = bt::progress_channel(1);
+
+    let content = vec![0xaa; 2000];
+    let write = infinitime.write_file("/big.bin", &content, 0, Some(progress_tx), Some(&token));
+    let cancel = async {
+        // Cancel once the first chunk is acknowledged
+        while let Some(event) = progress_rx.recv().await {
+            if let bt::ProgressEvent::Numbers { current, .. } = event {
+                if current > 0 {
+                    token.cancel();
+                }
+            }
+        }
+    };
+    let (result, ()) = futures::join!(write, cancel);
+
+    assert!(matches!(result, Err(Error::Cancelled)));
+    assert!(sim.file_paths().is_empty());
+}
+
+#[tokio::test(start_paused = true)]
+async fn late_chunk_response_removes_partial_file() {
+    let sim = Simulator::new();
+    let infinitime = sim.infinitime();
+    // Response to the second chunk comes only after the next request
+    sim.set_fs_fault(Some(FsFault::DelayWriteResponse(2)));
+
+    let result = infinitime.write_file("/big.bin", &[0xaa; 1000], 0, None, None).await;
+    assert!(matches!(result, Err(Error::Timeout)));
+    assert!(sim.file_paths().is_empty());
+
+    // The late response doesn't confuse later requests
+    infinitime.write_file("/big.bin", &[0xbb; 1000], 0, None, None).await.unwrap();
+    assert_eq!(sim.read_file("/big.bin"), Some(vec![0xbb; 1000]));
+}
+
+#[tokio::test(start_paused = true)]
+async fn late_init_response_removes_created_file() {
+    let sim = Simulator::new();
+    let infinitime = sim.infinitime();
+    sim.set_fs_fault(Some(FsFault::DelayWriteResponse(0)));
+
+    let result = infinitime.write_file("/big.bin", &[0xaa; 1000], 0, None, None).await;
+    assert!(matches!(result, Err(Error::Timeout)));
+    assert!(sim.file_paths().is_empty());
+}
+
+#[tokio::test]
+async fn refused_write_keeps_existing_dir() {
+    let sim = Simulator::new();
+    let infinitime = sim.infinitime();
+    infinitime.make_dir("/fonts", None).await.unwrap();
+
+    // Directory in place of the file
+    let result = infinitime.write_file("/fonts", b"data", 0, None, None).await;
+    assert!(matches!(result, Err(Error::FileSystem(_))));
+    let entries = infinitime.list_dir("/", None).await.unwrap();
+    assert!(entries.iter().any(|e| e.path == "fonts" && e.is_dir));
+}
+
+
+#[tokio::test(start_paused = true)]
+async fn cancel_pending_fs_request() {
+    // The watch never responds
+    let transport = FakeTransport::new(Address::any());
+    transport.on_write(uuids::CHR_FS_TRANSFER, |_, _| Ok(()));
+    let infinitime = bt::InfiniTime::with_transport(transport);
+    let token = bt::CancellationToken::new();
+
+    let started = tokio::time::Instant::now();
+    let cancel = async {
+        tokio::time::sleep(Duration::from_secs(1)).await;
+        token.cancel();
+    };
+    let (result, ()) = futures::join!(infinitime.list_dir("/", Some(&token)), cancel);
+    assert!(matches!(result, Err(Error::Cancelled)));
+    assert!(started.elapsed() < bt::DEFAULT_FS_TIMEOUT);
+
+    let result = infinitime.make_dir("/fonts", None).await;
+    assert!(matches!(result, Err(Error::Timeout)));
+}
+
+// -- Firmware upgrade --
+
+#[tokio::test]
+async fn firmware_upgrade() {
+    let sim = Simulator::new();
+    let infinitime = sim.infinitime();
+    let firmware = firmware();
+
+    infinitime.firmware_upgrade(&dfu_package(&firmware), None).await.unwrap();
+    assert_eq!(sim.installed_firmware(), Some(firmware));
+    assert!(!infinitime.is_upgrading_firmware());
+}
+
+#[tokio::test]
+async fn firmware_upgrade_rejects_corrupted_package() {
+    let sim = Simulator::new();
+    let infinitime = sim.infinitime();
+    let firmware = firmware();
+    let package = dfu_package_with_crc(&firmware, crc16(&firmware) ^ 1);
+
+    let result = infinitime.firmware_upgrade(&package, None).await;
+    assert!(matches!(result, Err(Error::Archive(_))));
+    assert!(sim.installed_firmware().is_none());
+    assert!(sim.transport().writes_to(&uuids::CHR_FWUPD_PACKET).is_empty());
+}
+
+/// Number of times the transfer was started from the beginning
+fn dfu_starts(sim: &Simulator) -> usize {
+    sim.transport().writes_to(&uuids::CHR_FWUPD_CONTROL_POINT).iter()
+        .filter(|req| req.as_slice() == [0x01, 0x04])
+        .count()
+}
+
+/// Whether the DFU session was reset after a failed transfer
+fn dfu_reset(sim: &Simulator) -> bool {
+    sim.transport().writes_to(&uuids::CHR_FWUPD_CONTROL_POINT).iter()
+        .any(|req| req.as_slice() == [0x06])
+}
+
+#[tokio::test]
+async fn firmware_upgrade_after_lost_packet() {
+    let sim = Simulator::new();
+    let infinitime = sim.infinitime();
+    let firmware = firmware();
+    // Noticed at the first packet receipt
+    sim.set_dfu_fault(Some(DfuFault::LosePacket(10)));
+
+    let result = infinitime.firmware_upgrade(&dfu_package(&firmware), None).await;
+    assert!(matches!(result, Err(Error::PacketLost(_))));
+    assert!(sim.installed_firmware().is_none());
+    assert_eq!(dfu_starts(&sim), 1);
+    assert!(dfu_reset(&sim));
+
+    // Started over from scratch, as after reconnection
+    infinitime.firmware_upgrade(&dfu_package(&firmware), None).await.unwrap();
+    assert_eq!(sim.installed_firmware(), Some(firmware));
+    assert_eq!(dfu_starts(&sim), 2);
+}
+
+#[tokio::test(start_paused = true)]
+async fn firmware_upgrade_after_lost_last_packet() {
+    let sim = Simulator::new();
+    let infinitime = sim.infinitime();
+    let firmware = firmware();
+    // The watch keeps waiting for the rest of the image
+    sim.set_dfu_fault(Some(DfuFault::LosePacket((FIRMWARE_SIZE / 20 - 1) as u32)));
+
+    let result = infinitime.firmware_upgrade(&dfu_package(&firmware), None).await;
+    assert!(matches!(result, Err(Error::Timeout)));
+    assert!(dfu_reset(&sim));
+
+    infinitime.firmware_upgrade(&dfu_package(&firmware), None).await.unwrap();
+    assert_eq!(sim.installed_firmware(), Some(firmware));
+    assert_eq!(dfu_starts(&sim), 2);
+}
+
+#[tokio::test(start_paused = true)]
+async fn firmware_upgrade_stalled() {
+    let sim = Simulator::new();
+    let infinitime = sim.infinitime();
+    sim.set_dfu_fault(Some(DfuFault::StallAfter(3000)));
+
+    let result = infinitime.firmware_upgrade(&dfu_package(&firmware()), None).await;
+    assert!(matches!(result, Err(Error::Timeout)));
+    assert!(sim.installed_firmware().is_none());
+    assert_eq!(dfu_starts(&sim), 1);
+    assert!(!infinitime.is_upgrading_firmware());
+}
+
+
+// -- Resources --
+
+#[tokio::test]
+async fn upload_resources() {
+    let sim = Simulator::new();
+    let infinitime = sim.infinitime();
+    sim.write_file("/fonts/old.bin", b"old");
+    sim.write_file("/fonts/future.bin", b"future");
+
+    infinitime.upload_resources(&resources_bundle(), None, None).await.unwrap();
+
+    assert_eq!(sim.file_paths(), [
+        "/fonts/7segments_40.bin",
+        "/fonts/future.bin",
+        "/fonts/lv_font_dots_40.bin",
+        "/fonts/teko.bin",
+    ]);
+    assert_eq!(sim.read_file("/fonts/teko.bin"), Some(vec![1; 300]));
+    assert_eq!(sim.read_file("/fonts/7segments_40.bin"), Some(vec![]));
+}
+
+#[tokio::test]
+async fn upload_resources_without_manifest() {
+    let sim = Simulator::new();
+    let infinitime = sim.infinitime();
+
+    let bundle = zip(&[("teko.bin", &[1; 10])]);
+    assert!(infinitime.upload_resources(&bundle, None, None).await.is_err());
+    assert!(sim.file_paths().is_empty());
+}
+
+
+// -- Media player --
+
+#[tokio::test]
+async fn media_player_events() {
+    let sim = Simulator::new();
+    let infinitime = sim.infinitime();
+
+    let stream = infinitime.get_media_player_events_stream().await.unwrap();
+    futures::pin_mut!(stream);
+    sim.press_media_button(MediaPlayerEvent::AppOpenned);
+    sim.press_media_button(MediaPlayerEvent::Pause);
+    sim.press_media_button(MediaPlayerEvent::VolumeUp);
+
+    assert_eq!(stream.next().await, Some(MediaPlayerEvent::AppOpenned));
+    assert_eq!(stream.next().await, Some(MediaPlayerEvent::Pause));
+    assert_eq!(stream.next().await, Some(MediaPlayerEvent::VolumeUp));
+}
+
+#[tokio::test]
+async fn media_player_state() {
+    let sim = Simulator::new();
+    let infinitime = sim.infinitime();
+
+    infinitime.write_mp_artist("Artist").await.unwrap();
+    infinitime.write_mp_album("Album").await.unwrap();
+    infinitime.write_mp_track("Track").await.unwrap();
+    infinitime.write_mp_playback_status(true).await.unwrap();
+    infinitime.write_mp_position(42).await.unwrap();
+    infinitime.write_mp_duration(180).await.unwrap();
+    infinitime.write_mp_playback_speed(1.5).await.unwrap();
+    infinitime.write_mp_repeat(false).await.unwrap();
+    infinitime.write_mp_shuffle(true).await.unwrap();
+
+    assert_eq!(sim.media_player(), infinitime::sim::MediaPlayerState {
+        artist: String::from("Artist"),
+        album: String::from("Album"),
+        track: String::from("Track"),
+        playing: true,
+        position: 42,
+        duration: 180,
+        speed: 150,
+        repeat: false,
+        shuffle: true,
+    });
+}
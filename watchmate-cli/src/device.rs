@@ -0,0 +1,37 @@
+use infinitime::{bluer, bt};
+use anyhow::Result;
+use futures::{pin_mut, StreamExt};
+use std::{sync::Arc, time::Duration};
+
+
+/// Run device discovery for the given time, so that nearby
+/// devices become known to the adapter
+pub async fn discover(adapter: &bluer::Adapter, duration: Duration) -> Result<()> {
+    eprintln!("Scanning for {} seconds...", duration.as_secs());
+    let stream = adapter.discover_devices().await?;
+    pin_mut!(stream);
+    // Discovery keeps running while the stream is alive
+    let _ = tokio::time::timeout(duration, stream.count()).await;
+    Ok(())
+}
+
+pub async fn list(adapter: &bluer::Adapter) -> Result<()> {
+    for device in bt::InfiniTime::list_known_devices(adapter).await? {
+        let state = if device.is_connected().await? { "connected" } else { "disconnected" };
+        println!("{}\t{}\t{}", device.address(), device.alias().await?, state);
+    }
+    Ok(())
+}
+
+pub async fn disconnect(adapter: &bluer::Adapter, address: Option<bluer::Address>) -> Result<()> {
+    let device = bt::find_device(adapter, address).await?;
+    if device.is_connected().await? {
+        device.disconnect().await?;
+    }
+    Ok(())
+}
+
+pub async fn infinitime(adapter: &bluer::Adapter, address: Option<bluer::Address>) -> Result<bt::InfiniTime> {
+    let device = bt::connect_device(adapter, address).await?;
+    Ok(bt::InfiniTime::new(Arc::new(device)).await?)
+}
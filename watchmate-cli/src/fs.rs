@@ -0,0 +1,111 @@
+use crate::progress;
+use infinitime::bt;
+use anyhow::{anyhow, Result};
+use clap::Subcommand;
+use std::{io::Write, path::PathBuf};
+
+
+#[derive(Subcommand, Debug)]
+pub enum Command {
+    /// List directory
+    Ls {
+        #[arg(default_value = "/")]
+        path: String,
+    },
+    /// Download file from the watch
+    Get {
+        remote: String,
+        /// Local destination, "-" for stdout. Defaults to the remote file name
+        local: Option<PathBuf>,
+    },
+    /// Upload file to the watch
+    Put {
+        local: PathBuf,
+        remote: String,
+        /// Create parent directories as needed
+        #[arg(short, long)]
+        parents: bool,
+    },
+    /// Remove file or empty directory
+    Rm {
+        path: String,
+    },
+    /// Move or rename file or directory
+    Mv {
+        old_path: String,
+        new_path: String,
+    },
+    /// Make directory
+    Mkdir {
+        path: String,
+        /// Create parent directories as needed
+        #[arg(short, long)]
+        parents: bool,
+    },
+}
+
+
+pub async fn run(infinitime: &bt::InfiniTime, command: Command) -> Result<()> {
+    match command {
+        Command::Ls { path } => {
+            for entry in infinitime.list_dir(&path, None).await? {
+                if entry.path == "." || entry.path == ".." {
+                    continue;
+                }
+                let kind = if entry.is_dir { 'd' } else { '-' };
+                println!("{} {:>8} {}", kind, entry.size, entry.path);
+            }
+            Ok(())
+        }
+        Command::Get { remote, local } => {
+            let (progress_tx, progress_rx) = bt::progress_channel(16);
+            let progress_task = tokio::spawn(progress::print(progress_rx));
+            let cancel = crate::cancel_on_interrupt();
+            let result = infinitime.read_file(&remote, 0, Some(progress_tx), Some(&cancel)).await;
+            progress_task.await?;
+            let content = result?;
+
+            let local = match local {
+                Some(local) => local,
+                None => PathBuf::from(file_name(&remote)?),
+            };
+            if local.as_os_str() == "-" {
+                std::io::stdout().write_all(&content)?;
+            } else {
+                tokio::fs::write(&local, &content).await?;
+            }
+            Ok(())
+        }
+        Command::Put { local, remote, parents } => {
+            let content = tokio::fs::read(&local).await?;
+            let cancel = crate::cancel_on_interrupt();
+            if parents {
+                infinitime.make_dirs(&remote, Some(&cancel)).await?;
+            }
+            let (progress_tx, progress_rx) = bt::progress_channel(16);
+            let progress_task = tokio::spawn(progress::print(progress_rx));
+            let result = infinitime.write_file(&remote, &content, 0, Some(progress_tx), Some(&cancel)).await;
+            progress_task.await?;
+            Ok(result?)
+        }
+        Command::Rm { path } => {
+            Ok(infinitime.delete_file(&path, None).await?)
+        }
+        Command::Mv { old_path, new_path } => {
+            Ok(infinitime.move_file(&old_path, &new_path, None).await?)
+        }
+        Command::Mkdir { path, parents } => {
+            if parents {
+                infinitime.make_dirs(&path, None).await?;
+            }
+            Ok(infinitime.make_dir(&path, None).await?)
+        }
+    }
+}
+
+fn file_name(path: &str) -> Result<&str> {
+    match path.rsplit('/').next() {
+        Some(name) if !name.is_empty() => Ok(name),
+        _ => Err(anyhow!("Cannot derive local file name from '{}'", path)),
+    }
+}
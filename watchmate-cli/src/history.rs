@@ -0,0 +1,51 @@
+use infinitime::{bluer, history::{export, History}};
+use anyhow::{anyhow, Result};
+use chrono::{Duration, Utc};
+use clap::ValueEnum;
+use std::path::Path;
+
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq)]
+pub enum Format {
+    Csv,
+    Tcx,
+    Fit,
+}
+
+impl From<Format> for export::Format {
+    fn from(format: Format) -> Self {
+        match format {
+            Format::Csv => Self::Csv,
+            Format::Tcx => Self::Tcx,
+            Format::Fit => Self::Fit,
+        }
+    }
+}
+
+pub fn export(address: Option<bluer::Address>, output: &Path, format: Option<Format>, days: u32) -> Result<()> {
+    let format = match format {
+        Some(format) => format.into(),
+        None => detect_format(output)?,
+    };
+    let history = History::open_default()?;
+    let address = match address {
+        Some(address) => address,
+        None => match history.devices()?.as_slice() {
+            [address] => *address,
+            [] => return Err(anyhow!("No history recorded yet")),
+            _ => return Err(anyhow!("History of several watches is recorded, specify one with --device")),
+        }
+    };
+    let end = Utc::now();
+    let start = end - Duration::days(days as i64);
+    let content = export::export(&history, address, start..end, format)?;
+    std::fs::write(output, content)?;
+    Ok(())
+}
+
+fn detect_format(output: &Path) -> Result<export::Format> {
+    let extension = output.extension().and_then(|e| e.to_str()).unwrap_or_default();
+    export::Format::ALL.into_iter()
+        .find(|format| format.extension().eq_ignore_ascii_case(extension))
+        .ok_or(anyhow!("Unknown export format, specify it with --format"))
+}
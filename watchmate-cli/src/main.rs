@@ -0,0 +1,487 @@
+use infinitime::{bluer, bt, compatibility::{self, UpdatePlan}};
+use anyhow::Result;
+use clap::{Parser, Subcommand, ValueEnum};
+use futures::StreamExt;
+use std::{path::{Path, PathBuf}, time::{SystemTime, UNIX_EPOCH}};
+
+mod device;
+mod fs;
+mod history;
+mod progress;
+
+
+/// Command-line companion for InfiniTime-powered watches
+#[derive(Parser, Debug)]
+#[command(name = "watchmate-cli", version)]
+struct Args {
+    /// Bluetooth address of the watch. If not specified, already connected
+    /// InfiniTime device is used, or the only known one is connected to.
+    #[arg(short, long, global = true)]
+    device: Option<bluer::Address>,
+
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// List known InfiniTime devices
+    List {
+        /// Discover nearby devices for the given number of seconds first
+        #[arg(long, value_name = "SECONDS")]
+        scan: Option<u64>,
+    },
+    /// Connect to the watch
+    Connect,
+    /// Disconnect from the watch
+    Disconnect,
+    /// Read data from the watch
+    Read {
+        #[arg(value_enum, default_value_t = Value::All)]
+        value: Value,
+    },
+    /// Stream accelerometer samples to stdout as CSV
+    Motion {
+        /// Stop after the given number of samples
+        #[arg(short = 'n', long)]
+        count: Option<usize>,
+    },
+    /// Show features supported by the watch firmware
+    Capabilities {
+        /// Fail unless all of the given features are supported
+        #[arg(long, value_name = "FEATURE")]
+        require: Vec<bt::Feature>,
+    },
+    /// Access the watch file system
+    Fs {
+        /// How long to wait for the watch to respond to each request
+        #[arg(long, value_name = "SECONDS")]
+        timeout: Option<u64>,
+        #[command(subcommand)]
+        command: fs::Command,
+    },
+    /// Flash firmware (DFU) or resources archive
+    Flash {
+        file: PathBuf,
+        /// Archive type. Detected from the archive contents by default
+        #[arg(long = "type", value_enum)]
+        asset_type: Option<AssetType>,
+        /// Reconnect and start firmware upgrade over this many times if it fails
+        #[arg(long, default_value_t = 3)]
+        attempts: u32,
+        /// Flash even if the update is likely to leave the watch unusable
+        #[arg(long)]
+        force: bool,
+    },
+    /// Validate DFU archive and show its details without flashing
+    Inspect {
+        file: PathBuf,
+    },
+    /// Flash the same firmware and/or resources to several watches
+    FlashBatch {
+        /// Bluetooth addresses of the watches
+        #[arg(required = true, value_name = "ADDRESS")]
+        devices: Vec<bluer::Address>,
+        /// DFU archive
+        #[arg(long, required_unless_present = "resources")]
+        firmware: Option<PathBuf>,
+        /// Resources archive, uploaded after the firmware
+        #[arg(long)]
+        resources: Option<PathBuf>,
+        /// Number of watches to flash at once
+        #[arg(short, long, default_value_t = 1)]
+        jobs: usize,
+    },
+    /// Export recorded history of battery level, heart rate and steps
+    Export {
+        /// Output file. Format is detected from its extension by default
+        output: PathBuf,
+        #[arg(long, value_enum)]
+        format: Option<history::Format>,
+        /// Number of days to export, counting back from now
+        #[arg(long, default_value_t = 7)]
+        days: u32,
+    },
+    /// Send notification to the watch
+    Notify {
+        title: String,
+        body: Option<String>,
+        /// Send as incoming call
+        #[arg(long)]
+        call: bool,
+    },
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq)]
+enum Value {
+    Battery,
+    HeartRate,
+    Steps,
+    Motion,
+    Firmware,
+    All,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq)]
+enum AssetType {
+    Firmware,
+    Resources,
+}
+
+
+#[tokio::main]
+async fn main() {
+    env_logger::Builder::new()
+        .format_timestamp(None)
+        .filter_module("watchmate_cli", log::LevelFilter::Info)
+        .parse_default_env()
+        .init();
+
+    let args = Args::parse();
+    if let Err(error) = run(args).await {
+        eprintln!("Error: {:#}", error);
+        std::process::exit(1);
+    }
+}
+
+async fn run(args: Args) -> Result<()> {
+    // History is available without Bluetooth
+    if let Command::Export { output, format, days } = &args.command {
+        return history::export(args.device, output, *format, *days);
+    }
+    if let Command::Inspect { file } = &args.command {
+        return inspect(file);
+    }
+
+    let adapter = bt::init_adapter().await?;
+    match args.command {
+        Command::List { scan } => {
+            if let Some(seconds) = scan {
+                device::discover(&adapter, std::time::Duration::from_secs(seconds)).await?;
+            }
+            device::list(&adapter).await
+        }
+        Command::Connect => {
+            let device = bt::connect_device(&adapter, args.device).await?;
+            println!("Connected to {}", device.address());
+            Ok(())
+        }
+        Command::Disconnect => {
+            device::disconnect(&adapter, args.device).await
+        }
+        Command::Flash { file, asset_type, attempts, force } => {
+            let content = tokio::fs::read(&file).await?;
+            let asset_type = match asset_type {
+                Some(asset_type) => asset_type,
+                None => detect_asset_type(&content)?,
+            };
+            let package = match asset_type {
+                // Refuse broken packages before touching the watch
+                AssetType::Firmware => Some(bt::DfuPackage::parse(&content)?),
+                AssetType::Resources => None,
+            };
+
+            let infinitime = device::infinitime(&adapter, args.device).await?;
+            let current = infinitime.read_firmware_version().await?;
+            let plan = match &package {
+                Some(package) => UpdatePlan::new(Some(&current)).firmware_package(package),
+                None => {
+                    let version = file.file_name()
+                        .and_then(|name| name.to_str())
+                        .and_then(compatibility::version_from_file_name);
+                    UpdatePlan::new(Some(&current)).resources(version.as_deref())
+                }
+            };
+            check_compatibility(&plan, force)?;
+
+            let address = infinitime.address();
+            drop(infinitime);
+            flash(adapter, address, &content, asset_type, attempts).await
+        }
+        Command::FlashBatch { devices, firmware, resources, jobs } => {
+            flash_batch(adapter, &devices, firmware.as_deref(), resources.as_deref(), jobs).await
+        }
+        command => {
+            let infinitime = device::infinitime(&adapter, args.device).await?;
+            match command {
+                Command::Read { value } => read(&infinitime, value).await,
+                Command::Motion { count } => motion(&infinitime, count).await,
+                Command::Capabilities { require } => capabilities(&infinitime, &require).await,
+                Command::Fs { timeout, command } => {
+                    if let Some(seconds) = timeout {
+                        infinitime.set_fs_timeout(std::time::Duration::from_secs(seconds));
+                    }
+                    fs::run(&infinitime, command).await
+                }
+                Command::Notify { title, body, call } => notify(&infinitime, &title, body.as_deref(), call).await,
+                _ => unreachable!(),
+            }
+        }
+    }
+}
+
+async fn read(infinitime: &bt::InfiniTime, value: Value) -> Result<()> {
+    let all = value == Value::All;
+    if all || value == Value::Battery {
+        let level = infinitime.read_battery_level().await?;
+        print_value(all, "Battery", format!("{}%", level));
+    }
+    if all || value == Value::HeartRate {
+        let rate = infinitime.read_heart_rate().await?;
+        print_value(all, "Heart rate", format!("{} BPM", rate));
+    }
+    if all || value == Value::Steps {
+        let steps = infinitime.read_step_count().await?;
+        print_value(all, "Steps", steps.to_string());
+    }
+    if all || value == Value::Motion {
+        // Not every firmware exposes motion service, don't fail the whole listing
+        match infinitime.read_motion().await {
+            Ok(m) => print_value(all, "Motion", format!("{} {} {}", m.x, m.y, m.z)),
+            Err(error) if all => print_value(all, "Motion", format!("unavailable ({error})")),
+            Err(error) => return Err(error.into()),
+        }
+    }
+    if all || value == Value::Firmware {
+        let version = infinitime.read_firmware_version().await?;
+        print_value(all, "Firmware", version);
+    }
+    Ok(())
+}
+
+async fn motion(infinitime: &bt::InfiniTime, count: Option<usize>) -> Result<()> {
+    let stream = infinitime.get_motion_stream().await?;
+    let stream = stream.take(count.unwrap_or(usize::MAX));
+    futures::pin_mut!(stream);
+    println!("timestamp,x,y,z");
+    while let Some(sample) = stream.next().await {
+        let timestamp = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs_f64();
+        println!("{:.3},{},{},{}", timestamp, sample.x, sample.y, sample.z);
+    }
+    Ok(())
+}
+
+async fn capabilities(infinitime: &bt::InfiniTime, require: &[bt::Feature]) -> Result<()> {
+    let capabilities = infinitime.capabilities().await?;
+    if require.is_empty() {
+        println!("{:<18}{}", "firmware:", capabilities.firmware_version);
+        if let Some(version) = capabilities.fs_version {
+            println!("{:<18}{}", "fs-version:", version);
+        }
+        for feature in bt::Feature::ALL {
+            let supported = if capabilities.supports(feature) { "yes" } else { "no" };
+            println!("{:<18}{}", format!("{}:", feature), supported);
+        }
+        Ok(())
+    } else {
+        let missing: Vec<_> = require.iter()
+            .filter(|f| !capabilities.supports(**f))
+            .map(|f| f.name())
+            .collect();
+        if missing.is_empty() {
+            Ok(())
+        } else {
+            Err(anyhow::anyhow!("Unsupported features: {}", missing.join(", ")))
+        }
+    }
+}
+
+/// Token which is cancelled on Ctrl+C, so that file transfers
+/// can stop cleanly instead of leaving partially written files behind
+fn cancel_on_interrupt() -> bt::CancellationToken {
+    let token = bt::CancellationToken::new();
+    let child = token.clone();
+    tokio::spawn(async move {
+        if tokio::signal::ctrl_c().await.is_ok() {
+            eprintln!("Interrupted, stopping...");
+            child.cancel();
+        }
+    });
+    token
+}
+
+/// Print labeled value, or bare value when a single one is requested,
+/// so the output is easy to consume from scripts
+fn print_value(labeled: bool, label: &str, value: String) {
+    if labeled {
+        println!("{:<12}{}", format!("{}:", label), value);
+    } else {
+        println!("{}", value);
+    }
+}
+
+/// Print compatibility warnings, fail on critical ones unless forced
+fn check_compatibility(plan: &UpdatePlan, force: bool) -> Result<()> {
+    let warnings = plan.check();
+    for warning in &warnings {
+        eprintln!("Warning: {}", warning);
+    }
+    if !force && warnings.iter().any(|w| w.is_critical()) {
+        Err(anyhow::anyhow!("Refusing to flash, use --force to proceed anyway"))
+    } else {
+        Ok(())
+    }
+}
+
+async fn flash(
+    adapter: bluer::Adapter,
+    address: bluer::Address,
+    content: &[u8],
+    asset_type: AssetType,
+    attempts: u32,
+) -> Result<()> {
+    let manager = bt::DeviceManager::new(std::sync::Arc::new(adapter));
+    match asset_type {
+        AssetType::Firmware => with_progress(|progress_tx| {
+            manager.firmware_upgrade_with_retry(
+                address, content, Some(progress_tx), attempts, bt::DEFAULT_RECONNECT_TIMEOUT
+            )
+        }).await,
+        AssetType::Resources => with_progress(|progress_tx| async move {
+            let infinitime = manager.connect(address).await?;
+            let cancel = cancel_on_interrupt();
+            infinitime.upload_resources(content, Some(progress_tx), Some(&cancel)).await
+        }).await,
+    }
+}
+
+/// Run the task, printing its progress until it's done. The task owns
+/// the progress sender, so printing stops even if the task fails early.
+async fn with_progress<F, Fut>(task: F) -> Result<()>
+where
+    F: FnOnce(bt::ProgressTx) -> Fut,
+    Fut: std::future::Future<Output = infinitime::Result<()>>,
+{
+    let (progress_tx, progress_rx) = bt::progress_channel(16);
+    let progress_task = tokio::spawn(progress::print(progress_rx));
+    let result = task(progress_tx).await;
+    progress_task.await?;
+    Ok(result?)
+}
+
+async fn flash_batch(
+    adapter: bluer::Adapter,
+    devices: &[bluer::Address],
+    firmware: Option<&Path>,
+    resources: Option<&Path>,
+    jobs: usize,
+) -> Result<()> {
+    let firmware = match firmware {
+        Some(path) => Some(tokio::fs::read(path).await?),
+        None => None,
+    };
+    let resources = match resources {
+        Some(path) => Some(tokio::fs::read(path).await?),
+        None => None,
+    };
+    let batch = bt::BatchFlash {
+        firmware: firmware.as_deref(),
+        resources: resources.as_deref(),
+        concurrency: jobs,
+        ..Default::default()
+    };
+
+    let manager = bt::DeviceManager::new(std::sync::Arc::new(adapter));
+    let (progress_tx, progress_rx) = bt::batch_progress_channel(16);
+    let progress_task = tokio::spawn(progress::print_batch(progress_rx));
+    let reports = manager.flash_batch(devices, batch, Some(progress_tx)).await;
+    progress_task.await?;
+
+    let mut failed = 0;
+    for report in &reports {
+        match &report.result {
+            Ok(version) => println!("{}\tok\t{}", report.address, version),
+            Err(error) => {
+                println!("{}\tfailed\t{}", report.address, error);
+                failed += 1;
+            }
+        }
+    }
+    if failed > 0 {
+        Err(anyhow::anyhow!("Failed to flash {} of {} watches", failed, reports.len()))
+    } else {
+        Ok(())
+    }
+}
+
+/// Validate DFU package and print its details
+fn inspect(file: &Path) -> Result<()> {
+    let content = std::fs::read(file)?;
+    let package = bt::DfuPackage::parse(&content)?;
+    let init = &package.init_packet;
+    let softdevices: Vec<_> = init.softdevice_req.iter().map(|id| format!("{:#06x}", id)).collect();
+    println!("{:<18}{}", "version:", package.version().as_deref().unwrap_or("unknown"));
+    println!("{:<18}{}", "mcuboot-image:", if package.is_mcuboot_image() { "yes" } else { "no" });
+    println!("{:<18}{} bytes", "size:", package.firmware.len());
+    println!("{:<18}{:#06x}", "device-type:", init.device_type);
+    println!("{:<18}{:#06x}", "device-revision:", init.device_revision);
+    println!("{:<18}{}", "softdevice-req:", softdevices.join(", "));
+    println!("{:<18}{:#06x} (ok)", "crc16:", init.firmware_crc16);
+    if let Some(version) = package.dfu_version {
+        println!("{:<18}{}", "dfu-version:", version);
+    }
+    Ok(())
+}
+
+/// Detect whether the archive is a DFU package or resources bundle
+fn detect_asset_type(content: &[u8]) -> Result<AssetType> {
+    let zip = zip::ZipArchive::new(std::io::Cursor::new(content))?;
+    if zip.file_names().any(|name| name == "manifest.json") {
+        Ok(AssetType::Firmware)
+    } else if zip.file_names().any(|name| name == "resources.json") {
+        Ok(AssetType::Resources)
+    } else {
+        Err(anyhow::anyhow!("Unknown archive type, specify it with --type"))
+    }
+}
+
+async fn notify(infinitime: &bt::InfiniTime, title: &str, body: Option<&str>, call: bool) -> Result<()> {
+    let notification = if call {
+        bt::Notification::Call { title }
+    } else {
+        bt::Notification::Alert { title, content: body.unwrap_or_default() }
+    };
+    Ok(infinitime.write_notification(notification).await?)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use infinitime::{simulator::{self, Simulator}, Error};
+    use std::time::Duration;
+
+    /// Only the simulated watch can be reached
+    async fn connect(sim: &Simulator, address: bluer::Address) -> infinitime::Result<bt::InfiniTime> {
+        if address == simulator::DEFAULT_ADDRESS {
+            Ok(sim.infinitime())
+        } else {
+            Err(Error::Timeout)
+        }
+    }
+
+    #[tokio::test]
+    async fn unreachable_watch() {
+        let sim = Simulator::new();
+        let address = bluer::Address::new([0xc0, 0xff, 0xee, 0x00, 0x00, 0x02]);
+        let flashing = with_progress(|progress_tx| async move {
+            let infinitime = connect(&sim, address).await?;
+            infinitime.upload_resources(&[], Some(progress_tx), None).await
+        });
+        let result = tokio::time::timeout(Duration::from_secs(5), flashing).await
+            .expect("progress printing didn't stop");
+        assert!(result.unwrap_err().to_string().contains("Timed out"));
+    }
+
+    #[tokio::test]
+    async fn reachable_watch() {
+        let sim = Simulator::new();
+        let flashing = with_progress(|progress_tx| async move {
+            let infinitime = connect(&sim, simulator::DEFAULT_ADDRESS).await?;
+            infinitime.upload_resources(b"not a zip", Some(progress_tx), None).await
+        });
+        let result = tokio::time::timeout(Duration::from_secs(5), flashing).await
+            .expect("progress printing didn't stop");
+        assert!(result.is_err());
+    }
+}
@@ -0,0 +1,64 @@
+use infinitime::{bluer, bt};
+use std::collections::HashMap;
+use std::io::Write;
+
+const BAR_WIDTH: usize = 40;
+
+
+/// Print progress events to stderr until the sender is dropped.
+/// Numbers are rendered as a progress bar, updated in place.
+pub async fn print(mut progress_rx: bt::ProgressRx) {
+    let mut bar_shown = false;
+    while let Some(event) = progress_rx.recv().await {
+        match event {
+            bt::ProgressEvent::Message(msg) => {
+                if bar_shown {
+                    eprintln!();
+                    bar_shown = false;
+                }
+                eprintln!("{}", msg);
+            }
+            bt::ProgressEvent::Numbers { current, total } => {
+                eprint!("\r{}", bar(current, total));
+                let _ = std::io::stderr().flush();
+                bar_shown = true;
+            }
+        }
+    }
+    if bar_shown {
+        eprintln!();
+    }
+}
+
+/// Print progress of several watches flashed at once, one line per event
+/// prefixed with the watch address. Numbers are printed every 10%.
+pub async fn print_batch(mut progress_rx: bt::BatchProgressRx) {
+    let mut reported: HashMap<bluer::Address, u32> = HashMap::new();
+    while let Some((address, event)) = progress_rx.recv().await {
+        match event {
+            bt::ProgressEvent::Message(msg) => {
+                reported.remove(&address);
+                eprintln!("{}: {}", address, msg);
+            }
+            bt::ProgressEvent::Numbers { current, total } => {
+                let percent = if total > 0 { (current as u64 * 100 / total as u64) as u32 } else { 100 };
+                let step = percent / 10 * 10;
+                if reported.insert(address, step) != Some(step) {
+                    eprintln!("{}: {}%", address, step);
+                }
+            }
+        }
+    }
+}
+
+fn bar(current: u32, total: u32) -> String {
+    let ratio = if total > 0 { (current as f64 / total as f64).min(1.0) } else { 1.0 };
+    let filled = (ratio * BAR_WIDTH as f64).round() as usize;
+    format!(
+        "[{}{}] {:>3}% {}/{}",
+        "#".repeat(filled),
+        " ".repeat(BAR_WIDTH - filled),
+        (ratio * 100.0).round() as u32,
+        current, total,
+    )
+}
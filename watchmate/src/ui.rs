@@ -1,16 +1,18 @@
-use infinitime::{bluer, bt};
-use std::{sync::Arc, path::PathBuf};
+use infinitime::{bluer, bt, fdo, gh, history::History, zbus};
+use std::{collections::BTreeMap, sync::Arc, path::PathBuf};
 use futures::{pin_mut, StreamExt};
-use gtk::{gio, prelude::{BoxExt, GtkWindowExt, SettingsExt}};
+use gtk::{gio, prelude::{BoxExt, GtkWindowExt, SettingsExt, WidgetExt}};
 use relm4::{
     adw, gtk, Component, ComponentController, ComponentParts,
-    ComponentSender, Controller, RelmApp, MessageBroker
+    ComponentSender, Controller, JoinHandle, RelmApp, MessageBroker
 };
 
+mod chart;
 mod dashboard;
 mod devices;
 mod firmware_update;
 mod firmware_panel;
+mod history;
 mod media_player;
 mod notifications;
 mod settings;
@@ -24,12 +26,17 @@ static BROKER: relm4::MessageBroker<Input> = MessageBroker::new();
 enum Input {
     SetView(View),
     SetAutoReconnect(bool),
+    AdapterReady(Arc<bt::DeviceManager>),
     DeviceConnected(Arc<bluer::Device>),
-    DeviceDisconnected,
+    DeviceDisconnected(bluer::Address),
     DeviceReady(Arc<bt::InfiniTime>),
     DeviceRejected,
-    FlashAssetFromFile(PathBuf, AssetType),
-    FlashAssetFromUrl(String, AssetType),
+    ShowDevice(bluer::Address),
+    DaemonAttached,
+    DaemonDetached,
+    FlashAssetFromFile(bluer::Address, PathBuf, AssetType),
+    FlashAssetFromRelease(bluer::Address, AssetOrigin, gh::Asset, AssetType),
+    FlashRelease(bluer::Address, AssetOrigin, gh::Asset, gh::Asset),
     Toast(String),
     ToastStatic(&'static str),
     ToastWithLink {
@@ -42,15 +49,89 @@ enum Input {
 struct Model {
     // UI state
     active_view: View,
-    is_connected: bool,
+    active_device: Option<bluer::Address>,
     // Components
-    dashboard: Controller<dashboard::Model>,
+    dashboards: BTreeMap<bluer::Address, Controller<dashboard::Model>>,
     devices: Controller<devices::Model>,
     fwupd: Controller<firmware_update::Model>,
+    history: Controller<history::Model>,
     settings: Controller<settings::Model>,
     // Other
-    infinitime: Option<Arc<bt::InfiniTime>>,
+    manager: Option<Arc<bt::DeviceManager>>,
+    fwupd_device: Option<bluer::Address>,
+    history_db: Option<Arc<History>>,
+    history_tasks: BTreeMap<bluer::Address, JoinHandle<()>>,
+    daemon_attached: bool,
+    persistent_settings: gio::Settings,
     toast_overlay: adw::ToastOverlay,
+    dashboard_stack: gtk::Stack,
+}
+
+impl Model {
+    /// Record history of the watch, unless watchmated does it
+    fn start_history_recorder(&mut self, infinitime: Arc<bt::InfiniTime>) {
+        let (Some(history), false) = (self.history_db.clone(), self.daemon_attached) else {
+            return;
+        };
+        let address = infinitime.address();
+        let task = relm4::spawn(async move {
+            if let Err(error) = infinitime::history::run_recorder(&history, &infinitime).await {
+                log::error!("History recording failed: {error}");
+            }
+        });
+        if let Some(stale) = self.history_tasks.insert(address, task) {
+            stale.abort();
+        }
+    }
+
+    /// Create dashboard for the newly connected device
+    fn add_dashboard(&mut self, infinitime: Arc<bt::InfiniTime>, root: &adw::ApplicationWindow, sender: &ComponentSender<Self>) {
+        let address = infinitime.address();
+        let dashboard = dashboard::Model::builder()
+            .launch((root.clone(), self.persistent_settings.clone(), self.history_db.clone()))
+            .forward(sender.input_sender(), move |message| match message {
+                dashboard::Output::FlashAssetFromFile(file, atype) => Input::FlashAssetFromFile(address, file, atype),
+                dashboard::Output::FlashAssetFromRelease(origin, asset, atype) => {
+                    Input::FlashAssetFromRelease(address, origin, asset, atype)
+                }
+                dashboard::Output::FlashRelease(origin, firmware, resources) => {
+                    Input::FlashRelease(address, origin, firmware, resources)
+                }
+            });
+        if self.daemon_attached {
+            dashboard.emit(dashboard::Input::DaemonAttached);
+        }
+        dashboard.emit(dashboard::Input::Connected(infinitime));
+        self.dashboard_stack.add_named(dashboard.widget(), Some(&address.to_string()));
+        // Replace the stale one, if any
+        if let Some(stale) = self.dashboards.insert(address, dashboard) {
+            self.dashboard_stack.remove(stale.widget());
+        }
+    }
+
+    fn remove_dashboard(&mut self, address: bluer::Address) {
+        if let Some(dashboard) = self.dashboards.remove(&address) {
+            dashboard.emit(dashboard::Input::Disconnected);
+            self.dashboard_stack.remove(dashboard.widget());
+        }
+    }
+
+    /// Point firmware update at the device whose dashboard requested it
+    fn set_fwupd_device(&mut self, address: bluer::Address) {
+        self.fwupd_device = Some(address);
+        match self.manager.as_ref().and_then(|manager| manager.get(address)) {
+            Some(infinitime) => self.fwupd.emit(firmware_update::Input::Connected(infinitime)),
+            None => self.fwupd.emit(firmware_update::Input::Disconnected),
+        }
+    }
+
+    fn show_device(&mut self, address: bluer::Address) {
+        if self.dashboards.contains_key(&address) {
+            self.dashboard_stack.set_visible_child_name(&address.to_string());
+            self.active_device = Some(address);
+            self.history.emit(history::Input::Device(address));
+        }
+    }
 }
 
 #[relm4::component]
@@ -73,8 +154,11 @@ impl Component for Model {
                 #[wrap(Some)]
                 set_child = &gtk::Stack {
                     add_named[Some("dashboard_view")] = &gtk::Box {
-                        // set_visible: watch!(components.dashboard.model.device.is_some()),
-                        append: model.dashboard.widget(),
+                        // One dashboard per connected device
+                        #[local_ref]
+                        dashboard_stack -> gtk::Stack {
+                            set_hexpand: true,
+                        },
                     },
                     add_named[Some("devices_view")] = &gtk::Box {
                         append: model.devices.widget(),
@@ -82,6 +166,9 @@ impl Component for Model {
                     add_named[Some("fwupd_view")] = &gtk::Box {
                         append: model.fwupd.widget(),
                     },
+                    add_named[Some("history_view")] = &gtk::Box {
+                        append: model.history.widget(),
+                    },
                     add_named[Some("settings_view")] = &gtk::Box {
                         append: model.settings.widget(),
                     },
@@ -90,6 +177,7 @@ impl Component for Model {
                         View::Dashboard => "dashboard_view",
                         View::Devices => "devices_view",
                         View::FirmwareUpdate => "fwupd_view",
+                        View::History => "history_view",
                         View::Settings => "settings_view",
                     },
                 },
@@ -99,22 +187,27 @@ impl Component for Model {
 
     fn init(_: Self::Init, root: &Self::Root, sender: ComponentSender<Self>) -> ComponentParts<Self> {
         let persistent_settings = gio::Settings::new(APP_ID);
-        // Components
-        let dashboard = dashboard::Model::builder()
-            .launch((root.clone(), persistent_settings.clone()))
-            .forward(&sender.input_sender(), |message| match message {
-                dashboard::Output::FlashAssetFromFile(file, atype) => Input::FlashAssetFromFile(file, atype),
-                dashboard::Output::FlashAssetFromUrl(url, atype) => Input::FlashAssetFromUrl(url, atype),
-            });
 
+        let history_db = History::open_default()
+            .map(Arc::new)
+            .map_err(|error| log::error!("Failed to open history database: {error}"))
+            .ok();
+
+        // Components
         let devices = devices::Model::builder()
             .launch(())
             .forward(&sender.input_sender(), |message| match message {
+                devices::Output::AdapterReady(manager) => Input::AdapterReady(manager),
                 devices::Output::DeviceConnected(device) => Input::DeviceConnected(device),
+                devices::Output::DeviceSelected(address) => Input::ShowDevice(address),
             });
 
         let fwupd = firmware_update::Model::builder()
-            .launch(())
+            .launch(history_db.clone())
+            .detach();
+
+        let history = history::Model::builder()
+            .launch((root.clone(), history_db.clone()))
             .detach();
 
         let settings = settings::Model::builder()
@@ -124,25 +217,51 @@ impl Component for Model {
             });
 
         let toast_overlay = adw::ToastOverlay::new();
+        let dashboard_stack = gtk::Stack::new();
 
         let model = Model {
             // UI state
             active_view: View::Devices,
-            is_connected: false,
+            active_device: None,
             // Components
-            dashboard,
+            dashboards: BTreeMap::new(),
             devices,
             fwupd,
+            history,
             settings,
             // Other
-            infinitime: None,
+            manager: None,
+            fwupd_device: None,
+            history_db,
+            history_tasks: BTreeMap::new(),
+            daemon_attached: false,
+            persistent_settings: persistent_settings.clone(),
             toast_overlay: toast_overlay.clone(),
+            dashboard_stack: dashboard_stack.clone(),
         };
 
         let widgets = view_output!();
 
         model.devices.emit(devices::Input::SetAutoReconnect(persistent_settings.boolean("auto-reconnect-enabled")));
 
+        // Leave background sessions to watchmated while it's running
+        let sender_ = sender.clone();
+        relm4::spawn(async move {
+            let running = match zbus::Connection::session().await {
+                Ok(connection) => fdo::daemon::watch_running(&connection).await,
+                Err(error) => Err(error.into()),
+            };
+            match running {
+                Ok(running) => {
+                    pin_mut!(running);
+                    while let Some(running) = running.next().await {
+                        sender_.input(if running { Input::DaemonAttached } else { Input::DaemonDetached });
+                    }
+                }
+                Err(error) => log::warn!("Failed to check whether watchmated is running: {error}"),
+            }
+        });
+
         ComponentParts { model, widgets }
     }
 
@@ -157,43 +276,69 @@ impl Component for Model {
                     if view == View::Devices {
                         self.devices.emit(devices::Input::StartDiscovery);
                     }
+                    if view == View::History {
+                        self.history.emit(history::Input::Refresh);
+                    }
                     self.active_view = view;
                 }
             }
             Input::SetAutoReconnect(enabled) => {
                 self.devices.emit(devices::Input::SetAutoReconnect(enabled));
             }
+            Input::AdapterReady(manager) => {
+                self.manager = Some(manager);
+            }
             Input::DeviceConnected(device) => {
                 log::info!("Device connected: {}", device.address());
-                self.is_connected = true;
-                relm4::spawn(async move {
-                    match bt::InfiniTime::new(device).await {
-                        Ok(infinitime) => {
-                            sender.input(Input::DeviceReady(Arc::new(infinitime)));
-                        }
-                        Err(error) => {
-                            sender.input(Input::DeviceRejected);
-                            log::error!("Device is rejected: {}", error);
-                            sender.input(Input::ToastStatic("Device is rejected by the app"));
+                if let Some(manager) = self.manager.clone() {
+                    relm4::spawn(async move {
+                        match manager.attach(device).await {
+                            Ok(infinitime) => {
+                                sender.input(Input::DeviceReady(infinitime));
+                            }
+                            Err(error) => {
+                                sender.input(Input::DeviceRejected);
+                                log::error!("Device is rejected: {}", error);
+                                sender.input(Input::ToastStatic("Device is rejected by the app"));
+                            }
                         }
-                    }
-                });
+                    });
+                }
             }
-            Input::DeviceDisconnected => {
-                log::info!("PineTime disconnected");
-                if let Some(infinitime) = self.infinitime.take() {
-                    self.devices.emit(devices::Input::DeviceConnectionLost(infinitime.device().address()));
+            Input::DeviceDisconnected(address) => {
+                log::info!("PineTime disconnected: {}", address);
+                if let Some(manager) = &self.manager {
+                    manager.detach(address);
+                }
+                self.devices.emit(devices::Input::DeviceConnectionLost(address));
+                self.history_tasks.remove(&address).map(|h| h.abort());
+                self.remove_dashboard(address);
+                if self.fwupd_device == Some(address) {
+                    self.fwupd.emit(firmware_update::Input::Disconnected);
+                }
+                if self.active_device == Some(address) {
+                    self.active_device = None;
+                    // Switch to another connected device, if any
+                    match self.dashboards.keys().next().copied() {
+                        Some(other) => self.show_device(other),
+                        None => sender.input(Input::SetView(View::Devices)),
+                    }
                 }
-                self.dashboard.emit(dashboard::Input::Disconnected);
-                self.fwupd.emit(firmware_update::Input::Disconnected);
-                sender.input(Input::SetView(View::Devices));
             }
             Input::DeviceReady(infinitime) => {
-                log::info!("PineTime recognized");
-                self.infinitime = Some(infinitime.clone());
-                self.active_view = View::Dashboard;
-                self.dashboard.emit(dashboard::Input::Connected(infinitime.clone()));
-                self.fwupd.emit(firmware_update::Input::Connected(infinitime.clone()));
+                let address = infinitime.address();
+                log::info!("PineTime recognized: {}", address);
+                self.add_dashboard(infinitime.clone(), root, &sender);
+                // Don't take the view away from another device's dashboard
+                if self.active_device.is_none() || self.active_view == View::Devices {
+                    self.show_device(address);
+                    self.active_view = View::Dashboard;
+                }
+                // Firmware update reconnects after the watch reboots
+                if self.fwupd_device == Some(address) {
+                    self.fwupd.emit(firmware_update::Input::Connected(infinitime.clone()));
+                }
+                self.start_history_recorder(infinitime.clone());
                 // Handle disconnection
                 relm4::spawn(async move {
                     match infinitime.get_property_stream().await {
@@ -206,20 +351,63 @@ impl Component for Model {
                             log::error!("Failed to get property stream: {}", error);
                         }
                     }
-                    sender.input(Input::DeviceDisconnected);
+                    sender.input(Input::DeviceDisconnected(address));
                 });
             }
             Input::DeviceRejected => {
                 self.devices.emit(devices::Input::StartDiscovery);
             }
-            Input::FlashAssetFromFile(file, atype) => {
+            Input::ShowDevice(address) => {
+                self.show_device(address);
+                sender.input(Input::SetView(View::Dashboard));
+            }
+            Input::DaemonAttached if !self.daemon_attached => {
+                log::info!("watchmated is running, attaching to it");
+                self.daemon_attached = true;
+                for (_, task) in std::mem::take(&mut self.history_tasks) {
+                    task.abort();
+                }
+                self.devices.emit(devices::Input::DaemonAttached);
+                for dashboard in self.dashboards.values() {
+                    dashboard.emit(dashboard::Input::DaemonAttached);
+                }
+            }
+            Input::DaemonDetached if self.daemon_attached => {
+                log::info!("watchmated has stopped, taking background sessions back");
+                self.daemon_attached = false;
+                self.devices.emit(devices::Input::DaemonDetached);
+                self.devices.emit(devices::Input::SetAutoReconnect(
+                    self.persistent_settings.boolean("auto-reconnect-enabled")
+                ));
+                for dashboard in self.dashboards.values() {
+                    dashboard.emit(dashboard::Input::DaemonDetached);
+                }
+                if let Some(manager) = self.manager.clone() {
+                    for infinitime in manager.devices() {
+                        self.start_history_recorder(infinitime);
+                    }
+                }
+            }
+            Input::DaemonAttached | Input::DaemonDetached => (),
+            Input::FlashAssetFromFile(address, file, atype) => {
+                self.set_fwupd_device(address);
                 self.fwupd.emit(firmware_update::Input::FlashAssetFromFile(file, atype));
                 sender.input(Input::SetView(View::FirmwareUpdate));
             }
-            Input::FlashAssetFromUrl(url, atype) => {
-                self.fwupd.emit(firmware_update::Input::FlashAssetFromUrl(url, atype));
+            Input::FlashAssetFromRelease(address, origin, asset, atype) => {
+                self.set_fwupd_device(address);
+                self.fwupd.emit(firmware_update::Input::FlashAssetFromRelease(origin, asset, atype));
                 sender.input(Input::SetView(View::FirmwareUpdate));
             }
+            Input::FlashRelease(address, origin, firmware, resources) => {
+                if let Some(manager) = self.manager.clone() {
+                    self.set_fwupd_device(address);
+                    self.fwupd.emit(firmware_update::Input::FlashRelease {
+                        manager, address, origin, firmware, resources,
+                    });
+                    sender.input(Input::SetView(View::FirmwareUpdate));
+                }
+            }
             Input::Toast(message) => {
                 self.toast_overlay.add_toast(adw::Toast::new(&message));
             }
@@ -247,10 +435,63 @@ pub enum View {
     Dashboard,
     Devices,
     FirmwareUpdate,
+    History,
     Settings,
 }
 
 
+/// Cache of the firmware releases source configured in settings
+fn release_cache(settings: &gio::Settings) -> infinitime::Result<gh::ReleaseCache> {
+    let source = gh::ReleaseSource::new(
+        &settings.string("release-source"),
+        &settings.string("release-source-location"),
+    )?;
+    let token = Some(settings.string("github-token").into());
+    Ok(gh::ReleaseCache::user_default(source)?.with_token(token))
+}
+
+/// Names of the release assets configured in settings
+fn asset_names(settings: &gio::Settings) -> gh::AssetNames {
+    gh::AssetNames {
+        dfu_prefix: settings.string("dfu-asset-prefix").into(),
+        resources_prefix: settings.string("resources-asset-prefix").into(),
+    }
+}
+
+/// CI artifacts of the repository configured in settings
+fn ci_artifacts(settings: &gio::Settings) -> gh::CiArtifacts {
+    let token = Some(settings.string("github-token").into());
+    gh::CiArtifacts::new(&settings.string("ci-repository"), token)
+        .with_branch(&settings.string("ci-branch"))
+}
+
+/// Where a release asset is downloaded from
+#[derive(Debug, Clone)]
+enum AssetOrigin {
+    /// Release, by its tag
+    Release(gh::ReleaseCache, String),
+    /// Unstable CI build
+    Ci(gh::CiArtifacts),
+}
+
+/// Asset content, from the cache if it's a release asset downloaded before
+async fn download_release_asset(
+    origin: &AssetOrigin,
+    asset: &gh::Asset,
+    kind: gh::AssetKind,
+    progress: Option<bt::ProgressTx>,
+) -> infinitime::Result<Vec<u8>> {
+    let result = match origin {
+        AssetOrigin::Release(cache, tag) => cache.download(tag, asset, kind, progress).await,
+        AssetOrigin::Ci(artifacts) => artifacts.download(asset, kind, progress).await,
+    };
+    if let Err(error) = &result {
+        log::error!("Failed to download {}: {}", asset.name, error);
+    }
+    result
+}
+
+
 pub fn run() {
     // Init GTK before libadwaita (ToastOverlay)
     gtk::init().unwrap();
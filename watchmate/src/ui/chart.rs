@@ -0,0 +1,147 @@
+use gtk::{cairo, prelude::{DrawingAreaExt, DrawingAreaExtManual, WidgetExt}};
+use relm4::gtk;
+use std::{cell::RefCell, rc::Rc};
+
+const MARGIN_LEFT: f64 = 36.0;
+const MARGIN_BOTTOM: f64 = 18.0;
+const MARGIN_TOP: f64 = 8.0;
+const MARGIN_RIGHT: f64 = 8.0;
+const GRID_LINES: u32 = 4;
+// Adwaita blue
+const ACCENT: (f64, f64, f64) = (0.21, 0.52, 0.89);
+
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Style {
+    Line,
+    /// Bars of the given width in X units, starting at point X
+    Bars(f64),
+}
+
+#[derive(Debug, Clone)]
+pub struct Data {
+    pub style: Style,
+    pub points: Vec<(f64, f64)>,
+    pub x_range: (f64, f64),
+    pub y_max: f64,
+    pub x_labels: (String, String),
+}
+
+/// Simple cairo chart, just enough to show recorded watch data
+pub struct Chart {
+    area: gtk::DrawingArea,
+    data: Rc<RefCell<Option<Data>>>,
+}
+
+impl Chart {
+    pub fn new(height: i32) -> Self {
+        let area = gtk::DrawingArea::new();
+        area.set_content_height(height);
+        area.set_hexpand(true);
+
+        let data: Rc<RefCell<Option<Data>>> = Rc::default();
+        let data_ = data.clone();
+        area.set_draw_func(move |area, cr, width, height| {
+            let fg = area.color();
+            let fg = (fg.red() as f64, fg.green() as f64, fg.blue() as f64);
+            match data_.borrow().as_ref() {
+                Some(data) if !data.points.is_empty() => {
+                    draw(cr, width as f64, height as f64, fg, data);
+                }
+                _ => draw_placeholder(cr, width as f64, height as f64, fg),
+            }
+        });
+
+        Self { area, data }
+    }
+
+    pub fn widget(&self) -> &gtk::DrawingArea {
+        &self.area
+    }
+
+    pub fn set_data(&self, data: Option<Data>) {
+        *self.data.borrow_mut() = data;
+        self.area.queue_draw();
+    }
+}
+
+
+fn draw(cr: &cairo::Context, width: f64, height: f64, fg: (f64, f64, f64), data: &Data) {
+    let plot_w = width - MARGIN_LEFT - MARGIN_RIGHT;
+    let plot_h = height - MARGIN_TOP - MARGIN_BOTTOM;
+    let (x0, x1) = data.x_range;
+    let y_max = if data.y_max > 0.0 { data.y_max } else { 1.0 };
+    let to_x = |x: f64| MARGIN_LEFT + (x - x0) / (x1 - x0) * plot_w;
+    let to_y = |y: f64| MARGIN_TOP + plot_h - y / y_max * plot_h;
+
+    // Grid with Y labels
+    cr.set_line_width(1.0);
+    cr.set_font_size(10.0);
+    for i in 0..=GRID_LINES {
+        let value = y_max * i as f64 / GRID_LINES as f64;
+        let y = to_y(value).round() + 0.5;
+        cr.set_source_rgba(fg.0, fg.1, fg.2, 0.15);
+        cr.move_to(MARGIN_LEFT, y);
+        cr.line_to(MARGIN_LEFT + plot_w, y);
+        _ = cr.stroke();
+
+        cr.set_source_rgba(fg.0, fg.1, fg.2, 0.6);
+        let label = format_value(value);
+        if let Ok(extents) = cr.text_extents(&label) {
+            cr.move_to(MARGIN_LEFT - extents.width() - 6.0, y + extents.height() / 2.0);
+            _ = cr.show_text(&label);
+        }
+    }
+
+    // X labels
+    let (start, end) = &data.x_labels;
+    cr.move_to(MARGIN_LEFT, height - 4.0);
+    _ = cr.show_text(start);
+    if let Ok(extents) = cr.text_extents(end) {
+        cr.move_to(MARGIN_LEFT + plot_w - extents.width(), height - 4.0);
+        _ = cr.show_text(end);
+    }
+
+    // Data
+    cr.set_source_rgb(ACCENT.0, ACCENT.1, ACCENT.2);
+    match data.style {
+        Style::Line => {
+            cr.set_line_width(2.0);
+            cr.set_line_join(cairo::LineJoin::Round);
+            let mut points = data.points.iter();
+            if let Some(&(x, y)) = points.next() {
+                cr.move_to(to_x(x), to_y(y));
+                for &(x, y) in points {
+                    cr.line_to(to_x(x), to_y(y));
+                }
+                _ = cr.stroke();
+            }
+        }
+        Style::Bars(bar_width) => {
+            let bar_w = (bar_width / (x1 - x0) * plot_w * 0.8).max(1.0);
+            let gap = bar_width / (x1 - x0) * plot_w * 0.1;
+            for &(x, y) in &data.points {
+                cr.rectangle(to_x(x) + gap, to_y(y), bar_w, to_y(0.0) - to_y(y));
+            }
+            _ = cr.fill();
+        }
+    }
+}
+
+fn draw_placeholder(cr: &cairo::Context, width: f64, height: f64, fg: (f64, f64, f64)) {
+    let text = "No data";
+    cr.set_source_rgba(fg.0, fg.1, fg.2, 0.6);
+    cr.set_font_size(14.0);
+    if let Ok(extents) = cr.text_extents(text) {
+        cr.move_to((width - extents.width()) / 2.0, (height + extents.height()) / 2.0);
+        _ = cr.show_text(text);
+    }
+}
+
+fn format_value(value: f64) -> String {
+    if value >= 10_000.0 {
+        format!("{:.0}k", value / 1000.0)
+    } else {
+        format!("{:.0}", value)
+    }
+}
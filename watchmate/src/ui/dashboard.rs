@@ -1,10 +1,10 @@
 use crate::ui;
 use ui::{media_player, firmware_panel, notifications, AssetType};
-use infinitime::{tokio, bt};
+use infinitime::{battery::{self, BatteryMonitor}, bluer, gh, history::History, tokio, bt};
 
 use std::{sync::Arc, path::PathBuf};
 use futures::{stream, StreamExt};
-use gtk::prelude::{BoxExt, ButtonExt, OrientableExt, ListBoxRowExt, WidgetExt};
+use gtk::prelude::{ApplicationExt, BoxExt, ButtonExt, OrientableExt, ListBoxRowExt, SettingsExt, WidgetExt};
 use adw::prelude::{PreferencesRowExt, ExpanderRowExt};
 use relm4::{adw, gtk::{self, gio}, ComponentController, ComponentParts, ComponentSender, Component, Controller, JoinHandle, RelmWidgetExt};
 use anyhow::{Result, Context};
@@ -17,32 +17,41 @@ pub enum Input {
     Disconnected,
     LatestFirmwareVersion(Option<String>),
     FlashAssetFromFile(PathBuf, AssetType),
-    FlashAssetFromUrl(String, AssetType),
+    FlashAssetFromRelease(ui::AssetOrigin, gh::Asset, AssetType),
+    FlashRelease(ui::AssetOrigin, gh::Asset, gh::Asset),
     BatteryLevel(u8),
-    HeartRate(u8),
+    HeartRate(u16),
     StepCount(u32),
+    Motion(bt::MotionSample),
     Alias(String),
     Address(String),
     FirmwareVersion(String),
+    Capabilities(bt::Capabilities),
+    DaemonAttached,
+    DaemonDetached,
 }
 
 #[derive(Debug)]
 pub enum Output {
     FlashAssetFromFile(PathBuf, AssetType),
-    FlashAssetFromUrl(String, AssetType),
+    FlashAssetFromRelease(ui::AssetOrigin, gh::Asset, AssetType),
+    FlashRelease(ui::AssetOrigin, gh::Asset, gh::Asset),
 }
 
 pub struct Model {
     // UI state
     // - InfiniTime data
     battery_level: Option<u8>,
-    heart_rate: Option<u8>,
+    heart_rate: Option<u16>,
     step_count: Option<u32>,
+    motion: Option<bt::MotionSample>,
     alias: Option<String>,
     address: Option<String>,
     fw_version: Option<String>,
     fw_latest: Option<String>,
     fw_update_available: bool,
+    capabilities: Option<bt::Capabilities>,
+    battery_monitor: BatteryMonitor,
     // Components
     player_panel: Controller<media_player::Model>,
     notifications_panel: Controller<notifications::Model>,
@@ -50,6 +59,9 @@ pub struct Model {
     // Other
     infinitime: Option<Arc<bt::InfiniTime>>,
     data_task: Option<JoinHandle<()>>,
+    daemon_attached: bool,
+    settings: gio::Settings,
+    history_db: Option<Arc<History>>,
 }
 
 impl Model {
@@ -64,11 +76,17 @@ impl Model {
             }
         };
 
-        sender.input(Input::Address(infinitime.device().address().to_string()));
+        sender.input(Input::Address(infinitime.address().to_string()));
 
-        send_checked(infinitime.device().alias().await
-            .map(Input::Alias)
-            .context("Failed to read alias"));
+        send_checked(infinitime.capabilities().await
+            .map(Input::Capabilities)
+            .context("Failed to detect watch capabilities"));
+
+        if let Some(device) = infinitime.device() {
+            send_checked(device.alias().await
+                .map(Input::Alias)
+                .context("Failed to read alias"));
+        }
 
         send_checked(infinitime.read_firmware_version().await
             .map(Input::FirmwareVersion)
@@ -85,6 +103,10 @@ impl Model {
         send_checked(infinitime.read_step_count().await
             .map(Input::StepCount)
             .context("Failed to read step count"));
+
+        if let Ok(sample) = infinitime.read_motion().await {
+            sender.input(Input::Motion(sample));
+        }
     }
 
     async fn run_info_listener(infinitime: Arc<bt::InfiniTime>, sender: ComponentSender<Self>) {
@@ -108,16 +130,74 @@ impl Model {
             .map(StreamExt::boxed)
             .unwrap_or(stream::empty().boxed());
 
+        let mut mo_stream = infinitime.get_motion_stream().await
+            .map_err(log_error)
+            .map(StreamExt::boxed)
+            .unwrap_or(stream::empty().boxed());
+
         loop {
             tokio::select! {
                 Some(bl) = bl_stream.next() => sender.input(Input::BatteryLevel(bl)),
                 Some(hr) = hr_stream.next() => sender.input(Input::HeartRate(hr)),
                 Some(sc) = sc_stream.next() => sender.input(Input::StepCount(sc)),
+                Some(mo) = mo_stream.next() => sender.input(Input::Motion(mo)),
                 else => break
             }
         }
     }
 
+    /// Features are assumed to be supported until capabilities are known
+    fn supports(&self, feature: bt::Feature) -> bool {
+        self.capabilities.as_ref().map_or(true, |c| c.supports(feature))
+    }
+
+    fn reset_battery_monitor(&mut self, address: bluer::Address) {
+        self.battery_monitor = BatteryMonitor::default();
+        // Continue the discharge timeline from the recorded levels
+        if let Some(history) = &self.history_db {
+            let now = chrono::Utc::now();
+            match history.battery_levels(address, now - chrono::Duration::days(1)..now) {
+                Ok(samples) => self.battery_monitor.seed(
+                    samples.into_iter().map(|sample| (sample.timestamp, sample.value))
+                ),
+                Err(error) => log::error!("Failed to read battery history: {error}"),
+            }
+        }
+    }
+
+    fn battery_alert_thresholds(&self) -> Vec<u8> {
+        if self.settings.boolean("battery-alerts-enabled") {
+            ["battery-low-threshold", "battery-critical-threshold"].iter()
+                .map(|key| self.settings.int(key).clamp(0, 100) as u8)
+                .collect()
+        } else {
+            Vec::new()
+        }
+    }
+
+    fn show_battery_alert(&self, level: u8) {
+        let body = match self.battery_monitor.time_to_empty() {
+            Some(estimate) => format!(
+                "{level}% left, about {} until empty", battery::format_duration(estimate)
+            ),
+            None => format!("{level}% left"),
+        };
+        let title = match &self.alias {
+            Some(alias) => format!("{alias} battery is low"),
+            None => String::from("Watch battery is low"),
+        };
+        let notification = gio::Notification::new(&title);
+        notification.set_body(Some(&body));
+        notification.set_icon(&gio::ThemedIcon::new("battery-caution-symbolic"));
+        notification.set_priority(gio::NotificationPriority::Urgent);
+        // One notification per watch, replaced by the next alert
+        let id = match &self.address {
+            Some(address) => format!("battery-low-{address}"),
+            None => String::from("battery-low"),
+        };
+        relm4::main_application().send_notification(Some(&id), &notification);
+    }
+
     fn check_fw_update_available(&mut self) {
         let latest = self.fw_latest.as_ref()
             .and_then(|v| Version::from(v));
@@ -132,7 +212,7 @@ impl Model {
 #[relm4::component(pub)]
 impl Component for Model {
     type CommandOutput = ();
-    type Init = (adw::ApplicationWindow, gio::Settings);
+    type Init = (adw::ApplicationWindow, gio::Settings, Option<Arc<History>>);
     type Input = Input;
     type Output = Output;
     type Widgets = Widgets;
@@ -162,6 +242,13 @@ impl Component for Model {
                         ui::BROKER.send(ui::Input::SetView(ui::View::Settings));
                     },
                 },
+                pack_end = &gtk::Button {
+                    set_tooltip_text: Some("History"),
+                    set_icon_name: "document-open-recent-symbolic",
+                    connect_clicked => |_| {
+                        ui::BROKER.send(ui::Input::SetView(ui::View::History));
+                    },
+                },
             },
 
             gtk::ScrolledWindow {
@@ -186,6 +273,8 @@ impl Component for Model {
                                     set_selectable: false,
                                     #[watch]
                                     set_sensitive: model.battery_level.is_some(),
+                                    #[watch]
+                                    set_visible: model.supports(bt::Feature::BatteryLevel),
 
                                     gtk::Box {
                                         set_orientation: gtk::Orientation::Horizontal,
@@ -209,9 +298,12 @@ impl Component for Model {
 
                                         gtk::Label {
                                             #[watch]
-                                            set_label: match model.battery_level {
-                                                Some(soc) => format!("{}%", soc),
-                                                None => String::from("Loading..."),
+                                            set_label: match (model.battery_level, model.battery_monitor.time_to_empty()) {
+                                                (Some(soc), Some(estimate)) => {
+                                                    format!("{}% · {} left", soc, battery::format_duration(estimate))
+                                                }
+                                                (Some(soc), None) => format!("{}%", soc),
+                                                (None, _) => String::from("Loading..."),
                                             }.as_str(),
                                             add_css_class: "dim-label",
                                         },
@@ -222,6 +314,8 @@ impl Component for Model {
                                     set_selectable: false,
                                     #[watch]
                                     set_sensitive: model.heart_rate.is_some(),
+                                    #[watch]
+                                    set_visible: model.supports(bt::Feature::HeartRate),
 
                                     gtk::Box {
                                         set_orientation: gtk::Orientation::Horizontal,
@@ -251,6 +345,8 @@ impl Component for Model {
                                     set_selectable: false,
                                     #[watch]
                                     set_sensitive: model.step_count.is_some(),
+                                    #[watch]
+                                    set_visible: model.supports(bt::Feature::StepCount),
 
                                     gtk::Box {
                                         set_orientation: gtk::Orientation::Horizontal,
@@ -275,6 +371,38 @@ impl Component for Model {
                                         },
                                     },
                                 },
+
+                                gtk::ListBoxRow {
+                                    set_selectable: false,
+                                    #[watch]
+                                    set_sensitive: model.motion.is_some(),
+                                    #[watch]
+                                    set_visible: model.supports(bt::Feature::Motion),
+
+                                    gtk::Box {
+                                        set_orientation: gtk::Orientation::Horizontal,
+                                        set_margin_all: 12,
+                                        set_spacing: 10,
+
+                                        gtk::Label {
+                                            set_label: "Motion",
+                                            set_hexpand: true,
+                                            set_halign: gtk::Align::Start,
+                                        },
+
+                                        gtk::Label {
+                                            #[watch]
+                                            set_label: match model.motion {
+                                                Some(m) => format!("X: {}  Y: {}  Z: {}", m.x, m.y, m.z),
+                                                None => String::from("Loading..."),
+                                            }.as_str(),
+                                            add_css_class: "dim-label",
+                                            add_css_class: "numeric",
+                                            set_hexpand: true,
+                                            set_halign: gtk::Align::End,
+                                        },
+                                    },
+                                },
                             },
 
                             gtk::Label {
@@ -291,6 +419,8 @@ impl Component for Model {
                                     set_selectable: false,
                                     #[watch]
                                     set_sensitive: model.alias.is_some(),
+                                    #[watch]
+                                    set_visible: !model.daemon_attached && model.supports(bt::Feature::MediaPlayer),
                                     set_child: Some(model.player_panel.widget()),
                                 },
 
@@ -298,8 +428,24 @@ impl Component for Model {
                                     set_selectable: false,
                                     #[watch]
                                     set_sensitive: model.alias.is_some(),
+                                    #[watch]
+                                    set_visible: !model.daemon_attached && model.supports(bt::Feature::Notifications),
                                     set_child: Some(model.notifications_panel.widget()),
                                 },
+
+                                gtk::ListBoxRow {
+                                    set_selectable: false,
+                                    #[watch]
+                                    set_visible: model.daemon_attached,
+
+                                    #[wrap(Some)]
+                                    set_child = &gtk::Label {
+                                        set_label: "Media player and notifications are handled by watchmated",
+                                        set_wrap: true,
+                                        set_margin_all: 12,
+                                        add_css_class: "dim-label",
+                                    },
+                                },
                             },
 
                             gtk::Label {
@@ -398,6 +544,8 @@ impl Component for Model {
                                     add_row = &gtk::ListBoxRow {
                                         set_selectable: false,
                                         #[watch]
+                                        set_visible: model.supports(bt::Feature::FirmwareUpgrade),
+                                        #[watch]
                                         set_child: Some(model.firmware_panel.widget()),
                                     },
                                 },
@@ -429,38 +577,45 @@ impl Component for Model {
         }
     }
 
-    fn init((window, settings): Self::Init, root: &Self::Root, sender: ComponentSender<Self>) -> ComponentParts<Self> {
+    fn init((window, settings, history_db): Self::Init, root: &Self::Root, sender: ComponentSender<Self>) -> ComponentParts<Self> {
 
         let player_panel = media_player::Model::builder()
             .launch(())
             .detach();
 
         let notifications_panel = notifications::Model::builder()
-            .launch(settings)
+            .launch(settings.clone())
             .detach();
 
         let firmware_panel = firmware_panel::Model::builder()
-            .launch(window)
+            .launch((window, settings.clone()))
             .forward(&sender.input_sender(), |message| match message {
                 firmware_panel::Output::LatestFirmwareVersion(f) => Input::LatestFirmwareVersion(f),
                 firmware_panel::Output::FlashAssetFromFile(f, t) => Input::FlashAssetFromFile(f, t),
-                firmware_panel::Output::FlashAssetFromUrl(u, t) => Input::FlashAssetFromUrl(u, t),
+                firmware_panel::Output::FlashAssetFromRelease(c, a, t) => Input::FlashAssetFromRelease(c, a, t),
+                firmware_panel::Output::FlashRelease(c, f, r) => Input::FlashRelease(c, f, r),
             });
 
         let model = Model {
             battery_level: None,
             heart_rate: None,
             step_count: None,
+            motion: None,
             alias: None,
             address: None,
             fw_version: None,
             fw_latest: None,
             fw_update_available: false,
+            capabilities: None,
+            battery_monitor: BatteryMonitor::default(),
             player_panel,
             notifications_panel,
             firmware_panel,
             infinitime: None,
             data_task: None,
+            daemon_attached: false,
+            settings,
+            history_db,
         };
 
         let widgets = view_output!();
@@ -472,13 +627,16 @@ impl Component for Model {
         match msg {
             Input::Connected(infinitime) => {
                 self.infinitime = Some(infinitime.clone());
-                // Propagate to components
-                self.player_panel.emit(
-                    media_player::Input::Device(Some(infinitime.clone()))
-                );
-                self.notifications_panel.emit(
-                    notifications::Input::Device(Some(infinitime.clone()))
-                );
+                self.reset_battery_monitor(infinitime.address());
+                // Propagate to components, unless watchmated runs these sessions
+                if !self.daemon_attached {
+                    self.player_panel.emit(
+                        media_player::Input::Device(Some(infinitime.clone()))
+                    );
+                    self.notifications_panel.emit(
+                        notifications::Input::Device(Some(infinitime.clone()))
+                    );
+                }
                 // Read data from the watch
                 self.data_task = Some(relm4::spawn(async move {
                     // Read initial values
@@ -491,10 +649,12 @@ impl Component for Model {
             Input::Disconnected => {
                 self.battery_level = None;
                 self.heart_rate = None;
+                self.motion = None;
                 self.alias = None;
                 self.address = None;
                 self.fw_version = None;
                 self.fw_update_available = false;
+                self.capabilities = None;
                 self.infinitime = None;
                 // Abort data update task
                 self.data_task.take().map(|h| h.abort());
@@ -509,12 +669,20 @@ impl Component for Model {
             Input::FlashAssetFromFile(f, t) => {
                 sender.output(Output::FlashAssetFromFile(f, t)).unwrap();
             }
-            Input::FlashAssetFromUrl(u, t) => {
-                sender.output(Output::FlashAssetFromUrl(u, t)).unwrap();
+            Input::FlashAssetFromRelease(c, a, t) => {
+                sender.output(Output::FlashAssetFromRelease(c, a, t)).unwrap();
+            }
+            Input::FlashRelease(c, f, r) => {
+                sender.output(Output::FlashRelease(c, f, r)).unwrap();
             }
             // -- Watch data --
             Input::BatteryLevel(soc) => {
                 self.battery_level = Some(soc);
+                self.battery_monitor.set_thresholds(&self.battery_alert_thresholds());
+                // watchmated shows its own alerts
+                if self.battery_monitor.update(soc).is_some() && !self.daemon_attached {
+                    self.show_battery_alert(soc);
+                }
             }
             Input::HeartRate(rate) => {
                 self.heart_rate = Some(rate);
@@ -522,6 +690,9 @@ impl Component for Model {
             Input::StepCount(count) => {
                 self.step_count = Some(count);
             }
+            Input::Motion(sample) => {
+                self.motion = Some(sample);
+            }
             Input::Alias(alias) => {
                 self.alias = Some(alias);
             }
@@ -535,7 +706,36 @@ impl Component for Model {
                 self.fw_version = Some(version);
                 self.check_fw_update_available();
             }
+            Input::Capabilities(capabilities) => {
+                // Don't run host sessions the firmware can't take part in
+                if !capabilities.supports(bt::Feature::MediaPlayer) {
+                    self.player_panel.emit(media_player::Input::Device(None));
+                }
+                if !capabilities.supports(bt::Feature::Notifications) {
+                    self.notifications_panel.emit(notifications::Input::Device(None));
+                }
+                self.capabilities = Some(capabilities);
+            }
+            Input::DaemonAttached => {
+                self.daemon_attached = true;
+                self.player_panel.emit(media_player::Input::Device(None));
+                self.notifications_panel.emit(notifications::Input::Device(None));
+            }
+            Input::DaemonDetached => {
+                self.daemon_attached = false;
+                if let Some(infinitime) = &self.infinitime {
+                    if self.supports(bt::Feature::MediaPlayer) {
+                        self.player_panel.emit(media_player::Input::Device(Some(infinitime.clone())));
+                    }
+                    if self.supports(bt::Feature::Notifications) {
+                        self.notifications_panel.emit(notifications::Input::Device(Some(infinitime.clone())));
+                    }
+                }
+            }
         }
     }
-}
 
+    fn shutdown(&mut self, _widgets: &mut Self::Widgets, _output: relm4::Sender<Self::Output>) {
+        self.data_task.take().map(|h| h.abort());
+    }
+}
@@ -1,6 +1,6 @@
 use crate::ui;
 use infinitime::{ bluer, bt };
-use std::sync::Arc;
+use std::{collections::HashSet, sync::Arc};
 use futures::{pin_mut, StreamExt};
 use gtk::prelude::{BoxExt, ButtonExt, OrientableExt, ListBoxRowExt, WidgetExt};
 use relm4::{
@@ -25,37 +25,35 @@ pub enum Input {
     DeviceConnectionFailed,
     DeviceConnectionLost(bluer::Address),
     SetAutoReconnect(bool),
+    DaemonAttached,
+    DaemonDetached,
 }
 
 #[derive(Debug)]
 pub enum Output {
+    AdapterReady(Arc<bt::DeviceManager>),
     DeviceConnected(Arc<bluer::Device>),
+    /// Already connected device is selected
+    DeviceSelected(bluer::Address),
 }
 
 #[derive(Debug)]
 pub enum CommandOutput {
-    InitAdapterResult(bluer::Result<bluer::Adapter>),
-    GattServicesResult(bluer::Result<bluer::gatt::local::ApplicationHandle>),
+    InitAdapterResult(infinitime::Result<bluer::Adapter>),
+    GattServicesResult(infinitime::Result<()>),
     KnownDevices(Vec<DeviceInfo>),
 }
 
 pub struct Model {
     devices: FactoryVecDeque<DeviceInfo>,
-    adapter: Option<Arc<bluer::Adapter>>,
-    gatt_server: Option<bluer::gatt::local::ApplicationHandle>,
+    manager: Option<Arc<bt::DeviceManager>>,
     discovery_task: Option<JoinHandle<()>>,
     auto_reconnect: bool,
-    auto_reconnect_address: Option<bluer::Address>,
+    auto_reconnect_addresses: HashSet<bluer::Address>,
+    daemon_attached: bool,
 }
 
 impl Model {
-    async fn init_adapter() -> bluer::Result<bluer::Adapter> {
-        let session = bluer::Session::new().await?;
-        let adapter = session.default_adapter().await?;
-        adapter.set_powered(true).await?;
-        Ok(adapter)
-    }
-
     async fn run_discovery(adapter: Arc<bluer::Adapter>, sender: ComponentSender<Self>) {
         match adapter.discover_devices().await {
             Ok(stream) => {
@@ -121,7 +119,7 @@ impl Component for Model {
                 set_maximum_size: 400,
                 set_vexpand: true,
 
-                if model.adapter.is_some() {
+                if model.manager.is_some() {
                     gtk::Box {
                         set_orientation: gtk::Orientation::Vertical,
                         set_margin_all: 12,
@@ -174,11 +172,11 @@ impl Component for Model {
             });
         let model = Self {
             devices,
-            adapter: None,
-            gatt_server: None,
+            manager: None,
             discovery_task: None,
             auto_reconnect: false,
-            auto_reconnect_address: None,
+            auto_reconnect_addresses: HashSet::new(),
+            daemon_attached: false,
         };
 
         let factory_widget = model.devices.widget();
@@ -193,13 +191,14 @@ impl Component for Model {
         match msg {
             Input::InitAdapter => {
                 sender.oneshot_command(async move {
-                    CommandOutput::InitAdapterResult(Self::init_adapter().await)
+                    CommandOutput::InitAdapterResult(bt::init_adapter().await)
                 });
             }
 
             Input::StartDiscovery => {
                 if self.discovery_task.is_none() {
-                    if let Some(adapter) = self.adapter.clone() {
+                    if let Some(manager) = &self.manager {
+                        let adapter = manager.adapter().clone();
                         self.devices.guard().clear();
                         self.discovery_task = Some(relm4::spawn(Self::run_discovery(adapter, sender)));
                         log::info!("Device discovery started");
@@ -223,16 +222,19 @@ impl Component for Model {
                 let address = info.address;
                 let mut devices = self.devices.guard();
                 devices.push_front(info);
-                if Some(address) == self.auto_reconnect_address {
+                if self.auto_reconnect_addresses.remove(&address) {
                     log::debug!("Detected lost device: {}. Trying to reconnect...", address);
-                    sender.input(Input::StopDiscovery);
+                    // Keep looking for the other lost devices
+                    if self.auto_reconnect_addresses.is_empty() {
+                        sender.input(Input::StopDiscovery);
+                    }
                     devices.send(0, DeviceInput::Connect);
                 }
             }
 
             Input::DeviceAdded(address) => {
-                if let Some(adapter) = &self.adapter {
-                    if let Ok(device) = adapter.device(address) {
+                if let Some(manager) = &self.manager {
+                    if let Ok(device) = manager.adapter().device(address) {
                         let device = Arc::new(device);
                         relm4::spawn(async move {
                             if bt::InfiniTime::check_device(&device).await {
@@ -261,22 +263,30 @@ impl Component for Model {
 
             Input::DeviceSelected(index) => {
                 log::debug!("Device selected: {}", index);
-                sender.input(Input::StopDiscovery);
-                self.auto_reconnect_address = None;
-                self.devices.send(index as usize, DeviceInput::Connect);
+                let index = index as usize;
+                match self.devices.get(index) {
+                    // Several devices can be connected at once, show the selected one
+                    Some(info) if info.state == DeviceState::Connected => {
+                        sender.output(Output::DeviceSelected(info.address)).unwrap();
+                    }
+                    Some(info) => {
+                        sender.input(Input::StopDiscovery);
+                        self.auto_reconnect_addresses.remove(&info.address);
+                        self.devices.send(index, DeviceInput::Connect);
+                    }
+                    None => (),
+                }
             }
 
             Input::DeviceConnected(device) => {
                 log::debug!("Device connected successfully: {}", device.address());
-                self.auto_reconnect_address = None;
+                self.auto_reconnect_addresses.remove(&device.address());
                 sender.output(Output::DeviceConnected(device)).unwrap();
             }
 
             Input::DeviceDisconnected(device) => {
                 log::debug!("Device disconnected successfully: {}", device.address());
-                if Some(device.address()) == self.auto_reconnect_address {
-                    self.auto_reconnect_address = None;
-                }
+                self.auto_reconnect_addresses.remove(&device.address());
                 sender.input(Input::StartDiscovery);
             }
 
@@ -294,14 +304,35 @@ impl Component for Model {
                     devices.send(idx, DeviceInput::StateUpdated(DeviceState::Disconnected));
                 }
                 if self.auto_reconnect {
-                    self.auto_reconnect_address = Some(address);
+                    self.auto_reconnect_addresses.insert(address);
                     sender.input(Input::StartDiscovery);
                 }
             }
 
             Input::SetAutoReconnect(enabled) => {
-                self.auto_reconnect = enabled;
-                self.auto_reconnect_address = None;
+                // Reconnection is handled by watchmated if it's running
+                self.auto_reconnect = enabled && !self.daemon_attached;
+                self.auto_reconnect_addresses.clear();
+            }
+
+            Input::DaemonAttached => {
+                // watchmated owns the connection and serves current time
+                self.daemon_attached = true;
+                self.auto_reconnect = false;
+                self.auto_reconnect_addresses.clear();
+                if let Some(manager) = &self.manager {
+                    manager.stop_gatt_services();
+                }
+            }
+
+            Input::DaemonDetached => {
+                // Serve current time again, reconnection is set up by the caller
+                self.daemon_attached = false;
+                if let Some(manager) = self.manager.clone() {
+                    sender.oneshot_command(async move {
+                        CommandOutput::GattServicesResult(manager.start_gatt_services().await)
+                    });
+                }
             }
         }
     }
@@ -310,19 +341,20 @@ impl Component for Model {
         match msg {
             CommandOutput::InitAdapterResult(result) => match result {
                 Ok(adapter) => {
-                    let adapter = Arc::new(adapter);
-                    self.adapter = Some(adapter.clone());
+                    let manager = Arc::new(bt::DeviceManager::new(Arc::new(adapter)));
+                    self.manager = Some(manager.clone());
+                    sender.output(Output::AdapterReady(manager.clone())).unwrap();
 
                     // Start GATT serices
-                    let adapter_ = adapter.clone();
+                    let manager_ = manager.clone();
                     sender.oneshot_command(async move {
-                        CommandOutput::GattServicesResult(bt::start_gatt_services(&adapter_).await)
+                        CommandOutput::GattServicesResult(manager_.start_gatt_services().await)
                     });
 
                     // Read known devices list
                     sender.oneshot_command(async move {
                         let mut devices = Vec::new();
-                        for device in bt::InfiniTime::list_known_devices(&adapter).await.unwrap() {
+                        for device in manager.list_known_devices().await.unwrap() {
                             devices.push(DeviceInfo::new(Arc::new(device)).await.unwrap())
                         }
                         CommandOutput::KnownDevices(devices)
@@ -333,8 +365,10 @@ impl Component for Model {
                 }
             }
             CommandOutput::GattServicesResult(result) => match result {
-                Ok(handle) => {
-                    self.gatt_server = Some(handle);
+                Ok(()) => {
+                    if let (Some(manager), true) = (&self.manager, self.daemon_attached) {
+                        manager.stop_gatt_services();
+                    }
                 }
                 Err(error) => {
                     log::error!("Failed to start GATT server: {error}");
@@ -343,9 +377,10 @@ impl Component for Model {
             }
 
             CommandOutput::KnownDevices(devices) => {
-                let connected = devices.iter()
-                    .find(|d| d.state == DeviceState::Connected)
-                    .map(|d| d.address);
+                let connected: Vec<_> = devices.iter()
+                    .filter(|d| d.state == DeviceState::Connected)
+                    .map(|d| d.device.clone())
+                    .collect();
 
                 let mut devices_guard = self.devices.guard();
                 for device in devices {
@@ -353,14 +388,11 @@ impl Component for Model {
                 }
 
                 // Automatic device selection logic
-                if let Some(address) = connected {
-                    // If suitable device is already connected - just report it as connected
-                    if let Some(adapter) = &self.adapter {
-                        if let Ok(device) = adapter.device(address) {
-                            let device = Arc::new(device);
-                            sender.output(Output::DeviceConnected(device)).unwrap();
-                            log::info!("InfiniTime ({}) is already connected", address.to_string());
-                        }
+                if !connected.is_empty() {
+                    // If suitable devices are already connected - just report them as connected
+                    for device in connected {
+                        log::info!("InfiniTime ({}) is already connected", device.address().to_string());
+                        sender.output(Output::DeviceConnected(device)).unwrap();
                     }
                 } else {
                     if devices_guard.len() == 1 {
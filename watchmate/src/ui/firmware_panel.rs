@@ -1,16 +1,14 @@
 use crate::ui;
 use super::AssetType;
-use infinitime::gh;
+use infinitime::{bt, compatibility::{self, UpdatePlan, Warning}, gh, tokio, Result};
 
-use std::path::PathBuf;
+use std::{path::PathBuf, time::SystemTime};
 use relm4::{
     adw, gtk::{self, gio},
     gtk::prelude::*,
     ComponentController, ComponentParts, ComponentSender, Component, Controller, JoinHandle, RelmWidgetExt
 };
 use relm4_components::{open_dialog::*, save_dialog::*, alert::*};
-use anyhow::Result;
-use version_compare as vercomp;
 
 
 #[derive(Debug)]
@@ -24,7 +22,8 @@ pub enum Input {
     // Firmware & Resources Download
     DownloadFirmware,
     DownloadResources,
-    DownloadAsset(gh::Asset),
+    DownloadAsset(String, gh::Asset, gh::AssetKind),
+    DownloadProgress(u32, u32),
     CancelDownloading,
     FinishedDownloading(Result<Vec<u8>>),
     SaveFile(PathBuf),
@@ -38,18 +37,37 @@ pub enum Input {
     FlashResourcesFromReleaseClicked,
     FlashResourcesFromRelease,
     FlashResourcesFromFile(PathBuf),
+    FlashAllFromReleaseClicked,
+    FlashAllFromRelease,
+
+    // Unstable CI builds
+    CiSettingsChanged,
+    RequestCiBuilds,
+    SelectedCiBuild(u32),
+    FlashCiBuildClicked(AssetType),
+    FlashCiBuild(AssetType),
+    CiBuildPage,
+
+    // Previously downloaded assets
+    RequestCachedAssets,
+    SelectedCachedAsset(u32),
+    FlashFromCacheClicked,
 }
 
 #[derive(Debug)]
 pub enum Output {
     FlashAssetFromFile(PathBuf, AssetType),
-    FlashAssetFromUrl(String, AssetType),
+    FlashAssetFromRelease(ui::AssetOrigin, gh::Asset, AssetType),
+    /// Firmware and resources assets of the same release
+    FlashRelease(ui::AssetOrigin, gh::Asset, gh::Asset),
     LatestFirmwareVersion(Option<String>),
 }
 
 #[derive(Debug)]
 pub enum CommandOutput {
-    FirmwareReleasesResponse(Result<Vec<gh::ReleaseInfo>>),
+    FirmwareReleasesResponse(Result<gh::ReleaseList>),
+    CiBuildsResponse(Result<Vec<gh::CiBuild>>),
+    CachedAssetsResponse(Result<Vec<gh::CachedAsset>>),
     SaveFileResponse(Result<()>),
 }
 
@@ -87,6 +105,29 @@ impl FirmwareReleasesState {
     }
 }
 
+#[derive(Debug, Default)]
+pub enum CiBuildsState {
+    #[default]
+    None,
+    Requested,
+    Some(Vec<gh::CiBuild>),
+    Error,
+}
+
+impl CiBuildsState {
+    pub fn as_option(&self) -> Option<&Vec<gh::CiBuild>> {
+        match &self {
+            CiBuildsState::Some(builds) => Some(builds),
+            _ => None,
+        }
+    }
+
+    /// Whether there are any builds to choose from
+    pub fn is_some(&self) -> bool {
+        self.as_option().is_some_and(|builds| !builds.is_empty())
+    }
+}
+
 pub struct Model {
     // UI state
     releases: FirmwareReleasesState,
@@ -94,16 +135,33 @@ pub struct Model {
     selected_index: u32,
     resources_available: bool,
     current_version: String,
+    // Configured releases source, refreshed along with releases
+    release_cache: Option<gh::ReleaseCache>,
+    asset_names: gh::AssetNames,
+    // Set when the source is unreachable and releases come from the cache
+    releases_cached_at: Option<SystemTime>,
+    // Exceeded GitHub API rate limit, reported by the last request
+    rate_limit: Option<gh::RateLimit>,
+    cached_assets: Vec<gh::CachedAsset>,
+    cached_names: Option<gtk::StringList>,
+    cached_index: u32,
+    ci_artifacts: Option<gh::CiArtifacts>,
+    ci_builds: CiBuildsState,
+    ci_labels: Option<gtk::StringList>,
+    ci_index: u32,
     // Firmware download state
     download_task: Option<JoinHandle<()>>,
+    // Downloaded and total bytes, if the size is known
+    download_progress: (u32, u32),
     download_content: Option<Vec<u8>>,
     download_filepath: Option<PathBuf>,
     // Components
     dfu_open_dialog: Controller<OpenDialog>,
     res_open_dialog: Controller<OpenDialog>,
     save_dialog: Controller<SaveDialog>,
-    firmware_downgrade_warning: Controller<Alert>,
-    resource_mismatch_warning: Controller<Alert>,
+    compatibility_alert: Option<Controller<Alert>>,
+    main_window: adw::ApplicationWindow,
+    settings: gio::Settings,
 }
 
 impl Model {
@@ -126,12 +184,98 @@ impl Model {
             None
         }
     }
+
+    /// Selected release along with the cache it's listed by
+    fn selected_release(&self) -> Option<(&gh::ReleaseCache, &gh::ReleaseInfo)> {
+        Some((self.release_cache.as_ref()?, self.selected_release_info()?))
+    }
+
+    fn selected_ci_build(&self) -> Option<(&gh::CiArtifacts, &gh::CiBuild)> {
+        let build = self.ci_builds.as_option()?.get(self.ci_index as usize)?;
+        Some((self.ci_artifacts.as_ref()?, build))
+    }
+
+    fn ci_status(&self) -> &'static str {
+        match &self.ci_builds {
+            CiBuildsState::None => "CI builds are not loaded",
+            CiBuildsState::Requested => "Getting CI builds...",
+            CiBuildsState::Some(_) => "No CI builds found",
+            CiBuildsState::Error => "Failed to get CI builds",
+        }
+    }
+
+    fn selected_cached_asset(&self) -> Option<&gh::CachedAsset> {
+        self.cached_assets.get(self.cached_index as usize)
+    }
+
+    fn offline_status(&self) -> Option<String> {
+        let cached_at = chrono::DateTime::<chrono::Local>::from(self.releases_cached_at?);
+        let status = if self.rate_limit.is_some() { "Cached" } else { "Offline" };
+        Some(format!("{}, releases as of {}", status, cached_at.format("%Y-%m-%d %H:%M")))
+    }
+
+    fn rate_limit_status(&self) -> Option<String> {
+        let rate_limit = self.rate_limit?;
+        let mut status = String::from("GitHub API rate limit exceeded");
+        if let Some(reset) = rate_limit.reset {
+            let reset = chrono::DateTime::<chrono::Local>::from(reset);
+            status.push_str(&format!(" until {}", reset.format("%H:%M")));
+        }
+        if self.settings.string("github-token").is_empty() {
+            status.push_str(", set a GitHub token in settings to raise it");
+        }
+        Some(status)
+    }
+
+    fn update_plan(&self) -> UpdatePlan {
+        let current = Some(self.current_version.as_str()).filter(|v| !v.is_empty());
+        UpdatePlan::new(current)
+    }
+
+    /// Send the flashing input right away,
+    /// or after the user confirms compatibility warnings
+    fn confirm_flashing(
+        &mut self,
+        warnings: Vec<Warning>,
+        flash: impl Fn() -> Input + 'static,
+        sender: ComponentSender<Self>,
+    ) {
+        if warnings.is_empty() {
+            sender.input(flash());
+            return;
+        }
+
+        let text = match warnings.as_slice() {
+            [warning] => format!("Warning: {}!", warning.title().to_lowercase()),
+            _ => String::from("Warning: compatibility issues!"),
+        };
+        let details = warnings.iter().map(ToString::to_string).collect::<Vec<_>>().join("\n\n");
+        let critical = warnings.iter().any(Warning::is_critical);
+        let alert = Alert::builder()
+            .transient_for(&self.main_window)
+            .launch(AlertSettings {
+                text,
+                secondary_text: Some(details),
+                confirm_label: String::from(if critical { "Proceed Anyway" } else { "Proceed" }),
+                cancel_label: String::from("Cancel"),
+                option_label: None,
+                is_modal: true,
+                destructive_accept: true,
+            })
+            .forward(sender.input_sender(), move |message| match message {
+                AlertResponse::Confirm => flash(),
+                AlertResponse::Cancel => Input::None,
+                AlertResponse::Option => Input::None,
+            });
+        alert.emit(AlertMsg::Show);
+        self.compatibility_alert = Some(alert);
+    }
 }
 
 #[relm4::component(pub)]
 impl Component for Model {
     type CommandOutput = CommandOutput;
-    type Init = adw::ApplicationWindow;
+    type Init = (adw::ApplicationWindow, gio::Settings);
     type Input = Input;
     type Output = Output;
     type Widgets = Widgets;
@@ -143,7 +287,7 @@ impl Component for Model {
             set_spacing: 10,
 
             gtk::Label {
-                set_label: "Update from GitHub release",
+                set_label: "Update from release",
                 set_halign: gtk::Align::Start,
             },
 
@@ -189,6 +333,14 @@ impl Component for Model {
                             set_spacing: 10,
                             set_orientation: gtk::Orientation::Vertical,
 
+                            gtk::Button {
+                                set_label: "Flash Firmware and Resources",
+                                set_tooltip_text: Some("Flash firmware, then upload matching resources once the watch restarts"),
+                                #[watch]
+                                set_sensitive: model.resources_available,
+                                connect_clicked => Input::FlashAllFromReleaseClicked,
+                            },
+
                             gtk::Button {
                                 set_label: "Download",
                                 connect_clicked => Input::DownloadFirmware,
@@ -242,6 +394,130 @@ impl Component for Model {
                 }
             },
 
+            gtk::Label {
+                set_halign: gtk::Align::Start,
+                add_css_class: "dim-label",
+                #[watch]
+                set_visible: model.download_task.is_some() && model.download_progress.1 > 0,
+                #[watch]
+                set_label: &format!(
+                    "Downloading: {:.1} KB / {:.1} KB",
+                    model.download_progress.0 as f32 / 1024.0,
+                    model.download_progress.1 as f32 / 1024.0,
+                ),
+            },
+
+            gtk::Label {
+                set_halign: gtk::Align::Start,
+                add_css_class: "dim-label",
+                #[watch]
+                set_visible: model.releases_cached_at.is_some(),
+                #[watch]
+                set_label: &model.offline_status().unwrap_or_default(),
+            },
+
+            gtk::Label {
+                set_halign: gtk::Align::Start,
+                set_wrap: true,
+                add_css_class: "warning",
+                #[watch]
+                set_visible: model.rate_limit.is_some(),
+                #[watch]
+                set_label: &model.rate_limit_status().unwrap_or_default(),
+            },
+
+            gtk::Separator {
+                set_orientation: gtk::Orientation::Horizontal,
+            },
+
+            gtk::Box {
+                set_spacing: 6,
+
+                gtk::Label {
+                    set_label: "Update from CI build",
+                    set_halign: gtk::Align::Start,
+                },
+
+                gtk::Label {
+                    set_label: "Unstable",
+                    set_tooltip_text: Some("Unreleased builds of pull requests and the main branch"),
+                    add_css_class: "warning",
+                    add_css_class: "caption-heading",
+                },
+            },
+
+            gtk::Box {
+                set_spacing: 10,
+
+                gtk::DropDown {
+                    set_hexpand: true,
+                    #[watch]
+                    set_visible: model.ci_builds.is_some(),
+                    #[watch]
+                    set_model: model.ci_labels.as_ref(),
+                    connect_selected_notify[sender] => move |wgt| {
+                        sender.input(Input::SelectedCiBuild(wgt.selected()));
+                    }
+                },
+
+                adw::SplitButton {
+                    #[watch]
+                    set_visible: model.ci_builds.is_some(),
+                    set_label: "Flash",
+                    connect_clicked => Input::FlashCiBuildClicked(AssetType::Firmware),
+                    #[wrap(Some)]
+                    set_popover = &gtk::Popover {
+                        gtk::Box {
+                            set_spacing: 10,
+                            set_orientation: gtk::Orientation::Vertical,
+
+                            gtk::Button {
+                                set_label: "Flash Firmware and Resources",
+                                connect_clicked => Input::FlashCiBuildClicked(AssetType::FirmwareAndResources),
+                            },
+
+                            gtk::Button {
+                                set_label: "Flash Resources",
+                                connect_clicked => Input::FlashCiBuildClicked(AssetType::Resources),
+                            },
+
+                            gtk::Button {
+                                set_label: "Build Page",
+                                connect_clicked => Input::CiBuildPage,
+                            },
+                        },
+                    },
+                },
+
+                gtk::Label {
+                    set_hexpand: true,
+                    #[watch]
+                    set_visible: !model.ci_builds.is_some(),
+                    #[watch]
+                    set_label: model.ci_status(),
+                },
+
+                if matches!(model.ci_builds, CiBuildsState::Requested) {
+                    gtk::Spinner {
+                        set_spinning: true,
+                    }
+                } else {
+                    gtk::Button {
+                        set_tooltip_text: Some("Refresh CI builds list"),
+                        set_icon_name: "refresh-symbolic",
+                        connect_clicked => Input::RequestCiBuilds,
+                    }
+                }
+            },
+
+            gtk::Label {
+                set_halign: gtk::Align::Start,
+                add_css_class: "dim-label",
+                set_label: "Downloading CI builds requires a GitHub token, see settings",
+                #[watch]
+                set_visible: model.ci_builds.is_some() && !model.ci_artifacts.as_ref().is_some_and(|a| a.has_token()),
+            },
+
             gtk::Separator {
                 set_orientation: gtk::Orientation::Horizontal,
             },
@@ -266,11 +542,55 @@ impl Component for Model {
                     set_hexpand: true,
                     connect_clicked => Input::OpenResourcesFileDialog,
                 },
+            },
+
+            gtk::Separator {
+                set_orientation: gtk::Orientation::Horizontal,
+            },
+
+            gtk::Label {
+                set_label: "Update from cache",
+                set_halign: gtk::Align::Start,
+            },
+
+            gtk::Box {
+                set_spacing: 10,
+
+                gtk::DropDown {
+                    set_hexpand: true,
+                    #[watch]
+                    set_visible: !model.cached_assets.is_empty(),
+                    #[watch]
+                    set_model: model.cached_names.as_ref(),
+                    connect_selected_notify[sender] => move |wgt| {
+                        sender.input(Input::SelectedCachedAsset(wgt.selected()));
+                    }
+                },
+
+                gtk::Button {
+                    set_label: "Flash",
+                    #[watch]
+                    set_visible: !model.cached_assets.is_empty(),
+                    connect_clicked => Input::FlashFromCacheClicked,
+                },
+
+                gtk::Label {
+                    set_hexpand: true,
+                    #[watch]
+                    set_visible: model.cached_assets.is_empty(),
+                    set_label: "No downloaded firmware or resources",
+                },
+
+                gtk::Button {
+                    set_tooltip_text: Some("Refresh cached files list"),
+                    set_icon_name: "refresh-symbolic",
+                    connect_clicked => Input::RequestCachedAssets,
+                },
             }
         }
     }
 
-    fn init(main_window: Self::Init, root: &Self::Root, sender: ComponentSender<Self>) -> ComponentParts<Self> {
+    fn init((main_window, settings): Self::Init, root: &Self::Root, sender: ComponentSender<Self>) -> ComponentParts<Self> {
         let file_filter = gtk::FileFilter::new();
         file_filter.add_pattern("*.zip");
 
@@ -306,57 +626,44 @@ impl Component for Model {
                 SaveDialogResponse::Cancel => Input::CancelDownloading,
             });
 
-        let firmware_downgrade_warning = Alert::builder()
-            .transient_for(&main_window)
-            .launch(AlertSettings {
-                text: String::from("Warning: downgrading!"),
-                secondary_text: Some(String::from("Are you sure you want to downgrade the firmware?")),
-                confirm_label: String::from("Proceed"),
-                cancel_label: String::from("Cancel"),
-                option_label: None,
-                is_modal: true,
-                destructive_accept: true,
-            })
-            .forward(sender.input_sender(), |message| match message {
-                AlertResponse::Confirm => Input::FlashFirmwareFromRelease,
-                AlertResponse::Cancel => Input::None,
-                AlertResponse::Option => Input::None,
-            });
-
-        let resource_mismatch_warning = Alert::builder()
-            .transient_for(&main_window)
-            .launch(AlertSettings {
-                text: String::from("Warning: version mismatch!"),
-                secondary_text: Some(String::from("Selected resources do not match the current firmware version")),
-                confirm_label: String::from("Proceed"),
-                cancel_label: String::from("Cancel"),
-                option_label: None,
-                is_modal: true,
-                destructive_accept: true,
-            })
-            .forward(sender.input_sender(), |message| match message {
-                AlertResponse::Confirm => Input::FlashResourcesFromRelease,
-                AlertResponse::Cancel => Input::None,
-                AlertResponse::Option => Input::None,
-            });
-
         let model = Model {
             releases: FirmwareReleasesState::default(),
             tags: None,
             selected_index: 0,
             resources_available: false,
             current_version: String::new(),
+            release_cache: None,
+            asset_names: gh::AssetNames::default(),
+            releases_cached_at: None,
+            rate_limit: None,
+            cached_assets: Vec::new(),
+            cached_names: None,
+            cached_index: 0,
+            ci_artifacts: None,
+            ci_builds: CiBuildsState::default(),
+            ci_labels: None,
+            ci_index: 0,
             download_task: None,
+            download_progress: (0, 0),
             download_content: None,
             download_filepath: None,
             dfu_open_dialog,
             res_open_dialog,
             save_dialog,
-            firmware_downgrade_warning,
-            resource_mismatch_warning,
+            compatibility_alert: None,
+            main_window,
+            settings,
         };
 
         let widgets = view_output!();
+        for key in ["release-source", "release-source-location", "dfu-asset-prefix", "resources-asset-prefix", "github-token"] {
+            let sender = sender.clone();
+            model.settings.connect_changed(Some(key), move |_, _| sender.input(Input::RequestReleases));
+        }
+        for key in ["ci-repository", "ci-branch", "github-token"] {
+            let sender = sender.clone();
+            model.settings.connect_changed(Some(key), move |_, _| sender.input(Input::CiSettingsChanged));
+        }
         sender.input(Input::RequestReleases);
         ComponentParts { model, widgets }
     }
@@ -368,15 +675,31 @@ impl Component for Model {
                 self.current_version = version;
             }
             Input::RequestReleases => {
-                self.releases = FirmwareReleasesState::Requested;
-                sender.oneshot_command(async move {
-                    CommandOutput::FirmwareReleasesResponse(gh::list_releases().await)
-                });
+                self.asset_names = ui::asset_names(&self.settings);
+                match ui::release_cache(&self.settings) {
+                    Ok(cache) => {
+                        self.release_cache = Some(cache.clone());
+                        self.releases = FirmwareReleasesState::Requested;
+                        sender.oneshot_command(async move {
+                            CommandOutput::FirmwareReleasesResponse(cache.list_releases().await)
+                        });
+                        sender.input(Input::RequestCachedAssets);
+                    }
+                    Err(error) => {
+                        log::error!("Failed to set up firmware releases source: {error}");
+                        ui::BROKER.send(ui::Input::Toast(error.to_string()));
+                        self.release_cache = None;
+                        self.tags = None;
+                        self.releases = FirmwareReleasesState::Error;
+                        self.cached_assets.clear();
+                        self.cached_names = None;
+                    }
+                }
             }
             Input::SelectedRelease(index) => {
                 self.selected_index = index;
                 if let Some(release) = self.selected_release_info() {
-                    self.resources_available = release.get_resources_asset().is_some();
+                    self.resources_available = release.get_resources_asset(&self.asset_names).is_some();
                 }
             }
             Input::ReleaseNotes => {
@@ -387,9 +710,9 @@ impl Component for Model {
             }
             Input::DownloadFirmware => {
                 if let Some(release) = self.selected_release_info() {
-                    match release.get_dfu_asset() {
+                    match release.get_dfu_asset(&self.asset_names) {
                         Some(asset) => {
-                            sender.input(Input::DownloadAsset(asset.clone()));
+                            sender.input(Input::DownloadAsset(release.tag.clone(), asset.clone(), gh::AssetKind::Firmware));
                         }
                         None => {
                             ui::BROKER.send(ui::Input::ToastStatic("DFU file not found"));
@@ -399,9 +722,9 @@ impl Component for Model {
             }
             Input::DownloadResources => {
                 if let Some(release) = self.selected_release_info() {
-                    match release.get_resources_asset() {
+                    match release.get_resources_asset(&self.asset_names) {
                         Some(asset) => {
-                            sender.input(Input::DownloadAsset(asset.clone()));
+                            sender.input(Input::DownloadAsset(release.tag.clone(), asset.clone(), gh::AssetKind::Resources));
                         }
                         None => {
                             ui::BROKER.send(ui::Input::ToastStatic("Resources file not found"));
@@ -409,16 +732,30 @@ impl Component for Model {
                     }
                 }
             }
-            Input::DownloadAsset(asset) => {
-                    let url = asset.url;
-                    let filename = asset.name;
+            Input::DownloadAsset(tag, asset, kind) => {
+                if let Some(cache) = self.release_cache.clone() {
+                    let filename = asset.name.clone();
+                    let origin = ui::AssetOrigin::Release(cache, tag);
+                    let (progress_tx, mut progress_rx) = bt::progress_channel(32);
                     let task = relm4::spawn(async move {
-                        sender.input(Input::FinishedDownloading(
-                            gh::download_content(url.as_str()).await
-                        ))
+                        let progress_updater = async {
+                            while let Some(event) = progress_rx.recv().await {
+                                if let bt::ProgressEvent::Numbers { current, total } = event {
+                                    sender.input(Input::DownloadProgress(current, total));
+                                }
+                            }
+                        };
+                        let download = ui::download_release_asset(&origin, &asset, kind, Some(progress_tx));
+                        let (_, result) = tokio::join!(progress_updater, download);
+                        sender.input(Input::FinishedDownloading(result))
                     });
+                    self.download_progress = (0, 0);
                     self.download_task = Some(task);
                     self.save_dialog.emit(SaveDialogMsg::SaveAs(filename));
+                }
+            }
+            Input::DownloadProgress(current, total) => {
+                self.download_progress = (current, total);
             }
             Input::CancelDownloading => {
                 self.download_task.take().map(|h| h.abort());
@@ -430,6 +767,7 @@ impl Component for Model {
                 match result {
                     Ok(content) => {
                         self.download_content = Some(content);
+                        sender.input(Input::RequestCachedAssets);
                         self.save_downloaded_file(sender);
                     }
                     Err(error) => {
@@ -451,27 +789,18 @@ impl Component for Model {
             }
             Input::FlashFirmwareFromReleaseClicked => {
                 if let Some(release) = self.selected_release_info() {
-                    let manifest = vercomp::Manifest { ignore_text: true, ..Default::default() };
-                    let selected = vercomp::Version::from_manifest(&release.tag, &manifest);
-                    let current = vercomp::Version::from_manifest(&self.current_version, &manifest);
-                    if let (Some(selected), Some(current)) = (selected, current) {
-                        if selected < current {
-                            self.firmware_downgrade_warning.emit(AlertMsg::Show);
-                        } else {
-                            sender.input(Input::FlashFirmwareFromRelease);
-                        }
-                    } else {
-                        sender.input(Input::FlashFirmwareFromRelease);
-                    }
+                    let warnings = self.update_plan().firmware(Some(release.tag.as_str())).check();
+                    self.confirm_flashing(warnings, || Input::FlashFirmwareFromRelease, sender);
                 }
             }
             Input::FlashFirmwareFromRelease => {
-                if let Some(release) = self.selected_release_info() {
-                    match release.get_dfu_asset() {
+                if let Some((cache, release)) = self.selected_release() {
+                    match release.get_dfu_asset(&self.asset_names) {
                         Some(asset) => {
-                            let url = asset.url.clone();
-                            let atype = AssetType::Firmware;
-                            sender.output(Output::FlashAssetFromUrl(url, atype)).unwrap();
+                            let output = Output::FlashAssetFromRelease(
+                                ui::AssetOrigin::Release(cache.clone(), release.tag.clone()), asset.clone(), AssetType::Firmware
+                            );
+                            sender.output(output).unwrap();
                         }
                         None => {
                             ui::BROKER.send(ui::Input::ToastStatic("DFU file not found"));
@@ -485,27 +814,18 @@ impl Component for Model {
             }
             Input::FlashResourcesFromReleaseClicked => {
                 if let Some(release) = self.selected_release_info() {
-                    let manifest = vercomp::Manifest { ignore_text: true, ..Default::default() };
-                    let selected = vercomp::Version::from_manifest(&release.tag, &manifest);
-                    let current = vercomp::Version::from_manifest(&self.current_version, &manifest);
-                    if let (Some(selected), Some(current)) = (selected, current) {
-                        if selected != current {
-                            self.resource_mismatch_warning.emit(AlertMsg::Show);
-                        } else {
-                            sender.input(Input::FlashResourcesFromRelease);
-                        }
-                    } else {
-                        sender.input(Input::FlashResourcesFromRelease);
-                    }
+                    let warnings = self.update_plan().resources(Some(release.tag.as_str())).check();
+                    self.confirm_flashing(warnings, || Input::FlashResourcesFromRelease, sender);
                 }
             }
             Input::FlashResourcesFromRelease => {
-                if let Some(release) = self.selected_release_info() {
-                    match release.get_resources_asset() {
+                if let Some((cache, release)) = self.selected_release() {
+                    match release.get_resources_asset(&self.asset_names) {
                         Some(asset) => {
-                            let url = asset.url.clone();
-                            let atype = AssetType::Resources;
-                            sender.output(Output::FlashAssetFromUrl(url, atype)).unwrap();
+                            let output = Output::FlashAssetFromRelease(
+                                ui::AssetOrigin::Release(cache.clone(), release.tag.clone()), asset.clone(), AssetType::Resources
+                            );
+                            sender.output(output).unwrap();
                         }
                         None => {
                             ui::BROKER.send(ui::Input::ToastStatic("Resources asset not found"));
@@ -517,13 +837,111 @@ impl Component for Model {
                 let atype = AssetType::Resources;
                 sender.output(Output::FlashAssetFromFile(filepath, atype)).unwrap();
             }
+            Input::FlashAllFromReleaseClicked => {
+                if let Some(release) = self.selected_release_info() {
+                    let version = Some(release.tag.as_str());
+                    let warnings = self.update_plan().firmware(version).resources(version).check();
+                    self.confirm_flashing(warnings, || Input::FlashAllFromRelease, sender);
+                }
+            }
+            Input::FlashAllFromRelease => {
+                if let Some((cache, release)) = self.selected_release() {
+                    match (release.get_dfu_asset(&self.asset_names), release.get_resources_asset(&self.asset_names)) {
+                        (Some(dfu), Some(resources)) => {
+                            let origin = ui::AssetOrigin::Release(cache.clone(), release.tag.clone());
+                            let output = Output::FlashRelease(origin, dfu.clone(), resources.clone());
+                            sender.output(output).unwrap();
+                        }
+                        _ => {
+                            ui::BROKER.send(ui::Input::ToastStatic("Release assets not found"));
+                        }
+                    }
+                }
+            }
+            Input::CiSettingsChanged => {
+                // Builds are loaded on demand only, to save API rate limit
+                if !matches!(self.ci_builds, CiBuildsState::None) {
+                    sender.input(Input::RequestCiBuilds);
+                }
+            }
+            Input::RequestCiBuilds => {
+                let artifacts = ui::ci_artifacts(&self.settings);
+                self.ci_artifacts = Some(artifacts.clone());
+                self.ci_builds = CiBuildsState::Requested;
+                sender.oneshot_command(async move {
+                    CommandOutput::CiBuildsResponse(artifacts.list_builds().await)
+                });
+            }
+            Input::SelectedCiBuild(index) => {
+                self.ci_index = index;
+            }
+            Input::FlashCiBuildClicked(asset_type) => {
+                if let Some((_, build)) = self.selected_ci_build() {
+                    let plan = self.update_plan().unstable_build(&build.label());
+                    let plan = match asset_type {
+                        AssetType::Firmware => plan.firmware(None),
+                        AssetType::Resources => plan.resources(None),
+                        AssetType::FirmwareAndResources => plan.firmware(None).resources(None),
+                    };
+                    self.confirm_flashing(plan.check(), move || Input::FlashCiBuild(asset_type), sender);
+                }
+            }
+            Input::FlashCiBuild(asset_type) => {
+                if let Some((artifacts, build)) = self.selected_ci_build() {
+                    let origin = ui::AssetOrigin::Ci(artifacts.clone());
+                    let output = match (asset_type, &build.firmware, &build.resources) {
+                        (AssetType::Firmware, Some(firmware), _) => {
+                            Output::FlashAssetFromRelease(origin, firmware.clone(), asset_type)
+                        }
+                        (AssetType::Resources, _, Some(resources)) => {
+                            Output::FlashAssetFromRelease(origin, resources.clone(), asset_type)
+                        }
+                        (AssetType::FirmwareAndResources, Some(firmware), Some(resources)) => {
+                            Output::FlashRelease(origin, firmware.clone(), resources.clone())
+                        }
+                        _ => return ui::BROKER.send(ui::Input::ToastStatic("Build artifacts not found")),
+                    };
+                    sender.output(output).unwrap();
+                }
+            }
+            Input::CiBuildPage => {
+                if let Some((_, build)) = self.selected_ci_build() {
+                    gtk::UriLauncher::new(&build.url)
+                        .launch(adw::ApplicationWindow::NONE, gio::Cancellable::NONE, |_| ());
+                }
+            }
+            Input::RequestCachedAssets => {
+                if let Some(cache) = self.release_cache.clone() {
+                    sender.oneshot_command(async move {
+                        CommandOutput::CachedAssetsResponse(cache.assets().await)
+                    });
+                }
+            }
+            Input::SelectedCachedAsset(index) => {
+                self.cached_index = index;
+            }
+            Input::FlashFromCacheClicked => {
+                if let Some(asset) = self.selected_cached_asset() {
+                    let version = compatibility::version_from_file_name(&asset.name);
+                    let path = asset.path.clone();
+                    if asset.is_dfu(&self.asset_names) {
+                        let warnings = self.update_plan().firmware(version.as_deref()).check();
+                        self.confirm_flashing(warnings, move || Input::FlashFirmwareFromFile(path.clone()), sender);
+                    } else {
+                        let warnings = self.update_plan().resources(version.as_deref()).check();
+                        self.confirm_flashing(warnings, move || Input::FlashResourcesFromFile(path.clone()), sender);
+                    }
+                }
+            }
         }
     }
 
     fn update_cmd(&mut self, msg: Self::CommandOutput, sender: ComponentSender<Self>, _root: &Self::Root) {
         match msg {
             CommandOutput::FirmwareReleasesResponse(response) => match response {
-                Ok(releases) => {
+                Ok(gh::ReleaseList { releases, cached_at, rate_limit }) => {
+                    self.releases_cached_at = cached_at;
+                    self.rate_limit = rate_limit;
                     let tags = releases.iter().map(|r| r.tag.as_str()).collect::<Vec<&str>>();
                     let latest = tags.first().map(|t| t.to_string());
                     self.tags = Some(gtk::StringList::new(&tags));
@@ -531,12 +949,53 @@ impl Component for Model {
                     sender.output(Output::LatestFirmwareVersion(latest)).unwrap();
                 }
                 Err(error) => {
+                    if let infinitime::Error::RateLimited(rate_limit) = &error {
+                        self.rate_limit = Some(*rate_limit);
+                    }
                     self.tags = None;
+                    self.releases_cached_at = None;
                     self.releases = FirmwareReleasesState::Error;
                     sender.output(Output::LatestFirmwareVersion(None)).unwrap();
                     log::error!("Failed to fetch firmware releases: {error}");
                 }
             }
+            CommandOutput::CiBuildsResponse(response) => match response {
+                Ok(builds) => {
+                    let labels = builds.iter().map(|b| b.label()).collect::<Vec<String>>();
+                    let labels = labels.iter().map(String::as_str).collect::<Vec<&str>>();
+                    self.ci_labels = Some(gtk::StringList::new(&labels));
+                    self.ci_index = 0;
+                    self.ci_builds = CiBuildsState::Some(builds);
+                    self.rate_limit = None;
+                }
+                Err(error) => {
+                    if let infinitime::Error::RateLimited(rate_limit) = &error {
+                        self.rate_limit = Some(*rate_limit);
+                    }
+                    self.ci_labels = None;
+                    self.ci_builds = CiBuildsState::Error;
+                    log::error!("Failed to fetch CI builds: {error}");
+                }
+            }
+            CommandOutput::CachedAssetsResponse(response) => match response {
+                Ok(assets) => {
+                    self.cached_assets = assets.into_iter()
+                        .filter(|a| a.is_dfu(&self.asset_names) || a.is_resources(&self.asset_names))
+                        .collect();
+                    // Releases may have same-named assets
+                    let names = self.cached_assets.iter()
+                        .map(|a| format!("{}/{}", a.tag, a.name))
+                        .collect::<Vec<String>>();
+                    let names = names.iter().map(String::as_str).collect::<Vec<&str>>();
+                    self.cached_names = Some(gtk::StringList::new(&names));
+                    self.cached_index = 0;
+                }
+                Err(error) => {
+                    self.cached_assets.clear();
+                    self.cached_names = None;
+                    log::error!("Failed to list cached firmware: {error}");
+                }
+            }
             CommandOutput::SaveFileResponse(response) => match response {
                 Ok(()) => {
                     ui::BROKER.send(ui::Input::ToastStatic("Firmware downloaded"));
@@ -1,10 +1,11 @@
 use crate::ui;
 use infinitime::{
+    bluer,
     tokio::{self, io::AsyncReadExt},
-    bt::{self, ProgressEvent, InfiniTime}, gh
+    bt::{self, CancellationToken, ProgressEvent}, gh, history::History,
 };
 
-use std::{sync::Arc, path::PathBuf};
+use std::{future::Future, sync::Arc, path::PathBuf};
 use gtk::prelude::{BoxExt, ButtonExt, OrientableExt, WidgetExt};
 use relm4::{adw, gtk, ComponentParts, ComponentSender, Component, JoinHandle, RelmWidgetExt};
 
@@ -14,13 +15,23 @@ pub enum Input {
     Disconnected,
 
     FlashAssetFromFile(PathBuf, AssetType),
-    FlashAssetFromUrl(String, AssetType),
+    FlashAssetFromRelease(ui::AssetOrigin, gh::Asset, AssetType),
+    /// Flash firmware and then resources of the same release
+    FlashRelease {
+        manager: Arc<bt::DeviceManager>,
+        address: bluer::Address,
+        origin: ui::AssetOrigin,
+        firmware: gh::Asset,
+        resources: gh::Asset,
+    },
 
     ContentReady(Vec<u8>),
+    ReleaseReady(Vec<u8>, Vec<u8>),
 
     OtaProgress(ProgressEvent),
     OtaFinished,
     OtaFailed(String),
+    OtaAborted,
 
     Retry,
     Abort,
@@ -28,7 +39,8 @@ pub enum Input {
 
 pub enum Source {
     File(Arc<PathBuf>),
-    Url(Arc<String>),
+    Asset(Arc<ui::AssetOrigin>, Arc<gh::Asset>),
+    Release(Arc<ui::AssetOrigin>, Arc<gh::Asset>, Arc<gh::Asset>),
 }
 
 #[derive(PartialEq, Default)]
@@ -44,6 +56,7 @@ pub enum AssetType {
     #[default]
     Firmware,
     Resources,
+    FirmwareAndResources,
 }
 
 impl AssetType {
@@ -51,6 +64,15 @@ impl AssetType {
         match self {
             AssetType::Firmware => "Firmware",
             AssetType::Resources => "Resources",
+            AssetType::FirmwareAndResources => "Firmware and resources",
+        }
+    }
+
+    /// Kind of the asset downloaded first
+    fn asset_kind(&self) -> gh::AssetKind {
+        match self {
+            AssetType::Resources => gh::AssetKind::Resources,
+            AssetType::Firmware | AssetType::FirmwareAndResources => gh::AssetKind::Firmware,
         }
     }
 }
@@ -58,27 +80,81 @@ impl AssetType {
 #[derive(Default)]
 pub struct Model {
     progress_status: String,
+    // Details of the firmware package being flashed
+    package_details: String,
     progress_current: u32,
     progress_total: u32,
     state: State,
     asset_type: AssetType,
     asset_content: Option<Arc<Vec<u8>>>,
+    // Resources flashed after the firmware in asset_content
+    resources_content: Option<Arc<Vec<u8>>>,
     asset_source: Option<Source>,
 
     infinitime: Option<Arc<bt::InfiniTime>>,
+    // Combined update outlives the connection, as the watch reboots
+    release_target: Option<(Arc<bt::DeviceManager>, bluer::Address)>,
     task_handle: Option<JoinHandle<()>>,
+    // Set while uploading resources, which are aborted gracefully
+    // to remove partially written files
+    cancel_token: Option<CancellationToken>,
+    // Reboots after firmware upgrade are recorded to account for step counter resets
+    history: Option<Arc<History>>,
 }
 
 impl Model {
-    fn download_asset(url: Arc<String>, sender: ComponentSender<Self>) -> JoinHandle<()> {
+    /// Download the asset, forwarding its progress to the component
+    async fn download_with_progress(
+        origin: &ui::AssetOrigin,
+        asset: &gh::Asset,
+        kind: gh::AssetKind,
+        sender: &ComponentSender<Self>,
+    ) -> infinitime::Result<Vec<u8>> {
+        let (progress_tx, mut progress_rx) = bt::progress_channel(32);
+        let progress_updater = async move {
+            while let Some(event) = progress_rx.recv().await {
+                sender.input(Input::OtaProgress(event));
+            }
+        };
+        let download = ui::download_release_asset(origin, asset, kind, Some(progress_tx));
+        let (_, result) = tokio::join!(progress_updater, download);
+        result
+    }
+
+    fn download_asset(
+        origin: Arc<ui::AssetOrigin>,
+        asset: Arc<gh::Asset>,
+        kind: gh::AssetKind,
+        sender: ComponentSender<Self>,
+    ) -> JoinHandle<()> {
         relm4::spawn(async move {
-            match gh::download_content(url.as_str()).await {
+            match Self::download_with_progress(&origin, &asset, kind, &sender).await {
                 Ok(content) => sender.input(Input::ContentReady(content)),
                 Err(_) => sender.input(Input::OtaFailed("Downloading failed".to_string())),
             }
         })
     }
 
+    fn download_release(
+        origin: Arc<ui::AssetOrigin>,
+        firmware: Arc<gh::Asset>,
+        resources: Arc<gh::Asset>,
+        sender: ComponentSender<Self>,
+    ) -> JoinHandle<()> {
+        relm4::spawn(async move {
+            let firmware = match Self::download_with_progress(&origin, &firmware, gh::AssetKind::Firmware, &sender).await {
+                Ok(content) => content,
+                Err(_) => return sender.input(Input::OtaFailed("Downloading firmware failed".to_string())),
+            };
+            sender.input(Input::OtaProgress(ProgressEvent::Message("Downloading resources".to_string())));
+            sender.input(Input::OtaProgress(ProgressEvent::Numbers { current: 0, total: 0 }));
+            match Self::download_with_progress(&origin, &resources, gh::AssetKind::Resources, &sender).await {
+                Ok(resources) => sender.input(Input::ReleaseReady(firmware, resources)),
+                Err(_) => sender.input(Input::OtaFailed("Downloading resources failed".to_string())),
+            }
+        })
+    }
+
     fn read_asset_file(filepath: Arc<PathBuf>, sender: ComponentSender<Self>) -> JoinHandle<()> {
         relm4::spawn(async move {
             match tokio::fs::File::open(filepath.as_path()).await {
@@ -97,8 +173,13 @@ impl Model {
         })
     }
 
-    fn flash_asset(infinitime: Arc<InfiniTime>, content: Arc<Vec<u8>>, asset_type: AssetType, sender: ComponentSender<Self>) -> JoinHandle<()> {
+    /// Run flashing, forwarding its progress and result to the component
+    fn spawn_flashing<F>(sender: ComponentSender<Self>, flasher: impl FnOnce(bt::ProgressTx) -> F) -> JoinHandle<()>
+    where
+        F: Future<Output = infinitime::Result<()>> + Send + 'static,
+    {
         let (progress_tx, mut progress_rx) = bt::progress_channel(32);
+        let flasher = flasher(progress_tx);
 
         let sender_ = sender.clone();
         let progress_updater = async move {
@@ -107,31 +188,93 @@ impl Model {
             }
         };
 
-        let flasher = async move {
-            match asset_type {
-                AssetType::Firmware => {
-                    infinitime.firmware_upgrade(&content, Some(progress_tx)).await
-                }
-                AssetType::Resources => {
-                    infinitime.upload_resources(&content, Some(progress_tx)).await
-                }
-            }
-        };
-
         relm4::spawn(async move {
             let (_, result) = tokio::join!(progress_updater, flasher);
             match result {
                 Ok(()) => sender.input(Input::OtaFinished),
+                Err(infinitime::Error::Cancelled) => sender.input(Input::OtaAborted),
                 Err(err) => sender.input(Input::OtaFailed(err.to_string())),
             }
         })
     }
+
+    fn start_flashing(&mut self, sender: ComponentSender<Self>) {
+        let Some(content) = self.asset_content.clone() else {
+            return;
+        };
+        let cancel_token = CancellationToken::new();
+        let token = cancel_token.clone();
+        let task = match self.asset_type {
+            AssetType::Firmware => {
+                let Some(infinitime) = self.infinitime.clone() else {
+                    return;
+                };
+                let history = self.history.clone();
+                Self::spawn_flashing(sender, move |progress_tx| async move {
+                    // The bootloader discards partially received image, nothing to clean up
+                    tokio::select! {
+                        result = infinitime.firmware_upgrade(&content, Some(progress_tx)) => result?,
+                        _ = token.cancelled() => return Err(infinitime::Error::Cancelled),
+                    }
+                    record_reboot(history.as_deref(), infinitime.address());
+                    Ok(())
+                })
+            }
+            AssetType::Resources => {
+                let Some(infinitime) = self.infinitime.clone() else {
+                    return;
+                };
+                Self::spawn_flashing(sender, move |progress_tx| async move {
+                    infinitime.upload_resources(&content, Some(progress_tx), Some(&token)).await
+                })
+            }
+            AssetType::FirmwareAndResources => {
+                let (Some((manager, address)), Some(resources)) = (self.release_target.clone(), self.resources_content.clone()) else {
+                    return;
+                };
+                let history = self.history.clone();
+                Self::spawn_flashing(sender, move |progress_tx| async move {
+                    let update = bt::DeviceUpdate {
+                        firmware: Some(content.as_slice()),
+                        resources: Some(resources.as_slice()),
+                        ..Default::default()
+                    };
+                    let version = manager.update_device(address, update, Some(progress_tx), Some(&token)).await?;
+                    log::info!("Watch {} is updated to firmware {}", address, version);
+                    record_reboot(history.as_deref(), address);
+                    Ok(())
+                })
+            }
+        };
+        self.cancel_token = Some(cancel_token);
+        self.state = State::InProgress;
+        self.task_handle = Some(task);
+    }
+
+    /// Check DFU package and show its details. Fails the update if the package is broken.
+    fn check_package(&mut self, content: &[u8], sender: &ComponentSender<Self>) -> bool {
+        // Refuse corrupt or wrong-device packages before touching the watch
+        match bt::DfuPackage::parse(content) {
+            Ok(package) => {
+                self.package_details = format!(
+                    "Version: {} · {:.1} KB · CRC OK",
+                    package.version().as_deref().unwrap_or("unknown"),
+                    package.firmware.len() as f32 / 1024.0,
+                );
+                true
+            }
+            Err(err) => {
+                sender.input(Input::OtaFailed(err.to_string()));
+                false
+            }
+        }
+    }
 }
 
 #[relm4::component(pub)]
 impl Component for Model {
     type CommandOutput = ();
-    type Init = ();
+    type Init = Option<Arc<History>>;
     type Input = Input;
     type Output = ();
     type Widgets = Widgets;
@@ -175,6 +318,15 @@ impl Component for Model {
                         set_margin_top: 20,
                     },
 
+                    gtk::Label {
+                        #[watch]
+                        set_label: &model.package_details,
+                        #[watch]
+                        set_visible: !model.package_details.is_empty(),
+                        set_halign: gtk::Align::Center,
+                        add_css_class: "dim-label",
+                    },
+
                     gtk::LevelBar {
                         set_min_value: 0.0,
                         #[watch]
@@ -232,8 +384,8 @@ impl Component for Model {
         }
     }
 
-    fn init(_: Self::Init, root: &Self::Root, sender: ComponentSender<Self>) -> ComponentParts<Self> {
-        let model = Self::default();
+    fn init(history: Self::Init, root: &Self::Root, sender: ComponentSender<Self>) -> ComponentParts<Self> {
+        let model = Self { history, ..Default::default() };
         let widgets = view_output!();
         ComponentParts { model, widgets }
     }
@@ -249,6 +401,7 @@ impl Component for Model {
             Input::FlashAssetFromFile(filepath, asset_type) => {
                 let filepath = Arc::new(filepath);
                 self.progress_status = format!("Reading {} file", asset_type.name().to_lowercase());
+                self.package_details.clear();
                 self.progress_current = 0;
                 self.progress_total = 0;
                 self.state = State::InProgress;
@@ -256,34 +409,78 @@ impl Component for Model {
                 self.asset_source = Some(Source::File(filepath.clone()));
                 self.task_handle = Some(Self::read_asset_file(filepath.clone(), sender));
             }
-            Input::FlashAssetFromUrl(url, asset_type) => {
-                let url = Arc::new(url);
+            Input::FlashAssetFromRelease(origin, asset, asset_type) => {
+                let origin = Arc::new(origin);
+                let asset = Arc::new(asset);
                 self.progress_status = format!("Downloading {}", asset_type.name().to_lowercase());
+                self.package_details.clear();
                 self.progress_current = 0;
                 self.progress_total = 0;
                 self.state = State::InProgress;
                 self.asset_type = asset_type;
-                self.asset_source = Some(Source::Url(url.clone()));
-                self.task_handle = Some(Self::download_asset(url.clone(), sender));
+                self.asset_source = Some(Source::Asset(origin.clone(), asset.clone()));
+                self.task_handle = Some(Self::download_asset(origin, asset, asset_type.asset_kind(), sender));
+            }
+            Input::FlashRelease { manager, address, origin, firmware, resources } => {
+                let origin = Arc::new(origin);
+                let firmware = Arc::new(firmware);
+                let resources = Arc::new(resources);
+                self.progress_status = String::from("Downloading firmware");
+                self.package_details.clear();
+                self.progress_current = 0;
+                self.progress_total = 0;
+                self.state = State::InProgress;
+                self.asset_type = AssetType::FirmwareAndResources;
+                self.asset_source = Some(Source::Release(origin.clone(), firmware.clone(), resources.clone()));
+                self.release_target = Some((manager, address));
+                self.task_handle = Some(Self::download_release(origin, firmware, resources, sender));
             }
             Input::ContentReady(content) => {
-                if let Some(infinitime) = self.infinitime.clone() {
-                    let content = Arc::new(content);
+                self.package_details.clear();
+                self.progress_current = 0;
+                self.progress_total = 0;
+                if let AssetType::Firmware = self.asset_type {
+                    if !self.check_package(&content, &sender) {
+                        return;
+                    }
+                }
+                if self.infinitime.is_some() {
                     self.asset_source = None;
-                    self.asset_content = Some(content.clone());
-                    self.task_handle = Some(Self::flash_asset(infinitime, content, self.asset_type, sender));
+                    self.asset_content = Some(Arc::new(content));
+                    self.start_flashing(sender);
+                }
+            }
+            Input::ReleaseReady(firmware, resources) => {
+                self.package_details.clear();
+                self.progress_current = 0;
+                self.progress_total = 0;
+                if !self.check_package(&firmware, &sender) {
+                    return;
                 }
+                self.asset_source = None;
+                self.asset_content = Some(Arc::new(firmware));
+                self.resources_content = Some(Arc::new(resources));
+                self.start_flashing(sender);
             }
             Input::OtaFinished => {
                 self.progress_status = format!("{} update complete :)", self.asset_type.name());
                 self.state = State::Finished;
                 self.task_handle = None;
+                self.cancel_token = None;
                 self.asset_content = None;
+                self.resources_content = None;
             }
             Input::OtaFailed(message) => {
                 self.progress_status = format!("{} update failed: {}", self.asset_type.name(), message);
                 self.state = State::Aborted;
                 self.task_handle = None;
+                self.cancel_token = None;
+            }
+            Input::OtaAborted => {
+                self.progress_status = format!("{} update aborted", self.asset_type.name());
+                self.state = State::Aborted;
+                self.task_handle = None;
+                self.cancel_token = None;
             }
             Input::OtaProgress(event) => {
                 match event {
@@ -299,25 +496,31 @@ impl Component for Model {
             Input::Retry => {
                 self.progress_current = 0;
                 self.progress_total = 0;
-                if let Some(content) = self.asset_content.clone() {
-                    if let Some(infinitime) = self.infinitime.clone() {
-                        self.state = State::InProgress;
-                        self.task_handle = Some(Self::flash_asset(infinitime, content, self.asset_type, sender));
-                    }
+                if self.asset_content.is_some() {
+                    self.start_flashing(sender);
                 } else {
                     match &self.asset_source {
                         Some(Source::File(filepath)) => {
                             self.task_handle = Some(Self::read_asset_file(filepath.clone(), sender));
                         }
-                        Some(Source::Url(url)) => {
-                            self.task_handle = Some(Self::download_asset(url.clone(), sender));
+                        Some(Source::Asset(origin, asset)) => {
+                            let kind = self.asset_type.asset_kind();
+                            self.task_handle = Some(Self::download_asset(origin.clone(), asset.clone(), kind, sender));
+                        }
+                        Some(Source::Release(origin, firmware, resources)) => {
+                            let task = Self::download_release(origin.clone(), firmware.clone(), resources.clone(), sender);
+                            self.task_handle = Some(task);
                         }
                         None => {}
                     }
                 }
             }
             Input::Abort => {
-                if let Some(handle) = self.task_handle.take() {
+                if let Some(token) = &self.cancel_token {
+                    // The task reports back once the partial file is cleaned up
+                    token.cancel();
+                    self.progress_status = format!("Aborting {} update...", self.asset_type.name().to_lowercase());
+                } else if let Some(handle) = self.task_handle.take() {
                     handle.abort();
                     self.progress_status = format!("{} update aborted", self.asset_type.name());
                     self.state = State::Aborted;
@@ -326,3 +529,12 @@ impl Component for Model {
         }
     }
 }
+
+/// The watch reboots into the new firmware, which resets its step counter
+fn record_reboot(history: Option<&History>, address: bluer::Address) {
+    if let Some(history) = history {
+        if let Err(error) = history.record_reboot(address) {
+            log::error!("Failed to record reboot of {}: {}", address, error);
+        }
+    }
+}
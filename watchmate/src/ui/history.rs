@@ -0,0 +1,439 @@
+use crate::ui;
+use super::chart::{self, Chart};
+use infinitime::{bluer, history::{export, History}, Result};
+
+use std::{path::PathBuf, sync::Arc};
+use chrono::{DateTime, Duration, Local, NaiveDate, TimeZone, Utc};
+use gtk::prelude::{BoxExt, ButtonExt, OrientableExt, ToggleButtonExt, WidgetExt};
+use relm4::{adw, gtk, ComponentController, ComponentParts, ComponentSender, Component, Controller, RelmWidgetExt};
+use relm4_components::save_dialog::*;
+
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Period {
+    Day,
+    Week,
+    Month,
+    Year,
+}
+
+impl Period {
+    fn duration(&self) -> Duration {
+        match self {
+            Self::Day => Duration::days(1),
+            Self::Week => Duration::days(7),
+            Self::Month => Duration::days(30),
+            Self::Year => Duration::days(365),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum Input {
+    Device(bluer::Address),
+    SetPeriod(Period),
+    Refresh,
+    Export(export::Format),
+    SaveExport(PathBuf),
+    None,
+}
+
+#[derive(Debug)]
+pub enum CommandOutput {
+    Loaded(Result<Data>),
+    Exported(Result<()>),
+}
+
+#[derive(Debug)]
+pub struct Data {
+    range: (DateTime<Utc>, DateTime<Utc>),
+    steps: Vec<(NaiveDate, u32)>,
+    steps_bucket_days: i64,
+    heart_rate: Vec<(DateTime<Utc>, u16)>,
+    battery_level: Vec<(DateTime<Utc>, u8)>,
+}
+
+pub struct Model {
+    history: Option<Arc<History>>,
+    address: Option<bluer::Address>,
+    period: Period,
+    steps_total: u32,
+    steps_average: u32,
+    hr_range: Option<(u16, u16)>,
+    steps_chart: Chart,
+    hr_chart: Chart,
+    battery_chart: Chart,
+    export_format: Option<export::Format>,
+    save_dialog: Controller<SaveDialog>,
+}
+
+impl Model {
+    fn load(&self, sender: &ComponentSender<Self>) {
+        if let (Some(history), Some(address)) = (self.history.clone(), self.address) {
+            let period = self.period;
+            sender.spawn_oneshot_command(move || {
+                CommandOutput::Loaded(Self::query(&history, address, period))
+            });
+        }
+    }
+
+    fn export(&self, path: PathBuf, format: export::Format, sender: &ComponentSender<Self>) {
+        if let (Some(history), Some(address)) = (self.history.clone(), self.address) {
+            let end = Utc::now();
+            let start = end - self.period.duration();
+            sender.spawn_oneshot_command(move || {
+                let result = export::export(&history, address, start..end, format)
+                    .and_then(|content| Ok(std::fs::write(path, content)?));
+                CommandOutput::Exported(result)
+            });
+        }
+    }
+
+    fn query(history: &History, address: bluer::Address, period: Period) -> Result<Data> {
+        let end = Utc::now();
+        let start = end - period.duration();
+        let range = start..end;
+        // Show weekly totals when daily bars get too thin
+        let (steps, steps_bucket_days) = if period == Period::Year {
+            let weeks = history.weekly_steps(address, range.clone())?;
+            (weeks.into_iter().map(|w| (w.week_start, w.steps)).collect(), 7)
+        } else {
+            let days = history.daily_steps(address, range.clone())?;
+            (days.into_iter().map(|d| (d.date, d.steps)).collect(), 1)
+        };
+        let heart_rate = history.heart_rates(address, range.clone())?
+            .into_iter().map(|s| (s.timestamp, s.value)).collect();
+        let battery_level = history.battery_levels(address, range)?
+            .into_iter().map(|s| (s.timestamp, s.value)).collect();
+        Ok(Data { range: (start, end), steps, steps_bucket_days, heart_rate, battery_level })
+    }
+
+    fn show(&mut self, data: Data) {
+        let (start, end) = data.range;
+        let x_range = (start.timestamp() as f64, end.timestamp() as f64);
+        let x_labels = (format_time(start, self.period), format_time(end, self.period));
+
+        // Steps
+        let days = (end - start).num_days().max(1) as u32;
+        self.steps_total = data.steps.iter().map(|(_, steps)| steps).sum();
+        self.steps_average = self.steps_total / days;
+        let steps_points: Vec<_> = data.steps.iter()
+            .filter_map(|(date, steps)| {
+                let midnight = Local.from_local_datetime(&date.and_hms_opt(0, 0, 0)?).earliest()?;
+                Some((midnight.timestamp() as f64, *steps as f64))
+            })
+            .collect();
+        let steps_max = steps_points.iter().map(|p| p.1).fold(0.0, f64::max);
+        // Buckets are aligned to local midnight, so they may stick out of the range
+        let bucket = data.steps_bucket_days as f64 * 86400.0;
+        let steps_x_range = (
+            steps_points.first().map_or(x_range.0, |p| p.0.min(x_range.0)),
+            steps_points.last().map_or(x_range.1, |p| (p.0 + bucket).max(x_range.1)),
+        );
+        self.steps_chart.set_data(Some(chart::Data {
+            style: chart::Style::Bars(bucket),
+            points: steps_points,
+            x_range: steps_x_range,
+            y_max: nice_max(steps_max),
+            x_labels: x_labels.clone(),
+        }));
+
+        // Heart rate
+        self.hr_range = data.heart_rate.iter().fold(None, |range, (_, bpm)| match range {
+            None => Some((*bpm, *bpm)),
+            Some((min, max)) => Some((min.min(*bpm), max.max(*bpm))),
+        });
+        let hr_max = self.hr_range.map_or(0, |(_, max)| max) as f64;
+        self.hr_chart.set_data(Some(chart::Data {
+            style: chart::Style::Line,
+            points: data.heart_rate.iter().map(|(t, bpm)| (t.timestamp() as f64, *bpm as f64)).collect(),
+            x_range,
+            y_max: nice_max(hr_max),
+            x_labels: x_labels.clone(),
+        }));
+
+        // Battery
+        self.battery_chart.set_data(Some(chart::Data {
+            style: chart::Style::Line,
+            points: data.battery_level.iter().map(|(t, level)| (t.timestamp() as f64, *level as f64)).collect(),
+            x_range,
+            y_max: 100.0,
+            x_labels,
+        }));
+    }
+}
+
+#[relm4::component(pub)]
+impl Component for Model {
+    type CommandOutput = CommandOutput;
+    type Init = (adw::ApplicationWindow, Option<Arc<History>>);
+    type Input = Input;
+    type Output = ();
+    type Widgets = Widgets;
+
+    view! {
+        gtk::Box {
+            set_hexpand: true,
+            set_orientation: gtk::Orientation::Vertical,
+
+            adw::HeaderBar {
+                #[wrap(Some)]
+                set_title_widget = &gtk::Label {
+                    set_label: "History",
+                },
+
+                pack_start = &gtk::Button {
+                    set_tooltip_text: Some("Back"),
+                    set_icon_name: "go-previous-symbolic",
+                    connect_clicked => |_| {
+                        ui::BROKER.send(ui::Input::SetView(super::View::Dashboard));
+                    },
+                },
+
+                pack_end = &gtk::Button {
+                    set_tooltip_text: Some("Refresh"),
+                    set_icon_name: "refresh-symbolic",
+                    connect_clicked => Input::Refresh,
+                },
+            },
+
+            gtk::ScrolledWindow {
+                set_hscrollbar_policy: gtk::PolicyType::Never,
+                set_vexpand: true,
+
+                adw::Clamp {
+                    set_maximum_size: 400,
+                    set_vexpand: true,
+
+                    gtk::Box {
+                        set_orientation: gtk::Orientation::Vertical,
+                        set_margin_all: 12,
+                        set_spacing: 10,
+
+                        gtk::Box {
+                            set_halign: gtk::Align::Center,
+                            add_css_class: "linked",
+
+                            #[name = "day_button"]
+                            gtk::ToggleButton {
+                                set_label: "Day",
+                                set_active: true,
+                                connect_toggled[sender] => move |b| if b.is_active() {
+                                    sender.input(Input::SetPeriod(Period::Day));
+                                },
+                            },
+                            gtk::ToggleButton {
+                                set_label: "Week",
+                                set_group: Some(&day_button),
+                                connect_toggled[sender] => move |b| if b.is_active() {
+                                    sender.input(Input::SetPeriod(Period::Week));
+                                },
+                            },
+                            gtk::ToggleButton {
+                                set_label: "Month",
+                                set_group: Some(&day_button),
+                                connect_toggled[sender] => move |b| if b.is_active() {
+                                    sender.input(Input::SetPeriod(Period::Month));
+                                },
+                            },
+                            gtk::ToggleButton {
+                                set_label: "Year",
+                                set_group: Some(&day_button),
+                                connect_toggled[sender] => move |b| if b.is_active() {
+                                    sender.input(Input::SetPeriod(Period::Year));
+                                },
+                            },
+                        },
+
+                        gtk::Label {
+                            #[watch]
+                            set_visible: model.history.is_none(),
+                            set_label: "History database is not available",
+                            add_css_class: "dim-label",
+                        },
+
+                        gtk::Box {
+                            set_orientation: gtk::Orientation::Horizontal,
+                            set_margin_top: 10,
+
+                            gtk::Label {
+                                set_label: "Steps",
+                                set_hexpand: true,
+                                set_halign: gtk::Align::Start,
+                            },
+                            gtk::Label {
+                                #[watch]
+                                set_label: &format!("{} total, {} per day", model.steps_total, model.steps_average),
+                                add_css_class: "dim-label",
+                            },
+                        },
+                        #[local_ref]
+                        steps_chart -> gtk::DrawingArea {},
+
+                        gtk::Box {
+                            set_orientation: gtk::Orientation::Horizontal,
+                            set_margin_top: 10,
+
+                            gtk::Label {
+                                set_label: "Heart Rate",
+                                set_hexpand: true,
+                                set_halign: gtk::Align::Start,
+                            },
+                            gtk::Label {
+                                #[watch]
+                                set_label: &match model.hr_range {
+                                    Some((min, max)) => format!("{}–{} BPM", min, max),
+                                    None => String::new(),
+                                },
+                                add_css_class: "dim-label",
+                            },
+                        },
+                        #[local_ref]
+                        hr_chart -> gtk::DrawingArea {},
+
+                        gtk::Label {
+                            set_label: "Battery",
+                            set_halign: gtk::Align::Start,
+                            set_margin_top: 10,
+                        },
+                        #[local_ref]
+                        battery_chart -> gtk::DrawingArea {},
+
+                        gtk::Box {
+                            set_orientation: gtk::Orientation::Horizontal,
+                            set_margin_top: 20,
+                            set_spacing: 10,
+                            #[watch]
+                            set_sensitive: model.address.is_some(),
+
+                            gtk::Label {
+                                set_label: "Export",
+                                set_hexpand: true,
+                                set_halign: gtk::Align::Start,
+                            },
+                            gtk::Box {
+                                add_css_class: "linked",
+
+                                gtk::Button {
+                                    set_label: "CSV",
+                                    set_tooltip_text: Some("All readings, for spreadsheets and notebooks"),
+                                    connect_clicked => Input::Export(export::Format::Csv),
+                                },
+                                gtk::Button {
+                                    set_label: "TCX",
+                                    set_tooltip_text: Some("Heart rate, for fitness tools"),
+                                    connect_clicked => Input::Export(export::Format::Tcx),
+                                },
+                                gtk::Button {
+                                    set_label: "FIT",
+                                    set_tooltip_text: Some("Heart rate, for fitness tools"),
+                                    connect_clicked => Input::Export(export::Format::Fit),
+                                },
+                            },
+                        },
+                    }
+                }
+            }
+        }
+    }
+
+    fn init((main_window, history): Self::Init, root: &Self::Root, sender: ComponentSender<Self>) -> ComponentParts<Self> {
+        // Show any known watch until one gets connected
+        let address = history.as_ref()
+            .and_then(|h| h.devices().ok())
+            .and_then(|devices| devices.first().cloned());
+
+        let model = Model {
+            history,
+            address,
+            period: Period::Day,
+            steps_total: 0,
+            steps_average: 0,
+            hr_range: None,
+            steps_chart: Chart::new(120),
+            hr_chart: Chart::new(160),
+            battery_chart: Chart::new(120),
+            export_format: None,
+            save_dialog: SaveDialog::builder()
+                .transient_for_native(&main_window)
+                .launch(SaveDialogSettings::default())
+                .forward(&sender.input_sender(), |message| match message {
+                    SaveDialogResponse::Accept(path) => Input::SaveExport(path),
+                    SaveDialogResponse::Cancel => Input::None,
+                }),
+        };
+
+        let steps_chart = model.steps_chart.widget();
+        let hr_chart = model.hr_chart.widget();
+        let battery_chart = model.battery_chart.widget();
+        let widgets = view_output!();
+
+        ComponentParts { model, widgets }
+    }
+
+    fn update(&mut self, msg: Self::Input, sender: ComponentSender<Self>, _root: &Self::Root) {
+        match msg {
+            Input::Device(address) => {
+                self.address = Some(address);
+                self.load(&sender);
+            }
+            Input::SetPeriod(period) => {
+                self.period = period;
+                self.load(&sender);
+            }
+            Input::Refresh => {
+                self.load(&sender);
+            }
+            Input::Export(format) => {
+                self.export_format = Some(format);
+                let filename = format!("watchmate-history.{}", format.extension());
+                self.save_dialog.emit(SaveDialogMsg::SaveAs(filename));
+            }
+            Input::SaveExport(path) => {
+                if let Some(format) = self.export_format.take() {
+                    self.export(path, format, &sender);
+                }
+            }
+            Input::None => {}
+        }
+    }
+
+    fn update_cmd(&mut self, msg: Self::CommandOutput, _sender: ComponentSender<Self>, _root: &Self::Root) {
+        match msg {
+            CommandOutput::Loaded(Ok(data)) => self.show(data),
+            CommandOutput::Loaded(Err(error)) => {
+                log::error!("Failed to load history: {}", error);
+                ui::BROKER.send(ui::Input::ToastStatic("Failed to load history"));
+            }
+            CommandOutput::Exported(Ok(())) => {
+                ui::BROKER.send(ui::Input::ToastStatic("History exported"));
+            }
+            CommandOutput::Exported(Err(error)) => {
+                log::error!("Failed to export history: {}", error);
+                ui::BROKER.send(ui::Input::ToastStatic("Failed to export history"));
+            }
+        }
+    }
+}
+
+
+fn format_time(time: DateTime<Utc>, period: Period) -> String {
+    let time = time.with_timezone(&Local);
+    match period {
+        Period::Day => time.format("%H:%M").to_string(),
+        Period::Week | Period::Month => time.format("%b %d").to_string(),
+        Period::Year => time.format("%b %Y").to_string(),
+    }
+}
+
+/// Round chart maximum up to a value that splits nicely into grid lines
+fn nice_max(value: f64) -> f64 {
+    if value <= 0.0 {
+        return 0.0;
+    }
+    let magnitude = 10f64.powf(value.log10().floor());
+    [1.0, 2.0, 4.0, 5.0, 10.0].into_iter()
+        .map(|m| m * magnitude)
+        .find(|&max| max >= value)
+        .unwrap_or(10.0 * magnitude)
+}
@@ -204,5 +204,9 @@ impl Component for Model {
             }
         }
     }
-}
 
+    fn shutdown(&mut self, _widgets: &mut Self::Widgets, _output: relm4::Sender<Self::Output>) {
+        self.stop_control_task();
+        self.stop_update_task();
+    }
+}
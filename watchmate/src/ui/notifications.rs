@@ -1,7 +1,7 @@
 use crate::ui;
 use infinitime::{zbus, bt, fdo::notifications};
 use std::sync::Arc;
-use gtk::{gio, prelude::{BoxExt, OrientableExt, WidgetExt, SettingsExt, SettingsExtManual}};
+use gtk::{gio, prelude::{BoxExt, OrientableExt, WidgetExt, SettingsExt}};
 use relm4::{gtk, ComponentParts, ComponentSender, Component, JoinHandle, RelmWidgetExt};
 
 #[derive(Debug)]
@@ -11,14 +11,49 @@ pub enum Input {
     NotificationSessionEnded,
 }
 
-#[derive(Default)]
 pub struct Model {
     infinitime: Option<Arc<bt::InfiniTime>>,
     is_enabled: bool,
     task: Option<JoinHandle<()>>,
+    settings: gio::Settings,
 }
 
 impl Model {
+    /// Forwarding is enabled per watch, so it's possible to choose which
+    /// of the connected watches receive notifications
+    fn is_enabled_for(&self, infinitime: &bt::InfiniTime) -> bool {
+        let address = infinitime.address().to_string();
+        self.settings.strv("notification-forwarding-devices").iter().any(|a| a.as_str() == address)
+    }
+
+    fn save_enabled(&self, enabled: bool) {
+        if let Some(infinitime) = &self.infinitime {
+            let address = infinitime.address().to_string();
+            let mut addresses: Vec<String> = self.settings.strv("notification-forwarding-devices")
+                .iter()
+                .map(|a| a.to_string())
+                .filter(|a| *a != address)
+                .collect();
+            if enabled {
+                addresses.push(address);
+            }
+            let addresses: Vec<&str> = addresses.iter().map(String::as_str).collect();
+            if let Err(error) = self.settings.set_strv("notification-forwarding-devices", addresses.as_slice()) {
+                log::error!("Failed to save notification forwarding setting: {error}");
+            }
+        }
+    }
+
+    /// Forwarding used to be a single switch for any watch. If it was on,
+    /// carry it over to the first watch connected after the upgrade.
+    fn migrate_legacy_setting(&self) {
+        if self.settings.boolean("notification-forwarding-enabled") {
+            log::info!("Migrating notification forwarding setting to the connected watch");
+            self.save_enabled(true);
+            self.settings.reset("notification-forwarding-enabled");
+        }
+    }
+
     fn start_notifications_task(&mut self, sender: ComponentSender<Self>) {
         if let Some(infinitime) = self.infinitime.clone() {
             self.stop_notifications_task();
@@ -26,7 +61,7 @@ impl Model {
             let infinitime = infinitime.clone();
             self.task = Some(relm4::spawn(async move {
                 if let Err(error) = notifications::run_notification_session(&infinitime).await {
-                    if let Some(zbus::fdo::Error::AccessDenied(_)) = error.downcast_ref() {
+                    if let infinitime::Error::DBusFdo(zbus::fdo::Error::AccessDenied(_)) = error {
                         log::warn!(
                             "Notification session failed: the app doesn't have permissions to monitor \
                             D-Bus session bus. If you're running it from flatpak, you can grant access with \
@@ -77,8 +112,10 @@ impl Component for Model {
                 set_halign: gtk::Align::Start,
             },
 
-            #[name = "switch"]
             gtk::Switch {
+                #[watch]
+                #[block_signal(toggle_handler)]
+                set_active: model.is_enabled,
                 #[watch]
                 set_state: model.is_enabled && model.task.is_some(),
                 set_halign: gtk::Align::End,
@@ -86,16 +123,19 @@ impl Component for Model {
                 connect_active_notify[sender] => move |switch| {
                     let state = switch.is_active();
                     sender.input(Input::SetNotificationSession(state));
-                }
+                } @toggle_handler,
             }
         }
     }
 
     fn init(persistent_settings: Self::Init, root: &Self::Root, sender: ComponentSender<Self>) -> ComponentParts<Self> {
-        let is_enabled = persistent_settings.boolean("notification-forwarding-enabled");
-        let model = Self { is_enabled, ..Default::default() };
+        let model = Self {
+            infinitime: None,
+            is_enabled: false,
+            task: None,
+            settings: persistent_settings,
+        };
         let widgets = view_output!();
-        persistent_settings.bind("notification-forwarding-enabled", &widgets.switch, "active").build();
         ComponentParts { model, widgets }
     }
 
@@ -103,14 +143,20 @@ impl Component for Model {
         match msg {
             Input::Device(infinitime) => {
                 self.infinitime = infinitime;
-                match self.infinitime {
-                    Some(_) if self.is_enabled => self.start_notifications_task(sender),
-                    Some(_) => {},
+                match &self.infinitime {
+                    Some(infinitime) => {
+                        self.migrate_legacy_setting();
+                        self.is_enabled = self.is_enabled_for(infinitime);
+                        if self.is_enabled {
+                            self.start_notifications_task(sender);
+                        }
+                    }
                     None => self.stop_notifications_task(),
                 }
             }
             Input::SetNotificationSession(state) => {
                 self.is_enabled = state;
+                self.save_enabled(state);
                 match state {
                     true => self.start_notifications_task(sender),
                     false => self.stop_notifications_task(),
@@ -121,5 +167,8 @@ impl Component for Model {
             }
         }
     }
-}
 
+    fn shutdown(&mut self, _widgets: &mut Self::Widgets, _output: relm4::Sender<Self::Output>) {
+        self.stop_notifications_task();
+    }
+}
@@ -1,9 +1,12 @@
 use crate::ui;
-use gtk::{gio, prelude::{OrientableExt, WidgetExt, ButtonExt, SettingsExtManual}};
-use adw::prelude::{PreferencesPageExt, PreferencesGroupExt, PreferencesRowExt, ActionRowExt};
+use gtk::{gio, prelude::{Cast, OrientableExt, WidgetExt, ButtonExt, EditableExt, SettingsExt, SettingsExtManual}};
+use adw::prelude::{PreferencesPageExt, PreferencesGroupExt, PreferencesRowExt, ActionRowExt, ComboRowExt, EntryRowExt};
 use relm4::{adw, gtk, ComponentParts, ComponentSender, Component};
 
 
+/// Values of "release-source" key, in the order of the source row items
+const RELEASE_SOURCES: [&str; 4] = ["github", "gitea", "http-index", "local-dir"];
+
 #[derive(Debug)]
 pub enum Output {
     SetAutoReconnect(bool),
@@ -51,6 +54,86 @@ impl Component for Model {
                             _ = sender.output(Output::SetAutoReconnect(wgt.is_active()));
                         }
                     }
+                },
+
+                add = &adw::PreferencesGroup {
+                    set_title: "Battery",
+
+                    #[name = "battery_alerts_switch"]
+                    add = &adw::SwitchRow {
+                        set_title: "Low battery alerts",
+                        set_subtitle: "Desktop notification when the watch battery runs low",
+                    },
+
+                    #[name = "battery_low_row"]
+                    add = &adw::SpinRow::with_range(1.0, 100.0, 1.0) {
+                        set_title: "Low level",
+                        set_subtitle: "Percent",
+                    },
+
+                    #[name = "battery_critical_row"]
+                    add = &adw::SpinRow::with_range(1.0, 100.0, 1.0) {
+                        set_title: "Critical level",
+                        set_subtitle: "Percent",
+                    },
+                },
+
+                add = &adw::PreferencesGroup {
+                    set_title: "Firmware releases",
+
+                    #[name = "release_source_row"]
+                    add = &adw::ComboRow {
+                        set_title: "Source",
+                        set_model: Some(&gtk::StringList::new(&["GitHub", "Gitea", "HTTP index", "Local directory"])),
+                    },
+
+                    #[name = "release_location_row"]
+                    add = &adw::EntryRow {
+                        set_title: "Location",
+                        set_tooltip_text: Some("GitHub repository as owner/name, Gitea repository URL, index URL or directory path"),
+                        set_show_apply_button: true,
+                    },
+
+                    #[name = "dfu_prefix_row"]
+                    add = &adw::EntryRow {
+                        set_title: "Firmware file name prefix",
+                        set_show_apply_button: true,
+                    },
+
+                    #[name = "resources_prefix_row"]
+                    add = &adw::EntryRow {
+                        set_title: "Resources file name prefix",
+                        set_show_apply_button: true,
+                    },
+                },
+
+                add = &adw::PreferencesGroup {
+                    set_title: "CI builds",
+                    set_description: Some("Unreleased firmware of pull requests and the main branch"),
+
+                    #[name = "ci_repository_row"]
+                    add = &adw::EntryRow {
+                        set_title: "GitHub repository",
+                        set_show_apply_button: true,
+                    },
+
+                    #[name = "ci_branch_row"]
+                    add = &adw::EntryRow {
+                        set_title: "Branch",
+                        set_show_apply_button: true,
+                    },
+                },
+
+                add = &adw::PreferencesGroup {
+                    set_title: "GitHub",
+                    set_description: Some("Anonymous API requests are limited to 60 per hour, shared by everyone on the network"),
+
+                    #[name = "github_token_row"]
+                    add = &adw::PasswordEntryRow {
+                        set_title: "Personal access token",
+                        set_tooltip_text: Some("Raises the API rate limit, required to download CI artifacts"),
+                        set_show_apply_button: true,
+                    },
                 }
             }
         }
@@ -60,6 +143,46 @@ impl Component for Model {
         let model = Self {};
         let widgets = view_output!();
         persistent_settings.bind("auto-reconnect-enabled", &widgets.autoreconnect_switch, "active").build();
+        persistent_settings.bind("battery-alerts-enabled", &widgets.battery_alerts_switch, "active").build();
+        for (key, row) in [
+            ("battery-low-threshold", &widgets.battery_low_row),
+            ("battery-critical-threshold", &widgets.battery_critical_row),
+        ] {
+            persistent_settings.bind("battery-alerts-enabled", row, "sensitive")
+                .flags(gio::SettingsBindFlags::GET)
+                .build();
+            // Thresholds are stored as integers, while the row value is double
+            row.set_value(persistent_settings.int(key) as f64);
+            let settings = persistent_settings.clone();
+            row.connect_value_notify(move |row| {
+                _ = settings.set_int(key, row.value() as i32);
+            });
+        }
+
+        let source = persistent_settings.string("release-source");
+        let source_index = RELEASE_SOURCES.iter().position(|s| *s == source.as_str()).unwrap_or(0);
+        widgets.release_source_row.set_selected(source_index as u32);
+        let settings = persistent_settings.clone();
+        widgets.release_source_row.connect_selected_notify(move |row| {
+            if let Some(source) = RELEASE_SOURCES.get(row.selected() as usize) {
+                _ = settings.set_string("release-source", source);
+            }
+        });
+        // Applied explicitly, as every change refreshes releases
+        for (key, row) in [
+            ("release-source-location", &widgets.release_location_row),
+            ("dfu-asset-prefix", &widgets.dfu_prefix_row),
+            ("resources-asset-prefix", &widgets.resources_prefix_row),
+            ("ci-repository", &widgets.ci_repository_row),
+            ("ci-branch", &widgets.ci_branch_row),
+            ("github-token", widgets.github_token_row.upcast_ref()),
+        ] {
+            row.set_text(&persistent_settings.string(key));
+            let settings = persistent_settings.clone();
+            row.connect_apply(move |row| {
+                _ = settings.set_string(key, &row.text());
+            });
+        }
         ComponentParts { model, widgets }
     }
 
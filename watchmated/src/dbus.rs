@@ -0,0 +1,240 @@
+use infinitime::{bt, compatibility::{UpdatePlan, Warning}, history::History};
+use futures::{pin_mut, StreamExt};
+use std::sync::{Arc, Mutex};
+use zbus::{dbus_interface, fdo, InterfaceRef, SignalContext};
+
+
+/// Server side of `fdo::daemon::WatchMateProxy`
+pub struct WatchMate {
+    state: Arc<Mutex<State>>,
+    // Reboots after firmware upgrade are recorded to account for step counter resets
+    history: Option<Arc<History>>,
+}
+
+#[derive(Default)]
+struct State {
+    infinitime: Option<Arc<bt::InfiniTime>>,
+    address: String,
+    battery_level: u8,
+    heart_rate: u16,
+    step_count: u32,
+    firmware_version: String,
+}
+
+impl WatchMate {
+    pub fn new(history: Option<Arc<History>>) -> Self {
+        Self { state: Default::default(), history }
+    }
+
+    fn state(&self) -> std::sync::MutexGuard<'_, State> {
+        self.state.lock().unwrap()
+    }
+
+    fn infinitime(&self) -> fdo::Result<Arc<bt::InfiniTime>> {
+        self.state().infinitime.clone()
+            .ok_or(fdo::Error::Failed(String::from("Watch is not connected")))
+    }
+
+    /// Publish newly connected watch and keep its properties up to date
+    /// until the data streams end
+    pub async fn run_device_session(iface: &InterfaceRef<Self>, infinitime: Arc<bt::InfiniTime>) {
+        let ctxt = iface.signal_context();
+        {
+            let watchmate = iface.get().await;
+            let mut state = watchmate.state();
+            state.infinitime = Some(infinitime.clone());
+            state.address = infinitime.address().to_string();
+        }
+        let watchmate = iface.get().await;
+        _ = watchmate.connected_changed(ctxt).await;
+        _ = watchmate.address_changed(ctxt).await;
+        drop(watchmate);
+
+        // Initial values
+        if let Ok(version) = infinitime.read_firmware_version().await {
+            iface.get().await.state().firmware_version = version;
+            _ = iface.get().await.firmware_version_changed(ctxt).await;
+        }
+        if let Ok(level) = infinitime.read_battery_level().await {
+            Self::set_battery_level(iface, level).await;
+        }
+        if let Ok(rate) = infinitime.read_heart_rate().await {
+            Self::set_heart_rate(iface, rate).await;
+        }
+        if let Ok(count) = infinitime.read_step_count().await {
+            Self::set_step_count(iface, count).await;
+        }
+
+        // Updates
+        let bl_stream = infinitime.get_battery_level_stream().await;
+        let hr_stream = infinitime.get_heart_rate_stream().await;
+        let sc_stream = infinitime.get_step_count_stream().await;
+        match (bl_stream, hr_stream, sc_stream) {
+            (Ok(bl_stream), Ok(hr_stream), Ok(sc_stream)) => {
+                pin_mut!(bl_stream, hr_stream, sc_stream);
+                loop {
+                    tokio::select! {
+                        Some(bl) = bl_stream.next() => Self::set_battery_level(iface, bl).await,
+                        Some(hr) = hr_stream.next() => Self::set_heart_rate(iface, hr).await,
+                        Some(sc) = sc_stream.next() => Self::set_step_count(iface, sc).await,
+                        else => break,
+                    }
+                }
+            }
+            _ => log::error!("Failed to create data streams"),
+        }
+    }
+
+    /// Reset properties after disconnection
+    pub async fn clear_device(iface: &InterfaceRef<Self>) {
+        let ctxt = iface.signal_context();
+        let watchmate = iface.get().await;
+        *watchmate.state() = State::default();
+        _ = watchmate.connected_changed(ctxt).await;
+        _ = watchmate.address_changed(ctxt).await;
+        _ = watchmate.battery_level_changed(ctxt).await;
+        _ = watchmate.heart_rate_changed(ctxt).await;
+        _ = watchmate.step_count_changed(ctxt).await;
+        _ = watchmate.firmware_version_changed(ctxt).await;
+    }
+
+    async fn set_battery_level(iface: &InterfaceRef<Self>, level: u8) {
+        let watchmate = iface.get().await;
+        watchmate.state().battery_level = level;
+        _ = watchmate.battery_level_changed(iface.signal_context()).await;
+    }
+
+    async fn set_heart_rate(iface: &InterfaceRef<Self>, rate: u16) {
+        let watchmate = iface.get().await;
+        watchmate.state().heart_rate = rate;
+        _ = watchmate.heart_rate_changed(iface.signal_context()).await;
+    }
+
+    async fn set_step_count(iface: &InterfaceRef<Self>, count: u32) {
+        let watchmate = iface.get().await;
+        watchmate.state().step_count = count;
+        _ = watchmate.step_count_changed(iface.signal_context()).await;
+    }
+}
+
+#[dbus_interface(name = "io.gitlab.azymohliad.WatchMate1")]
+impl WatchMate {
+    async fn send_notification(&self, title: &str, body: &str) -> fdo::Result<()> {
+        let notification = bt::Notification::Alert { title, content: body };
+        self.infinitime()?.write_notification(notification).await.map_err(failed)
+    }
+
+    async fn send_call(&self, caller: &str) -> fdo::Result<()> {
+        let notification = bt::Notification::Call { title: caller };
+        self.infinitime()?.write_notification(notification).await.map_err(failed)
+    }
+
+    async fn upload_file(
+        &self,
+        source: &str,
+        destination: &str,
+        #[zbus(signal_context)] ctxt: SignalContext<'_>,
+    ) -> fdo::Result<()> {
+        let infinitime = self.infinitime()?;
+        let content = tokio::fs::read(source).await.map_err(|e| fdo::Error::IOError(e.to_string()))?;
+        infinitime.make_dirs(destination, None).await.map_err(failed)?;
+        let (progress_tx, progress_rx) = bt::progress_channel(16);
+        let (result, _) = tokio::join!(
+            infinitime.write_file(destination, &content, 0, Some(progress_tx), None),
+            forward_progress(progress_rx, &ctxt),
+        );
+        result.map_err(failed)
+    }
+
+    async fn check_firmware(&self, path: &str) -> fdo::Result<Vec<String>> {
+        let infinitime = self.infinitime()?;
+        let content = tokio::fs::read(path).await.map_err(|e| fdo::Error::IOError(e.to_string()))?;
+        let warnings = check_firmware(&infinitime, &content).await?;
+        Ok(warnings.iter().map(ToString::to_string).collect())
+    }
+
+    async fn flash_firmware(
+        &self,
+        path: &str,
+        #[zbus(signal_context)] ctxt: SignalContext<'_>,
+    ) -> fdo::Result<()> {
+        let infinitime = self.infinitime()?;
+        let content = tokio::fs::read(path).await.map_err(|e| fdo::Error::IOError(e.to_string()))?;
+        let warnings = check_firmware(&infinitime, &content).await?;
+        if let Some(warning) = warnings.iter().find(|w| w.is_critical()) {
+            return Err(fdo::Error::Failed(format!("Refusing to flash: {}", warning)));
+        }
+        let (progress_tx, progress_rx) = bt::progress_channel(16);
+        let (result, _) = tokio::join!(
+            infinitime.firmware_upgrade(&content, Some(progress_tx)),
+            forward_progress(progress_rx, &ctxt),
+        );
+        result.map_err(failed)?;
+        // The watch reboots into the new firmware, which resets its step counter
+        if let Some(history) = &self.history {
+            if let Err(error) = history.record_reboot(infinitime.address()) {
+                log::error!("Failed to record reboot: {error}");
+            }
+        }
+        Ok(())
+    }
+
+    #[dbus_interface(signal)]
+    async fn progress(ctxt: &SignalContext<'_>, message: &str, current: u32, total: u32) -> zbus::Result<()>;
+
+    #[dbus_interface(property)]
+    fn connected(&self) -> bool {
+        self.state().infinitime.is_some()
+    }
+
+    #[dbus_interface(property)]
+    fn address(&self) -> String {
+        self.state().address.clone()
+    }
+
+    #[dbus_interface(property)]
+    fn battery_level(&self) -> u8 {
+        self.state().battery_level
+    }
+
+    #[dbus_interface(property)]
+    fn heart_rate(&self) -> u16 {
+        self.state().heart_rate
+    }
+
+    #[dbus_interface(property)]
+    fn step_count(&self) -> u32 {
+        self.state().step_count
+    }
+
+    #[dbus_interface(property)]
+    fn firmware_version(&self) -> String {
+        self.state().firmware_version.clone()
+    }
+}
+
+/// Re-emit progress events as D-Bus signals
+async fn forward_progress(mut progress_rx: bt::ProgressRx, ctxt: &SignalContext<'_>) {
+    let mut message = String::new();
+    while let Some(event) = progress_rx.recv().await {
+        let (current, total) = match event {
+            bt::ProgressEvent::Message(msg) => {
+                message = msg;
+                (0, 0)
+            }
+            bt::ProgressEvent::Numbers { current, total } => (current, total),
+        };
+        _ = WatchMate::progress(ctxt, &message, current, total).await;
+    }
+}
+
+/// Validate DFU archive and check it against the installed firmware
+async fn check_firmware(infinitime: &bt::InfiniTime, content: &[u8]) -> fdo::Result<Vec<Warning>> {
+    let package = bt::DfuPackage::parse(content).map_err(failed)?;
+    let current = infinitime.read_firmware_version().await.map_err(failed)?;
+    Ok(UpdatePlan::new(Some(&current)).firmware_package(&package).check())
+}
+
+fn failed(error: infinitime::Error) -> fdo::Error {
+    fdo::Error::Failed(error.to_string())
+}
@@ -0,0 +1,134 @@
+use infinitime::{bluer, bt, fdo, history::History};
+use anyhow::{anyhow, Result};
+use clap::Parser;
+use std::{sync::Arc, time::Duration};
+
+mod dbus;
+mod session;
+
+
+/// Background service that keeps InfiniTime watch session alive:
+/// current time service, notifications forwarding, media player control,
+/// low battery alerts and automatic reconnection
+#[derive(Parser, Debug)]
+#[command(name = "watchmated", version)]
+pub struct Args {
+    /// Bluetooth address of the watch. If not specified, already connected
+    /// InfiniTime device is used, or the only known one is connected to.
+    #[arg(short, long)]
+    pub device: Option<bluer::Address>,
+
+    /// Don't forward desktop notifications to the watch
+    #[arg(long)]
+    pub no_notifications: bool,
+
+    /// Don't let the watch control media players
+    #[arg(long)]
+    pub no_media_player: bool,
+
+    /// Don't record battery, heart rate and step count history
+    #[arg(long)]
+    pub no_history: bool,
+
+    /// Don't show desktop alerts when the watch battery is low
+    #[arg(long)]
+    pub no_battery_alerts: bool,
+
+    /// Battery levels (in percent) to show low battery alerts at
+    #[arg(long, value_name = "PERCENT", value_delimiter = ',', default_values_t = [20, 10])]
+    pub battery_thresholds: Vec<u8>,
+
+    /// Exit when the connection is lost instead of reconnecting
+    #[arg(long)]
+    pub no_reconnect: bool,
+
+    /// Delay between reconnection attempts
+    #[arg(long, value_name = "SECONDS", default_value_t = 10)]
+    pub reconnect_interval: u64,
+}
+
+
+#[tokio::main]
+async fn main() {
+    env_logger::Builder::new()
+        .format_timestamp(None)
+        .filter_module("watchmated", log::LevelFilter::Info)
+        .filter_module("infinitime", log::LevelFilter::Info)
+        .parse_default_env()
+        .init();
+
+    let args = Args::parse();
+    tokio::select! {
+        result = run(args) => if let Err(error) = result {
+            log::error!("{:#}", error);
+            std::process::exit(1);
+        },
+        _ = shutdown_signal() => log::info!("Shutting down"),
+    }
+}
+
+async fn run(args: Args) -> Result<()> {
+    let history = if args.no_history {
+        None
+    } else {
+        History::open_default()
+            .map(Arc::new)
+            .map_err(|error| log::error!("Failed to open history database: {error}"))
+            .ok()
+    };
+
+    let session_bus = zbus::Connection::session().await?;
+    let watchmate = dbus::WatchMate::new(history.clone());
+    session_bus.object_server().at(fdo::daemon::OBJECT_PATH, watchmate).await?;
+    let iface = session_bus.object_server()
+        .interface::<_, dbus::WatchMate>(fdo::daemon::OBJECT_PATH).await?;
+    session_bus.request_name(fdo::daemon::BUS_NAME).await
+        .map_err(|error| match error {
+            zbus::Error::NameTaken => anyhow!("Another instance is already running"),
+            error => error.into(),
+        })?;
+
+    let adapter = bt::init_adapter().await?;
+
+    // Keep the handle alive, the service is unregistered when it's dropped
+    let _gatt_server = match bt::start_gatt_services(&adapter).await {
+        Ok(handle) => Some(handle),
+        Err(error) => {
+            log::error!("Failed to start GATT server: {error}");
+            None
+        }
+    };
+
+    loop {
+        match bt::connect_device(&adapter, args.device).await {
+            Ok(device) => {
+                let address = device.address();
+                log::info!("Connected to {}", address);
+                if let Err(error) = session::run(device, &session_bus, &iface, history.as_deref(), &args).await {
+                    log::error!("Session failed: {:#}", error);
+                }
+                log::info!("Disconnected from {}", address);
+            }
+            Err(error) => {
+                log::warn!("Failed to connect: {:#}", error);
+            }
+        }
+        if args.no_reconnect {
+            break Ok(());
+        }
+        tokio::time::sleep(Duration::from_secs(args.reconnect_interval)).await;
+    }
+}
+
+async fn shutdown_signal() {
+    use tokio::signal::unix::{signal, SignalKind};
+    match signal(SignalKind::terminate()) {
+        Ok(mut sigterm) => tokio::select! {
+            _ = sigterm.recv() => (),
+            _ = tokio::signal::ctrl_c() => (),
+        },
+        Err(_) => {
+            let _ = tokio::signal::ctrl_c().await;
+        }
+    }
+}
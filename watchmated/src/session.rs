@@ -0,0 +1,121 @@
+use crate::{dbus, Args};
+use infinitime::{battery, bluer, bt, fdo, history};
+use anyhow::Result;
+use futures::{future, pin_mut, StreamExt};
+use std::sync::Arc;
+
+
+/// Run background sessions for the connected watch until it disconnects
+pub async fn run(
+    device: bluer::Device,
+    session_bus: &zbus::Connection,
+    iface: &zbus::InterfaceRef<dbus::WatchMate>,
+    history: Option<&history::History>,
+    args: &Args,
+) -> Result<()> {
+    let infinitime = Arc::new(bt::InfiniTime::new(Arc::new(device)).await?);
+
+    let property_stream = infinitime.get_property_stream().await?;
+    pin_mut!(property_stream);
+    // The stream ends when the device disconnects
+    let disconnection = property_stream.count();
+
+    let notifications = async {
+        if !args.no_notifications {
+            log::info!("Notification session started");
+            match fdo::notifications::run_notification_session(&infinitime).await {
+                Ok(()) => log::warn!("Notification session ended unexpectedly"),
+                Err(infinitime::Error::DBusFdo(zbus::fdo::Error::AccessDenied(_))) => log::error!(
+                    "Notification session failed: no permission to monitor D-Bus session bus"
+                ),
+                Err(error) => log::error!("Notification session failed: {error}"),
+            }
+        }
+        future::pending::<()>().await
+    };
+
+    let media_player = async {
+        if !args.no_media_player {
+            match fdo::mpris::run_auto_control_session(session_bus, &infinitime).await {
+                Ok(()) => log::warn!("Media player session ended unexpectedly"),
+                Err(error) => log::error!("Media player session failed: {error}"),
+            }
+        }
+        future::pending::<()>().await
+    };
+
+    let recorder = async {
+        if let Some(history) = history {
+            if let Err(error) = history::run_recorder(history, &infinitime).await {
+                log::error!("History recording failed: {error}");
+            }
+        }
+        future::pending::<()>().await
+    };
+
+    let battery_alerts = async {
+        if !args.no_battery_alerts {
+            if let Err(error) = run_battery_alerts(session_bus, &infinitime, history, args).await {
+                log::error!("Battery alerts failed: {error}");
+            }
+        }
+        future::pending::<()>().await
+    };
+
+    let api = async {
+        dbus::WatchMate::run_device_session(iface, infinitime.clone()).await;
+        future::pending::<()>().await
+    };
+
+    tokio::select! {
+        _ = disconnection => (),
+        _ = notifications => (),
+        _ = media_player => (),
+        _ = recorder => (),
+        _ = battery_alerts => (),
+        _ = api => (),
+    }
+    dbus::WatchMate::clear_device(iface).await;
+    Ok(())
+}
+
+async fn run_battery_alerts(
+    session_bus: &zbus::Connection,
+    infinitime: &bt::InfiniTime,
+    history: Option<&history::History>,
+    args: &Args,
+) -> infinitime::Result<()> {
+    let mut monitor = battery::BatteryMonitor::new(&args.battery_thresholds);
+    if let Some(history) = history {
+        let now = chrono::Utc::now();
+        let range = now - chrono::Duration::days(1)..now;
+        match history.battery_levels(infinitime.address(), range) {
+            Ok(samples) => monitor.seed(samples.into_iter().map(|s| (s.timestamp, s.value))),
+            Err(error) => log::error!("Failed to read battery history: {error}"),
+        }
+    }
+
+    let mut notification_id = 0;
+    let levels = futures::stream::once(infinitime.read_battery_level())
+        .filter_map(|level| future::ready(level.ok()))
+        .chain(infinitime.get_battery_level_stream().await?);
+    pin_mut!(levels);
+    while let Some(level) = levels.next().await {
+        if let Some(threshold) = monitor.update(level) {
+            log::warn!("Watch battery level is {level}% (below {threshold}%)");
+            let body = match monitor.time_to_empty() {
+                Some(estimate) => format!(
+                    "{level}% left, about {} until empty", battery::format_duration(estimate)
+                ),
+                None => format!("{level}% left"),
+            };
+            match fdo::notifications::show_alert(
+                session_bus, "battery-caution-symbolic", "Watch battery is low", &body, notification_id
+            ).await {
+                Ok(id) => notification_id = id,
+                Err(error) => log::error!("Failed to show battery alert: {error}"),
+            }
+        }
+    }
+    Ok(())
+}